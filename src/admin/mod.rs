@@ -0,0 +1,149 @@
+use crate::game::start::StartPolicy;
+
+// Longest command line accepted from an admin connection
+pub const MAX_LINE: usize = 1024;
+
+pub const HELP: &str = "\
+connections              list client connections
+games                    list game rooms
+kick <player>            disconnect a player
+mute <player>            stop a player's take back requests reaching their opponent
+unmute <player>          allow a muted player's take back requests again
+end <game id>            end a game, returning its players to the lobby
+start <game id> <policy> choose who starts a room's next games: random,
+                         alternate, loser, winner or coin
+announce <text>          send an announcement to every named player
+reload                   re-read the config file
+reload-certs             load the TLS certificate and key again
+help                     show this list
+<player> is a player id or user name";
+
+// Player named in an admin command
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    Id(usize),
+    Name(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Help,
+    Connections,
+    Games,
+    Kick(Target),
+    Mute(Target),
+    Unmute(Target),
+    End(u64),
+    Start(u64, StartPolicy),
+    Announce(String),
+    Reload,
+    ReloadCertificates,
+}
+
+// Parse one line typed at the admin console
+pub fn parse(line: &str) -> Result<Command, String> {
+    let line = line.trim();
+    let (name, argument) = match line.find(char::is_whitespace) {
+        Some(split) => (&line[..split], line[split..].trim()),
+        None => (line, ""),
+    };
+    let command = match name {
+        "help" => Command::Help,
+        "connections" => Command::Connections,
+        "games" => Command::Games,
+        "kick" => Command::Kick(parse_target(argument)?),
+        "mute" => Command::Mute(parse_target(argument)?),
+        "unmute" => Command::Unmute(parse_target(argument)?),
+        "end" => Command::End(parse_game_id(argument)?),
+        "start" => {
+            let (game_id, policy) = argument.split_once(char::is_whitespace).unwrap_or((argument, ""));
+            let policy = policy.trim();
+            Command::Start(parse_game_id(game_id)?,
+                StartPolicy::from_name(policy).ok_or_else(|| format!("bad start policy {:?}", policy))?)
+        },
+        "announce" if argument.is_empty() => return Err(String::from("nothing to announce")),
+        "announce" => Command::Announce(argument.to_string()),
+        "reload" => Command::Reload,
+        "reload-certs" => Command::ReloadCertificates,
+        _ => return Err(format!("unknown command {:?}, try help", name)),
+    };
+    let takes_argument = matches!(command,
+            Command::Kick(_) | Command::Mute(_) | Command::Unmute(_) | Command::End(_) | Command::Start(..) | Command::Announce(_));
+    if !takes_argument && !argument.is_empty() {
+        return Err(format!("{} takes no arguments", name));
+    }
+    Ok(command)
+}
+
+fn parse_game_id(argument: &str) -> Result<u64, String> {
+    argument.parse().map_err(|_| format!("bad game id {:?}", argument))
+}
+
+fn parse_target(argument: &str) -> Result<Target, String> {
+    if argument.is_empty() {
+        return Err(String::from("missing player id or name"));
+    }
+    Ok(argument.parse().map_or_else(|_| Target::Name(argument.to_string()), Target::Id))
+}
+
+// Remove and return the complete lines at the start of `input`
+pub fn take_lines(input: &mut Vec<u8>) -> Vec<String> {
+    let mut lines = Vec::new();
+    while let Some(end) = input.iter().position(|&byte| byte == b'\n') {
+        let line: Vec<u8> = input.drain(..=end).collect();
+        lines.push(String::from_utf8_lossy(&line).trim_end().to_string());
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_start_policy() {
+        assert_eq!(parse("start 4 coin"), Ok(Command::Start(4, StartPolicy::CoinFlip)));
+        assert_eq!(parse(" start  12   winner "), Ok(Command::Start(12, StartPolicy::WinnerStarts)));
+        assert!(parse("start 4").is_err());
+        assert!(parse("start x loser").is_err());
+        assert!(parse("start 4 sometimes").is_err());
+    }
+
+    #[test]
+    fn parses_player_commands() {
+        assert_eq!(parse("kick 3"), Ok(Command::Kick(Target::Id(3))));
+        assert_eq!(parse("kick alice"), Ok(Command::Kick(Target::Name("alice".to_string()))));
+        assert_eq!(parse("mute  7 "), Ok(Command::Mute(Target::Id(7))));
+        assert_eq!(parse("unmute Bob Smith"), Ok(Command::Unmute(Target::Name("Bob Smith".to_string()))));
+        for line in &["kick", "mute ", "unmute\t"] {
+            assert_eq!(parse(line), Err(String::from("missing player id or name")), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn parses_server_commands() {
+        assert_eq!(parse("end 12"), Ok(Command::End(12)));
+        assert_eq!(parse("end twelve"), Err(String::from("bad game id \"twelve\"")));
+        assert!(parse("end").is_err());
+        assert!(parse("end -1").is_err());
+        assert_eq!(parse("announce  back in 5 minutes "), Ok(Command::Announce("back in 5 minutes".to_string())));
+        assert_eq!(parse("announce   "), Err(String::from("nothing to announce")));
+        assert_eq!(parse("reload"), Ok(Command::Reload));
+        assert_eq!(parse("reload now"), Err(String::from("reload takes no arguments")));
+        assert_eq!(parse("games all"), Err(String::from("games takes no arguments")));
+        assert_eq!(parse("reload-certs"), Ok(Command::ReloadCertificates));
+        assert_eq!(parse("shutdown"), Err(String::from("unknown command \"shutdown\", try help")));
+        assert!(parse("").is_err());
+    }
+
+    #[test]
+    fn takes_complete_lines() {
+        let mut input = b"games\r\nkick 3\nannou".to_vec();
+        assert_eq!(take_lines(&mut input), ["games", "kick 3"]);
+        assert_eq!(input, b"annou");
+        assert!(take_lines(&mut input).is_empty());
+        input.extend_from_slice(b"nce hi\n");
+        assert_eq!(take_lines(&mut input), ["announce hi"]);
+        assert!(input.is_empty());
+    }
+}
@@ -0,0 +1,108 @@
+use std::{env,fs,net};
+use std::io::BufReader;
+use std::sync::Arc;
+
+use clientserver::client::{Event, GameClient};
+use clientserver::game::Outcome;
+
+use dirs::home_dir;
+
+// Plays games against whoever the server matches it with, making the move
+// each game suggests, and prints what happens
+fn main () {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        println!("Usage: {} <user name> [games] [address:port]", args[0]);
+        return;
+    }
+    let games = args.get(2).and_then(|games| games.parse::<u32>().ok()).unwrap_or(1);
+    let addr: net::SocketAddr = match args.get(3).map_or(Ok("127.0.0.1:9797".parse().unwrap()), |addr| addr.parse()) {
+        Ok(addr) => addr,
+        Err(_) => {
+            println!("Bad server address");
+            return;
+        },
+    };
+
+    // rustls configuration
+    let mut config = rustls::ClientConfig::new();
+    let mut path_buf = home_dir().unwrap();
+    path_buf.push("ca.cheese.crt.pem");
+    let certfile = fs::File::open(path_buf).expect("Cannot open CA file");
+    config.root_store.add_pem_file(&mut BufReader::new(certfile)).unwrap();
+
+    let mut client = match GameClient::connect(addr, Arc::new(config), "localhost") {
+        Ok(client) => client,
+        Err(e) => {
+            println!("Couldn't connect to server: {}", e);
+            return;
+        },
+    };
+    if let Err(e) = client.login(&args[1]) {
+        println!("Cannot log in: {}", e);
+        return;
+    }
+
+    let mut played = 0;
+    // Set once a move is sent, until the server answers it
+    let mut moved = false;
+    loop {
+        let event = match client.next_event(None) {
+            Ok(Some(event)) => event,
+            Ok(None) => continue,
+            Err(e) => {
+                println!("Connection lost: {}", e);
+                return;
+            },
+        };
+        let mut game_over = false;
+        match event {
+            Event::LoggedIn { user_name } => println!("Logged in as {}", user_name),
+            Event::NameRejected { reason } => {
+                println!("Name rejected: {}", reason.map_or("unknown reason".to_string(), |reason| reason.description()));
+                return;
+            },
+            Event::PlayerAdded { player_id, player_name } => println!("{} [{}] joined", player_name, player_id),
+            Event::PlayerMoved { player_id, player_move } => {
+                if let Some(game) = client.game() {
+                    println!("{} played {}", game.player_name(player_id).unwrap_or("?"), game.format_move(&player_move));
+                    game_over = game.is_finished();
+                }
+                moved = false;
+            },
+            Event::ActivePlayer { .. } | Event::GameStarted { .. } => moved = false,
+            Event::OpponentDisconnected => println!("Opponent left"),
+            Event::Announcement { text } | Event::Tournament { text } => println!("{}", text),
+            _ => (),
+        }
+
+        let result = if game_over {
+            match client.game().map(|game| game.outcome()) {
+                Some(Outcome::Decided { ref losers, .. }) if losers.contains(&client.view().user_id) => println!("Game over: lost"),
+                Some(Outcome::Decided { .. }) => println!("Game over: won"),
+                _ => println!("Game over: draw"),
+            }
+            played += 1;
+            if played >= games {
+                let _ = client.end_game().and_then(|_| client.flush());
+                return;
+            }
+            client.restart()
+        } else if client.is_my_turn() && !moved {
+            moved = true;
+            match client.game().and_then(|game| game.suggest_move()) {
+                Some(player_move) => client.send_move(&player_move),
+                None => {
+                    println!("No move to make");
+                    return;
+                },
+            }
+        } else {
+            Ok(())
+        };
+        if let Err(e) = result {
+            println!("Unable to send to server: {}", e);
+            return;
+        }
+    }
+}
@@ -1,561 +1,782 @@
-use std::{str,fs};
-use std::io::{self, Read, Write, BufReader};
+use std::{env,fs,process};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
 use std::net;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc};
-use std::collections::HashSet;
+use std::thread;
+use std::time::{Duration,Instant,SystemTime};
 
 use mio::{Events, Poll, Ready, PollOpt, Token};
 use mio::net::{TcpListener, TcpStream};
+use mio::unix::EventedFd;
 
-use log::{debug};
+use log::{debug, error, info, trace, warn};
 use dirs::home_dir;
 
-use rustls;
-use rustls::{ServerConfig,ServerSession,Session,NoClientAuth};
+use rustls::{ServerConfig,ServerSession};
 
-use clientserver::game::{GameData,Message};
+use clientserver::ratings::Ratings;
+use clientserver::lobby::{ClientState,Lobby,Settings,parse_game_args,MAX_PLAYERS};
+use clientserver::logging::{self,Context};
+use clientserver::metrics;
+use clientserver::admin::{self,Command};
+use clientserver::tls;
+use clientserver::shard::{self,MailboxSender};
+use clientserver::limits::{self,Limits,Peers,TokenBucket};
+use clientserver::listener::{self,ListenerConfig,Security};
 
 use slab::Slab;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+
+// Client listeners use tokens from here up, clear of the player ids
+const LISTENERS: usize = MAX_PLAYERS;
+const MAX_LISTENERS: usize = 8;
+const METRICS_LISTENER: Token = Token(LISTENERS + MAX_LISTENERS);
+const ADMIN_LISTENER: Token = Token(LISTENERS + MAX_LISTENERS + 1);
+const WORKER_EVENTS: Token = Token(LISTENERS + MAX_LISTENERS + 2);
+// Metrics endpoint connections use tokens from here up
+const METRICS_CLIENTS: usize = LISTENERS + MAX_LISTENERS + 3;
+const MAX_METRICS_CLIENTS: usize = 16;
+// Admin console connections use tokens from here up
+const ADMIN_CLIENTS: usize = METRICS_CLIENTS + MAX_METRICS_CLIENTS;
+const MAX_ADMIN_CLIENTS: usize = 4;
+const MATCHMAKER_INTERVAL: Duration = Duration::from_secs(1);
+// How often the certificate and key files are checked for changes
+const CERTIFICATE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+// Unsent bytes a client's TLS session may buffer before it is disconnected
+const MAX_OUTBOUND_BACKLOG: usize = 64 * 1024;
+
+// Server-wide state shared by every connection
+struct ServerState {
+    lobby: Lobby,
+    // Settings from the command line, which the admin reload command applies
+    // the settings file on top of
+    settings: Settings,
+    // Settings file re-read by the admin reload command
+    config_path: Option<PathBuf>,
+    certificates: Certificates,
+    // Connection counts, violations and bans by client address
+    peers: Peers,
+}
+
+// TLS configuration for new sessions, rebuilt when the certificate or key
+// file changes. Sessions hold their own reference to the configuration they
+// started with, so replacing it does not affect connected clients.
+struct Certificates {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    // File holding the passphrase of an encrypted key
+    passphrase_path: Option<PathBuf>,
+    config: Arc<ServerConfig>,
+    // Modification times of the files the current configuration was built from
+    modified: (Option<SystemTime>, Option<SystemTime>),
+    last_checked: Instant,
+}
 
-const MAX_SOCKETS: usize = 1024;
-const LISTENER: Token = Token(MAX_SOCKETS);
+impl Certificates {
+    fn load(cert_path: PathBuf, key_path: PathBuf, passphrase_path: Option<PathBuf>) -> Result<Certificates, String> {
+        let modified = (modified_time(&cert_path), modified_time(&key_path));
+        let config = build_tls_config(&cert_path, &key_path, passphrase_path.as_deref())?;
+        Ok(Certificates { cert_path, key_path, passphrase_path, config, modified, last_checked: Instant::now() })
+    }
+
+    fn config(&self) -> &Arc<ServerConfig> { &self.config }
+
+    // Build a new configuration from the files, keeping the current one if
+    // they cannot be loaded
+    fn reload(&mut self) -> Result<(), String> {
+        let modified = (modified_time(&self.cert_path), modified_time(&self.key_path));
+        let config = build_tls_config(&self.cert_path, &self.key_path, self.passphrase_path.as_deref())?;
+        self.config = config;
+        self.modified = modified;
+        info!("Loaded certificate {:?} and key {:?}", self.cert_path, self.key_path);
+        Ok(())
+    }
 
-// Enumeration to store client state
-enum ClientState {
-    Connected,
-    WaitingOnOpponent,
-    GameInProgress(Token),
+    // Reload if either file has changed since the last load. A failed load
+    // is retried on the next change, as the files may be half written.
+    fn reload_if_changed(&mut self, now: Instant) {
+        if now.duration_since(self.last_checked) < CERTIFICATE_CHECK_INTERVAL {
+            return;
+        }
+        self.last_checked = now;
+        let modified = (modified_time(&self.cert_path), modified_time(&self.key_path));
+        if modified != self.modified {
+            if let Err(e) = self.reload() {
+                warn!("Keeping the current certificate: {}", e);
+                self.modified = modified;
+            }
+        }
+    }
 }
 
-struct SocketData {
-    player_name: String,
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn build_tls_config(cert_path: &Path, key_path: &Path, passphrase_path: Option<&Path>) -> Result<Arc<ServerConfig>, String> {
+    debug!("Loading certificate from {:?}", cert_path);
+    let passphrase = match passphrase_path {
+        Some(passphrase_path) => Some(tls::read_passphrase(passphrase_path).map_err(|e| e.to_string())?),
+        None => None,
+    };
+    let config = tls::server_config(cert_path, key_path, passphrase.as_ref().map(String::as_bytes))
+        .map_err(|e| e.to_string())?;
+    //println!("ServerConfig; ciphersuites={:?}", config.ciphersuites);
+    Ok(Arc::new(config))
+}
+
+// Connection to the metrics endpoint, answered once and then closed
+struct MetricsClient {
     socket: TcpStream,
-    session: ServerSession,
-    state: ClientState,
+    request: Vec<u8>,
+    response: Vec<u8>,
+}
+
+impl MetricsClient {
+    // Read the request and write as much of the response as the socket
+    // will take. Returns false once the client should be closed.
+    fn serve<F>(&mut self, render: F) -> io::Result<bool> where F: FnOnce() -> String {
+        if self.response.is_empty() {
+            let mut buffer = [0; 1024];
+            loop {
+                match self.socket.read(&mut buffer) {
+                    Ok(0) => return Ok(false),
+                    Ok(n) => self.request.extend_from_slice(&buffer[..n]),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                }
+                if self.request.len() > metrics::MAX_REQUEST {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "request too large"));
+                }
+            }
+            if !metrics::is_complete_request(&self.request) {
+                return Ok(true);
+            }
+            self.response = metrics::http_response(&self.request, render);
+        }
+        while !self.response.is_empty() {
+            match self.socket.write(&self.response) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => { self.response.drain(..n); },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(false)
+    }
+}
+
+// Where a player's connection lives
+struct SocketData {
+    // Worker thread that owns the socket and TLS session
+    shard: usize,
+    // Tells this connection's worker events apart from those of earlier
+    // connections with the same player id
+    connection_id: u64,
+    address: net::IpAddr,
+    accepted: Instant,
+    // Until the TLS handshake completes
+    handshaking: bool,
+    messages: TokenBucket,
+}
+
+// Operator connected to the admin console socket
+struct AdminClient {
+    stream: UnixStream,
+    input: Vec<u8>,
+    output: Vec<u8>,
+}
+
+impl AdminClient {
+    // Read whatever the operator has typed. Returns false once they have
+    // closed the connection.
+    fn read(&mut self) -> io::Result<bool> {
+        let mut buffer = [0; 1024];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Ok(false),
+                Ok(n) => self.input.extend_from_slice(&buffer[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Write as much of the pending output as the socket will take
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => { self.output.drain(..n); },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn interest(&self) -> Ready {
+        if self.output.is_empty() { Ready::readable() } else { Ready::readable() | Ready::writable() }
+    }
+}
+
+// Bind the admin socket at `path` so that only the server's user can ever
+// open it. The socket is bound inside a private directory and restricted
+// there, then moved into place.
+fn bind_admin_socket(path: &Path) -> io::Result<UnixListener> {
+    let file_name = path.file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "admin socket path has no file name"))?;
+    let private = path.with_file_name(format!(".{}.{}", file_name.to_string_lossy(), process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let staged = private.join("admin");
+    let result = UnixListener::bind(&staged).and_then(|admin_listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+        let _ = fs::remove_file(path);
+        fs::rename(&staged, path)?;
+        Ok(admin_listener)
+    });
+    let _ = fs::remove_dir_all(&private);
+    result
 }
 
 fn main () {
-    // Used to store the sockets.
-    let mut sockets: Slab<SocketData> = Slab::with_capacity(MAX_SOCKETS);
-    let mut games: Vec<GameData> = Vec::new();
-    let mut names: HashSet<String> = HashSet::new();
+    // Game hosted in every room, chosen on the command line
+    let args: Vec<String> = env::args().collect();
+    let options = match parse_args(&args[1..]) {
+        Some(options) => options,
+        None => {
+            println!("Usage: {} [--start random|alternate|loser|winner|coin] [--tournament-start random|alternate|loser|winner|coin] [--listen address:port[,tls|plaintext][,websocket|json]] [--seed n] [--workers n] [--max-connections n] [--max-connections-per-ip n] [--message-rate n] [--message-burst n] [--handshake-timeout seconds] [--max-violations n] [--ban-time seconds] [--best-of n] [--tournament elimination|roundrobin] [--registration seconds] [--config path] [--admin socket path] [--key-passphrase-file path] [--metrics address:port] [--log-level level[,target=level...]] [--log-format text|json] [--log-file path] [--log-file-size bytes] [--log-files n] [counting [moves...] | nim [misere] [heap sizes...]]", args[0]);
+            return;
+        },
+    };
+    if let Err(e) = logging::init(options.logging) {
+        println!("Unable to start logging: {}", e);
+        return;
+    }
+    let mut settings = options.settings.clone();
+    if let Some(ref config_path) = options.config_path {
+        if let Err(e) = settings.load(config_path) {
+            error!("Unable to load settings: {}", e);
+            return;
+        }
+    }
 
     // rustls configuration
     let mut cert_buffer = home_dir().unwrap();
     cert_buffer.push("leaf.crt.pem");
-    println!("{:?}", cert_buffer);
-    let mut config = ServerConfig::new(NoClientAuth::new());
-    let certs = load_certs(cert_buffer.as_path());
     let mut privkey_buffer = home_dir().unwrap();
     privkey_buffer.push("leaf.key.pem");
-    let privkey = load_private_key(privkey_buffer.as_path());
-    config.set_single_cert(certs, privkey).expect("bad certificates/private key");
-    //println!("ServerConfig; ciphersuites={:?}", config.ciphersuites);
-    let rc_config = Arc::new(config);
+    let certificates = match Certificates::load(cert_buffer, privkey_buffer, options.passphrase_path) {
+        Ok(certificates) => certificates,
+        Err(e) => {
+            error!("Unable to load certificates: {}", e);
+            return;
+        },
+    };
 
-    //let addr: net::SocketAddr = "0.0.0.0:9797".parse().unwrap();
-    let addr: net::SocketAddr = "127.0.0.1:9797".parse().unwrap();
-    let listener = TcpListener::bind(&addr).unwrap();
+    // Used to store the sockets, keyed by player id
+    let mut sockets: HashMap<usize, SocketData> = HashMap::with_capacity(MAX_PLAYERS);
+    let mut history_buffer = home_dir().unwrap();
+    history_buffer.push("clientserver-games");
+    let mut ratings_buffer = home_dir().unwrap();
+    ratings_buffer.push("clientserver.ratings");
+    let ratings = Ratings::load(ratings_buffer.as_path()).expect("cannot load ratings");
+    let rng = match options.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    let mut server = ServerState {
+        lobby: Lobby::new(&settings, history_buffer, ratings, rng),
+        settings: options.settings,
+        config_path: options.config_path,
+        certificates,
+        peers: Peers::new(options.limits),
+    };
 
     let poll = Poll::new().unwrap();
     let mut events = Events::with_capacity(1024);
 
-    poll.register(&listener, LISTENER, Ready::readable(), PollOpt::level()).unwrap();
+    // Client listeners, each with its own token
+    let listeners: Vec<(TcpListener, ListenerConfig)> = match listener::bind(&options.listeners) {
+        Ok(listeners) => listeners.into_iter().zip(options.listeners.iter())
+            .map(|(listener, config)| (TcpListener::from_std(listener).unwrap(), config.clone()))
+            .collect(),
+        Err(e) => {
+            error!("Unable to listen on {}", e);
+            return;
+        },
+    };
+    for (index, (listener, config)) in listeners.iter().enumerate() {
+        poll.register(listener, Token(LISTENERS + index), Ready::readable(), PollOpt::level()).unwrap();
+        info!("Listening on {} ({}{})", listener.local_addr().unwrap(), config.security, config.framing());
+    }
 
-    // Outgoing message queue
-    let mut message_queue = Vec::<(usize, Vec::<u8>)>::new();
-    let mut data_queue = Vec::<u8>::new();
+    // Sockets and TLS sessions are spread over worker threads, which pass
+    // complete messages back here. Game state stays on this thread.
+    let (worker_sender, worker_events) = shard::mailbox();
+    poll.register(&worker_events, WORKER_EVENTS, Ready::readable(), PollOpt::edge()).unwrap();
+    let workers: Vec<MailboxSender<shard::Command>> = (0..options.workers)
+        .map(|index| shard::spawn(index, worker_sender.clone()).expect("cannot start worker thread"))
+        .collect();
+    info!("Started {} worker threads", workers.len());
+    let mut next_connection_id: u64 = 1;
+
+    // Prometheus metrics are served over plain HTTP on a separate port
+    let metrics_listener = options.metrics_address.map(|metrics_address| {
+        let metrics_listener = TcpListener::bind(&metrics_address).expect("cannot bind metrics address");
+        poll.register(&metrics_listener, METRICS_LISTENER, Ready::readable(), PollOpt::level()).unwrap();
+        info!("Serving metrics on http://{}/metrics", metrics_address);
+        metrics_listener
+    });
+    let mut metrics_clients: Slab<MetricsClient> = Slab::with_capacity(MAX_METRICS_CLIENTS);
+
+    // Admin console on a Unix socket only the server's user can open
+    let admin_listener = options.admin_path.map(|admin_path| {
+        let admin_listener = bind_admin_socket(&admin_path).expect("cannot bind admin socket");
+        admin_listener.set_nonblocking(true).unwrap();
+        poll.register(&EventedFd(&admin_listener.as_raw_fd()), ADMIN_LISTENER, Ready::readable(), PollOpt::level()).unwrap();
+        info!("Admin console listening on {:?}", admin_path);
+        admin_listener
+    });
+    let mut admin_clients: Slab<AdminClient> = Slab::with_capacity(MAX_ADMIN_CLIENTS);
 
     loop {
 
-        poll.poll(&mut events, None).unwrap();
+        // Wake up periodically while players are queued so the matchmaker
+        // can widen their rating windows or handshakes are pending, and to
+        // look for new certificates
+        let busy = server.lobby.is_waiting() || sockets.values().any(|socket_data| socket_data.handshaking);
+        let timeout = if busy { MATCHMAKER_INTERVAL } else { CERTIFICATE_CHECK_INTERVAL };
+        poll.poll(&mut events, Some(timeout)).unwrap();
 
         for event in &events {
             match event.token() {
-                LISTENER => {
+                // Take every pending connection, turning away those over a
+                // limit, so one refusal does not hold up the rest
+                token if (LISTENERS..LISTENERS + listeners.len()).contains(&usize::from(token)) => loop {
+                    let (ref listener, ref config) = listeners[usize::from(token) - LISTENERS];
                     match listener.accept() {
                         Ok((socket, addr)) => {
-                            println!("Accepting new connection from {:?}", addr);
+                            debug!("Accepting new connection from {:?}", addr);
                             // check max connections
-                            if sockets.len() >= MAX_SOCKETS {
-                                println!("Max connections reached {}" , MAX_SOCKETS);
-                                socket.shutdown(net::Shutdown::Both).unwrap();
-                                break;
+                            let max_connections = server.peers.limits().connections;
+                            if sockets.len() >= max_connections {
+                                warn!("Max connections reached {}", max_connections);
+                                server.lobby.metrics().connection_rejected();
+                                let _ = socket.shutdown(net::Shutdown::Both);
+                                continue;
+                            }
+                            let now = Instant::now();
+                            if let Err(refusal) = server.peers.admit(addr.ip(), now) {
+                                info!("Refused connection from {:?}: {}", addr, refusal);
+                                server.lobby.metrics().connection_limited();
+                                let _ = socket.shutdown(net::Shutdown::Both);
+                                continue;
                             }
 
-                            let socket_entry = sockets.vacant_entry();
-                            let token = Token(socket_entry.key());
-                            poll.register(&socket, token, Ready::readable() | Ready::writable(), PollOpt::level()).unwrap();
-                            socket_entry.insert(SocketData{player_name: String::from(""), socket: socket, session: ServerSession::new(&rc_config), state: ClientState::Connected});
-                            // Send a Welcome message
-                            // player_id (token)
-                            let mut welcome_message: Vec<u8> = [Message::Welcome as u8, 1].to_vec();
-                            welcome_message.push(usize::from(token) as u8);
-                            message_queue.push((usize::from(token), welcome_message));
-
+                            // Player ids stay below MAX_PLAYERS, clear of
+                            // the other tokens
+                            let player_id = server.lobby.connect(addr);
+                            let _scope = logging::scope(Context::connection(player_id));
+                            info!("Connection from {:?}", addr);
+                            server.lobby.metrics().connection_accepted();
+                            let shard = player_id % workers.len();
+                            let connection_id = next_connection_id;
+                            next_connection_id += 1;
+                            let session = match config.security {
+                                Security::Tls => Some(Box::new(ServerSession::new(server.certificates.config()))),
+                                Security::Plaintext => None,
+                            };
+                            let websocket = config.websocket;
+                            let handshaking = session.is_some() || websocket;
+                            let messages = TokenBucket::new(server.peers.limits(), now);
+                            sockets.insert(player_id, SocketData { shard, connection_id, address: limits::canonical(addr.ip()), accepted: now, handshaking, messages });
+                            let json = config.json;
+                            workers[shard].send(shard::Command::Add { player_id, connection_id, socket, session, websocket, json, buffer_limit: MAX_OUTBOUND_BACKLOG });
                         },
                         Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                            println!("Listening socket would block");
+                            trace!("Listening socket would block");
+                            break;
+                        },
+                        Err(e) => {
+                            warn!("Unable to accept connection: {}", e);
                             break;
                         },
-                        e => panic!("Err={:?}", e),
                     }
                 },
-                token => {
-                    let mut socket_data = &mut sockets.get_mut(usize::from(token)).unwrap();
-
-
-                    if event.readiness().is_readable() && socket_data.session.wants_read() {
-                        println!("session[is_handshaking={};]",
-                                socket_data.session.is_handshaking());
-
-
-                    // Print data off socket here to debug TLS from Apple
-                    let mut debug_buf: [u8; 2500] = [0; 2500];
-                    let mut temp_buffer: Vec<u8> = Vec::new();
-                    match socket_data.socket.read(&mut debug_buf) {
-                        Ok(n) => {
-                            println!("Debug Buffer({})={:?}", n, &debug_buf[0..n]);
-                            temp_buffer.extend_from_slice(&debug_buf[0..n]);
-                        },
-
-                        Err(e) => {
-                            println!("Err={}", e);
+                METRICS_LISTENER => {
+                    if let Some(ref metrics_listener) = metrics_listener {
+                        match metrics_listener.accept() {
+                            Ok((socket, _)) if metrics_clients.len() >= MAX_METRICS_CLIENTS => {
+                                let _ = socket.shutdown(net::Shutdown::Both);
+                            },
+                            Ok((socket, _)) => {
+                                let client_entry = metrics_clients.vacant_entry();
+                                let token = Token(METRICS_CLIENTS + client_entry.key());
+                                poll.register(&socket, token, Ready::readable(), PollOpt::level()).unwrap();
+                                client_entry.insert(MetricsClient { socket, request: Vec::new(), response: Vec::new() });
+                            },
+                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                            Err(e) => warn!("Unable to accept metrics connection: {}", e),
                         }
-
                     }
-
-                        //match socket_data.session.read_tls(&mut socket_data.socket) {
-                        match socket_data.session.read_tls(&mut temp_buffer.as_slice()) {
-                            Ok(0) => {
-                                // Client disconnected, find partner client if it exists
-                                let mut partner_token: Option<Token> = None;
-                                match socket_data.state {
-                                    ClientState::GameInProgress(temp_partner_token) => {
-                                        partner_token = Some(temp_partner_token);
-                                    },
-                                    _ => (),
-                                }
-
-                                // Clean up state of partner if necessary
-                                if let Some(partner_token) = partner_token {
-                                    match sockets.get(usize::from(partner_token)).unwrap().state {
-                                        ClientState::GameInProgress(_token) => {
-                                            println!("Client disconnected, update partner");
-                                            let disconnect_message: Vec<u8> =
-                                                [Message::OpponentDisconnect as u8, 0].to_vec();
-                                            message_queue.push((usize::from(partner_token), disconnect_message));
-                                            sockets.get_mut(usize::from(partner_token)).unwrap().state = ClientState::WaitingOnOpponent;
-                                        },
-                                        _ => unreachable!(),
-                                    }
-                                }
-
-                                // Socket is closed
-                                println!("Socket closed");
-                                poll.deregister(& sockets.get(usize::from(token)).unwrap().socket).unwrap();
-                                let gd = sockets.remove(usize::from(token));
-                                names.remove(&gd.player_name);
-
-                                // Remove/Update GameData
-                                games.retain(|game| {
-                                    !game.game_has_player(usize::from(token))
-                                });
-                                break;
-                            }
-                            Ok(n) => {
-                                println!("read_tls: {} bytes", n);
-                                // Process packets
-                                match socket_data.session.process_new_packets() {
-                                    Ok(_) => {
-                                        let mut data = Vec::<u8>::new();
-                                        match socket_data.session.read_to_end(&mut data) {
-                                            Ok(0) => {
-                                                println!("TLS data only");
-                                            },
-                                            Ok(n) => {
-                                                println!("read_to_end: {}", n);
-                                                println!("Got a data: {:?}", data);
-                                                io::stdout().flush().unwrap();
-
-                                                // Append data to data queue
-                                                data_queue.append(&mut data);
-
-                                                // process the data
-                                                while data_queue.len() >= 2 {
-                                                    // Ensure we have the entire client message
-                                                    let msg_len = data_queue[1] as usize;
-                                                    if data_queue.len() >= 2 + msg_len {
-                                                        process_client_data(
-                                                                data_queue[0],
-                                                                data_queue[1],
-                                                                &data_queue[2..(2+msg_len)],
-                                                                token,
-                                                                &mut socket_data,
-                                                                &mut games,
-                                                                &mut names,
-                                                                &mut message_queue);
-
-                                                        let vec2 = data_queue.split_off(2 + msg_len);
-                                                        data_queue = vec2;
-                                                    } else {
-                                                        // Do not have full message, need more data
-                                                        break;
-                                                    }
-                                                }
-                                            },
-                                            Err(e) => panic!(e),
-                                        }
-                                    }
-                                    Err(err) => {
-                                        debug!("Error: {}", err)
-                                    }
-                                }
-                            }
-                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                                // Socket is not ready anymore, stop reading
-                                println!("Read Would block");
-                            }
-                            Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => {
-                                poll.deregister(& sockets.get(usize::from(token)).unwrap().socket).unwrap();
-                                sockets.remove(usize::from(token));
-                                break;
-                            }
-                            e => panic!("err={:?}", e), // Unexpected error
+                },
+                ADMIN_LISTENER => {
+                    if let Some(ref admin_listener) = admin_listener {
+                        match admin_listener.accept() {
+                            Ok((stream, _)) if admin_clients.len() >= MAX_ADMIN_CLIENTS => {
+                                let _ = stream.shutdown(net::Shutdown::Both);
+                            },
+                            Ok((stream, _)) => {
+                                stream.set_nonblocking(true).unwrap();
+                                let client_entry = admin_clients.vacant_entry();
+                                let token = Token(ADMIN_CLIENTS + client_entry.key());
+                                poll.register(&EventedFd(&stream.as_raw_fd()), token, Ready::readable(), PollOpt::level()).unwrap();
+                                info!("Admin console connected");
+                                client_entry.insert(AdminClient { stream, input: Vec::new(), output: Vec::new() });
+                            },
+                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                            Err(e) => warn!("Unable to accept admin connection: {}", e),
                         }
                     }
-
-                    if event.readiness().is_writable() && socket_data.session.wants_write() {
-                        println!("session[is_handshaking={};]",
-                                socket_data.session.is_handshaking());
-
-                    // Print data off socket here to debug TLS goimng to Apple
-                    let mut temp_buffer: Vec<u8> = Vec::new();
-                    match socket_data.session.write_tls(&mut temp_buffer) {
-                        Ok(n) => {
-                            println!("Write Debug Buffer({})={:?}", n, temp_buffer);
-                        },
-
-                        Err(e) => {
-                            println!("Err={}", e);
+                },
+                token if usize::from(token) >= ADMIN_CLIENTS => {
+                    let key = usize::from(token) - ADMIN_CLIENTS;
+                    let client = match admin_clients.get_mut(key) {
+                        Some(client) => client,
+                        None => continue,
+                    };
+                    let mut result = if event.readiness().is_readable() { client.read() } else { Ok(true) };
+                    for line in admin::take_lines(&mut client.input) {
+                        if !line.is_empty() {
+                            let reply = run_admin_command(&line, &workers, &mut sockets, &mut server);
+                            client.output.extend_from_slice(reply.as_bytes());
+                            client.output.push(b'\n');
                         }
-
                     }
-                        //match socket_data.session.write_tls(&mut socket_data.socket) {
-                        match socket_data.socket.write(temp_buffer.as_slice()) {
-                            Ok(size) => {
-                                println!("Wrote {} bytes", size);
-                            }
-                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                                // Socket is not ready anymore, stop reading
-                                println!("Write Would block");
-                            }
-                            Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => {
-                                // Socket is not ready anymore, stop reading
-                                println!("Connection reset breaking loop");
-                                break;
-                            }
-                            e => panic!("err={:?}", e), // Unexpected error
-                        }
+                    if client.input.len() > admin::MAX_LINE {
+                        result = Err(io::Error::new(io::ErrorKind::InvalidData, "command too long"));
                     }
-
-                    // TODO: Move this into the message handling code
-                    // Update state of any socket connections that don't have active games
-                    for (check_token, check_socket_data) in sockets.iter_mut() {
-                        match check_socket_data.state {
-                            ClientState::GameInProgress(_) => {
-                                // If this token doesn't have an active game, update state
-                                if !games.iter().any(|game| game.game_has_player(usize::from(check_token))) {
-                                    // No game to match up with this socket_data, reset status to
-                                    // WaitingOnOpponent
-                                    check_socket_data.state = ClientState::WaitingOnOpponent;
-                                }
-                            },
-                            _ => (),
-                        }
+                    let result = result.and_then(|open| client.flush().map(|_| open));
+                    match result {
+                        Ok(true) => {
+                            poll.reregister(&EventedFd(&client.stream.as_raw_fd()), token, client.interest(), PollOpt::level()).unwrap();
+                        },
+                        result => {
+                            if let Err(e) = result {
+                                warn!("Admin connection failed: {}", e);
+                            }
+                            info!("Admin console disconnected");
+                            let client = admin_clients.remove(key);
+                            poll.deregister(&EventedFd(&client.stream.as_raw_fd())).unwrap();
+                        },
                     }
-
-
-                    // Check to see if there are two clients we can pair up in a game
-                    // See if there are any other
-                    // clients we could start a game
-                    // with
-                    let mut partner1_token: Option<Token> = None;
-                    let mut partner1_name = "".to_string();
-                    let mut partner2_token: Option<Token> = None;
-                    let mut partner2_name = "".to_string();
-                    for (check_token, check_socket_data) in sockets.iter() {
-                        match check_socket_data.state {
-                            ClientState::WaitingOnOpponent => {
-                                // Found a client waiting for an opponent
-                                if partner1_token == None {
-                                    debug!("Found first client who is waiting for a game");
-                                    partner1_token = Some(Token::from(check_token));
-                                    partner1_name = check_socket_data.player_name.to_string();
-                                    continue;
-                                } else if partner2_token == None {
-                                    debug!("Found second client who is waiting for a game");
-                                    partner2_token = Some(Token::from(check_token));
-                                    partner2_name = check_socket_data.player_name.to_string();
-                                    break;
-                                } else {
-                                    unreachable!();
-                                }
+                },
+                token if usize::from(token) >= METRICS_CLIENTS => {
+                    let key = usize::from(token) - METRICS_CLIENTS;
+                    let client = match metrics_clients.get_mut(key) {
+                        Some(client) => client,
+                        None => continue,
+                    };
+                    match client.serve(|| server.lobby.render_metrics()) {
+                        Ok(true) if !client.response.is_empty() => {
+                            poll.reregister(&client.socket, token, Ready::writable(), PollOpt::level()).unwrap();
+                        },
+                        Ok(true) => (),
+                        result => {
+                            if let Err(e) = result {
+                                debug!("Metrics request failed: {}", e);
                             }
-                            _ => (),
-                        }
+                            let client = metrics_clients.remove(key);
+                            poll.deregister(&client.socket).unwrap();
+                            let _ = client.socket.shutdown(net::Shutdown::Both);
+                        },
                     }
-
-                    if let Some(partner1_token) = partner1_token {
-                        if let Some(partner2_token) = partner2_token {
-
-                            println!("STARTING NEW GAME!  {} vs {}", partner1_name, partner2_name);
-                            // Have two clients to match up in a game
-                            let partner1_name = sockets.get_mut(usize::from(partner1_token)).unwrap().player_name.to_string();
-                            let partner2_name = sockets.get_mut(usize::from(partner2_token)).unwrap().player_name.to_string();
-
-                            // Update client status
-                            sockets.get_mut(usize::from(partner1_token)).unwrap().state = ClientState::GameInProgress(partner2_token);
-                            sockets.get_mut(usize::from(partner2_token)).unwrap().state = ClientState::GameInProgress(partner1_token);
-
-                            // Build a new Game object
-                            let mut game_data = GameData::new(2, 3, 10);
-                            game_data.add_player(usize::from(partner1_token), &partner1_name);
-                            game_data.add_player(usize::from(partner2_token), &partner2_name);
-
-                            // Send GameData message to clients:
-                            // max_players, max_move, game_board_size
-                            let game_data_message: Vec<u8> = [Message::GameData as u8,3,2,3,10].to_vec();
-                            message_queue.push((usize::from(partner1_token), game_data_message.clone()));
-                            message_queue.push((usize::from(partner2_token), game_data_message));
-
-                            // Send Add_Player messages to both clients
-                            let mut add_player1_message: Vec<u8> = [Message::AddPlayer as u8].to_vec();
-                            add_player1_message.push((partner1_name.as_bytes().len() + 1) as u8);
-                            add_player1_message.push(usize::from(partner1_token) as u8);
-                            add_player1_message.extend_from_slice(partner1_name.as_bytes());
-
-                            let mut add_player2_message: Vec<u8> = [Message::AddPlayer as u8].to_vec();
-                            add_player2_message.push((partner2_name.as_bytes().len() + 1) as u8);
-                            add_player2_message.push(usize::from(partner2_token) as u8);
-                            add_player2_message.extend_from_slice(partner2_name.as_bytes());
-
-                            message_queue.push((usize::from(partner1_token), add_player1_message.clone()));
-                            message_queue.push((usize::from(partner2_token), add_player1_message));
-
-                            message_queue.push((usize::from(partner1_token), add_player2_message.clone()));
-                            message_queue.push((usize::from(partner2_token), add_player2_message));
-
-                            // Send Set_Active_Player message to both clients
-                            // player_id
-                            let mut set_active_player_message: Vec<u8> = [Message::SetActivePlayer as u8,1].to_vec();
-                            set_active_player_message.push(game_data.get_active_player_id());
-
-                            message_queue.push((usize::from(partner1_token), set_active_player_message.clone()));
-                            message_queue.push((usize::from(partner2_token), set_active_player_message));
-
-                            // Add the Game object to the global store
-                            games.push(game_data);
-
-
-                        }
+                },
+                WORKER_EVENTS => {
+                    for worker_event in worker_events.drain() {
+                        handle_worker_event(worker_event, &workers, &mut sockets, &mut server);
                     }
-
-                    // Clear out message queue
-                    message_queue.retain(|message| {
-                        println!("Message: [token={}; data={:?}", message.0, message.1);
-                        sockets.get_mut(message.0).unwrap().session.write(message.1.as_slice()).unwrap();
-                        false
-                    });
-
                 },
+                _ => (),
             }
+        }
 
+        // Drop connections that are slow to finish their handshake
+        let now = Instant::now();
+        let handshake_timeout = server.peers.limits().handshake_timeout;
+        let expired: Vec<usize> = sockets.iter()
+            .filter(|(_, socket_data)| socket_data.handshaking && now.saturating_duration_since(socket_data.accepted) >= handshake_timeout)
+            .map(|(&player_id, _)| player_id)
+            .collect();
+        for player_id in expired {
+            let _scope = logging::scope(Context::connection(player_id));
+            warn!("Handshake timed out");
+            server.lobby.metrics().handshake_timeout();
+            record_violation(player_id, &workers, &mut sockets, &mut server);
+            disconnect_client(player_id, &workers, &mut sockets, &mut server);
         }
+        server.peers.expire(now);
+
+        // Pick up rotated certificates for new connections
+        server.certificates.reload_if_changed(now);
+
+        // Start games and move tournaments along, then send out everything
+        // queued for the players
+        server.lobby.update(Instant::now());
+        flush_messages(&workers, &sockets, &mut server.lobby);
     }
 }
 
-fn process_client_data(
-        control_byte: u8,
-        data_len: u8,
-        data: &[u8],
-        token: Token,
-        socket_data: &mut SocketData,
-        games: &mut Vec<GameData>,
-        names: &mut HashSet<String>,
-        message_queue: &mut Vec::<(usize, Vec::<u8>)>) {
-
-    println!("Processing [control_byte: {}; data_len: {}; data: {:?}]", control_byte, data_len, data);
-    match control_byte {
-
-        // 0: User_Name Message
-        // control_byte: 0
-        // data_len: n
-        // data[..] = user_name
-        0 => {
-            let v = str::from_utf8(data).unwrap().to_string();
-            println!("{}: {:?}", v, v.clone().into_bytes());
-
-            // Got a string from the client, process it
-            // based on the client state
-            match socket_data.state {
-                // Only process when client is in Connected state
-                ClientState::Connected => {
-                    // Ensure name is not already in use
-                    if names.insert(v.clone()) {
-                        println!("Got client name, now WaitingOnOpponent");
-                        // Update client status to WaitingOnOpponent
-                        socket_data.state = ClientState::WaitingOnOpponent;
-                        socket_data.player_name = v.clone();
-
-                        // Send user name back to client
-                        let mut user_name_message: Vec<u8> = [Message::ServerUserName as u8].to_vec();
-                        user_name_message.push(v.as_bytes().len() as u8);
-                        user_name_message.extend_from_slice(v.as_bytes());
-                        message_queue.push((usize::from(token), user_name_message));
-                    }
-                },
-                _ => {
-                    // Do nothing
-                }
+// Act on a connection's socket activity reported by its worker
+fn handle_worker_event(
+        worker_event: shard::Event,
+        workers: &[MailboxSender<shard::Command>],
+        sockets: &mut HashMap<usize, SocketData>,
+        server: &mut ServerState) {
+    let (player_id, connection_id) = match worker_event {
+        shard::Event::HandshakeCompleted { player_id, connection_id } => (player_id, connection_id),
+        shard::Event::Received { player_id, connection_id, .. } => (player_id, connection_id),
+        shard::Event::Closed { player_id, connection_id, .. } => (player_id, connection_id),
+    };
+    // Events may still arrive from a connection the server has dropped
+    if sockets.get(&player_id).is_none_or(|socket_data| socket_data.connection_id != connection_id) {
+        return;
+    }
+    let _scope = logging::scope(Context::connection(player_id).with_game(server.lobby.game_id(player_id)));
+    match worker_event {
+        shard::Event::HandshakeCompleted { .. } => {
+            server.lobby.metrics().handshake_completed();
+            if let Some(socket_data) = sockets.get_mut(&player_id) {
+                socket_data.handshaking = false;
             }
         },
-
-        // 1: Player_Move message
-        // control_byte: 1
-        // data_len: 1
-        // data[0]: player_move
-        1 => {
-            match socket_data.state {
-                ClientState::GameInProgress(partner_token) => {
-                    // Get the game data
-                    if let Some(game_data) = games.iter_mut().find(|game| game.game_has_player(usize::from(token))) {
-                        // make the player move
-                        let player_move = data[0];
-                        game_data.move_player(usize::from(token), player_move);
-
-                        // Send Move_Player message
-                        let mut move_player_message: Vec<u8> = [Message::MovePlayer as u8, 2].to_vec();
-                        move_player_message.push(usize::from(token) as u8);
-                        move_player_message.push(player_move);
-
-                        message_queue.push((usize::from(partner_token), move_player_message.clone()));
-                        message_queue.push((usize::from(token), move_player_message));
-                    }
-                },
-
-                // Do nothing for any state other than GameInProgress
-                _ => { }
+        shard::Event::Received { control_byte, data, .. } => {
+            server.lobby.metrics().message_received(control_byte);
+            let allowed = sockets.get_mut(&player_id)
+                .is_some_and(|socket_data| socket_data.messages.take(server.peers.limits(), Instant::now()));
+            if !allowed {
+                warn!("Sending messages too fast");
+                server.lobby.metrics().rate_limited();
+                record_violation(player_id, workers, sockets, server);
+                disconnect_client(player_id, workers, sockets, server);
+            } else if !server.lobby.receive(player_id, control_byte, &data) {
+                record_violation(player_id, workers, sockets, server);
             }
         },
+        shard::Event::Closed { error, .. } => {
+            if let Some(e) = error {
+                if e.kind() == io::ErrorKind::InvalidData {
+                    server.lobby.metrics().tls_failure();
+                    record_violation(player_id, workers, sockets, server);
+                } else if e.kind() == io::ErrorKind::InvalidInput {
+                    server.lobby.metrics().protocol_error();
+                    record_violation(player_id, workers, sockets, server);
+                }
+            }
+            disconnect_client(player_id, workers, sockets, server);
+        },
+    }
+}
 
-        // 2: Restart_Game message
-        // control_byte: 2
-        // data_len: 0
-        2 => {
-            match socket_data.state {
-                ClientState::GameInProgress(partner_token) => {
-                    // Get the game data
-                    if let Some(game_data) = games.iter_mut().find(|game| game.game_has_player(usize::from(token))) {
-
-                        // Ensure game is over
-                        if game_data.is_game_over() {
-                            // Restart request, add_player
-                            game_data.add_player(usize::from(token), "");
+// Pass queued messages to the workers owning the recipients' connections
+fn flush_messages(
+        workers: &[MailboxSender<shard::Command>],
+        sockets: &HashMap<usize, SocketData>,
+        lobby: &mut Lobby) {
+    for (player_id, message) in lobby.take_messages() {
+        let _scope = logging::scope(Context::connection(player_id).with_game(lobby.game_id(player_id)));
+        debug!("Sending {:?}", message);
+        lobby.metrics().message_sent(message[0]);
+        // The recipient may have disconnected since the message was queued
+        if let Some(socket_data) = sockets.get(&player_id) {
+            workers[socket_data.shard].send(shard::Command::Send { player_id, data: message });
+        }
+    }
+}
 
-                            // Send Add_Player messages to both clients
-                            let mut add_player_message: Vec<u8> = [Message::AddPlayer as u8, 1].to_vec();
-                            add_player_message.push(usize::from(token) as u8);
+// Drop a client's connection and everything the lobby holds for it
+fn disconnect_client(
+        player_id: usize,
+        workers: &[MailboxSender<shard::Command>],
+        sockets: &mut HashMap<usize, SocketData>,
+        server: &mut ServerState) {
+    if let Some(socket_data) = sockets.remove(&player_id) {
+        workers[socket_data.shard].send(shard::Command::Close { player_id });
+        server.peers.release(socket_data.address);
+        server.lobby.disconnect(player_id);
+    }
+}
 
-                            message_queue.push((usize::from(partner_token), add_player_message.clone()));
-                            message_queue.push((usize::from(token), add_player_message));
+// Count a protocol violation against the client's address. Once that gets
+// the address banned, every connection from it is dropped.
+fn record_violation(
+        player_id: usize,
+        workers: &[MailboxSender<shard::Command>],
+        sockets: &mut HashMap<usize, SocketData>,
+        server: &mut ServerState) {
+    let address = match sockets.get(&player_id) {
+        Some(socket_data) => socket_data.address,
+        None => return,
+    };
+    if !server.peers.violation(address, Instant::now()) {
+        return;
+    }
+    warn!("Banning {} for {:?} after repeated protocol violations", address, server.peers.limits().ban_duration);
+    server.lobby.metrics().address_banned();
+    let banned: Vec<usize> = sockets.iter()
+        .filter(|(_, socket_data)| socket_data.address == address)
+        .map(|(&player_id, _)| player_id)
+        .collect();
+    for player_id in banned {
+        disconnect_client(player_id, workers, sockets, server);
+    }
+}
 
-                        } else {
-                        }
-                    } else {
-                        // TODO: No game to restart
-                    }
+// Carry out one admin console command, returning the text to show
+fn run_admin_command(
+        line: &str,
+        workers: &[MailboxSender<shard::Command>],
+        sockets: &mut HashMap<usize, SocketData>,
+        server: &mut ServerState) -> String {
+    let command = match admin::parse(line) {
+        Ok(command) => command,
+        Err(e) => return format!("error: {}", e),
+    };
+    info!("Admin command: {}", line);
+    let lobby = &mut server.lobby;
+    match command {
+        Command::Help => String::from(admin::HELP),
+        Command::Connections => {
+            let lines: Vec<String> = lobby.players().map(|(player_id, player)| {
+                let state = match player.state {
+                    ClientState::Connected => String::from("connected"),
+                    ClientState::WaitingOnOpponent => String::from("waiting"),
+                    ClientState::GameInProgress(partner_id) => format!("playing {}", partner_id),
+                };
+                let name = if player.player_name.is_empty() { "-" } else { player.player_name.as_str() };
+                let muted = if player.muted { " muted" } else { "" };
+                format!("{} {} {} {}{}", player_id, name, state, player.address, muted)
+            }).collect();
+            format!("{} connections\n{}", lines.len(), lines.join("\n")).trim_end().to_string()
+        },
+        Command::Games => {
+            let lines = lobby.describe_games();
+            format!("{} games\n{}", lines.len(), lines.join("\n")).trim_end().to_string()
+        },
+        Command::Kick(target) => match lobby.find_player(&target) {
+            Some(player_id) => {
+                disconnect_client(player_id, workers, sockets, server);
+                format!("kicked {}", player_id)
+            },
+            None => String::from("error: no such player"),
+        },
+        Command::Mute(ref target) | Command::Unmute(ref target) => {
+            let muted = matches!(command, Command::Mute(_));
+            match lobby.find_player(target) {
+                Some(player_id) => {
+                    lobby.set_muted(player_id, muted);
+                    format!("{} {}", if muted { "muted" } else { "unmuted" }, player_id)
                 },
-
-                // Do nothing for any state other than GameInProgress
-                _ => { }
+                None => String::from("error: no such player"),
             }
         },
-
-        // 3: End_Game message
-        // control_byte: 3
-        // data_len: 0
-        3 => {
-            match socket_data.state {
-                ClientState::GameInProgress(partner_token) => {
-                    // Get the game data
-                    if let Some(game_data) = games.iter_mut().find(|game| game.game_has_player(usize::from(token))) {
-
-                        // Ensure game is over
-                        if game_data.is_game_over() {
-                            // Send Opponent_Disconnect messages to both clients
-                            let disconnect_message: Vec::<u8> = [Message::OpponentDisconnect as u8, 0].to_vec();
-
-                            message_queue.push((usize::from(partner_token), disconnect_message.clone()));
-                            message_queue.push((usize::from(token), disconnect_message));
-
-                            // Update client status to WaitingOnOpponent
-                            socket_data.state = ClientState::WaitingOnOpponent;
-
-                        } else {
-                        }
-
-                        // Assumes the block above has sent Disconnect messages to both players
-                        // Remove the game being played from the active games list
-                        games.retain(|game| !game.game_has_player(usize::from(token)));
-                    } else {
-                        // TODO: No game to end
-                    }
+        Command::End(game_id) => {
+            if lobby.end_game(game_id) {
+                format!("ended game {}", game_id)
+            } else {
+                String::from("error: no such game")
+            }
+        },
+        Command::Start(game_id, policy) => {
+            if lobby.set_start_policy(game_id, policy) {
+                format!("game {} now starts with the {} policy", game_id, policy.name())
+            } else {
+                String::from("error: no such game")
+            }
+        },
+        Command::Announce(text) => format!("announced to {} players", lobby.announce(&text)),
+        Command::Reload => {
+            let mut settings = server.settings.clone();
+            let result = match server.config_path {
+                Some(ref config_path) => settings.load(config_path),
+                None => Err(String::from("no config file given with --config")),
+            };
+            match result {
+                Ok(()) => {
+                    lobby.apply_settings(&settings);
+                    String::from("reloaded settings for new games")
                 },
-
-                // Do nothing for any state other than GameInProgress
-                _ => { }
+                Err(e) => format!("error: {}", e),
             }
         },
-
-
-
-        // Unknown control byte; do nothing?
-        unknown => {
-            println!("Unknown control byte: {}", unknown);
-        }
+        Command::ReloadCertificates => match server.certificates.reload() {
+            Ok(()) => String::from("reloaded certificates for new connections"),
+            Err(e) => format!("error: {}", e),
+        },
     }
 }
 
-fn load_certs(filename: &std::path::Path) -> Vec<rustls::Certificate> {
-    let certfile = fs::File::open(filename).expect("cannot open certificate file");
-    let mut reader = BufReader::new(certfile);
-    rustls::internal::pemfile::certs(&mut reader).unwrap()
+// Settings from the command line
+struct Options {
+    logging: logging::Config,
+    listeners: Vec<ListenerConfig>,
+    metrics_address: Option<net::SocketAddr>,
+    admin_path: Option<PathBuf>,
+    config_path: Option<PathBuf>,
+    passphrase_path: Option<PathBuf>,
+    seed: Option<u64>,
+    // Worker threads handling client sockets
+    workers: usize,
+    limits: Limits,
+    settings: Settings,
 }
 
-fn load_private_key(filename: &std::path::Path) -> rustls::PrivateKey {
-    let rsa_keys = {
-        let keyfile = fs::File::open(filename)
-            .expect("cannot open private key file");
-        let mut reader = BufReader::new(keyfile);
-        rustls::internal::pemfile::rsa_private_keys(&mut reader)
-            .expect("file contains invalid rsa private key")
-    };
-
-    let pkcs8_keys = {
-        let keyfile = fs::File::open(filename)
-            .expect("cannot open private key file");
-        let mut reader = BufReader::new(keyfile);
-        rustls::internal::pemfile::pkcs8_private_keys(&mut reader)
-            .expect("file contains invalid pkcs8 private key (encrypted keys not supported)")
-    };
-
-    // prefer to load pkcs8 keys
-    if !pkcs8_keys.is_empty() {
-        pkcs8_keys[0].clone()
-    } else {
-        assert!(!rsa_keys.is_empty());
-        rsa_keys[0].clone()
+fn parse_args(args: &[String]) -> Option<Options> {
+    let mut logging = logging::Config::default();
+    let mut listeners = Vec::new();
+    let mut metrics_address = None;
+    let mut admin_path = None;
+    let mut config_path = None;
+    let mut passphrase_path = None;
+    let mut seed = None;
+    let mut workers = thread::available_parallelism().map_or(1, |workers| workers.get());
+    let mut limits = Limits::new();
+    let mut settings = Settings::new();
+    let mut index = 0;
+    while index < args.len() {
+        match args[index].as_str() {
+            "--listen" => listeners.push(parse_listener(args.get(index + 1)?)?),
+            "--seed" => seed = Some(args.get(index + 1)?.parse().ok()?),
+            "--workers" => workers = args.get(index + 1)?.parse().ok().filter(|&n| n > 0)?,
+            "--metrics" => metrics_address = Some(args.get(index + 1)?.parse().ok()?),
+            "--admin" => admin_path = Some(PathBuf::from(args.get(index + 1)?)),
+            "--config" => config_path = Some(PathBuf::from(args.get(index + 1)?)),
+            "--key-passphrase-file" => passphrase_path = Some(PathBuf::from(args.get(index + 1)?)),
+            flag if Settings::is_setting(flag) => settings.set(flag, args.get(index + 1)?)?,
+            flag if Limits::is_option(flag) => limits.set_option(flag, args.get(index + 1)?)?,
+            flag if logging::Config::is_option(flag) => logging.set_option(flag, args.get(index + 1)?)?,
+            _ => break,
+        }
+        index += 2;
+    }
+    settings.game = parse_game_args(&args[index..])?;
+    if listeners.is_empty() {
+        listeners.push(ListenerConfig::default());
     }
+    if listeners.len() > MAX_LISTENERS {
+        println!("At most {} listeners are allowed", MAX_LISTENERS);
+        return None;
+    }
+    Some(Options { logging, listeners, metrics_address, admin_path, config_path, passphrase_path, seed, workers, limits, settings })
 }
 
+fn parse_listener(spec: &str) -> Option<ListenerConfig> {
+    ListenerConfig::parse(spec).map_err(|e| println!("{}", e)).ok()
+}
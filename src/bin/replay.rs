@@ -0,0 +1,119 @@
+use std::{env,thread};
+use std::io::{self,Write};
+use std::path::Path;
+use std::time::Duration;
+
+use console::{Term, Style};
+
+use clientserver::game::{Game,Outcome};
+use clientserver::history::{GameLog,GameRecord};
+
+fn main () {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        println!("Usage: {} <game file> [delay_ms]", args[0]);
+        println!("Steps through a saved game; press enter to advance unless a delay is given");
+        return;
+    }
+
+    let log = match GameLog::load(Path::new(&args[1])) {
+        Ok(log) => log,
+        Err(e) => {
+            println!("Cannot load {}: {}", args[1], e);
+            return;
+        },
+    };
+    let delay = args.get(2).and_then(|delay| delay.parse::<u64>().ok()).map(Duration::from_millis);
+
+    let active_player_style = Style::new().green();
+    let losing_player_style = Style::new().red().reverse();
+    let term = Term::stdout();
+
+    let mut game = match log.new_game() {
+        Some(game) => game,
+        None => {
+            println!("Cannot replay {}: unknown game type {}", args[1], log.game_type);
+            return;
+        },
+    };
+    let start = log.records.first().map_or(0, |record| record.timestamp());
+
+    for (step, record) in log.records.iter().enumerate() {
+        let description = match *record {
+            GameRecord::Player { player_id, ref player_name, .. } => {
+                game.add_player(player_id, player_name);
+                format!("{} joined as player {}", player_name, player_id)
+            },
+            GameRecord::ActivePlayer { player_id, .. } => {
+                game.set_current_player(player_id);
+                format!("{} starts", player_name(game.as_ref(), player_id))
+            },
+            GameRecord::Move { player_id, ref player_move, .. } => {
+                match game.apply_move(player_id, player_move) {
+                    Ok(()) => format!("{}: {}", player_name(game.as_ref(), player_id), game.format_move(player_move)),
+                    Err(e) => format!("{} made an invalid move {:?}: {:?}", player_name(game.as_ref(), player_id), player_move, e),
+                }
+            },
+            GameRecord::GameOver { losing_player_id, .. } => {
+                format!("{} has lost the game", player_name(game.as_ref(), losing_player_id))
+            },
+            GameRecord::Draw { .. } => {
+                "The game is a draw".to_string()
+            },
+            GameRecord::Restart { .. } => {
+                // Every player agreed to play again
+                for player_id in game.player_ids().to_vec() {
+                    game.request_restart(player_id);
+                }
+                "Game restarted".to_string()
+            },
+            GameRecord::Undo { .. } => {
+                match game.undo_last_move() {
+                    Some((player_id, player_move)) =>
+                        format!("{} took back their move: {}", player_name(game.as_ref(), player_id), game.format_move(&player_move)),
+                    None => "Nothing to take back".to_string(),
+                }
+            },
+        };
+
+        term.clear_screen().unwrap();
+        println!("Replay: {} [{}/{}] +{:.1}s", args[1], step + 1, log.records.len(),
+            record.timestamp().saturating_sub(start) as f64 / 1000.0);
+        println!("{}", description);
+        println!();
+        println!("Game: {}", game.name());
+        let losers = match game.outcome() {
+            Outcome::Decided { losers, .. } => losers,
+            _ => Vec::new(),
+        };
+        for &player_id in game.player_ids() {
+            let name = player_name(game.as_ref(), player_id);
+            if losers.contains(&player_id) {
+                println!("Player: {} [{}]", losing_player_style.apply_to(name), player_id);
+            } else if game.current_player() == Some(player_id) {
+                println!("Player: {} [{}]", active_player_style.apply_to(name), player_id);
+            } else {
+                println!("Player: {} [{}]", name, player_id);
+            }
+        }
+        for line in game.describe() {
+            println!("{}", line);
+        }
+
+        match delay {
+            Some(delay) => thread::sleep(delay),
+            None => {
+                print!("[enter] ");
+                io::stdout().flush().unwrap();
+                let mut buffer = String::new();
+                if io::stdin().read_line(&mut buffer).unwrap_or(0) == 0 {
+                    break;
+                }
+            },
+        }
+    }
+}
+
+fn player_name(game: &dyn Game, player_id: usize) -> String {
+    game.player_name(player_id).unwrap_or("?").to_string()
+}
@@ -1,9 +1,12 @@
-use std::{str,thread,fs,time};
-use std::io::{Read,BufReader};
+use std::{str,thread,time};
+use std::io::Read;
+use std::path::Path;
 use std::net::{TcpListener,TcpStream,SocketAddr};
 use std::sync::{Arc};
 
-use rustls::{ServerConfig,ServerSession,Session,NoClientAuth};
+use rustls::{ServerSession,Session};
+
+use clientserver::tls;
 
 fn main () {
     let listener = TcpListener::bind("127.0.0.1:97").unwrap();
@@ -20,10 +23,10 @@ fn handle_client (mut stream: TcpStream, address: SocketAddr) {
 
     println!("New client address: {:?}", address);
 
-    let mut config = ServerConfig::new(NoClientAuth::new());
-    let certs = load_certs("C:\\Users\\ianc\\serverCertificateChain.pem");
-    let privkey = load_private_key("C:\\Users\\ianc\\serverPrivKey.pem");
-    config.set_single_cert(certs, privkey).expect("bad certificates/private key");
+    let config = tls::server_config(
+            Path::new("C:\\Users\\ianc\\serverCertificateChain.pem"),
+            Path::new("C:\\Users\\ianc\\serverPrivKey.pem"),
+            None).unwrap_or_else(|e| panic!("{}", e));
     let rc_config = Arc::new(config);
     let mut server = ServerSession::new(&rc_config);
 
@@ -81,35 +84,3 @@ fn handle_client (mut stream: TcpStream, address: SocketAddr) {
     } {}
     */
 }
-
-fn load_certs(filename: &str) -> Vec<rustls::Certificate> {
-    let certfile = fs::File::open(filename).expect("cannot open certificate file");
-    let mut reader = BufReader::new(certfile);
-    rustls::internal::pemfile::certs(&mut reader).unwrap()
-}
-
-fn load_private_key(filename: &str) -> rustls::PrivateKey {
-    let rsa_keys = {
-        let keyfile = fs::File::open(filename)
-            .expect("cannot open private key file");
-        let mut reader = BufReader::new(keyfile);
-        rustls::internal::pemfile::rsa_private_keys(&mut reader)
-            .expect("file contains invalid rsa private key")
-    };
-
-    let pkcs8_keys = {
-        let keyfile = fs::File::open(filename)
-            .expect("cannot open private key file");
-        let mut reader = BufReader::new(keyfile);
-        rustls::internal::pemfile::pkcs8_private_keys(&mut reader)
-            .expect("file contains invalid pkcs8 private key (encrypted keys not supported)")
-    };
-
-    // prefer to load pkcs8 keys
-    if !pkcs8_keys.is_empty() {
-        pkcs8_keys[0].clone()
-    } else {
-        assert!(!rsa_keys.is_empty());
-        rsa_keys[0].clone()
-    }
-}
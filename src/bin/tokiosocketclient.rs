@@ -0,0 +1,124 @@
+use std::{env,net,fs};
+use std::io::BufReader;
+use std::sync::Arc;
+
+use log::{debug, warn};
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+use clientserver::client::GameView;
+use clientserver::connection;
+use clientserver::logging;
+
+use console::Term;
+
+use dirs::home_dir;
+
+// Same console client as miosocketclient, reading the terminal and the
+// server side by side in one task
+#[tokio::main]
+async fn main () {
+    // Log to standard error, or a file so records do not disturb the prompt
+    let args: Vec<String> = env::args().collect();
+    let logging_config = match parse_args(&args[1..]) {
+        Some(logging_config) => logging_config,
+        None => {
+            println!("Usage: {} [--log-level level[,target=level...]] [--log-format text|json] [--log-file path] [--log-file-size bytes] [--log-files n]", args[0]);
+            return;
+        },
+    };
+    if let Err(e) = logging::init(logging_config) {
+        println!("Unable to start logging: {}", e);
+        return;
+    }
+
+    // rustls configuration
+    let mut config = rustls::ClientConfig::new();
+    let mut path_buf = home_dir().unwrap();
+    path_buf.push("ca.cheese.crt.pem");
+
+    let certfile = fs::File::open(path_buf).expect("Cannot open CA file");
+    let mut reader = BufReader::new(certfile);
+    config.root_store.add_pem_file(&mut reader).unwrap();
+    let connector = TlsConnector::from(Arc::new(config));
+    let example_com = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+
+    let addr: net::SocketAddr = "127.0.0.1:9797".parse().unwrap();
+    let stream = match TcpStream::connect(&addr).await {
+        Ok(stream) => stream,
+        Err(_) => {
+            println!("Couldn't connect to server...");
+            return;
+        },
+    };
+    let stream = match connector.connect(example_com, stream).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            println!("Handshake failed: {}", e);
+            return;
+        },
+    };
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+
+    let mut view = GameView::new();
+    let term = Term::stdout();
+    let mut buffer = [0; 4096];
+    let mut inbound = Vec::new();
+
+    loop {
+        // Display user-prompt
+        view.draw(&term).unwrap();
+
+        tokio::select! {
+            line = lines.next_line() => match line {
+                Ok(Some(line)) => {
+                    if let Some(message) = view.handle_input(line.trim()) {
+                        if let Err(e) = writer.write_all(&message).await {
+                            println!("Unable to send to server: {}", e);
+                            break;
+                        }
+                    }
+                },
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Error: {}", e);
+                    break;
+                },
+            },
+            read = reader.read(&mut buffer) => match read {
+                Ok(0) => {
+                    debug!("Socket closed");
+                    break;
+                },
+                Ok(n) => {
+                    debug!("Got a data: {:?}", &buffer[..n]);
+                    inbound.extend_from_slice(&buffer[..n]);
+                    while let Some((control_byte, data)) = connection::take_message(&mut inbound) {
+                        view.process_server_data(control_byte, &data);
+                    }
+                },
+                Err(e) => {
+                    println!("Connection lost: {}", e);
+                    break;
+                },
+            },
+        }
+    }
+}
+
+// Logging options from the command line. Only warnings are logged unless
+// asked for, as the prompt shares the terminal.
+fn parse_args(args: &[String]) -> Option<logging::Config> {
+    let mut logging = logging::Config::default();
+    logging.set_option("--log-level", "warn")?;
+    for option in args.chunks(2) {
+        if !logging::Config::is_option(&option[0]) {
+            return None;
+        }
+        logging.set_option(&option[0], option.get(1)?)?;
+    }
+    Some(logging)
+}
@@ -0,0 +1,476 @@
+use std::env;
+use std::collections::HashMap;
+use std::io;
+use std::net;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, warn};
+use dirs::home_dir;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio_rustls::TlsAcceptor;
+
+use clientserver::connection;
+use clientserver::limits::{self, Limits, Peers, TokenBucket};
+use clientserver::listener::{self, ListenerConfig, Security};
+use clientserver::lobby::{Lobby, Settings, parse_game_args};
+use clientserver::logging::{self, Context};
+use clientserver::ratings::Ratings;
+use clientserver::shard::Event;
+use clientserver::tls;
+use clientserver::json::JsonLines;
+use clientserver::websocket::WebSocket;
+
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+
+const MATCHMAKER_INTERVAL: Duration = Duration::from_secs(1);
+// Messages queued for a client before it is disconnected for not reading
+const MAX_OUTBOUND_MESSAGES: usize = 256;
+
+// The lobby's handle on a connection task
+struct Client {
+    // Tells this connection's events apart from those of earlier
+    // connections with the same player id
+    connection_id: u64,
+    // Dropping the sender closes the connection
+    outbound: mpsc::Sender<Vec<u8>>,
+    address: net::IpAddr,
+    messages: TokenBucket,
+}
+
+// Same protocol and game rules as miosocketlistener, with each connection
+// handled by its own task. The lobby runs on the main task and hears from
+// connections over a channel, so game state needs no locking.
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().collect();
+    let options = match parse_args(&args[1..]) {
+        Some(options) => options,
+        None => {
+            println!("Usage: {} [--start random|alternate|loser|winner|coin] [--tournament-start random|alternate|loser|winner|coin] [--listen address:port[,tls|plaintext][,websocket|json]] [--seed n] [--max-connections n] [--max-connections-per-ip n] [--message-rate n] [--message-burst n] [--handshake-timeout seconds] [--max-violations n] [--ban-time seconds] [--best-of n] [--tournament elimination|roundrobin] [--registration seconds] [--config path] [--key-passphrase-file path] [--log-level level[,target=level...]] [--log-format text|json] [--log-file path] [--log-file-size bytes] [--log-files n] [counting [moves...] | nim [misere] [heap sizes...]]", args[0]);
+            return;
+        },
+    };
+    if let Err(e) = logging::init(options.logging) {
+        println!("Unable to start logging: {}", e);
+        return;
+    }
+    let mut settings = options.settings;
+    if let Some(ref config_path) = options.config_path {
+        if let Err(e) = settings.load(config_path) {
+            error!("Unable to load settings: {}", e);
+            return;
+        }
+    }
+
+    // rustls configuration
+    let mut cert_buffer = home_dir().unwrap();
+    cert_buffer.push("leaf.crt.pem");
+    let mut privkey_buffer = home_dir().unwrap();
+    privkey_buffer.push("leaf.key.pem");
+    let acceptor = match load_acceptor(&cert_buffer, &privkey_buffer, options.passphrase_path.as_deref()) {
+        Ok(acceptor) => acceptor,
+        Err(e) => {
+            error!("Unable to load certificates: {}", e);
+            return;
+        },
+    };
+
+    let mut history_buffer = home_dir().unwrap();
+    history_buffer.push("clientserver-games");
+    let mut ratings_buffer = home_dir().unwrap();
+    ratings_buffer.push("clientserver.ratings");
+    let ratings = Ratings::load(ratings_buffer.as_path()).expect("cannot load ratings");
+    let rng = match options.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    let mut lobby = Lobby::new(&settings, history_buffer, ratings, rng);
+    let mut peers = Peers::new(options.limits);
+
+    // Each listener gets a task passing its connections to the main loop
+    let listeners = match listener::bind(&options.listeners) {
+        Ok(listeners) => listeners,
+        Err(e) => {
+            error!("Unable to listen on {}", e);
+            return;
+        },
+    };
+    let (accepted_sender, mut accepted) = mpsc::unbounded_channel();
+    for (listener, config) in listeners.into_iter().zip(options.listeners.iter()) {
+        let listener = TcpListener::from_std(listener).expect("cannot register listener");
+        info!("Listening on {} ({}{})", config.address, config.security, config.framing());
+        tokio::spawn(accept_connections(listener, config.clone(), accepted_sender.clone()));
+    }
+
+    let (event_sender, mut events) = mpsc::unbounded_channel();
+    let mut clients: HashMap<usize, Client> = HashMap::new();
+    let mut next_connection_id: u64 = 1;
+    let mut matchmaker_timer = tokio::time::interval(MATCHMAKER_INTERVAL);
+
+    loop {
+        tokio::select! {
+            Some((socket, addr, config)) = accepted.recv() => {
+                let max_connections = peers.limits().connections;
+                if clients.len() >= max_connections {
+                    warn!("Max connections reached {}", max_connections);
+                    lobby.metrics().connection_rejected();
+                    continue;
+                }
+                let now = Instant::now();
+                if let Err(refusal) = peers.admit(addr.ip(), now) {
+                    info!("Refused connection from {:?}: {}", addr, refusal);
+                    lobby.metrics().connection_limited();
+                    continue;
+                }
+                let player_id = lobby.connect(addr);
+                let _scope = logging::scope(Context::connection(player_id));
+                info!("Connection from {:?}", addr);
+                lobby.metrics().connection_accepted();
+                let connection_id = next_connection_id;
+                next_connection_id += 1;
+                let (outbound, outbound_receiver) = mpsc::channel(MAX_OUTBOUND_MESSAGES);
+                let messages = TokenBucket::new(peers.limits(), now);
+                clients.insert(player_id, Client { connection_id, outbound, address: limits::canonical(addr.ip()), messages });
+                let acceptor = match config.security {
+                    Security::Tls => Some(acceptor.clone()),
+                    Security::Plaintext => None,
+                };
+                let handshake_timeout = peers.limits().handshake_timeout;
+                let link = Link { player_id, connection_id, events: event_sender.clone(), outbound: outbound_receiver };
+                tokio::spawn(serve_client(socket, acceptor, config, handshake_timeout, link));
+            },
+            Some(event) = events.recv() => handle_event(event, &mut clients, &mut peers, &mut lobby),
+            _ = matchmaker_timer.tick() => peers.expire(Instant::now()),
+        }
+
+        // Start games and move tournaments along, then send out everything
+        // queued for the players
+        lobby.update(Instant::now());
+        flush_messages(&mut clients, &mut peers, &mut lobby);
+    }
+}
+
+fn load_acceptor(cert_path: &Path, key_path: &Path, passphrase_path: Option<&Path>) -> Result<TlsAcceptor, tls::TlsError> {
+    let passphrase = match passphrase_path {
+        Some(passphrase_path) => Some(tls::read_passphrase(passphrase_path)?),
+        None => None,
+    };
+    let config = tls::server_config(cert_path, key_path, passphrase.as_ref().map(String::as_bytes))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// Act on a connection task's report
+fn handle_event(event: Event, clients: &mut HashMap<usize, Client>, peers: &mut Peers, lobby: &mut Lobby) {
+    let (player_id, connection_id) = match event {
+        Event::HandshakeCompleted { player_id, connection_id } => (player_id, connection_id),
+        Event::Received { player_id, connection_id, .. } => (player_id, connection_id),
+        Event::Closed { player_id, connection_id, .. } => (player_id, connection_id),
+    };
+    // Events may still arrive from a connection the server has dropped
+    if clients.get(&player_id).is_none_or(|client| client.connection_id != connection_id) {
+        return;
+    }
+    let _scope = logging::scope(Context::connection(player_id).with_game(lobby.game_id(player_id)));
+    match event {
+        Event::HandshakeCompleted { .. } => lobby.metrics().handshake_completed(),
+        Event::Received { control_byte, data, .. } => {
+            lobby.metrics().message_received(control_byte);
+            let allowed = clients.get_mut(&player_id)
+                .is_some_and(|client| client.messages.take(peers.limits(), Instant::now()));
+            if !allowed {
+                warn!("Sending messages too fast");
+                lobby.metrics().rate_limited();
+                record_violation(player_id, clients, peers, lobby);
+                disconnect_client(player_id, clients, peers, lobby);
+            } else if !lobby.receive(player_id, control_byte, &data) {
+                record_violation(player_id, clients, peers, lobby);
+            }
+        },
+        Event::Closed { error, .. } => {
+            if let Some(e) = error {
+                if e.kind() == io::ErrorKind::InvalidData {
+                    lobby.metrics().tls_failure();
+                    record_violation(player_id, clients, peers, lobby);
+                } else if e.kind() == io::ErrorKind::InvalidInput {
+                    lobby.metrics().protocol_error();
+                    record_violation(player_id, clients, peers, lobby);
+                } else if e.kind() == io::ErrorKind::TimedOut {
+                    lobby.metrics().handshake_timeout();
+                    record_violation(player_id, clients, peers, lobby);
+                }
+            }
+            disconnect_client(player_id, clients, peers, lobby);
+        },
+    }
+}
+
+// Drop a client's connection and everything the lobby holds for it
+fn disconnect_client(player_id: usize, clients: &mut HashMap<usize, Client>, peers: &mut Peers, lobby: &mut Lobby) {
+    if let Some(client) = clients.remove(&player_id) {
+        peers.release(client.address);
+        lobby.disconnect(player_id);
+    }
+}
+
+// Count a protocol violation against the client's address. Once that gets
+// the address banned, every connection from it is dropped.
+fn record_violation(player_id: usize, clients: &mut HashMap<usize, Client>, peers: &mut Peers, lobby: &mut Lobby) {
+    let address = match clients.get(&player_id) {
+        Some(client) => client.address,
+        None => return,
+    };
+    if !peers.violation(address, Instant::now()) {
+        return;
+    }
+    warn!("Banning {} for {:?} after repeated protocol violations", address, peers.limits().ban_duration);
+    lobby.metrics().address_banned();
+    let banned: Vec<usize> = clients.iter()
+        .filter(|(_, client)| client.address == address)
+        .map(|(&player_id, _)| player_id)
+        .collect();
+    for player_id in banned {
+        disconnect_client(player_id, clients, peers, lobby);
+    }
+}
+
+// Pass queued messages to the connection tasks. Clients that cannot keep up
+// are disconnected, which may queue messages for their opponents, so repeat
+// until done.
+fn flush_messages(clients: &mut HashMap<usize, Client>, peers: &mut Peers, lobby: &mut Lobby) {
+    loop {
+        let messages = lobby.take_messages();
+        if messages.is_empty() {
+            break;
+        }
+        for (player_id, message) in messages {
+            let _scope = logging::scope(Context::connection(player_id).with_game(lobby.game_id(player_id)));
+            debug!("Sending {:?}", message);
+            lobby.metrics().message_sent(message[0]);
+            // The recipient may have disconnected since the message was queued
+            let result = match clients.get_mut(&player_id) {
+                Some(client) => client.outbound.try_send(message),
+                None => continue,
+            };
+            if let Err(TrySendError::Full(_)) = result {
+                warn!("Client is not reading its messages");
+            }
+            if result.is_err() {
+                disconnect_client(player_id, clients, peers, lobby);
+            }
+        }
+    }
+}
+
+// Pass a listener's connections to the main loop
+async fn accept_connections(
+        mut listener: TcpListener,
+        config: ListenerConfig,
+        accepted: mpsc::UnboundedSender<(TcpStream, net::SocketAddr, ListenerConfig)>) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, addr)) => {
+                if accepted.send((socket, addr, config.clone())).is_err() {
+                    break;
+                }
+            },
+            Err(e) => warn!("Unable to accept connection: {}", e),
+        }
+    }
+}
+
+// A connection task's channels to the main loop
+struct Link {
+    player_id: usize,
+    connection_id: u64,
+    events: mpsc::UnboundedSender<Event>,
+    outbound: mpsc::Receiver<Vec<u8>>,
+}
+
+impl Link {
+    fn send(&self, event: Event) {
+        let _ = self.events.send(event);
+    }
+
+    fn closed(&self, error: Option<io::Error>) {
+        self.send(Event::Closed { player_id: self.player_id, connection_id: self.connection_id, error });
+    }
+
+    // The task may move between threads at each await, so the logging
+    // context is set around each record rather than for the whole task
+    fn log_scope(&self) -> logging::Scope {
+        logging::scope(Context::connection(self.player_id))
+    }
+}
+
+// Run one client connection: the TLS handshake unless the listener is
+// plaintext, then passing messages both ways until either side closes it.
+// The handshake and any WebSocket upgrade must finish by the deadline.
+async fn serve_client(
+        socket: TcpStream,
+        acceptor: Option<TlsAcceptor>,
+        config: ListenerConfig,
+        handshake_timeout: Duration,
+        link: Link) {
+    let deadline = tokio::time::Instant::now() + handshake_timeout;
+    let websocket = if config.websocket { Some(WebSocket::new()) } else { None };
+    let json = if config.json { Some(JsonLines::new()) } else { None };
+    let acceptor = match acceptor {
+        Some(acceptor) => acceptor,
+        None => return relay(socket, websocket, json, deadline, link).await,
+    };
+    let stream = match tokio::time::timeout_at(deadline, acceptor.accept(socket)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => {
+            let _scope = link.log_scope();
+            warn!("Handshake failed: {}", e);
+            link.closed(Some(e));
+            return;
+        },
+        Err(_) => {
+            let _scope = link.log_scope();
+            warn!("Handshake timed out");
+            link.closed(Some(io::Error::new(io::ErrorKind::TimedOut, "handshake timed out")));
+            return;
+        },
+    };
+    if websocket.is_none() {
+        link.send(Event::HandshakeCompleted { player_id: link.player_id, connection_id: link.connection_id });
+    }
+    relay(stream, websocket, json, deadline, link).await
+}
+
+async fn relay<S: AsyncRead + AsyncWrite>(
+        stream: S,
+        mut websocket: Option<WebSocket>,
+        mut json: Option<JsonLines>,
+        deadline: tokio::time::Instant,
+        mut link: Link) {
+    let (player_id, connection_id) = (link.player_id, link.connection_id);
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut buffer = [0; 4096];
+    let mut inbound = Vec::new();
+    let error = loop {
+        let upgrading = websocket.as_ref().is_some_and(|websocket| websocket.is_upgrading());
+        tokio::select! {
+            read = reader.read(&mut buffer) => match read {
+                Ok(0) => break None,
+                Ok(n) => {
+                    // Unwrap WebSocket frames or JSON lines, answering pings
+                    // and the like
+                    let mut reply = Vec::new();
+                    let result = match (websocket.as_mut(), json.as_mut()) {
+                        (Some(websocket), _) => websocket.receive(&buffer[..n], &mut inbound, &mut reply),
+                        (None, Some(json)) => json.receive(&buffer[..n], &mut inbound, &mut reply),
+                        (None, None) => {
+                            inbound.extend_from_slice(&buffer[..n]);
+                            Ok(())
+                        },
+                    };
+                    if upgrading && websocket.as_ref().is_some_and(|websocket| !websocket.is_upgrading() && !websocket.is_closed()) {
+                        link.send(Event::HandshakeCompleted { player_id, connection_id });
+                    }
+                    while let Some((control_byte, data)) = connection::take_message(&mut inbound) {
+                        link.send(Event::Received { player_id, connection_id, control_byte, data });
+                    }
+                    if let Err(e) = writer.write_all(&reply).await {
+                        let _scope = link.log_scope();
+                        warn!("Write failed: {}", e);
+                        break Some(e);
+                    }
+                    if let Err(e) = result {
+                        let _scope = link.log_scope();
+                        warn!("Read failed: {}", e);
+                        break Some(e);
+                    }
+                    if websocket.as_ref().is_some_and(WebSocket::is_closed) {
+                        break None;
+                    }
+                },
+                Err(e) => {
+                    let _scope = link.log_scope();
+                    warn!("Read failed: {}", e);
+                    break Some(e);
+                },
+            },
+            message = link.outbound.recv() => match message {
+                Some(message) => {
+                    let message = match (websocket.as_mut(), json.as_mut()) {
+                        (Some(websocket), _) => {
+                            let mut frame = Vec::new();
+                            websocket.send(&message, &mut frame);
+                            frame
+                        },
+                        (None, Some(json)) => {
+                            let mut lines = Vec::new();
+                            json.send(&message, &mut lines);
+                            lines
+                        },
+                        (None, None) => message,
+                    };
+                    if let Err(e) = writer.write_all(&message).await {
+                        let _scope = link.log_scope();
+                        warn!("Write failed: {}", e);
+                        break Some(e);
+                    }
+                },
+                // The server has dropped the client
+                None => {
+                    let _ = writer.shutdown().await;
+                    return;
+                },
+            },
+            _ = tokio::time::delay_until(deadline), if upgrading => {
+                let _scope = link.log_scope();
+                warn!("WebSocket upgrade timed out");
+                break Some(io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"));
+            },
+        }
+    };
+    link.closed(error);
+}
+
+// Settings from the command line
+struct Options {
+    logging: logging::Config,
+    listeners: Vec<ListenerConfig>,
+    config_path: Option<PathBuf>,
+    passphrase_path: Option<PathBuf>,
+    seed: Option<u64>,
+    limits: Limits,
+    settings: Settings,
+}
+
+fn parse_args(args: &[String]) -> Option<Options> {
+    let mut logging = logging::Config::default();
+    let mut listeners = Vec::new();
+    let mut config_path = None;
+    let mut passphrase_path = None;
+    let mut seed = None;
+    let mut limits = Limits::new();
+    let mut settings = Settings::new();
+    let mut index = 0;
+    while index < args.len() {
+        match args[index].as_str() {
+            "--listen" => listeners.push(ListenerConfig::parse(args.get(index + 1)?).map_err(|e| println!("{}", e)).ok()?),
+            "--seed" => seed = Some(args.get(index + 1)?.parse().ok()?),
+            "--config" => config_path = Some(PathBuf::from(args.get(index + 1)?)),
+            "--key-passphrase-file" => passphrase_path = Some(PathBuf::from(args.get(index + 1)?)),
+            flag if Settings::is_setting(flag) => settings.set(flag, args.get(index + 1)?)?,
+            flag if Limits::is_option(flag) => limits.set_option(flag, args.get(index + 1)?)?,
+            flag if logging::Config::is_option(flag) => logging.set_option(flag, args.get(index + 1)?)?,
+            _ => break,
+        }
+        index += 2;
+    }
+    settings.game = parse_game_args(&args[index..])?;
+    if listeners.is_empty() {
+        listeners.push(ListenerConfig::default());
+    }
+    Some(Options { logging, listeners, config_path, passphrase_path, seed, limits, settings })
+}
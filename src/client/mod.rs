@@ -0,0 +1,845 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::str;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use console::{Term, style, Style};
+use rustls::{ClientConfig, ClientSession};
+
+use crate::connection::Connection;
+
+use crate::game::{self, decode_player_id, Game, Message, Outcome, PLAYER_ID_LENGTH};
+use crate::names::{self, NameRejection};
+use crate::ratings::{self, PlayerStats};
+use crate::series::{self, Series};
+
+// A client's copy of its state on the server, kept up to date from server
+// messages, and the console prompt drawn from it. GameClient keeps one
+// up to date; the tokio client feeds its own.
+pub struct GameView {
+    pub game_data: Option<Box<dyn Game>>,
+    pub user_name: String,
+    pub user_id: usize,
+    // Server responses and errors to display above the prompt
+    pub notices: Vec<String>,
+    // Score of the current best-of-N match, if playing one
+    pub match_score: Option<Series>,
+}
+
+impl Default for GameView {
+    fn default() -> GameView {
+        GameView::new()
+    }
+}
+
+impl GameView {
+    pub fn new() -> GameView {
+        GameView {
+            game_data: None,
+            user_name: String::new(),
+            user_id: usize::MAX,
+            notices: Vec::new(),
+            match_score: None,
+        }
+    }
+
+    // Clear the terminal and draw the notices, board and prompt
+    pub fn draw(&self, term: &Term) -> io::Result<()> {
+        let winning_player_style = Style::new().green().blink().reverse();
+        let losing_player_style = Style::new().red().blink().reverse();
+        let active_player_style = Style::new().green();
+        let inactive_player_style = Style::new().red();
+
+        term.clear_screen()?;
+        for notice in &self.notices {
+            println!("{}", notice);
+        }
+        if self.user_name.is_empty() {
+            print!("Please enter user name: ");
+        } else {
+            // Echo board to stdout
+            if let Some(ref game_data) = self.game_data {
+                match game_data.outcome() {
+                    Outcome::Decided { ref losers, .. } if losers.contains(&self.user_id) => {
+                        println!("Player: {} [{}]", losing_player_style.apply_to(self.user_name.clone()), self.user_id);
+                    },
+                    Outcome::Decided { .. } | Outcome::Draw => {
+                        println!("Player: {} [{}]", winning_player_style.apply_to(self.user_name.clone()), self.user_id);
+                    },
+                    Outcome::InProgress if game_data.current_player() == Some(self.user_id) => {
+                        println!("Player: {} [{}]", active_player_style.apply_to(self.user_name.clone()), self.user_id);
+                    },
+                    Outcome::InProgress => {
+                        println!("Player: {} [{}]", inactive_player_style.apply_to(self.user_name.clone()), self.user_id);
+                    },
+                }
+                println!("Game: {}", game_data.name());
+                if let Some(ref match_score) = self.match_score {
+                    println!("{}", format_score(match_score, game_data.as_ref()));
+                }
+                if let Some(active_player) = game_data.current_player() {
+                    println!("Active Player: {}", active_player);
+                }
+                for line in game_data.describe() {
+                    println!("{}", line);
+                }
+                let player_names: Vec<&str> = game_data.player_ids().iter()
+                    .filter_map(|&player_id| game_data.player_name(player_id))
+                    .collect();
+                println!("Game Players: {:?} ", player_names);
+                if game_data.is_finished() {
+                    print!("Play again (yes/no)? ");
+                } else if game_data.current_player() == Some(self.user_id) {
+                    print!("{}", game_data.move_prompt());
+                } else {
+                    println!("Waiting for other player to move");
+                }
+            } else {
+                println!("Player: {} [{}]", style(self.user_name.clone()).blue(), self.user_id);
+                println!("Status: Waiting for opponent");
+            }
+            print!("> ");
+        }
+        io::stdout().flush()
+    }
+
+    // Turn a line typed by the user into the message to send, if any
+    pub fn handle_input(&mut self, buffer: &str) -> Option<Vec<u8>> {
+        debug!("{}", buffer);
+        let buffer_data = buffer.as_bytes();
+        self.notices.clear();
+
+        // Check to see if we have a user name
+        if self.user_name.is_empty() {
+            // Send User_Name message
+            // control_byte: Message::ClientUserName (0)
+            // data_len: n
+            // data[0..n] = user name
+            // Validate locally first; the server has the final say
+            match names::normalize_name(buffer_data) {
+                Ok(_) => {
+                    let mut user_name_message: Vec<u8> = [Message::ClientUserName as u8].to_vec();
+                    user_name_message.push(buffer_data.len() as u8);
+                    user_name_message.extend_from_slice(buffer_data);
+                    Some(user_name_message)
+                },
+                Err(rejection) => {
+                    self.notices.push(format!("Invalid user name: {}", rejection.description()));
+                    None
+                },
+            }
+        } else if buffer.starts_with('/') {
+            // Client commands
+            let mut command = buffer.splitn(2, ' ');
+            let command_name = command.next().unwrap();
+            let argument = command.next().unwrap_or("").trim();
+            match command_name {
+                "/stats" => {
+                    // Send Get_Stats message
+                    // control_byte: Message::GetStats (11)
+                    // data_len: n
+                    // data[0..n] = player name; empty for self
+                    let mut get_stats_message: Vec<u8> = [Message::GetStats as u8].to_vec();
+                    get_stats_message.push(argument.len() as u8);
+                    get_stats_message.extend_from_slice(argument.as_bytes());
+                    Some(get_stats_message)
+                },
+                "/top" => {
+                    // Send Leaderboard message
+                    // control_byte: Message::Leaderboard (12)
+                    // data_len: 1
+                    // data[0] = top_n
+                    let top_n = argument.parse::<u8>().unwrap_or(10);
+                    self.notices.push("Leaderboard:".to_string());
+                    Some([Message::Leaderboard as u8, 1, top_n].to_vec())
+                },
+                "/undo" => {
+                    // Send Take_Back message
+                    // control_byte: Message::TakeBack (16)
+                    // data_len: 0
+                    Some([Message::TakeBack as u8, 0].to_vec())
+                },
+                "/hint" => {
+                    match self.game_data {
+                        Some(ref game_data) if game_data.current_player() == Some(self.user_id) => {
+                            match game_data.suggest_move() {
+                                Some(player_move) => self.notices.push(format!("Hint: {}", game_data.format_move(&player_move))),
+                                None => self.notices.push("No hint available for this game".to_string()),
+                            }
+                        },
+                        _ => self.notices.push("Hints are only available on your move".to_string()),
+                    }
+                    None
+                },
+                "/join" => {
+                    // Send Join_Tournament message
+                    // control_byte: Message::JoinTournament (23)
+                    // data_len: 0
+                    Some([Message::JoinTournament as u8, 0].to_vec())
+                },
+                "/leave" => {
+                    // Send Leave_Tournament message
+                    // control_byte: Message::LeaveTournament (24)
+                    // data_len: 0
+                    Some([Message::LeaveTournament as u8, 0].to_vec())
+                },
+                "/decline" => {
+                    // Send Decline_Take_Back message
+                    // control_byte: Message::DeclineTakeBack (17)
+                    // data_len: 0
+                    Some([Message::DeclineTakeBack as u8, 0].to_vec())
+                },
+                _ => {
+                    self.notices.push("Commands: /stats [name], /top [n], /undo, /decline, /hint, /join, /leave".to_string());
+                    None
+                },
+            }
+        } else {
+            let game_data = self.game_data.as_ref()?;
+            if game_data.is_finished() {
+                if "yes".eq_ignore_ascii_case(buffer) {
+                    // Send Restart_Game message
+                    // control_byte: Message::RestartGame (2)
+                    // data_len: 0
+                    Some([Message::RestartGame as u8, 0].to_vec())
+                } else if "no".eq_ignore_ascii_case(buffer) {
+                    // Send End_Game message
+                    // control_byte: Message::EndGame (3)
+                    // data_len: 0
+                    Some([Message::EndGame as u8, 0].to_vec())
+                } else {
+                    None
+                }
+            } else {
+                // Have a game, client has entered a move
+                // Parse the player move
+                match game_data.parse_move(buffer) {
+                    Some(player_move) => {
+                        // Send Player_Move message
+                        // control_byte: Message::PlayerMove (1)
+                        // data_len: n
+                        // data[..]: player_move
+                        let mut player_move_message: Vec<u8> = [Message::PlayerMove as u8, player_move.len() as u8].to_vec();
+                        player_move_message.extend_from_slice(&player_move);
+                        Some(player_move_message)
+                    },
+                    None => {
+                        self.notices.push(format!("Cannot parse player move '{}'", buffer));
+                        None
+                    },
+                }
+            }
+        }
+    }
+
+    pub fn process_server_data(&mut self, control_byte: u8, data: &[u8]) {
+        debug!("Processing [control_byte: {}; data_len: {}; data: {:?}]", control_byte, data.len(), data);
+        // Messages matched with Some(first) below are only handled when
+        // they carry data[0], and those matched with Some(player_id) when
+        // they start with a player id
+        match (Message::from_u8(control_byte), data.first().copied(), decode_player_id(data)) {
+            // 0: Opponent_Disconnect message
+            (Some(Message::OpponentDisconnect), _, _) => {
+                self.notices.push("Your opponent has left the game".to_string());
+
+                // Get rid of the game
+                self.game_data = None;
+                self.match_score = None;
+            },
+
+            // 4: Game Data update
+            // data[0] - game type
+            // data[1..] - rules, layout depends on the game type
+            (Some(Message::GameData), Some(game_type), _) => {
+                self.game_data = game::new_game(game_type, &data[1..]);
+                if self.game_data.is_none() {
+                    self.notices.push(format!("Unsupported game type {}", game_type));
+                }
+            },
+
+            // 5: Add_Player message
+            // data[0..2] - player id
+            // data[2..] - player name; empty when a player asks to play again
+            (Some(Message::AddPlayer), _, Some(player_id)) => {
+                // Process add player only if we already have a GameData struct
+                if let Some(ref mut game_data) = self.game_data {
+                    if game_data.is_finished() {
+                        game_data.request_restart(player_id);
+                    } else {
+                        game_data.add_player(player_id, &String::from_utf8_lossy(&data[PLAYER_ID_LENGTH..]));
+                    }
+                }
+            },
+
+            // 6: Move_Player message
+            // data[0..2] - player_id
+            // data[2..] - player_move
+            (Some(Message::MovePlayer), _, Some(player_id)) => {
+                // Process add player only if we already have a GameData struct
+                if let Some(ref mut game_data) = self.game_data {
+                    if let Err(e) = game_data.apply_move(player_id, &data[PLAYER_ID_LENGTH..]) {
+                        warn!("Server sent a move the local game rejected: {:?}", e);
+                    }
+                }
+            },
+
+            // 7: Set_Active_Player message
+            // data[0..2] - player_id
+            (Some(Message::SetActivePlayer), _, Some(player_id)) => {
+                // Process set active player only if we already have a GameData struct
+                if let Some(ref mut game_data) = self.game_data {
+                    game_data.set_current_player(player_id);
+                }
+            },
+
+            // 8: Welcome message
+            // data[0..2] - player_id
+            (Some(Message::Welcome), _, Some(player_id)) => {
+                // Set user_id to player_id
+                self.user_id = player_id;
+            },
+
+            // 9: User_Name message
+            // data[..] - user_name
+            (Some(Message::ServerUserName), _, _) => {
+                self.user_name = String::from_utf8_lossy(data).to_string();
+            },
+
+            // 10: User_Name_Rejected message
+            // data[0] - rejection reason
+            (Some(Message::UserNameRejected), Some(reason), _) => {
+                match NameRejection::from_u8(reason) {
+                    Some(rejection) => self.notices.push(format!("Invalid user name: {}", rejection.description())),
+                    None => self.notices.push("Invalid user name".to_string()),
+                }
+            },
+
+            // 13: Player_Stats message
+            // data[..] - Player_Stats payload
+            (Some(Message::PlayerStats), _, _) => {
+                if let Some(stats) = ratings::decode_stats(data) {
+                    self.notices.push(format_stats(&stats));
+                }
+            },
+
+            // 14: Leaderboard_Entry message
+            // data[0] - rank
+            // data[1..] - Player_Stats payload
+            (Some(Message::LeaderboardEntry), Some(rank), _) => {
+                if let Some(stats) = ratings::decode_stats(&data[1..]) {
+                    self.notices.push(format!("{:>2}. {}", rank, format_stats(&stats)));
+                }
+            },
+
+            // 18: Take_Back_Requested message
+            // data[0..2] - requesting player_id
+            (Some(Message::TakeBackRequested), _, Some(player_id)) => {
+                if player_id == self.user_id {
+                    self.notices.push("Waiting for your opponent to agree to take back the last move".to_string());
+                } else if let Some(ref game_data) = self.game_data {
+                    let name = game_data.player_name(player_id).unwrap_or("Your opponent");
+                    self.notices.push(format!("{} wants to take back the last move: /undo to agree, /decline to refuse", name));
+                }
+            },
+
+            // 19: Move_Taken_Back message
+            // data[0..2] - player_id whose move was undone
+            // data[2..] - player_move
+            (Some(Message::MoveTakenBack), _, Some(_)) => {
+                if let Some(ref mut game_data) = self.game_data {
+                    game_data.undo_last_move();
+                    self.notices.push(format!("Move taken back: {}", game_data.format_move(&data[PLAYER_ID_LENGTH..])));
+                }
+            },
+
+            // 20: Take_Back_Declined message
+            // data[0..2] - declining player_id
+            (Some(Message::TakeBackDeclined), _, _) => {
+                self.notices.push("Take back declined".to_string());
+            },
+
+            // 22: Match_Score message
+            // data[..] - series score, see series::encode_score
+            (Some(Message::MatchScore), _, _) => {
+                if let Some(score) = series::decode_score(data) {
+                    if let (Some(winner), Some(ref game_data)) = (score.winner(), self.game_data.as_ref()) {
+                        let name = game_data.player_name(winner).unwrap_or("?");
+                        self.notices.push(format!("{} wins the match {}", name, format_score(&score, game_data.as_ref())));
+                    }
+                    self.match_score = Some(score);
+                }
+            },
+
+            // 25: Tournament_Status message
+            // data[0] - phase: 0 registration, 1 in progress, 2 finished, 3 cancelled
+            // data[1] - format: 0 single elimination, 1 round robin
+            // data[2] - round
+            // data[3] - number of entrants
+            // data[4..6] - seconds until registration closes
+            (Some(Message::TournamentStatus), _, _) => {
+                if data.len() >= 6 {
+                    let format = if data[1] == 0 { "single elimination" } else { "round robin" };
+                    let remaining = u16::from_be_bytes([data[4], data[5]]);
+                    self.notices.push(match data[0] {
+                        0 => format!("Tournament ({}): {} entered, starts in {}s", format, data[3], remaining),
+                        1 => format!("Tournament ({}): round {} of {} players", format, data[2], data[3]),
+                        2 => "Tournament finished, final standings:".to_string(),
+                        _ => "Tournament cancelled: not enough entrants".to_string(),
+                    });
+                }
+            },
+
+            // 26: Tournament_Pairing message
+            // data[0] - round
+            // data[1..] - opponent name; empty for a bye
+            (Some(Message::TournamentPairing), Some(round), _) => {
+                if data.len() == 1 {
+                    self.notices.push(format!("Round {}: you have a bye", round));
+                } else {
+                    self.notices.push(format!("Round {}: you play {}", round, String::from_utf8_lossy(&data[1..])));
+                }
+            },
+
+            // 27: Tournament_Result message
+            // data[0] - round
+            // data[1] - 0 if the first player won, 1 for a draw
+            // data[2] - length n of the first player's name
+            // data[3..3+n] - first player's name
+            // data[3+n..] - second player's name
+            (Some(Message::TournamentResult), _, _) => {
+                if data.len() >= 3 && data.len() >= 3 + data[2] as usize {
+                    let split = 3 + data[2] as usize;
+                    let first = String::from_utf8_lossy(&data[3..split]);
+                    let second = String::from_utf8_lossy(&data[split..]);
+                    let verb = if data[1] == 1 { "drew with" } else { "beat" };
+                    self.notices.push(format!("Round {}: {} {} {}", data[0], first, verb, second));
+                }
+            },
+
+            // 28: Tournament_Standing message
+            // data[0] - place
+            // data[1..4] - wins, losses, draws
+            // data[4..] - player name
+            (Some(Message::TournamentStanding), _, _) => {
+                if data.len() >= 4 {
+                    self.notices.push(format!("{:>3}. {:<16} {}-{}-{}",
+                        data[0], String::from_utf8_lossy(&data[4..]), data[1], data[2], data[3]));
+                }
+            },
+
+            // 21: Coin_Flip message
+            // data[0..2] - player_id who won the toss and moves first
+            (Some(Message::CoinFlip), _, Some(player_id)) => {
+                if let Some(ref game_data) = self.game_data {
+                    let name = game_data.player_name(player_id).unwrap_or("Your opponent");
+                    self.notices.push(format!("Coin toss: {} moves first", name));
+                }
+            },
+
+            // 15: Unknown_Player message
+            // data[..] - player name
+            (Some(Message::UnknownPlayer), _, _) => {
+                self.notices.push(format!("No stats for {}", String::from_utf8_lossy(data)));
+            },
+
+            // 29: Announcement message
+            // data[..] - announcement text
+            (Some(Message::Announcement), _, _) => {
+                self.notices.push(format!("Server announcement: {}", String::from_utf8_lossy(data)));
+            },
+
+            // A message above that came without its data
+            (Some(Message::GameData), None, _) | (Some(Message::UserNameRejected), None, _)
+            | (Some(Message::LeaderboardEntry), None, _) | (Some(Message::TournamentPairing), None, _)
+            | (Some(Message::AddPlayer), _, None) | (Some(Message::MovePlayer), _, None) | (Some(Message::SetActivePlayer), _, None)
+            | (Some(Message::Welcome), _, None) | (Some(Message::TakeBackRequested), _, None) | (Some(Message::MoveTakenBack), _, None)
+            | (Some(Message::CoinFlip), _, None) => {
+                warn!("Short message from server, control byte {}", control_byte);
+                self.notices.push("Ignored a malformed message from the server".to_string());
+            },
+
+            // Unknown control byte; do nothing?
+            (Some(unknown), _, _) => {
+                warn!("Unknown control byte: {}", unknown as u8);
+            },
+
+            (None, _, _) => {
+                warn!("Unknown control byte: empty");
+            }
+
+        }
+    }
+}
+
+// What the server told a GameClient, decoded from one message. The
+// client's GameView has already been updated by the time it is returned.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    Welcome { player_id: usize },
+    // The server accepted the user name, as normalized
+    LoggedIn { user_name: String },
+    NameRejected { reason: Option<NameRejection> },
+    // A new game has been set up; the players follow
+    GameStarted { game_type: u8 },
+    PlayerAdded { player_id: usize, player_name: String },
+    // A player asked to play a finished game again
+    RestartRequested { player_id: usize },
+    PlayerMoved { player_id: usize, player_move: Vec<u8> },
+    ActivePlayer { player_id: usize },
+    OpponentDisconnected,
+    Stats { stats: PlayerStats },
+    LeaderboardEntry { rank: u8, stats: PlayerStats },
+    UnknownPlayer { name: String },
+    TakeBackRequested { player_id: usize },
+    MoveTakenBack { player_id: usize, player_move: Vec<u8> },
+    TakeBackDeclined { player_id: usize },
+    CoinFlip { player_id: usize },
+    MatchScore { score: Series },
+    Announcement { text: String },
+    // Tournament status, pairings, results and standings, as the console
+    // client shows them
+    Tournament { text: String },
+    // Anything else, including messages too short for their layout
+    Other { control_byte: u8, data: Vec<u8> },
+}
+
+// A connection to the game server for bots, tests and the console client:
+// it owns the TLS session and the GameView mirroring the server's state,
+// sends requests and turns server messages into events. Over a blocking
+// std TcpStream use connect and next_event; over a non-blocking socket
+// call read when it is readable, flush when it is writable, and take
+// events with poll_event.
+pub struct GameClient<T: Read + Write = TcpStream> {
+    connection: Connection<ClientSession, T>,
+    view: GameView,
+    events: VecDeque<Event>,
+}
+
+impl GameClient<TcpStream> {
+    // Connect and complete the TLS handshake, checking the server's
+    // certificate against config's roots for server_name
+    pub fn connect(address: SocketAddr, config: Arc<ClientConfig>, server_name: &str) -> io::Result<GameClient<TcpStream>> {
+        let name = webpki::DNSNameRef::try_from_ascii_str(server_name)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid server name"))?;
+        let socket = TcpStream::connect(address)?;
+        let mut client = GameClient::new(ClientSession::new(&config, name), socket);
+        while client.connection.is_handshaking() {
+            client.connection.flush()?;
+            if !client.read_for(READ_SLICE)? {
+                return Err(closed());
+            }
+        }
+        Ok(client)
+    }
+
+    // Wait up to timeout, or forever if None, for the next event. Returns
+    // None if the time runs out; fails with UnexpectedEof once the server
+    // closes the connection.
+    pub fn next_event(&mut self, timeout: Option<Duration>) -> io::Result<Option<Event>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            if let Some(event) = self.poll_event() {
+                return Ok(Some(event));
+            }
+            self.flush()?;
+            let slice = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(remaining) if remaining > Duration::from_millis(0) => remaining.min(READ_SLICE),
+                    _ => return Ok(None),
+                },
+                None => READ_SLICE,
+            };
+            if !self.read_for(slice)? {
+                return Err(closed());
+            }
+        }
+    }
+
+    // Read what arrives within duration, which bounds the blocking read,
+    // returning as soon as anything does
+    fn read_for(&mut self, duration: Duration) -> io::Result<bool> {
+        self.connection.socket().set_read_timeout(Some(duration))?;
+        let open = self.connection.read_once()?;
+        self.take_events();
+        Ok(open)
+    }
+}
+
+impl<T: Read + Write> GameClient<T> {
+    // Talk to the server over an already connected socket
+    pub fn new(session: ClientSession, socket: T) -> GameClient<T> {
+        GameClient { connection: Connection::new(session, socket), view: GameView::new(), events: VecDeque::new() }
+    }
+
+    pub fn socket(&self) -> &T { self.connection.socket() }
+
+    // The mirrored game state, user name and id. Its notices are the text
+    // the console client shows for each event, and collect until cleared.
+    pub fn view(&self) -> &GameView { &self.view }
+
+    pub fn view_mut(&mut self) -> &mut GameView { &mut self.view }
+
+    pub fn game(&self) -> Option<&dyn Game> { self.view.game_data.as_deref() }
+
+    // Whether it is this client's turn in a game still being played
+    pub fn is_my_turn(&self) -> bool {
+        self.game().is_some_and(|game| !game.is_finished() && game.current_player() == Some(self.view.user_id))
+    }
+
+    // Send User_Name message
+    // control_byte: Message::ClientUserName (0)
+    // data[..] = user name
+    pub fn login(&mut self, user_name: &str) -> io::Result<()> {
+        names::normalize_name(user_name.as_bytes())
+            .map_err(|rejection| io::Error::new(io::ErrorKind::InvalidInput, rejection.description()))?;
+        self.send_message(Message::ClientUserName, user_name.as_bytes())
+    }
+
+    // Send Player_Move message
+    // control_byte: Message::PlayerMove (1)
+    // data[..]: player_move, in the game's move layout
+    pub fn send_move(&mut self, player_move: &[u8]) -> io::Result<()> {
+        self.send_message(Message::PlayerMove, player_move)
+    }
+
+    // Parse a move as a user would type it and send it
+    pub fn play(&mut self, input: &str) -> io::Result<()> {
+        let player_move = self.game().and_then(|game| game.parse_move(input))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("cannot parse player move '{}'", input)))?;
+        self.send_move(&player_move)
+    }
+
+    // Send Restart_Game message to play a finished game again
+    pub fn restart(&mut self) -> io::Result<()> {
+        self.send_message(Message::RestartGame, &[])
+    }
+
+    // Send End_Game message to leave a finished game
+    pub fn end_game(&mut self) -> io::Result<()> {
+        self.send_message(Message::EndGame, &[])
+    }
+
+    // Send Join_Tournament message
+    pub fn join_tournament(&mut self) -> io::Result<()> {
+        self.send_message(Message::JoinTournament, &[])
+    }
+
+    // Send Leave_Tournament message
+    pub fn leave_tournament(&mut self) -> io::Result<()> {
+        self.send_message(Message::LeaveTournament, &[])
+    }
+
+    // Send Take_Back message, to ask for or agree to taking back a move
+    pub fn take_back(&mut self) -> io::Result<()> {
+        self.send_message(Message::TakeBack, &[])
+    }
+
+    // Send Decline_Take_Back message
+    pub fn decline_take_back(&mut self) -> io::Result<()> {
+        self.send_message(Message::DeclineTakeBack, &[])
+    }
+
+    // Send Get_Stats message; an empty name asks for this client's stats
+    pub fn get_stats(&mut self, player_name: &str) -> io::Result<()> {
+        self.send_message(Message::GetStats, player_name.as_bytes())
+    }
+
+    // Send Leaderboard message
+    pub fn leaderboard(&mut self, top_n: u8) -> io::Result<()> {
+        self.send_message(Message::Leaderboard, &[top_n])
+    }
+
+    fn send_message(&mut self, message: Message, data: &[u8]) -> io::Result<()> {
+        if data.len() > u8::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "message too long"));
+        }
+        let mut frame = vec![message as u8, data.len() as u8];
+        frame.extend_from_slice(data);
+        self.send(&frame)
+    }
+
+    // Queue one or more framed messages, such as GameView::handle_input
+    // returns; they go out on the next flush
+    pub fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        self.connection.write(message)
+    }
+
+    pub fn wants_write(&self) -> bool { self.connection.wants_write() }
+
+    // Write queued data until done or the socket would block
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.connection.flush()
+    }
+
+    // Read everything the socket has available and turn complete messages
+    // into events. Returns false once the server has closed the connection.
+    pub fn read(&mut self) -> io::Result<bool> {
+        let open = self.connection.read()?;
+        self.take_events();
+        Ok(open)
+    }
+
+    fn take_events(&mut self) {
+        while let Some((control_byte, data)) = self.connection.next_message() {
+            let event = decode_event(control_byte, &data);
+            self.view.process_server_data(control_byte, &data);
+            self.events.push_back(event);
+        }
+    }
+
+    // Next event already read, if any
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+}
+
+// How long a blocking read waits before checking deadlines again
+const READ_SLICE: Duration = Duration::from_millis(100);
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "server closed the connection")
+}
+
+fn decode_event(control_byte: u8, data: &[u8]) -> Event {
+    let text = |data: &[u8]| String::from_utf8_lossy(data).to_string();
+    let event = match (Message::from_u8(control_byte), data.first().copied(), decode_player_id(data)) {
+        (Some(Message::OpponentDisconnect), _, _) => Some(Event::OpponentDisconnected),
+        (Some(Message::Welcome), _, Some(player_id)) => Some(Event::Welcome { player_id }),
+        (Some(Message::ServerUserName), _, _) => Some(Event::LoggedIn { user_name: text(data) }),
+        (Some(Message::UserNameRejected), Some(reason), _) => Some(Event::NameRejected { reason: NameRejection::from_u8(reason) }),
+        (Some(Message::GameData), Some(game_type), _) => Some(Event::GameStarted { game_type }),
+        // An empty name asks to play again
+        (Some(Message::AddPlayer), _, Some(player_id)) if data.len() == PLAYER_ID_LENGTH => Some(Event::RestartRequested { player_id }),
+        (Some(Message::AddPlayer), _, Some(player_id)) => Some(Event::PlayerAdded { player_id, player_name: text(&data[PLAYER_ID_LENGTH..]) }),
+        (Some(Message::MovePlayer), _, Some(player_id)) => Some(Event::PlayerMoved { player_id, player_move: data[PLAYER_ID_LENGTH..].to_vec() }),
+        (Some(Message::SetActivePlayer), _, Some(player_id)) => Some(Event::ActivePlayer { player_id }),
+        (Some(Message::PlayerStats), _, _) => ratings::decode_stats(data).map(|stats| Event::Stats { stats }),
+        (Some(Message::LeaderboardEntry), Some(rank), _) => ratings::decode_stats(&data[1..]).map(|stats| Event::LeaderboardEntry { rank, stats }),
+        (Some(Message::UnknownPlayer), _, _) => Some(Event::UnknownPlayer { name: text(data) }),
+        (Some(Message::TakeBackRequested), _, Some(player_id)) => Some(Event::TakeBackRequested { player_id }),
+        (Some(Message::MoveTakenBack), _, Some(player_id)) => Some(Event::MoveTakenBack { player_id, player_move: data[PLAYER_ID_LENGTH..].to_vec() }),
+        (Some(Message::TakeBackDeclined), _, Some(player_id)) => Some(Event::TakeBackDeclined { player_id }),
+        (Some(Message::CoinFlip), _, Some(player_id)) => Some(Event::CoinFlip { player_id }),
+        (Some(Message::MatchScore), _, _) => series::decode_score(data).map(|score| Event::MatchScore { score }),
+        (Some(Message::Announcement), _, _) => Some(Event::Announcement { text: text(data) }),
+        (Some(Message::TournamentStatus), _, _) | (Some(Message::TournamentPairing), _, _)
+        | (Some(Message::TournamentResult), _, _) | (Some(Message::TournamentStanding), _, _) => {
+            // Reuse the console client's wording
+            let mut view = GameView::new();
+            view.process_server_data(control_byte, data);
+            view.notices.pop().map(|text| Event::Tournament { text })
+        },
+        _ => None,
+    };
+    event.unwrap_or_else(|| Event::Other { control_byte, data: data.to_vec() })
+}
+
+// e.g. "Match (best of 3): anna 2 - 1 ben"
+pub fn format_score(score: &Series, game_data: &dyn Game) -> String {
+    let players: Vec<String> = score.player_ids().iter()
+        .map(|&player_id| format!("{} {}", game_data.player_name(player_id).unwrap_or("?"), score.wins(player_id)))
+        .collect();
+    let draws = if score.draws() > 0 { format!(", {} drawn", score.draws()) } else { String::new() };
+    format!("Match (best of {}): {}{}", score.best_of(), players.join(" - "), draws)
+}
+
+pub fn format_stats(stats: &PlayerStats) -> String {
+    let streak = match stats.streak {
+        n if n > 0 => format!("W{}", n),
+        n if n < 0 => format!("L{}", -n),
+        _ => "-".to_string(),
+    };
+    format!("{} rating={} wins={} losses={} streak={} best={}",
+        stats.name, stats.rating, stats.wins, stats.losses, streak, stats.best_streak)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::net::TcpListener;
+    use std::path::{Path, PathBuf};
+    use std::sync::mpsc;
+    use std::thread;
+
+    use rustls::{ServerSession, StreamOwned};
+
+    use crate::tls;
+
+    // localhost_cert.pem is signed by ec_cert.pem
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("src/tls/testdata").join(name)
+    }
+
+    #[test]
+    fn returns_events_as_they_arrive() {
+        let server_config = Arc::new(tls::server_config(&testdata("localhost_cert.pem"), &testdata("localhost_key.pem"), None).unwrap());
+        let mut client_config = ClientConfig::new();
+        client_config.root_store.add_pem_file(&mut BufReader::new(File::open(testdata("ec_cert.pem")).unwrap())).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // Answers the login, then sends Welcome and hangs up when told to
+        let (go, wait) = mpsc::channel();
+        let server = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let mut stream = StreamOwned::new(ServerSession::new(&server_config), socket);
+            let mut login = [0; 7];
+            stream.read_exact(&mut login).unwrap();
+            assert_eq!(&login, b"\x00\x05alice");
+            stream.write_all(b"\x09\x05alice").unwrap();
+            wait.recv().unwrap();
+            stream.write_all(&[Message::Welcome as u8, 2, 0, 3]).unwrap();
+            wait.recv().unwrap();
+        });
+
+        let mut client = GameClient::connect(address, Arc::new(client_config), "localhost").unwrap();
+        client.login("alice").unwrap();
+        let event = client.next_event(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(event, Some(Event::LoggedIn { user_name: "alice".to_string() }));
+        assert_eq!(client.view().user_name, "alice");
+
+        let start = Instant::now();
+        assert_eq!(client.next_event(Some(Duration::from_millis(50))).unwrap(), None);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // A blocking read returns once data arrives rather than waiting out
+        // its timeout
+        go.send(()).unwrap();
+        let start = Instant::now();
+        assert!(client.read_for(Duration::from_secs(30)).unwrap());
+        assert!(start.elapsed() < Duration::from_secs(10), "took {:?}", start.elapsed());
+        let event = client.next_event(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(event, Some(Event::Welcome { player_id: 3 }));
+        assert_eq!(client.view().user_id, 3);
+
+        go.send(()).unwrap();
+        server.join().unwrap();
+        let error = client.next_event(None).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn ignores_malformed_server_messages() {
+        for control_byte in 0..=255 {
+            for data in &[&[][..], &[1], &[0xff, 0xfe]] {
+                let mut view = GameView::new();
+                view.process_server_data(control_byte, data);
+            }
+        }
+
+        let mut view = GameView::new();
+        view.process_server_data(Message::Welcome as u8, &[]);
+        assert_eq!(view.notices, ["Ignored a malformed message from the server"]);
+        view.process_server_data(Message::ServerUserName as u8, &[b'a', 0xff]);
+        assert_eq!(view.user_name, "a\u{fffd}");
+        view.process_server_data(Message::UserNameRejected as u8, &[NameRejection::TooLong as u8]);
+        assert_eq!(view.notices.last().unwrap(), "Invalid user name: name must be at most 16 characters");
+        view.process_server_data(Message::OpponentDisconnect as u8, &[]);
+        assert_eq!(view.notices.last().unwrap(), "Your opponent has left the game");
+    }
+}
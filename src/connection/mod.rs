@@ -0,0 +1,524 @@
+use std::io::{self, Read, Write};
+
+use log::{log_enabled, trace, Level};
+use rustls::Session;
+
+use crate::json::JsonLines;
+use crate::websocket::WebSocket;
+
+// Log target for hex dumps of the raw bytes sent and received.
+// Disabled unless the logger enables trace output for this target.
+pub const WIRE_TRACE: &str = "clientserver::wire";
+
+// Bytes shown per line of a wire trace
+const TRACE_LINE: usize = 16;
+
+// Largest plaintext a TLS record carries, and the most a record adds to it:
+// header, explicit nonce and authentication tag
+const MAX_FRAGMENT: usize = 16384;
+const RECORD_OVERHEAD: usize = 29;
+
+// Most bytes taken from the socket in one read call. The socket is level
+// triggered, so anything left is read on the next pass, after the messages
+// already received have been handled and rate limited.
+const MAX_READ: usize = 16384;
+// Most received data held before it is split into messages. The caller
+// drains complete messages after every read, and a read pass overshoots
+// MAX_READ by at most one TLS record, so only a caller that stops draining
+// can fill it.
+const MAX_INBOUND: usize = 4 * MAX_READ;
+
+// A TLS session over a non-blocking socket, along with the plaintext
+// received but not yet split into messages. Connections from plaintext
+// listeners have no session and buffer their unsent data themselves.
+// Messages may also travel in WebSocket frames or JSON lines inside either.
+pub struct Connection<S: Session, T: Read + Write> {
+    session: Option<S>,
+    socket: T,
+    websocket: Option<WebSocket>,
+    json: Option<JsonLines>,
+    inbound: Vec<u8>,
+    // Unsent data of a plaintext connection
+    outbound: Vec<u8>,
+    // Encrypted bytes the session may be holding for the socket, at most
+    unsent: usize,
+    // How many unsent bytes either may hold; no limit when zero
+    buffer_limit: usize,
+}
+
+impl<S: Session, T: Read + Write> Connection<S, T> {
+    pub fn new(session: S, socket: T) -> Connection<S, T> {
+        Connection { session: Some(session), socket, websocket: None, json: None, inbound: Vec::new(), outbound: Vec::new(), unsent: 0, buffer_limit: 0 }
+    }
+
+    pub fn plaintext(socket: T, buffer_limit: usize) -> Connection<S, T> {
+        Connection { session: None, socket, websocket: None, json: None, inbound: Vec::new(), outbound: Vec::new(), unsent: 0, buffer_limit }
+    }
+
+    pub fn set_buffer_limit(&mut self, limit: usize) {
+        self.buffer_limit = limit;
+    }
+
+    // Expect a WebSocket upgrade request and carry messages in WebSocket
+    // frames from then on
+    pub fn set_websocket(&mut self) {
+        self.websocket = Some(WebSocket::new());
+    }
+
+    // Let the client's first byte choose between binary messages and JSON
+    // lines
+    pub fn set_json(&mut self) {
+        self.json = Some(JsonLines::new());
+    }
+
+    pub fn socket(&self) -> &T { &self.socket }
+
+    // Until both the TLS handshake and any WebSocket upgrade are done
+    pub fn is_handshaking(&self) -> bool {
+        self.session.as_ref().is_some_and(|session| session.is_handshaking())
+            || self.websocket.as_ref().is_some_and(|websocket| websocket.is_upgrading())
+    }
+
+    pub fn wants_read(&self) -> bool {
+        self.session.as_ref().is_none_or(|session| session.wants_read())
+    }
+
+    // Read and decrypt everything the socket has available. Returns false
+    // once the peer has closed the connection. WebSocket and JSON protocol
+    // errors fail with InvalidInput.
+    pub fn read(&mut self) -> io::Result<bool> {
+        self.read_socket(false)
+    }
+
+    // Like read, but return once a read brings in any data. A blocking
+    // socket would otherwise sit out its read timeout waiting for more.
+    pub fn read_once(&mut self) -> io::Result<bool> {
+        self.read_socket(true)
+    }
+
+    fn read_socket(&mut self, once: bool) -> io::Result<bool> {
+        let mut total = 0;
+        loop {
+            let session = match self.session.as_mut() {
+                Some(session) => session,
+                None => return self.read_plaintext(once),
+            };
+            match session.read_tls(&mut Traced(&mut self.socket)) {
+                Ok(0) => return Ok(false),
+                Ok(n) => total += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+
+            // Records are processed as they arrive so the session never
+            // holds more than one read's worth of undecrypted data
+            if let Err(e) = session.process_new_packets() {
+                // Let the peer know why it is being dropped
+                let _ = self.flush();
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+            let mut received = Vec::new();
+            session.read_to_end(&mut received)?;
+            if !self.receive(&received)? {
+                return Ok(false);
+            }
+            if once || total >= MAX_READ {
+                return Ok(true);
+            }
+        }
+    }
+
+    fn read_plaintext(&mut self, once: bool) -> io::Result<bool> {
+        let mut buffer = [0; 4096];
+        let mut total = 0;
+        loop {
+            match Traced(&mut self.socket).read(&mut buffer) {
+                Ok(0) => return Ok(false),
+                Ok(n) => if !self.receive(&buffer[..n])? {
+                    return Ok(false);
+                } else {
+                    total += n;
+                    if once || total >= MAX_READ {
+                        return Ok(true);
+                    }
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Take in decrypted bytes, unwrapping any WebSocket frames or JSON
+    // lines. Returns false once the WebSocket has been closed. Fails with
+    // InvalidData if the caller has left too much undrained.
+    fn receive(&mut self, received: &[u8]) -> io::Result<bool> {
+        if self.inbound.len() + received.len() > MAX_INBOUND {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "inbound buffer full"));
+        }
+        let mut reply = Vec::new();
+        let (result, open) = match (self.websocket.as_mut(), self.json.as_mut()) {
+            (Some(websocket), _) => {
+                let result = websocket.receive(received, &mut self.inbound, &mut reply);
+                (result, !websocket.is_closed())
+            },
+            (None, Some(json)) => (json.receive(received, &mut self.inbound, &mut reply), true),
+            (None, None) => {
+                self.inbound.extend_from_slice(received);
+                return Ok(true);
+            },
+        };
+        self.write_raw(&reply)?;
+        if result.is_err() || !open {
+            // Let the peer know why it is being dropped
+            let _ = self.flush();
+        }
+        result.map(|_| open)
+    }
+
+    // Next complete message received: control byte and data
+    pub fn next_message(&mut self) -> Option<(u8, Vec<u8>)> {
+        take_message(&mut self.inbound)
+    }
+
+    // Queue a message for sending. Fails without queueing any of it if it
+    // would take the unsent backlog past the buffer limit, so a client that
+    // falls behind never gets part of a message.
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut encoded = Vec::new();
+        match (self.websocket.as_mut(), self.json.as_mut()) {
+            (Some(websocket), _) => websocket.send(data, &mut encoded),
+            (None, Some(json)) => json.send(data, &mut encoded),
+            (None, None) => return self.write_raw(data),
+        }
+        self.write_raw(&encoded)
+    }
+
+    fn write_raw(&mut self, data: &[u8]) -> io::Result<()> {
+        let (buffered, size) = match self.session {
+            Some(_) => (self.unsent, data.len() + data.len().div_ceil(MAX_FRAGMENT) * RECORD_OVERHEAD),
+            None => (self.outbound.len(), data.len()),
+        };
+        if self.buffer_limit != 0 && buffered + size > self.buffer_limit {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "outbound buffer full"));
+        }
+        match self.session {
+            Some(ref mut session) => {
+                session.write_all(data)?;
+                self.unsent += size;
+            },
+            None => self.outbound.extend_from_slice(data),
+        }
+        Ok(())
+    }
+
+    pub fn wants_write(&self) -> bool {
+        match self.session {
+            Some(ref session) => session.wants_write(),
+            None => !self.outbound.is_empty(),
+        }
+    }
+
+    // Write encrypted records to the socket until the session is empty or
+    // the socket would block
+    pub fn flush(&mut self) -> io::Result<()> {
+        let session = match self.session.as_mut() {
+            Some(session) => session,
+            None => return self.flush_plaintext(),
+        };
+        while session.wants_write() {
+            match session.write_tls(&mut Traced(&mut self.socket)) {
+                Ok(n) => self.unsent = self.unsent.saturating_sub(n),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        if !session.wants_write() {
+            self.unsent = 0;
+        }
+        Ok(())
+    }
+
+    fn flush_plaintext(&mut self) -> io::Result<()> {
+        while !self.outbound.is_empty() {
+            match Traced(&mut self.socket).write(&self.outbound) {
+                Ok(n) => { self.outbound.drain(..n); },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+// Remove the first complete message from `inbound`: control byte and data
+pub fn take_message(inbound: &mut Vec<u8>) -> Option<(u8, Vec<u8>)> {
+    if inbound.len() < 2 {
+        return None;
+    }
+    let msg_len = inbound[1] as usize;
+    if inbound.len() < 2 + msg_len {
+        // Do not have full message, need more data
+        return None;
+    }
+    let message: Vec<u8> = inbound.drain(..2 + msg_len).collect();
+    Some((message[0], message[2..].to_vec()))
+}
+
+// Socket adapter that dumps the bytes passing through it to the wire trace
+struct Traced<'a, T>(&'a mut T);
+
+impl<'a, T: Read> Read for Traced<'a, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.0.read(buf)?;
+        trace_bytes("read", &buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<'a, T: Write> Write for Traced<'a, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.0.write(buf)?;
+        trace_bytes("wrote", &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+fn trace_bytes(direction: &str, bytes: &[u8]) {
+    if !log_enabled!(target: WIRE_TRACE, Level::Trace) {
+        return;
+    }
+    trace!(target: WIRE_TRACE, "{} {} bytes", direction, bytes.len());
+    for (line, chunk) in bytes.chunks(TRACE_LINE).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+        trace!(target: WIRE_TRACE, "{:04x}  {}", line * TRACE_LINE, hex.join(" "));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    use rustls::{ClientConfig, ClientSession, ServerSession};
+    use webpki::DNSNameRef;
+
+    use crate::tls;
+
+    // Non-blocking socket stand-in: reads drain `input`, and writes are
+    // accepted until `capacity` bytes have gone out
+    struct Pipe {
+        input: Vec<u8>,
+        output: Vec<u8>,
+        capacity: usize,
+    }
+
+    impl Pipe {
+        fn new() -> Pipe {
+            Pipe { input: Vec::new(), output: Vec::new(), capacity: usize::MAX }
+        }
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.input.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(self.input.len());
+            buf[..n].copy_from_slice(&self.input[..n]);
+            self.input.drain(..n);
+            Ok(n)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.capacity == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(self.capacity);
+            self.capacity -= n;
+            self.output.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("src/tls/testdata").join(name)
+    }
+
+    // A server connection over a Pipe and the client session at the other
+    // end, with the handshake done
+    fn tls_pair() -> (Connection<ServerSession, Pipe>, ClientSession) {
+        let server_config = Arc::new(tls::server_config(&testdata("localhost_cert.pem"), &testdata("localhost_key.pem"), None).unwrap());
+        let mut client_config = ClientConfig::new();
+        client_config.root_store.add_pem_file(&mut BufReader::new(File::open(testdata("ec_cert.pem")).unwrap())).unwrap();
+        let mut client = ClientSession::new(&Arc::new(client_config), DNSNameRef::try_from_ascii_str("localhost").unwrap());
+        let mut server = Connection::new(ServerSession::new(&server_config), Pipe::new());
+        while client.is_handshaking() || server.is_handshaking() {
+            while client.wants_write() {
+                client.write_tls(&mut server.socket.input).unwrap();
+            }
+            assert!(server.read().unwrap());
+            server.flush().unwrap();
+            deliver(&mut server, &mut client);
+        }
+        (server, client)
+    }
+
+    // Pass whatever the server has written to the client, returning the
+    // plaintext it decrypts
+    fn deliver(server: &mut Connection<ServerSession, Pipe>, client: &mut ClientSession) -> Vec<u8> {
+        let output = std::mem::take(&mut server.socket.output);
+        let mut records = &output[..];
+        while !records.is_empty() {
+            client.read_tls(&mut records).unwrap();
+            client.process_new_packets().unwrap();
+        }
+        let mut plaintext = Vec::new();
+        client.read_to_end(&mut plaintext).unwrap();
+        plaintext
+    }
+
+    #[test]
+    fn tls_backlog_limit_refuses_whole_messages() {
+        let (mut server, mut client) = tls_pair();
+        server.set_buffer_limit(200);
+        server.socket.capacity = 0;
+
+        // 100 bytes take one record: 129 bytes counted against the limit
+        server.write(&[1; 100]).unwrap();
+        let error = server.write(&[2; 50]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WriteZero);
+        server.flush().unwrap();
+        assert!(server.wants_write());
+        assert!(server.write(&[2; 50]).is_err());
+
+        // Once the socket drains there is room again
+        server.socket.capacity = usize::MAX;
+        server.flush().unwrap();
+        assert!(!server.wants_write());
+        server.write(&[3; 50]).unwrap();
+        server.flush().unwrap();
+        assert_eq!(deliver(&mut server, &mut client), [&[1; 100][..], &[3; 50][..]].concat());
+    }
+
+    #[test]
+    fn plaintext_backlog_limit_refuses_whole_messages() {
+        let mut connection: Connection<ServerSession, Pipe> = Connection::plaintext(Pipe::new(), 10);
+        connection.socket.capacity = 0;
+        connection.write(&[1; 8]).unwrap();
+        assert_eq!(connection.write(&[2; 4]).unwrap_err().kind(), io::ErrorKind::WriteZero);
+        assert_eq!(connection.outbound, [1; 8]);
+
+        connection.socket.capacity = usize::MAX;
+        connection.flush().unwrap();
+        connection.write(&[3; 10]).unwrap();
+        assert!(connection.write(&[4]).is_err());
+        connection.flush().unwrap();
+        assert_eq!(connection.socket.output, [&[1; 8][..], &[3; 10][..]].concat());
+
+        // No limit when zero
+        let mut connection: Connection<ServerSession, Pipe> = Connection::plaintext(Pipe::new(), 0);
+        connection.socket.capacity = 0;
+        connection.write(&[1; 100_000]).unwrap();
+    }
+
+    #[test]
+    fn partial_writes_keep_the_rest_in_order() {
+        let (mut server, mut client) = tls_pair();
+        let message: Vec<u8> = (0..=255).cycle().take(40_000).collect();
+        server.write(&message).unwrap();
+
+        // The socket takes a few bytes per writable event
+        let mut received = Vec::new();
+        let mut flushes = 0;
+        while server.wants_write() {
+            server.socket.capacity = 1000;
+            server.flush().unwrap();
+            received.extend(deliver(&mut server, &mut client));
+            flushes += 1;
+        }
+        assert!(flushes > 40);
+        assert_eq!(server.unsent, 0);
+        assert_eq!(received, message);
+
+        let mut connection: Connection<ServerSession, Pipe> = Connection::plaintext(Pipe::new(), 0);
+        connection.write(&message).unwrap();
+        connection.socket.capacity = 7;
+        connection.flush().unwrap();
+        assert_eq!(connection.outbound.len(), message.len() - 7);
+        connection.socket.capacity = usize::MAX;
+        connection.flush().unwrap();
+        assert!(!connection.wants_write());
+        assert_eq!(connection.socket.output, message);
+    }
+
+    #[test]
+    fn reads_messages_split_across_records() {
+        let (mut server, mut client) = tls_pair();
+        for chunk in [&[5, 4, 1][..], &[2, 3], &[4, 1, 0, 9]].iter() {
+            client.write_all(chunk).unwrap();
+            while client.wants_write() {
+                client.write_tls(&mut server.socket.input).unwrap();
+            }
+            assert!(server.read().unwrap());
+        }
+        assert_eq!(server.next_message(), Some((5, vec![1, 2, 3, 4])));
+        assert_eq!(server.next_message(), Some((1, vec![])));
+        assert_eq!(server.next_message(), None);
+        assert_eq!(server.inbound, [9]);
+    }
+
+    #[test]
+    fn reads_at_most_max_read_per_pass() {
+        let (mut server, mut client) = tls_pair();
+        client.write_all(&[1, 0].repeat(MAX_READ)).unwrap();
+        while client.wants_write() {
+            client.write_tls(&mut server.socket.input).unwrap();
+        }
+        assert!(server.read().unwrap());
+        assert!(!server.socket.input.is_empty());
+        // At most one record past the limit
+        assert!(server.inbound.len() < MAX_READ + MAX_FRAGMENT);
+        let mut messages = 0;
+        loop {
+            while server.next_message().is_some() {
+                messages += 1;
+            }
+            if server.socket.input.is_empty() {
+                break;
+            }
+            assert!(server.read().unwrap());
+        }
+        assert_eq!(messages, MAX_READ);
+
+        let mut connection: Connection<ServerSession, Pipe> = Connection::plaintext(Pipe::new(), 0);
+        connection.socket.input = vec![0; 2 * MAX_READ + 1];
+        assert!(connection.read().unwrap());
+        assert_eq!(connection.inbound.len(), MAX_READ);
+        assert_eq!(connection.socket.input.len(), MAX_READ + 1);
+    }
+
+    #[test]
+    fn undrained_inbound_data_is_refused() {
+        let mut connection: Connection<ServerSession, Pipe> = Connection::plaintext(Pipe::new(), 0);
+        connection.socket.input = vec![0; MAX_INBOUND + 1];
+        for _ in 0..MAX_INBOUND / MAX_READ {
+            assert!(connection.read().unwrap());
+        }
+        assert_eq!(connection.read().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -1,59 +1,283 @@
 use std::collections::HashSet;
 
+use log::{debug, info};
+
+pub mod nim;
+pub mod start;
+pub mod subtraction;
+
+use nim::NimGame;
+
+// Game types sent in the Game_Data message
+pub const COUNTING_GAME: u8 = 0;
+pub const NIM_GAME: u8 = 1;
+// Counting game whose allowed moves are not simply 1..=max_move
+pub const SUBTRACTION_GAME: u8 = 2;
+
+// Result of a game as seen by the server
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    InProgress,
+    Decided { winners: Vec<usize>, losers: Vec<usize> },
+    Draw,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MoveError {
+    UnknownPlayer,
+    NotYourTurn,
+    IllegalMove,
+    GameOver,
+}
+
+// A turn based game the server can host. Rules, moves and state travel over
+// the wire as opaque bytes, so each game defines its own layouts.
+pub trait Game {
+    // Identifies the game in the Game_Data message
+    fn game_type(&self) -> u8;
+    fn name(&self) -> &'static str;
+    // Game_Data message payload following the game type
+    fn rules(&self) -> Vec<u8>;
+    fn max_players(&self) -> u8;
+
+    fn add_player(&mut self, player_id: usize, player_name: &str);
+    fn player_ids(&self) -> &[usize];
+    fn player_name(&self, player_id: usize) -> Option<&str>;
+    fn has_player(&self, player_id: usize) -> bool {
+        self.player_ids().contains(&player_id)
+    }
+
+    // Player whose turn it is; None before the game starts and once it is over
+    fn current_player(&self) -> Option<usize>;
+    // Choose who moves first; ignored once a move has been made
+    fn set_current_player(&mut self, player_id: usize);
+    fn legal_moves(&self) -> Vec<Vec<u8>>;
+    fn apply_move(&mut self, player_id: usize, player_move: &[u8]) -> Result<(), MoveError>;
+    fn outcome(&self) -> Outcome;
+    fn is_finished(&self) -> bool {
+        self.outcome() != Outcome::InProgress
+    }
+
+    // Ask to play again once the game is over; the game restarts once every
+    // player has asked
+    fn request_restart(&mut self, player_id: usize);
+
+    fn serialize_state(&self) -> Vec<u8>;
+    // Human readable description of the board, one entry per line
+    fn describe(&self) -> Vec<String>;
+    // Prompt and parser for moves typed by a player
+    fn move_prompt(&self) -> String;
+    fn parse_move(&self, input: &str) -> Option<Vec<u8>>;
+    fn format_move(&self, player_move: &[u8]) -> String {
+        format!("{:?}", player_move)
+    }
+    // Best move for the current player, for games with a built in AI
+    fn suggest_move(&self) -> Option<Vec<u8>> { None }
+
+    // Take backs; games that cannot undo moves keep the defaults
+    fn approve_take_back(&mut self, _player_id: usize) -> bool { false }
+    fn cancel_take_back(&mut self) {}
+    fn is_take_back_pending(&self) -> bool { false }
+    fn undo_last_move(&mut self) -> Option<(usize, Vec<u8>)> { None }
+}
+
+// Build a game from the game type and rules in a Game_Data message
+pub fn new_game(game_type: u8, rules: &[u8]) -> Option<Box<dyn Game>> {
+    match (game_type, rules) {
+        // A max_move of zero would leave no legal moves
+        (COUNTING_GAME, &[max_players, max_move, game_board_size]) if max_move > 0 =>
+            Some(Box::new(GameData::new(max_players, max_move, game_board_size))),
+        (SUBTRACTION_GAME, &[max_players, game_board_size, ref move_set @ ..]) if subtraction::is_valid_move_set(move_set) =>
+            Some(Box::new(GameData::with_move_set(max_players, move_set, game_board_size))),
+        (NIM_GAME, _) => NimGame::from_rules(rules).map(|game| Box::new(game) as Box<dyn Game>),
+        _ => None,
+    }
+}
+
+// Lets code that is generic over a game host one chosen at runtime
+impl<G: Game + ?Sized> Game for Box<G> {
+    fn game_type(&self) -> u8 { (**self).game_type() }
+    fn name(&self) -> &'static str { (**self).name() }
+    fn rules(&self) -> Vec<u8> { (**self).rules() }
+    fn max_players(&self) -> u8 { (**self).max_players() }
+    fn add_player(&mut self, player_id: usize, player_name: &str) { (**self).add_player(player_id, player_name) }
+    fn player_ids(&self) -> &[usize] { (**self).player_ids() }
+    fn player_name(&self, player_id: usize) -> Option<&str> { (**self).player_name(player_id) }
+    fn has_player(&self, player_id: usize) -> bool { (**self).has_player(player_id) }
+    fn current_player(&self) -> Option<usize> { (**self).current_player() }
+    fn set_current_player(&mut self, player_id: usize) { (**self).set_current_player(player_id) }
+    fn legal_moves(&self) -> Vec<Vec<u8>> { (**self).legal_moves() }
+    fn apply_move(&mut self, player_id: usize, player_move: &[u8]) -> Result<(), MoveError> {
+        (**self).apply_move(player_id, player_move)
+    }
+    fn outcome(&self) -> Outcome { (**self).outcome() }
+    fn is_finished(&self) -> bool { (**self).is_finished() }
+    fn request_restart(&mut self, player_id: usize) { (**self).request_restart(player_id) }
+    fn serialize_state(&self) -> Vec<u8> { (**self).serialize_state() }
+    fn describe(&self) -> Vec<String> { (**self).describe() }
+    fn move_prompt(&self) -> String { (**self).move_prompt() }
+    fn parse_move(&self, input: &str) -> Option<Vec<u8>> { (**self).parse_move(input) }
+    fn format_move(&self, player_move: &[u8]) -> String { (**self).format_move(player_move) }
+    fn suggest_move(&self) -> Option<Vec<u8>> { (**self).suggest_move() }
+    fn approve_take_back(&mut self, player_id: usize) -> bool { (**self).approve_take_back(player_id) }
+    fn cancel_take_back(&mut self) { (**self).cancel_take_back() }
+    fn is_take_back_pending(&self) -> bool { (**self).is_take_back_pending() }
+    fn undo_last_move(&mut self) -> Option<(usize, Vec<u8>)> { (**self).undo_last_move() }
+}
+
+// Everything that has happened to a game. GameData's state is a fold over
+// its events, so the log can be inspected, replayed or rewound.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GameEvent {
+    PlayerAdded { player_id: usize, player_name: String },
+    ActivePlayerSet { player: u8 },
+    Moved { player: u8, player_move: u8 },
+    GameOver { loser: u8 },
+    RestartRequested { player_id: usize },
+    Restarted,
+}
+
 pub struct GameData {
+    max_players: u8,
+    // Allowed moves, sorted and without duplicates
+    move_set: Vec<u8>,
+    game_board_size: u8,
+    events: Vec<GameEvent>,
+
+    // Derived from events
     player_names: Vec<String>,
     player_ids: Vec<usize>,
     restart_ids: HashSet<usize>,
     game_board: Vec<u8>,
     active_player: u8,
-    max_players: u8,
-    max_move: u8,
-    game_board_size: u8,
     state: Option<Box<dyn GameState>>,
+
+    // Players who have agreed to take back the last move
+    take_back_ids: HashSet<usize>,
 }
 
 impl GameData {
     pub fn new(max_players: u8, max_move: u8, game_board_size: u8) -> GameData {
+        let move_set: Vec<u8> = (1..=max_move).collect();
+        GameData::with_move_set(max_players, &move_set, game_board_size)
+    }
+
+    // A counting game where each move must be one of `move_set`
+    pub fn with_move_set(max_players: u8, move_set: &[u8], game_board_size: u8) -> GameData {
+        let mut move_set = move_set.to_vec();
+        move_set.sort_unstable();
+        move_set.dedup();
         GameData {
+            max_players,
+            move_set,
+            game_board_size,
+            events: Vec::new(),
             player_names: Vec::new(),
             player_ids: Vec::<usize>::new(),
             restart_ids: HashSet::<usize>::new(),
             game_board: Vec::new(),
-            active_player: std::u8::MAX,
-            max_players: max_players,
-            max_move: max_move,
-            game_board_size: game_board_size,
+            active_player: u8::MAX,
             state: Some(Box::new(WaitingForPlayers {})),
+            take_back_ids: HashSet::<usize>::new(),
         }
     }
 
+    // Rebuild a game from its rules and event log
+    pub fn from_events(max_players: u8, move_set: &[u8], game_board_size: u8, events: &[GameEvent]) -> GameData {
+        let mut game_data = GameData::with_move_set(max_players, move_set, game_board_size);
+        for event in events {
+            game_data.apply(event.clone());
+        }
+        game_data
+    }
+
     pub fn add_player(&mut self, player_id: usize, player_name: &str) {
-        if let Some(s) = self.state.take() {
-            self.state = Some(s.add_player(self, player_id, player_name));
+        if let Some(ref s) = self.state {
+            let events = s.add_player(self, player_id, player_name);
+            self.apply_all(events);
         }
     }
 
-    pub fn set_active_player(&mut self, player_id: usize) {
-        if let Some(player) = self.player_ids.iter().position(|id| *id == player_id) {
-            if let Some(s) = self.state.take() {
-                self.state = Some(s.set_active_player(self, player as u8));
-            }
-        } else {
-            panic!("Cannot find player: {}", player_id);
+    // Returns false if the player is not in this game or the request was
+    // refused in the current state
+    pub fn set_active_player(&mut self, player_id: usize) -> bool {
+        match (self.player_ids.iter().position(|id| *id == player_id), self.state.as_ref()) {
+            (Some(player), Some(s)) => {
+                let events = s.set_active_player(self, player as u8);
+                self.apply_all(events)
+            },
+            _ => false,
         }
     }
 
-    pub fn move_player(&mut self, player_id: usize, player_move: u8) {
-        println!("Moving player {} {} steps", player_id, player_move);
-        if let Some(player) = self.player_ids.iter().position(|id| *id == player_id) {
-            if let Some(s) = self.state.take() {
-                self.state = Some(s.move_player(self, player as u8, player_move));
-            }
-        } else {
-            panic!("Cannot find player: {}", player_id);
+    // Returns false if the player is not in this game or the move was
+    // refused in the current state
+    pub fn move_player(&mut self, player_id: usize, player_move: u8) -> bool {
+        debug!("Moving player {} {} steps", player_id, player_move);
+        match (self.player_ids.iter().position(|id| *id == player_id), self.state.as_ref()) {
+            (Some(player), Some(s)) => {
+                let events = s.move_player(self, player as u8, player_move);
+                self.apply_all(events)
+            },
+            _ => false,
+        }
+    }
+
+    // Ask to play again once the game is over; the game restarts once every
+    // player has asked. Returns false if the request was refused.
+    pub fn request_restart(&mut self, player_id: usize) -> bool {
+        match self.state.as_ref() {
+            Some(s) if self.game_has_player(player_id) => {
+                let events = s.request_restart(self, player_id);
+                self.apply_all(events)
+            },
+            _ => false,
+        }
+    }
+
+    // Record that a player agrees to take back the last move. Returns true
+    // once every player has agreed; the caller then calls undo_last_move.
+    pub fn approve_take_back(&mut self, player_id: usize) -> bool {
+        if !self.can_take_back() || !self.game_has_player(player_id) {
+            return false;
+        }
+        self.take_back_ids.insert(player_id);
+        self.take_back_ids.len() == self.player_ids.len()
+    }
+
+    pub fn cancel_take_back(&mut self) {
+        self.take_back_ids.clear();
+    }
+
+    pub fn is_take_back_pending(&self) -> bool {
+        !self.take_back_ids.is_empty()
+    }
+
+    // A move can be taken back while the game it was made in is still in progress
+    pub fn can_take_back(&self) -> bool {
+        !self.is_game_over() && self.last_move_index().is_some()
+    }
+
+    // Remove the last move from the log and rebuild the game state. Returns
+    // the id of the player whose move was undone and the move.
+    pub fn undo_last_move(&mut self) -> Option<(usize, u8)> {
+        if self.is_game_over() {
+            return None;
         }
+        let index = self.last_move_index()?;
+        let undone = match self.events[index] {
+            GameEvent::Moved { player, player_move } => (self.player_ids[player as usize], player_move),
+            _ => unreachable!(),
+        };
+
+        let mut events = std::mem::take(&mut self.events);
+        events.truncate(index);
+        *self = GameData::from_events(self.max_players, &self.move_set, self.game_board_size, &events);
+        Some(undone)
     }
 
+    pub fn get_events(&self) -> &[GameEvent] { self.events.as_slice() }
+
     pub fn is_game_over(&self) -> bool {
         if let Some(ref s) = self.state {
             return s.is_game_over();
@@ -65,26 +289,254 @@ impl GameData {
         self.player_ids.contains(&player_id)
     }
 
-    pub fn get_max_move(&self) -> u8 { self.max_move }
+    pub fn get_max_move(&self) -> u8 { *self.move_set.last().unwrap_or(&0) }
 
-    pub fn get_active_player_id(&self) -> u8 {
-        match self.player_ids.get(self.active_player as usize) {
-            Some(&i) => { i as u8 },
-            None => { std::u8::MAX },
-        }
+    pub fn get_move_set(&self) -> &[u8] { self.move_set.as_slice() }
+
+    // Units left to count before the game is over
+    pub fn get_remaining(&self) -> u8 {
+        self.game_board_size.saturating_sub(self.game_board.len() as u8)
+    }
+
+    // True if the allowed moves are exactly 1..=max_move
+    fn is_move_range(&self) -> bool {
+        self.move_set.iter().enumerate().all(|(index, &player_move)| player_move as usize == index + 1)
+    }
+
+    pub fn get_active_player_id(&self) -> Option<usize> {
+        self.player_ids.get(self.active_player as usize).copied()
     }
 
     pub fn get_game_board(&self) -> &[u8] { self.game_board.as_slice() }
 
     pub fn get_player_names(&self) -> &Vec<String> { &self.player_names }
 
+    pub fn get_player_name(&self, player_id: usize) -> Option<&str> {
+        let player = self.player_ids.iter().position(|id| *id == player_id)?;
+        self.player_names.get(player).map(String::as_str)
+    }
+
+    // The player who made the final move loses; None while the game is in progress
+    pub fn get_losing_player_id(&self) -> Option<usize> {
+        if self.is_game_over() {
+            self.player_ids.get(self.active_player as usize).copied()
+        } else {
+            None
+        }
+    }
+
+    pub fn get_player_ids(&self) -> &[usize] { self.player_ids.as_slice() }
+
+    // Index of the last move of the current game, if any
+    fn last_move_index(&self) -> Option<usize> {
+        for (index, event) in self.events.iter().enumerate().rev() {
+            match *event {
+                GameEvent::Moved { .. } => return Some(index),
+                GameEvent::Restarted => return None,
+                _ => (),
+            }
+        }
+        None
+    }
+
+    // Returns false if there was nothing to apply
+    fn apply_all(&mut self, events: Vec<GameEvent>) -> bool {
+        let applied = !events.is_empty();
+        for event in events {
+            self.apply(event);
+        }
+        applied
+    }
+
+    // Fold a single event into the game state
+    fn apply(&mut self, event: GameEvent) {
+        // Any change to the game cancels a pending take back
+        self.take_back_ids.clear();
+
+        match event {
+            GameEvent::PlayerAdded { player_id, ref player_name } => {
+                debug!("Adding player_id={}; player_name={}", player_id, player_name);
+                self.player_names.push(player_name.to_string());
+                self.player_ids.push(player_id);
+                if self.player_names.len() as u8 >= self.max_players {
+                    self.state = Some(Box::new(WaitingOnMove {}));
+                }
+            },
+            GameEvent::ActivePlayerSet { player } => {
+                self.active_player = player;
+            },
+            GameEvent::Moved { player, player_move } => {
+                for _ in 0..player_move {
+                    self.game_board.push(player);
+                }
+                // Echo game state
+                debug!("Game total is: {}", self.game_board.len());
+
+                // Game continues, next player's move
+                self.active_player = (self.active_player + 1)%(self.player_names.len() as u8);
+            },
+            GameEvent::GameOver { loser } => {
+                info!("{} has lost the game!!!", self.player_names.get(loser as usize).unwrap());
+                self.active_player = loser;
+                self.state = Some(Box::new(GameOver {}));
+            },
+            GameEvent::RestartRequested { player_id } => {
+                self.restart_ids.insert(player_id);
+            },
+            GameEvent::Restarted => {
+                // Restart the game - reset game state
+                self.restart_ids.clear();
+                self.game_board.clear();
+                self.state = Some(Box::new(WaitingOnMove {}));
+            },
+        }
+
+        self.events.push(event);
+    }
 }
 
+impl Game for GameData {
+    fn game_type(&self) -> u8 {
+        if self.is_move_range() { COUNTING_GAME } else { SUBTRACTION_GAME }
+    }
+
+    fn name(&self) -> &'static str { "Counting" }
+
+    // Counting game: max_players, max_move, game_board_size
+    // Subtraction game: max_players, game_board_size, move_set...
+    fn rules(&self) -> Vec<u8> {
+        if self.is_move_range() {
+            vec![self.max_players, self.get_max_move(), self.game_board_size]
+        } else {
+            let mut rules = vec![self.max_players, self.game_board_size];
+            rules.extend_from_slice(&self.move_set);
+            rules
+        }
+    }
+
+    fn max_players(&self) -> u8 { self.max_players }
+
+    fn add_player(&mut self, player_id: usize, player_name: &str) {
+        GameData::add_player(self, player_id, player_name);
+    }
+
+    fn player_ids(&self) -> &[usize] { self.get_player_ids() }
+
+    fn player_name(&self, player_id: usize) -> Option<&str> { self.get_player_name(player_id) }
+
+    fn current_player(&self) -> Option<usize> {
+        match self.state {
+            Some(ref s) if !s.is_game_over() => self.player_ids.get(self.active_player as usize).copied(),
+            _ => None,
+        }
+    }
+
+    fn set_current_player(&mut self, player_id: usize) {
+        self.set_active_player(player_id);
+    }
+
+    // Move layout: data[0] - number of units to count
+    fn legal_moves(&self) -> Vec<Vec<u8>> {
+        if self.current_player().is_none() {
+            return Vec::new();
+        }
+        self.move_set.iter().map(|&player_move| vec![player_move]).collect()
+    }
+
+    fn apply_move(&mut self, player_id: usize, player_move: &[u8]) -> Result<(), MoveError> {
+        if !self.game_has_player(player_id) {
+            return Err(MoveError::UnknownPlayer);
+        }
+        if self.is_game_over() {
+            return Err(MoveError::GameOver);
+        }
+        if self.current_player() != Some(player_id) {
+            return Err(MoveError::NotYourTurn);
+        }
+        match *player_move {
+            [n] if self.legal_moves().contains(&vec![n]) => {
+                self.move_player(player_id, n);
+                Ok(())
+            },
+            _ => Err(MoveError::IllegalMove),
+        }
+    }
+
+    fn outcome(&self) -> Outcome {
+        match self.get_losing_player_id() {
+            Some(loser) => Outcome::Decided {
+                winners: self.player_ids.iter().copied().filter(|&id| id != loser).collect(),
+                losers: vec![loser],
+            },
+            None => Outcome::InProgress,
+        }
+    }
+
+    fn request_restart(&mut self, player_id: usize) {
+        GameData::request_restart(self, player_id);
+    }
+
+    // data[0..2] - active player id, NO_PLAYER before the game starts
+    // data[2..] - game board
+    fn serialize_state(&self) -> Vec<u8> {
+        let mut state = encode_optional_player_id(self.get_active_player_id()).to_vec();
+        state.extend_from_slice(&self.game_board);
+        state
+    }
+
+    fn describe(&self) -> Vec<String> {
+        vec![
+            format!("Moves: {}", join_moves(&self.move_set)),
+            format!("Game Board: {:?} ", self.game_board),
+            format!("Game Total: {} / {}", self.game_board.len(), self.game_board_size),
+        ]
+    }
+
+    fn move_prompt(&self) -> String {
+        format!("Enter next move ({}) ", join_moves(&self.move_set))
+    }
+
+    fn parse_move(&self, input: &str) -> Option<Vec<u8>> {
+        input.trim().parse::<u8>().ok().map(|player_move| vec![player_move])
+    }
+
+    fn format_move(&self, player_move: &[u8]) -> String {
+        match *player_move {
+            [n] => format!("count {}", n),
+            _ => format!("{:?}", player_move),
+        }
+    }
+
+    fn suggest_move(&self) -> Option<Vec<u8>> {
+        self.current_player()?;
+        subtraction::best_move(&self.move_set, self.get_remaining()).map(|player_move| vec![player_move])
+    }
+
+    fn approve_take_back(&mut self, player_id: usize) -> bool {
+        GameData::approve_take_back(self, player_id)
+    }
+
+    fn cancel_take_back(&mut self) { GameData::cancel_take_back(self) }
+
+    fn is_take_back_pending(&self) -> bool { GameData::is_take_back_pending(self) }
+
+    fn undo_last_move(&mut self) -> Option<(usize, Vec<u8>)> {
+        GameData::undo_last_move(self).map(|(player_id, player_move)| (player_id, vec![player_move]))
+    }
+}
+
+fn join_moves(move_set: &[u8]) -> String {
+    move_set.iter().map(|player_move| player_move.to_string()).collect::<Vec<String>>().join(",")
+}
+
+// Each state decides which events a request produces; GameData::apply then
+// folds those events into the game
 trait GameState {
-    fn add_player(self: Box<Self>, game_data: &mut GameData, player_id: usize, player_name: &str) -> Box<dyn GameState>;
-    fn move_player(self: Box<Self>, game_data: &mut GameData, player: u8, player_move: u8) -> Box<dyn GameState>;
-    fn set_active_player(self: Box<Self>, game_data: &mut GameData, player: u8) -> Box<dyn GameState>;
-    fn is_game_over(&self) -> bool { return false; }
+    fn add_player(&self, game_data: &GameData, player_id: usize, player_name: &str) -> Vec<GameEvent>;
+    fn move_player(&self, game_data: &GameData, player: u8, player_move: u8) -> Vec<GameEvent>;
+    fn set_active_player(&self, game_data: &GameData, player: u8) -> Vec<GameEvent>;
+    fn request_restart(&self, _game_data: &GameData, _player_id: usize) -> Vec<GameEvent> { Vec::new() }
+    fn is_game_over(&self) -> bool { false }
 }
 
 struct WaitingForPlayers {
@@ -97,107 +549,115 @@ struct GameOver {
 }
 
 impl GameState for WaitingForPlayers {
-    fn add_player(self: Box<Self>, game_data: &mut GameData, player_id: usize, player_name: &str) -> Box<dyn GameState> {
-        println!("Adding player_id={}; player_name={}", player_id, player_name);
-        game_data.player_names.push(player_name.to_string());
-        game_data.player_ids.push(player_id);
-        if game_data.player_names.len() as u8 >= game_data.max_players {
-            // Randomly select an active player
-            game_data.active_player = 0;
-
-            // Update the game state
-            Box::new(WaitingOnMove {})
-        } else {
-            self
+    fn add_player(&self, game_data: &GameData, player_id: usize, player_name: &str) -> Vec<GameEvent> {
+        let mut events = vec![GameEvent::PlayerAdded { player_id, player_name: player_name.to_string() }];
+        if game_data.player_names.len() as u8 + 1 >= game_data.max_players {
+            // First player starts until the server picks a starting player
+            events.push(GameEvent::ActivePlayerSet { player: 0 });
         }
+        events
     }
 
     // Empty implementation
-    fn move_player(self: Box<Self>, _game_data: &mut GameData, _player: u8, _player_move: u8) -> Box<dyn GameState> { self }
-    fn set_active_player(self: Box<Self>, _game_data: &mut GameData, _player: u8) -> Box<dyn GameState> { self }
-    fn is_game_over(&self) -> bool { return false; }
+    fn move_player(&self, _game_data: &GameData, _player: u8, _player_move: u8) -> Vec<GameEvent> { Vec::new() }
+    fn set_active_player(&self, _game_data: &GameData, _player: u8) -> Vec<GameEvent> { Vec::new() }
+    fn is_game_over(&self) -> bool { false }
 }
 
 impl GameState for WaitingOnMove {
     // Empty implementation
-    fn add_player(self: Box<Self>, _game_data: &mut GameData, _player_id: usize, _player_name: &str) -> Box<dyn GameState> {
-        self
+    fn add_player(&self, _game_data: &GameData, _player_id: usize, _player_name: &str) -> Vec<GameEvent> {
+        Vec::new()
     }
 
-    fn move_player(self: Box<Self>, game_data: &mut GameData, player: u8, player_move: u8) -> Box<dyn GameState> {
-        println!("Game in progress, moving player");
+    fn move_player(&self, game_data: &GameData, player: u8, player_move: u8) -> Vec<GameEvent> {
+        debug!("Game in progress, moving player");
         // Check the active player is the one who is making the move
         if player != game_data.active_player {
-            println!("Player is not the active player, skipping move");
-            return self;
+            debug!("Player is not the active player, skipping move");
+            return Vec::new();
         }
 
         // Check the player is making a valid move
-        if player_move > game_data.max_move || player_move < 1 {
-            println!("Invalid move {}; max move is {}", player_move, game_data.max_move);
-            return self;
+        if !game_data.move_set.contains(&player_move) {
+            debug!("Invalid move {}; allowed moves are {:?}", player_move, game_data.move_set);
+            return Vec::new();
         }
 
-        for _ in 0..player_move {
-            game_data.game_board.push(player);
-        }
-
-        // Echo game state
-        println!("Game total is: {}", game_data.game_board.len());
+        let mut events = vec![GameEvent::Moved { player, player_move }];
 
         // Check for loser
-        if game_data.game_board.len() as u8 >= game_data.game_board_size {
-            println!("{} has lost the game!!!", game_data.player_names.get(player as usize).unwrap());
-            return Box::new(GameOver {});
+        if game_data.game_board.len() + player_move as usize >= game_data.game_board_size as usize {
+            events.push(GameEvent::GameOver { loser: player });
         }
-
-        // Game continues, next player's move
-        game_data.active_player = (game_data.active_player + 1)%(game_data.player_names.len() as u8);
-        self
+        events
     }
 
-    fn set_active_player(self: Box<Self>, game_data: &mut GameData, player: u8) -> Box<dyn GameState> {
+    fn set_active_player(&self, game_data: &GameData, player: u8) -> Vec<GameEvent> {
         // Only allow a change in active player if no moves have been made
-        if game_data.game_board.len() == 0 {
-            game_data.active_player = player;
+        if game_data.game_board.is_empty() {
+            vec![GameEvent::ActivePlayerSet { player }]
+        } else {
+            Vec::new()
         }
-        self
     }
 
-    fn is_game_over(&self) -> bool { return false; }
+    fn is_game_over(&self) -> bool { false }
 
 }
 
 impl GameState for GameOver {
-    fn add_player(self: Box<Self>, game_data: &mut GameData, player_id: usize, player_name: &str) -> Box<dyn GameState> {
-        println!("Adding player_id={}; player_name={}", player_id, player_name);
-        game_data.restart_ids.insert(player_id);
-        if game_data.player_ids.len() == game_data.restart_ids.len() {
-            // Restart the game - reset game state
-            game_data.restart_ids.clear();
-            game_data.game_board.clear();
-
-            // Leave starting player - last game's loser
-
-            // Update the game state
-            Box::new(WaitingOnMove {})
-        } else {
-            self
-        }
+    // Empty implementation
+    fn add_player(&self, _game_data: &GameData, _player_id: usize, _player_name: &str) -> Vec<GameEvent> {
+        Vec::new()
     }
 
     // Empty implementation
-    fn move_player(self: Box<Self>, _game_data: &mut GameData, _player: u8, _player_move: u8) -> Box<dyn GameState> {
-        println!("Game Over, cannot move player");
-        self
+    fn move_player(&self, _game_data: &GameData, _player: u8, _player_move: u8) -> Vec<GameEvent> {
+        debug!("Game Over, cannot move player");
+        Vec::new()
     }
 
-    fn set_active_player(self: Box<Self>, _game_data: &mut GameData, _player: u8) -> Box<dyn GameState> { self }
+    fn set_active_player(&self, _game_data: &GameData, _player: u8) -> Vec<GameEvent> { Vec::new() }
+
+    fn request_restart(&self, game_data: &GameData, player_id: usize) -> Vec<GameEvent> {
+        debug!("Restart requested by player_id={}", player_id);
+        let mut events = vec![GameEvent::RestartRequested { player_id }];
+        let mut restart_ids = game_data.restart_ids.clone();
+        restart_ids.insert(player_id);
+        if game_data.player_ids.len() == restart_ids.len() {
+            // Leave starting player - last game's loser
+            events.push(GameEvent::Restarted);
+        }
+        events
+    }
 
     // Game IS over!
-    fn is_game_over(&self) -> bool { return true; }
+    fn is_game_over(&self) -> bool { true }
+}
+
+// Player ids go over the wire as two bytes, big endian. NO_PLAYER stands
+// for no player at all, so ids are kept below it.
+pub const PLAYER_ID_LENGTH: usize = 2;
+pub const NO_PLAYER: u16 = u16::MAX;
+
+pub fn encode_player_id(player_id: usize) -> [u8; PLAYER_ID_LENGTH] {
+    debug_assert!(player_id < NO_PLAYER as usize);
+    (player_id as u16).to_be_bytes()
+}
+
+pub fn encode_optional_player_id(player_id: Option<usize>) -> [u8; PLAYER_ID_LENGTH] {
+    player_id.map_or(NO_PLAYER.to_be_bytes(), encode_player_id)
 }
 
+// Player id at the start of `data`; None if it is too short or holds
+// NO_PLAYER
+pub fn decode_player_id(data: &[u8]) -> Option<usize> {
+    match *data {
+        [high, low, ..] => Some(u16::from_be_bytes([high, low])).filter(|&player_id| player_id != NO_PLAYER).map(usize::from),
+        _ => None,
+    }
+}
 
 pub enum Message {
     // Client Messages
@@ -205,6 +665,12 @@ pub enum Message {
     PlayerMove = 1,
     RestartGame = 2,
     EndGame = 3,
+    GetStats = 11,
+    Leaderboard = 12,
+    TakeBack = 16,
+    DeclineTakeBack = 17,
+    JoinTournament = 23,
+    LeaveTournament = 24,
 
     // Server Messages
     OpponentDisconnect = 128,
@@ -214,6 +680,20 @@ pub enum Message {
     SetActivePlayer = 7,
     Welcome = 8,
     ServerUserName = 9,
+    UserNameRejected = 10,
+    PlayerStats = 13,
+    LeaderboardEntry = 14,
+    UnknownPlayer = 15,
+    TakeBackRequested = 18,
+    MoveTakenBack = 19,
+    TakeBackDeclined = 20,
+    CoinFlip = 21,
+    MatchScore = 22,
+    TournamentStatus = 25,
+    TournamentPairing = 26,
+    TournamentResult = 27,
+    TournamentStanding = 28,
+    Announcement = 29,
 }
 
 impl Message {
@@ -224,6 +704,12 @@ impl Message {
             1 => Some(Message::PlayerMove),
             2 => Some(Message::RestartGame),
             3 => Some(Message::EndGame),
+            11 => Some(Message::GetStats),
+            12 => Some(Message::Leaderboard),
+            16 => Some(Message::TakeBack),
+            17 => Some(Message::DeclineTakeBack),
+            23 => Some(Message::JoinTournament),
+            24 => Some(Message::LeaveTournament),
 
             // Server Messages
             128 => Some(Message::OpponentDisconnect),  // Changed from 0
@@ -233,6 +719,20 @@ impl Message {
             7 => Some(Message::SetActivePlayer),
             8 => Some(Message::Welcome),
             9 => Some(Message::ServerUserName),
+            10 => Some(Message::UserNameRejected),
+            13 => Some(Message::PlayerStats),
+            14 => Some(Message::LeaderboardEntry),
+            15 => Some(Message::UnknownPlayer),
+            18 => Some(Message::TakeBackRequested),
+            19 => Some(Message::MoveTakenBack),
+            20 => Some(Message::TakeBackDeclined),
+            21 => Some(Message::CoinFlip),
+            22 => Some(Message::MatchScore),
+            25 => Some(Message::TournamentStatus),
+            26 => Some(Message::TournamentPairing),
+            27 => Some(Message::TournamentResult),
+            28 => Some(Message::TournamentStanding),
+            29 => Some(Message::Announcement),
 
             // Not Found
             _ => None,
@@ -240,3 +740,125 @@ impl Message {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two players, moves of 1..=3, counting to 10; player 7 moves first
+    fn started_game() -> GameData {
+        let mut game_data = GameData::new(2, 3, 10);
+        game_data.add_player(4, "alice");
+        game_data.add_player(7, "bob");
+        assert!(game_data.set_active_player(7));
+        game_data
+    }
+
+    #[test]
+    fn replaying_events_rebuilds_the_game() {
+        let mut game_data = started_game();
+        assert!(game_data.move_player(7, 3));
+        assert!(game_data.move_player(4, 2));
+
+        let replayed = GameData::from_events(2, &[1, 2, 3], 10, game_data.get_events());
+        assert_eq!(replayed.get_events(), game_data.get_events());
+        assert_eq!(replayed.get_game_board(), [1, 1, 1, 0, 0]);
+        assert_eq!(replayed.get_active_player_id(), Some(7));
+        assert_eq!(replayed.get_player_names(), &["alice", "bob"]);
+    }
+
+    #[test]
+    fn take_back_restores_the_previous_turn() {
+        let mut game_data = started_game();
+        assert!(game_data.move_player(7, 3));
+        assert_eq!(game_data.get_active_player_id(), Some(4));
+
+        assert!(!game_data.approve_take_back(4));
+        assert!(game_data.is_take_back_pending());
+        assert!(game_data.approve_take_back(7));
+        assert_eq!(game_data.undo_last_move(), Some((7, 3)));
+        assert!(!game_data.is_take_back_pending());
+        assert!(game_data.get_game_board().is_empty());
+        assert_eq!(game_data.get_active_player_id(), Some(7));
+
+        // With no moves left the starting player may change again
+        assert!(!game_data.can_take_back());
+        assert_eq!(game_data.undo_last_move(), None);
+        assert!(game_data.set_active_player(4));
+    }
+
+    #[test]
+    fn player_255_is_a_player() {
+        let mut game_data = GameData::new(2, 3, 10);
+        assert_eq!(game_data.get_active_player_id(), None);
+        assert_eq!(game_data.serialize_state()[..PLAYER_ID_LENGTH], [255, 255]);
+
+        game_data.add_player(255, "alice");
+        game_data.add_player(4, "bob");
+        assert!(game_data.set_active_player(255));
+        assert_eq!(game_data.get_active_player_id(), Some(255));
+        let state = game_data.serialize_state();
+        assert_eq!(state[..PLAYER_ID_LENGTH], [0, 255]);
+        assert_eq!(decode_player_id(&state), Some(255));
+        assert_eq!(decode_player_id(&NO_PLAYER.to_be_bytes()), None);
+    }
+
+    #[test]
+    fn a_move_cancels_a_pending_take_back() {
+        let mut game_data = started_game();
+        assert!(game_data.move_player(7, 1));
+        assert!(!game_data.approve_take_back(7));
+        assert!(game_data.move_player(4, 1));
+        assert!(!game_data.is_take_back_pending());
+    }
+
+    #[test]
+    fn cannot_take_back_a_finished_game() {
+        let mut game_data = started_game();
+        for &(player_id, player_move) in &[(7, 3), (4, 3), (7, 3), (4, 1)] {
+            assert!(game_data.move_player(player_id, player_move));
+        }
+        assert_eq!(game_data.get_losing_player_id(), Some(4));
+
+        assert!(!game_data.can_take_back());
+        assert!(!game_data.approve_take_back(4));
+        assert_eq!(game_data.undo_last_move(), None);
+        assert_eq!(game_data.get_game_board().len(), 10);
+    }
+
+    #[test]
+    fn restart_keeps_players_and_their_names() {
+        let mut game_data = started_game();
+        for &(player_id, player_move) in &[(7, 3), (4, 3), (7, 3), (4, 1)] {
+            game_data.move_player(player_id, player_move);
+        }
+        assert!(!game_data.request_restart(9));
+        assert!(game_data.request_restart(4));
+        assert!(game_data.is_game_over());
+        assert!(game_data.request_restart(7));
+
+        assert!(!game_data.is_game_over());
+        assert!(game_data.get_game_board().is_empty());
+        assert_eq!(game_data.get_player_names(), &["alice", "bob"]);
+        assert_eq!(game_data.get_player_ids(), [4, 7]);
+        // The loser of the last game starts
+        assert_eq!(game_data.get_active_player_id(), Some(4));
+        // Moves before the restart cannot be taken back
+        assert!(!game_data.can_take_back());
+        // Restarting is not possible mid-game
+        assert!(!game_data.request_restart(4));
+    }
+
+    #[test]
+    fn rejects_unknown_players_and_illegal_moves() {
+        let mut game_data = started_game();
+        assert!(!game_data.move_player(9, 1));
+        assert!(!game_data.set_active_player(9));
+        assert!(!game_data.move_player(4, 1));
+        assert!(!game_data.move_player(7, 4));
+        assert!(game_data.get_game_board().is_empty());
+        assert_eq!(game_data.apply_move(9, &[1]), Err(MoveError::UnknownPlayer));
+        assert_eq!(game_data.apply_move(4, &[1]), Err(MoveError::NotYourTurn));
+        assert_eq!(game_data.apply_move(7, &[0]), Err(MoveError::IllegalMove));
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::HashSet;
+
+use super::{encode_optional_player_id, Game, MoveError, Outcome, NIM_GAME};
+
+pub const MAX_HEAPS: usize = 8;
+const NIM_PLAYERS: u8 = 2;
+
+// Multi-heap Nim. On their turn a player takes any number of objects from a
+// single heap. Under normal play whoever takes the last object wins; under
+// misère play whoever takes the last object loses.
+pub struct NimGame {
+    initial_heaps: Vec<u8>,
+    misere: bool,
+    heaps: Vec<u8>,
+    player_names: Vec<String>,
+    player_ids: Vec<usize>,
+    // Index into player_ids of the player to move
+    active_player: usize,
+    // Moves of the current game as (player index, heap, count)
+    moves: Vec<(usize, u8, u8)>,
+    // Index into player_ids of the loser once the game is over
+    loser: Option<usize>,
+    restart_ids: HashSet<usize>,
+    take_back_ids: HashSet<usize>,
+}
+
+impl NimGame {
+    pub fn new(heaps: &[u8], misere: bool) -> NimGame {
+        NimGame {
+            initial_heaps: heaps.to_vec(),
+            misere,
+            heaps: heaps.to_vec(),
+            player_names: Vec::new(),
+            player_ids: Vec::new(),
+            active_player: 0,
+            moves: Vec::new(),
+            loser: None,
+            restart_ids: HashSet::new(),
+            take_back_ids: HashSet::new(),
+        }
+    }
+
+    // Rules layout: data[0] - 1 for misère play, 0 for normal play
+    //               data[1..] - heap sizes
+    pub fn from_rules(rules: &[u8]) -> Option<NimGame> {
+        match *rules {
+            [misere, ref heaps @ ..] if misere <= 1 && is_valid_heaps(heaps) =>
+                Some(NimGame::new(heaps, misere == 1)),
+            _ => None,
+        }
+    }
+
+    pub fn get_heaps(&self) -> &[u8] { self.heaps.as_slice() }
+
+    pub fn is_misere(&self) -> bool { self.misere }
+
+    fn is_started(&self) -> bool {
+        self.player_ids.len() as u8 >= NIM_PLAYERS
+    }
+
+    fn player_index(&self, player_id: usize) -> Option<usize> {
+        self.player_ids.iter().position(|id| *id == player_id)
+    }
+
+    fn next_player(&self, player: usize) -> usize {
+        (player + 1) % self.player_ids.len()
+    }
+}
+
+// Heap sizes accepted in the rules: between one and MAX_HEAPS non-empty heaps
+pub fn is_valid_heaps(heaps: &[u8]) -> bool {
+    !heaps.is_empty() && heaps.len() <= MAX_HEAPS && heaps.iter().all(|&heap| heap > 0)
+}
+
+// Perfect play from the nim-sum of the heaps. Returns the heap index and
+// count to take, or None if every heap is empty. From a lost position the
+// smallest possible move is made to drag the game out.
+pub fn best_move(heaps: &[u8], misere: bool) -> Option<(usize, u8)> {
+    let largest = (0..heaps.len()).max_by_key(|&heap| heaps[heap])?;
+    if heaps[largest] == 0 {
+        return None;
+    }
+
+    // Misère play only differs once at most one heap holds more than one
+    // object: the winner then leaves an odd number of single-object heaps
+    let big_heaps = heaps.iter().filter(|&&heap| heap > 1).count();
+    if misere && big_heaps <= 1 {
+        let ones = heaps.iter().filter(|&&heap| heap == 1).count();
+        if big_heaps == 1 {
+            let keep = if ones % 2 == 1 { 0 } else { 1 };
+            return Some((largest, heaps[largest] - keep));
+        }
+        return Some((largest, 1));
+    }
+
+    let nim_sum = heaps.iter().fold(0, |sum, &heap| sum ^ heap);
+    if nim_sum == 0 {
+        return Some((largest, 1));
+    }
+    heaps.iter().enumerate()
+        .find(|&(_, &heap)| heap ^ nim_sum < heap)
+        .map(|(index, &heap)| (index, heap - (heap ^ nim_sum)))
+}
+
+impl Game for NimGame {
+    fn game_type(&self) -> u8 { NIM_GAME }
+
+    fn name(&self) -> &'static str {
+        if self.misere { "Nim (misère)" } else { "Nim" }
+    }
+
+    fn rules(&self) -> Vec<u8> {
+        let mut rules = vec![self.misere as u8];
+        rules.extend_from_slice(&self.initial_heaps);
+        rules
+    }
+
+    fn max_players(&self) -> u8 { NIM_PLAYERS }
+
+    fn add_player(&mut self, player_id: usize, player_name: &str) {
+        if !self.is_started() && !self.has_player(player_id) {
+            self.player_ids.push(player_id);
+            self.player_names.push(player_name.to_string());
+        }
+    }
+
+    fn player_ids(&self) -> &[usize] { self.player_ids.as_slice() }
+
+    fn player_name(&self, player_id: usize) -> Option<&str> {
+        self.player_index(player_id).map(|player| self.player_names[player].as_str())
+    }
+
+    fn current_player(&self) -> Option<usize> {
+        if self.is_started() && self.loser.is_none() {
+            Some(self.player_ids[self.active_player])
+        } else {
+            None
+        }
+    }
+
+    fn set_current_player(&mut self, player_id: usize) {
+        if self.moves.is_empty() && self.loser.is_none() {
+            if let Some(player) = self.player_index(player_id) {
+                self.active_player = player;
+            }
+        }
+    }
+
+    // Move layout: data[0] - heap index
+    //              data[1] - number of objects to take
+    fn legal_moves(&self) -> Vec<Vec<u8>> {
+        if self.current_player().is_none() {
+            return Vec::new();
+        }
+        let mut moves = Vec::new();
+        for (heap, &size) in self.heaps.iter().enumerate() {
+            for count in 1..=size {
+                moves.push(vec![heap as u8, count]);
+            }
+        }
+        moves
+    }
+
+    fn apply_move(&mut self, player_id: usize, player_move: &[u8]) -> Result<(), MoveError> {
+        let player = self.player_index(player_id).ok_or(MoveError::UnknownPlayer)?;
+        if self.loser.is_some() {
+            return Err(MoveError::GameOver);
+        }
+        if self.current_player() != Some(player_id) {
+            return Err(MoveError::NotYourTurn);
+        }
+        let (heap, count) = match *player_move {
+            [heap, count] if count > 0 && self.heaps.get(heap as usize).is_some_and(|&size| count <= size) =>
+                (heap, count),
+            _ => return Err(MoveError::IllegalMove),
+        };
+
+        self.take_back_ids.clear();
+        self.heaps[heap as usize] -= count;
+        self.moves.push((player, heap, count));
+        self.active_player = self.next_player(player);
+        if self.heaps.iter().all(|&size| size == 0) {
+            self.loser = Some(if self.misere { player } else { self.active_player });
+        }
+        Ok(())
+    }
+
+    fn outcome(&self) -> Outcome {
+        match self.loser {
+            Some(loser) => Outcome::Decided {
+                winners: self.player_ids.iter().enumerate()
+                    .filter(|&(player, _)| player != loser)
+                    .map(|(_, &id)| id)
+                    .collect(),
+                losers: vec![self.player_ids[loser]],
+            },
+            None => Outcome::InProgress,
+        }
+    }
+
+    fn request_restart(&mut self, player_id: usize) {
+        if self.loser.is_none() || !self.has_player(player_id) {
+            return;
+        }
+        self.restart_ids.insert(player_id);
+        if self.restart_ids.len() == self.player_ids.len() {
+            // The loser moves first in the next game
+            self.active_player = self.loser.take().unwrap();
+            self.heaps = self.initial_heaps.clone();
+            self.moves.clear();
+            self.restart_ids.clear();
+        }
+    }
+
+    // data[0..2] - active player id, NO_PLAYER when nobody is to move
+    // data[2..] - heap sizes
+    fn serialize_state(&self) -> Vec<u8> {
+        let mut state = encode_optional_player_id(self.current_player()).to_vec();
+        state.extend_from_slice(&self.heaps);
+        state
+    }
+
+    fn describe(&self) -> Vec<String> {
+        let mut lines = vec![format!("Last object {}", if self.misere { "loses" } else { "wins" })];
+        for (heap, &size) in self.heaps.iter().enumerate() {
+            lines.push(format!("Heap {}: {:<width$} ({})", heap + 1, "|".repeat(size as usize), size,
+                width = *self.initial_heaps.iter().max().unwrap() as usize));
+        }
+        lines
+    }
+
+    fn move_prompt(&self) -> String {
+        "Enter heap and number to take (e.g. 1 3) ".to_string()
+    }
+
+    // Heaps are numbered from 1 for players
+    fn parse_move(&self, input: &str) -> Option<Vec<u8>> {
+        let fields: Vec<u8> = input.split_whitespace().map(|field| field.parse().ok()).collect::<Option<_>>()?;
+        match *fields {
+            [heap, count] if heap > 0 => Some(vec![heap - 1, count]),
+            _ => None,
+        }
+    }
+
+    fn format_move(&self, player_move: &[u8]) -> String {
+        match *player_move {
+            [heap, count] => format!("take {} from heap {}", count, heap as usize + 1),
+            _ => format!("{:?}", player_move),
+        }
+    }
+
+    fn suggest_move(&self) -> Option<Vec<u8>> {
+        self.current_player()?;
+        best_move(&self.heaps, self.misere).map(|(heap, count)| vec![heap as u8, count])
+    }
+
+    fn approve_take_back(&mut self, player_id: usize) -> bool {
+        if self.loser.is_some() || self.moves.is_empty() || !self.has_player(player_id) {
+            return false;
+        }
+        self.take_back_ids.insert(player_id);
+        self.take_back_ids.len() == self.player_ids.len()
+    }
+
+    fn cancel_take_back(&mut self) {
+        self.take_back_ids.clear();
+    }
+
+    fn is_take_back_pending(&self) -> bool {
+        !self.take_back_ids.is_empty()
+    }
+
+    fn undo_last_move(&mut self) -> Option<(usize, Vec<u8>)> {
+        if self.loser.is_some() {
+            return None;
+        }
+        let (player, heap, count) = self.moves.pop()?;
+        self.take_back_ids.clear();
+        self.heaps[heap as usize] += count;
+        self.active_player = player;
+        Some((self.player_ids[player], vec![heap, count]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_heaps() {
+        let game = NimGame::new(&[3, 1], false);
+        assert_eq!(game.describe(), ["Last object wins", "Heap 1: ||| (3)", "Heap 2: |   (1)"]);
+        assert!(NimGame::from_rules(&[0]).is_none());
+    }
+
+    #[test]
+    fn best_move_leaves_a_losing_position() {
+        assert_eq!(best_move(&[3, 4, 5], false), Some((0, 2)));
+        assert_eq!(best_move(&[0, 0], false), None);
+        assert_eq!(best_move(&[], false), None);
+        // Misère: leave an odd number of single-object heaps
+        assert_eq!(best_move(&[1, 1, 4], true), Some((2, 3)));
+        assert_eq!(best_move(&[1, 4], true), Some((1, 4)));
+    }
+}
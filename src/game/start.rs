@@ -0,0 +1,144 @@
+use rand::Rng;
+use rand::rngs::StdRng;
+
+use super::Outcome;
+
+// How a room decides who moves first in each game
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StartPolicy {
+    // Pick a player at random
+    Random,
+    // Take turns, starting with a random player
+    Alternate,
+    LoserStarts,
+    WinnerStarts,
+    // Pick at random and tell the players about the coin toss
+    CoinFlip,
+}
+
+impl StartPolicy {
+    pub fn from_name(name: &str) -> Option<StartPolicy> {
+        match name {
+            "random" => Some(StartPolicy::Random),
+            "alternate" => Some(StartPolicy::Alternate),
+            "loser" => Some(StartPolicy::LoserStarts),
+            "winner" => Some(StartPolicy::WinnerStarts),
+            "coin" => Some(StartPolicy::CoinFlip),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            StartPolicy::Random => "random",
+            StartPolicy::Alternate => "alternate",
+            StartPolicy::LoserStarts => "loser",
+            StartPolicy::WinnerStarts => "winner",
+            StartPolicy::CoinFlip => "coin",
+        }
+    }
+
+    // Whether clients are sent a Coin_Flip message for the choice
+    pub fn is_announced(self) -> bool {
+        self == StartPolicy::CoinFlip
+    }
+}
+
+// Chooses the starting player for each game played in one room. The random
+// number generator is passed in so a room can be replayed from a seed.
+pub struct StartingPlayer {
+    policy: StartPolicy,
+    rng: StdRng,
+    last_starter: Option<usize>,
+}
+
+impl StartingPlayer {
+    pub fn new(policy: StartPolicy, rng: StdRng) -> StartingPlayer {
+        StartingPlayer { policy, rng, last_starter: None }
+    }
+
+    pub fn policy(&self) -> StartPolicy { self.policy }
+
+    // Use a different policy from the room's next game on
+    pub fn set_policy(&mut self, policy: StartPolicy) {
+        self.policy = policy;
+    }
+
+    // Choose who moves first in the next game. `previous` is the outcome of
+    // the game just finished, or None for a room's first game. Draws and
+    // first games fall back to a random choice for the winner and loser
+    // policies.
+    pub fn choose(&mut self, player_ids: &[usize], previous: Option<&Outcome>) -> usize {
+        let chosen = match (self.policy, previous) {
+            (StartPolicy::Alternate, _) => self.last_starter
+                .and_then(|last| player_ids.iter().position(|id| *id == last))
+                .map(|last| player_ids[(last + 1) % player_ids.len()]),
+            (StartPolicy::LoserStarts, Some(Outcome::Decided { losers, .. })) =>
+                losers.iter().copied().find(|id| player_ids.contains(id)),
+            (StartPolicy::WinnerStarts, Some(Outcome::Decided { winners, .. })) =>
+                winners.iter().copied().find(|id| player_ids.contains(id)),
+            _ => None,
+        };
+        let chosen = chosen.unwrap_or_else(|| player_ids[self.rng.gen_range(0, player_ids.len())]);
+        self.last_starter = Some(chosen);
+        chosen
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    fn decided(winner: usize, loser: usize) -> Outcome {
+        Outcome::Decided { winners: vec![winner], losers: vec![loser] }
+    }
+
+    fn starting_player(policy: StartPolicy, seed: u64) -> StartingPlayer {
+        StartingPlayer::new(policy, StdRng::seed_from_u64(seed))
+    }
+
+    #[test]
+    fn alternate_takes_turns_after_a_random_first_game() {
+        let mut starting_player = starting_player(StartPolicy::Alternate, 1);
+        let first = starting_player.choose(&[4, 7], None);
+        let second = if first == 4 { 7 } else { 4 };
+        // Results do not matter when alternating
+        assert_eq!(starting_player.choose(&[4, 7], Some(&decided(first, second))), second);
+        assert_eq!(starting_player.choose(&[4, 7], Some(&decided(first, second))), first);
+        assert_eq!(starting_player.choose(&[4, 7], Some(&Outcome::Draw)), second);
+    }
+
+    #[test]
+    fn loser_and_winner_start_the_next_game() {
+        let mut loser_starts = starting_player(StartPolicy::LoserStarts, 1);
+        let mut winner_starts = starting_player(StartPolicy::WinnerStarts, 1);
+        for &(winner, loser) in &[(4, 7), (7, 4), (7, 4)] {
+            assert_eq!(loser_starts.choose(&[4, 7], Some(&decided(winner, loser))), loser);
+            assert_eq!(winner_starts.choose(&[4, 7], Some(&decided(winner, loser))), winner);
+        }
+    }
+
+    #[test]
+    fn random_choices_repeat_for_a_seed() {
+        let choices = |seed| {
+            let mut starting_player = starting_player(StartPolicy::Random, seed);
+            (0..32).map(|_| starting_player.choose(&[4, 7], Some(&decided(4, 7)))).collect::<Vec<usize>>()
+        };
+        assert_eq!(choices(5), choices(5));
+        assert!(choices(5).contains(&4));
+        assert!(choices(5).contains(&7));
+        // Draws and first games fall back to a random player
+        let mut loser_starts = starting_player(StartPolicy::LoserStarts, 5);
+        assert_eq!(loser_starts.choose(&[4, 7], Some(&Outcome::Draw)), choices(5)[0]);
+    }
+
+    #[test]
+    fn policy_changes_apply_to_the_next_game() {
+        let mut starting_player = starting_player(StartPolicy::LoserStarts, 1);
+        assert_eq!(starting_player.choose(&[4, 7], Some(&decided(4, 7))), 7);
+        starting_player.set_policy(StartPolicy::Alternate);
+        assert_eq!(starting_player.policy(), StartPolicy::Alternate);
+        assert_eq!(starting_player.choose(&[4, 7], Some(&decided(4, 7))), 4);
+    }
+}
@@ -0,0 +1,87 @@
+// Analysis of the counting game as a subtraction game. Players take turns
+// adding one of the allowed moves to the count, and whoever brings the count
+// to the board size loses. Positions are described by how many units remain.
+
+pub const MAX_MOVE_SET: usize = 16;
+
+// Move sets accepted in the rules: between one and MAX_MOVE_SET distinct,
+// non-zero moves
+pub fn is_valid_move_set(move_set: &[u8]) -> bool {
+    let mut sorted = move_set.to_vec();
+    sorted.sort_unstable();
+    sorted.dedup();
+    !move_set.is_empty()
+        && move_set.len() <= MAX_MOVE_SET
+        && sorted.len() == move_set.len()
+        && sorted[0] > 0
+}
+
+// Whether the player to move wins a two player game with perfect play, for
+// every number of remaining units up to `remaining`. A move that reaches the
+// board size loses outright, so only moves leaving units behind are useful.
+pub fn winning_positions(move_set: &[u8], remaining: u8) -> Vec<bool> {
+    let mut winning = vec![false; remaining as usize + 1];
+    for units in 1..=remaining as usize {
+        winning[units] = move_set.iter()
+            .any(|&player_move| (player_move as usize) < units && !winning[units - player_move as usize]);
+    }
+    winning
+}
+
+// Move that leaves the opponent in a losing position. From a lost position
+// the smallest move that keeps the game going is made, and if every move
+// ends the game the smallest is made. None if no units remain.
+pub fn best_move(move_set: &[u8], remaining: u8) -> Option<u8> {
+    if remaining == 0 {
+        return None;
+    }
+    let winning = winning_positions(move_set, remaining);
+    let mut surviving = move_set.iter().copied().filter(|&player_move| player_move < remaining);
+    surviving.clone()
+        .find(|&player_move| !winning[(remaining - player_move) as usize])
+        .or_else(|| surviving.next())
+        .or_else(|| move_set.iter().copied().min())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn losing_positions(move_set: &[u8], remaining: u8) -> Vec<usize> {
+        winning_positions(move_set, remaining).iter().enumerate()
+            .filter(|&(units, &winning)| units > 0 && !winning)
+            .map(|(units, _)| units)
+            .collect()
+    }
+
+    #[test]
+    fn finds_known_losing_positions() {
+        // With moves 1..=3 the player to move loses when one more than a multiple of four remains
+        assert_eq!(losing_positions(&[1, 2, 3], 20), [1, 5, 9, 13, 17]);
+        assert_eq!(losing_positions(&[1, 4], 15), [1, 3, 6, 8, 11, 13]);
+        assert_eq!(winning_positions(&[1, 2, 3], 0), [false]);
+    }
+
+    #[test]
+    fn best_move_leaves_a_losing_position() {
+        assert_eq!(best_move(&[1, 2, 3], 10), Some(1));
+        assert_eq!(best_move(&[1, 2, 3], 8), Some(3));
+        assert_eq!(best_move(&[1, 4], 10), Some(4));
+        // Lost positions keep the game going if they can
+        assert_eq!(best_move(&[1, 2, 3], 5), Some(1));
+        assert_eq!(best_move(&[2, 3], 1), Some(2));
+        assert_eq!(best_move(&[1, 2, 3], 0), None);
+    }
+
+    #[test]
+    fn validates_move_sets() {
+        assert!(is_valid_move_set(&[1, 4]));
+        assert!(!is_valid_move_set(&[]));
+        assert!(!is_valid_move_set(&[0, 2]));
+        assert!(!is_valid_move_set(&[2, 2]));
+        assert!(!is_valid_move_set(&[1; MAX_MOVE_SET + 1]));
+        assert!(crate::game::new_game(crate::game::COUNTING_GAME, &[2, 0, 10]).is_none());
+        assert!(crate::game::new_game(crate::game::COUNTING_GAME, &[2, 3, 10]).is_some());
+        assert!(crate::game::new_game(crate::game::SUBTRACTION_GAME, &[2, 10]).is_none());
+    }
+}
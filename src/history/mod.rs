@@ -0,0 +1,313 @@
+// Game history files.
+//
+// Every game is recorded as an append-only text log, one record per line.
+// Fields are separated by single spaces; player names come last on their
+// line so they may contain spaces. Timestamps are milliseconds since the
+// Unix epoch.
+//
+//   clientserver-game 2
+//   rules <game_type> <rule bytes...>
+//   player <timestamp> <player_id> <player_name>
+//   active <timestamp> <player_id>
+//   move <timestamp> <player_id> <move bytes...>
+//   over <timestamp> <losing_player_id>
+//   draw <timestamp>
+//   restart <timestamp>
+//   undo <timestamp>
+//
+// The first line identifies the format and version. `rules` comes next,
+// holding the game type and rule bytes of the Game_Data message, followed by
+// one `player` record per player in seat order. The remaining records appear
+// in the order they happened; a file may end at any record if the server
+// stopped mid-game. Move bytes use the same layout as the Player_Move
+// message for the game. `undo` takes back the most recent move still in
+// effect.
+//
+// Version 1 files, written before games were pluggable, hold a counting game
+// and have no game type in their `rules` record.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::game::{Game, COUNTING_GAME};
+
+pub const FORMAT_HEADER: &str = "clientserver-game 2";
+const FORMAT_HEADER_V1: &str = "clientserver-game 1";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GameRecord {
+    Player { timestamp: u64, player_id: usize, player_name: String },
+    ActivePlayer { timestamp: u64, player_id: usize },
+    Move { timestamp: u64, player_id: usize, player_move: Vec<u8> },
+    GameOver { timestamp: u64, losing_player_id: usize },
+    Draw { timestamp: u64 },
+    Restart { timestamp: u64 },
+    Undo { timestamp: u64 },
+}
+
+impl GameRecord {
+    pub fn timestamp(&self) -> u64 {
+        match *self {
+            GameRecord::Player { timestamp, .. } => timestamp,
+            GameRecord::ActivePlayer { timestamp, .. } => timestamp,
+            GameRecord::Move { timestamp, .. } => timestamp,
+            GameRecord::GameOver { timestamp, .. } => timestamp,
+            GameRecord::Draw { timestamp } => timestamp,
+            GameRecord::Restart { timestamp } => timestamp,
+            GameRecord::Undo { timestamp } => timestamp,
+        }
+    }
+
+    fn to_line(&self) -> String {
+        match *self {
+            GameRecord::Player { timestamp, player_id, ref player_name } =>
+                format!("player {} {} {}", timestamp, player_id, player_name),
+            GameRecord::ActivePlayer { timestamp, player_id } =>
+                format!("active {} {}", timestamp, player_id),
+            GameRecord::Move { timestamp, player_id, ref player_move } =>
+                format!("move {} {} {}", timestamp, player_id, join_bytes(player_move)),
+            GameRecord::GameOver { timestamp, losing_player_id } =>
+                format!("over {} {}", timestamp, losing_player_id),
+            GameRecord::Draw { timestamp } =>
+                format!("draw {}", timestamp),
+            GameRecord::Restart { timestamp } =>
+                format!("restart {}", timestamp),
+            GameRecord::Undo { timestamp } =>
+                format!("undo {}", timestamp),
+        }
+    }
+
+    fn from_line(line: &str) -> Option<GameRecord> {
+        let mut fields = line.splitn(4, ' ');
+        let kind = fields.next()?;
+        let timestamp = fields.next()?.parse().ok()?;
+        let record = match kind {
+            "player" => GameRecord::Player {
+                timestamp,
+                player_id: fields.next()?.parse().ok()?,
+                player_name: fields.next().unwrap_or("").to_string(),
+            },
+            "active" => GameRecord::ActivePlayer { timestamp, player_id: fields.next()?.parse().ok()? },
+            "move" => GameRecord::Move {
+                timestamp,
+                player_id: fields.next()?.parse().ok()?,
+                player_move: split_bytes(&fields.collect::<Vec<&str>>().join(" "))?,
+            },
+            "over" => GameRecord::GameOver { timestamp, losing_player_id: fields.next()?.parse().ok()? },
+            "draw" => GameRecord::Draw { timestamp },
+            "restart" => GameRecord::Restart { timestamp },
+            "undo" => GameRecord::Undo { timestamp },
+            _ => return None,
+        };
+        Some(record)
+    }
+}
+
+fn join_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| byte.to_string()).collect::<Vec<String>>().join(" ")
+}
+
+fn split_bytes(fields: &str) -> Option<Vec<u8>> {
+    fields.split_whitespace().map(|field| field.parse().ok()).collect()
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+// Appends records for one game to its history file as they happen
+pub struct GameRecorder {
+    path: PathBuf,
+    file: File,
+}
+
+impl GameRecorder {
+    // Create a new history file in `directory` and write the header and rules
+    pub fn create(directory: &Path, game: &dyn Game) -> io::Result<GameRecorder> {
+        fs::create_dir_all(directory)?;
+        let timestamp = now_millis();
+        let mut suffix = 0;
+        let (path, file) = loop {
+            let path = directory.join(format!("{}-{}.game", timestamp, suffix));
+            match OpenOptions::new().append(true).create_new(true).open(&path) {
+                Ok(file) => break (path, file),
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => suffix += 1,
+                Err(e) => return Err(e),
+            }
+        };
+
+        let mut recorder = GameRecorder { path, file };
+        recorder.write_line(FORMAT_HEADER)?;
+        recorder.write_line(&format!("rules {} {}", game.game_type(), join_bytes(&game.rules())))?;
+        Ok(recorder)
+    }
+
+    pub fn path(&self) -> &Path { self.path.as_path() }
+
+    pub fn record(&mut self, record: GameRecord) -> io::Result<()> {
+        self.write_line(&record.to_line())
+    }
+
+    pub fn record_player(&mut self, player_id: usize, player_name: &str) -> io::Result<()> {
+        self.record(GameRecord::Player { timestamp: now_millis(), player_id, player_name: player_name.to_string() })
+    }
+
+    pub fn record_active_player(&mut self, player_id: usize) -> io::Result<()> {
+        self.record(GameRecord::ActivePlayer { timestamp: now_millis(), player_id })
+    }
+
+    pub fn record_move(&mut self, player_id: usize, player_move: &[u8]) -> io::Result<()> {
+        self.record(GameRecord::Move { timestamp: now_millis(), player_id, player_move: player_move.to_vec() })
+    }
+
+    pub fn record_game_over(&mut self, losing_player_id: usize) -> io::Result<()> {
+        self.record(GameRecord::GameOver { timestamp: now_millis(), losing_player_id })
+    }
+
+    pub fn record_draw(&mut self) -> io::Result<()> {
+        self.record(GameRecord::Draw { timestamp: now_millis() })
+    }
+
+    pub fn record_restart(&mut self) -> io::Result<()> {
+        self.record(GameRecord::Restart { timestamp: now_millis() })
+    }
+
+    pub fn record_undo(&mut self) -> io::Result<()> {
+        self.record(GameRecord::Undo { timestamp: now_millis() })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        // One write per record so a crash can at worst truncate the last line
+        self.file.write_all(format!("{}\n", line).as_bytes())?;
+        self.file.flush()
+    }
+}
+
+// A history file read back from disk
+pub struct GameLog {
+    pub game_type: u8,
+    pub rules: Vec<u8>,
+    pub records: Vec<GameRecord>,
+}
+
+impl GameLog {
+    pub fn load(path: &Path) -> io::Result<GameLog> {
+        let invalid = |line_number: usize, message: &str| {
+            io::Error::new(io::ErrorKind::InvalidData,
+                format!("{}:{}: {}", path.display(), line_number, message))
+        };
+
+        let mut lines = BufReader::new(File::open(path)?).lines();
+
+        let version_1 = match lines.next() {
+            Some(Ok(ref header)) if header == FORMAT_HEADER => false,
+            Some(Ok(ref header)) if header == FORMAT_HEADER_V1 => true,
+            Some(Err(e)) => return Err(e),
+            _ => return Err(invalid(1, "not a game history file")),
+        };
+
+        let rules_line = lines.next().unwrap_or_else(|| Ok(String::new()))?;
+        let mut rules = match rules_line.strip_prefix("rules ").and_then(split_bytes) {
+            Some(ref rules) if rules.is_empty() => return Err(invalid(2, "invalid rules record")),
+            Some(rules) => rules,
+            None => return Err(invalid(2, "invalid rules record")),
+        };
+        let game_type = if version_1 { COUNTING_GAME } else { rules.remove(0) };
+
+        let lines = lines.collect::<io::Result<Vec<String>>>()?;
+        let mut records = Vec::new();
+        for (index, line) in lines.iter().enumerate() {
+            match GameRecord::from_line(line) {
+                Some(record) => records.push(record),
+                // A partial final line is expected if the server was killed mid-write
+                None if index + 1 == lines.len() => (),
+                None => return Err(invalid(index + 3, "invalid record")),
+            }
+        }
+
+        Ok(GameLog { game_type, rules, records })
+    }
+
+    // A fresh game with the logged rules, ready for the records to be replayed
+    pub fn new_game(&self) -> Option<Box<dyn Game>> {
+        crate::game::new_game(self.game_type, &self.rules)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{GameData, Outcome};
+
+    // Apply records the way the replay binary does
+    fn replay(log: &GameLog) -> Box<dyn Game> {
+        let mut game = log.new_game().unwrap();
+        for record in &log.records {
+            match *record {
+                GameRecord::Player { player_id, ref player_name, .. } => game.add_player(player_id, player_name),
+                GameRecord::ActivePlayer { player_id, .. } => game.set_current_player(player_id),
+                GameRecord::Move { player_id, ref player_move, .. } => game.apply_move(player_id, player_move).unwrap(),
+                GameRecord::Restart { .. } => {
+                    for player_id in game.player_ids().to_vec() {
+                        game.request_restart(player_id);
+                    }
+                },
+                GameRecord::Undo { .. } => { game.undo_last_move().unwrap(); },
+                GameRecord::GameOver { .. } | GameRecord::Draw { .. } => (),
+            }
+        }
+        game
+    }
+
+    #[test]
+    fn written_history_replays_to_the_same_state() {
+        let directory = std::env::temp_dir().join(format!("clientserver-history-{}", std::process::id()));
+        let mut game = GameData::new(2, 3, 10);
+        let mut recorder = GameRecorder::create(&directory, &game).unwrap();
+
+        for &(player_id, player_name) in &[(4, "alice"), (7, "bob")] {
+            game.add_player(player_id, player_name);
+            recorder.record_player(player_id, player_name).unwrap();
+        }
+        Game::set_current_player(&mut game, 7);
+        recorder.record_active_player(7).unwrap();
+        for &(player_id, player_move) in &[(7, 3), (4, 2), (7, 1)] {
+            game.apply_move(player_id, &[player_move]).unwrap();
+            recorder.record_move(player_id, &[player_move]).unwrap();
+        }
+        Game::undo_last_move(&mut game).unwrap();
+        recorder.record_undo().unwrap();
+        for &(player_id, player_move) in &[(7, 2), (4, 3)] {
+            game.apply_move(player_id, &[player_move]).unwrap();
+            recorder.record_move(player_id, &[player_move]).unwrap();
+        }
+        recorder.record_game_over(4).unwrap();
+
+        let log = GameLog::load(recorder.path()).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(log.game_type, COUNTING_GAME);
+        assert_eq!(log.rules, game.rules());
+
+        let replayed = replay(&log);
+        assert_eq!(replayed.player_ids(), game.get_player_ids());
+        assert_eq!(replayed.player_name(7), Some("bob"));
+        assert_eq!(replayed.serialize_state(), game.serialize_state());
+        assert_eq!(replayed.outcome(), game.outcome());
+        assert_eq!(replayed.outcome(), Outcome::Decided { winners: vec![7], losers: vec![4] });
+    }
+
+    #[test]
+    fn ignores_a_truncated_final_record() {
+        let directory = std::env::temp_dir().join(format!("clientserver-truncated-{}", std::process::id()));
+        let game = GameData::new(2, 3, 10);
+        let mut recorder = GameRecorder::create(&directory, &game).unwrap();
+        recorder.record_player(4, "alice").unwrap();
+        recorder.write_line("move 12").unwrap();
+
+        let log = GameLog::load(recorder.path()).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(log.records.len(), 1);
+    }
+}
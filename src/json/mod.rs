@@ -0,0 +1,627 @@
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::io;
+use std::iter::Peekable;
+use std::str::{self, Chars};
+
+use log::debug;
+
+use crate::connection;
+use crate::game::Message;
+use crate::logging::push_json_string;
+
+// Longest line accepted from a client
+const MAX_LINE: usize = 4096;
+// Messages held for a client that has not yet said which encoding it uses
+const MAX_PENDING: usize = 64 * 1024;
+
+// How one part of a message's data is shown in JSON
+#[derive(Clone, Copy)]
+enum Field {
+    // One byte, as a number
+    Byte(&'static str),
+    // Two bytes big-endian, as a number
+    Word(&'static str),
+    // One byte holding a signed number
+    Signed(&'static str),
+    // The rest of the data as UTF-8 text
+    Text(&'static str),
+    // The rest of the data as a list of numbers
+    Bytes(&'static str),
+}
+
+use Field::*;
+
+// Player_Stats payload, see ratings::encode_stats
+const STATS: [Field; 6] = [Word("rating"), Word("wins"), Word("losses"), Signed("streak"), Byte("best_streak"), Text("name")];
+
+// Name and data layout of each message
+fn layout(message: &Message) -> (&'static str, &'static [Field]) {
+    match message {
+        // Client Messages
+        Message::ClientUserName => ("ClientUserName", &[Text("name")]),
+        Message::PlayerMove => ("PlayerMove", &[Bytes("move")]),
+        Message::RestartGame => ("RestartGame", &[]),
+        Message::EndGame => ("EndGame", &[]),
+        Message::GetStats => ("GetStats", &[Text("name")]),
+        Message::Leaderboard => ("Leaderboard", &[Byte("top_n")]),
+        Message::TakeBack => ("TakeBack", &[]),
+        Message::DeclineTakeBack => ("DeclineTakeBack", &[]),
+        Message::JoinTournament => ("JoinTournament", &[]),
+        Message::LeaveTournament => ("LeaveTournament", &[]),
+
+        // Server Messages
+        Message::OpponentDisconnect => ("OpponentDisconnect", &[]),
+        Message::GameData => ("GameData", &[Byte("game_type"), Bytes("rules")]),
+        Message::AddPlayer => ("AddPlayer", &[Word("player_id"), Text("name")]),
+        Message::MovePlayer => ("MovePlayer", &[Word("player_id"), Bytes("move")]),
+        Message::SetActivePlayer => ("SetActivePlayer", &[Word("player_id")]),
+        Message::Welcome => ("Welcome", &[Word("player_id")]),
+        Message::ServerUserName => ("ServerUserName", &[Text("name")]),
+        Message::UserNameRejected => ("UserNameRejected", &[Byte("reason")]),
+        Message::PlayerStats => ("PlayerStats", &STATS),
+        Message::LeaderboardEntry => ("LeaderboardEntry", &[Byte("rank"), Word("rating"), Word("wins"), Word("losses"), Signed("streak"), Byte("best_streak"), Text("name")]),
+        Message::UnknownPlayer => ("UnknownPlayer", &[Text("name")]),
+        Message::TakeBackRequested => ("TakeBackRequested", &[Word("player_id")]),
+        Message::MoveTakenBack => ("MoveTakenBack", &[Word("player_id"), Bytes("move")]),
+        Message::TakeBackDeclined => ("TakeBackDeclined", &[Word("player_id")]),
+        Message::CoinFlip => ("CoinFlip", &[Word("player_id")]),
+        Message::MatchScore => ("MatchScore", &[Bytes("score")]),
+        Message::TournamentStatus => ("TournamentStatus", &[Bytes("status")]),
+        Message::TournamentPairing => ("TournamentPairing", &[Byte("round"), Text("opponent")]),
+        Message::TournamentResult => ("TournamentResult", &[Bytes("result")]),
+        Message::TournamentStanding => ("TournamentStanding", &[Bytes("standing")]),
+        Message::Announcement => ("Announcement", &[Text("text")]),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    // Waiting for the client's first byte
+    Undecided,
+    Binary,
+    Json,
+}
+
+// Lets a client use line-delimited JSON instead of binary frames, for
+// debugging with nc or openssl s_client. A client whose first byte is '{'
+// sends one JSON object per line and gets one back for each message, e.g.
+// {"type":"ClientUserName","name":"alice"}
+// {"type":"PlayerMove","move":[3]}
+// {"control":1,"data":[3]}
+// Anyone else keeps the binary encoding. Like WebSocket it only transforms
+// bytes, so it works over any transport.
+pub struct JsonLines {
+    state: State,
+    // Part of a line received but not yet complete
+    input: Vec<u8>,
+    // Messages sent before the client picked an encoding, or the start of
+    // one not yet complete
+    pending: Vec<u8>,
+}
+
+impl JsonLines {
+    pub fn new() -> JsonLines {
+        JsonLines { state: State::Undecided, input: Vec::new(), pending: Vec::new() }
+    }
+
+    pub fn is_json(&self) -> bool { self.state == State::Json }
+
+    // Handle bytes from the client, appending the binary messages they carry
+    // to `data` and anything held back for the client to `reply`. A line
+    // that is not a message gets an {"error":...} reply and fails with
+    // InvalidInput.
+    pub fn receive(&mut self, input: &[u8], data: &mut Vec<u8>, reply: &mut Vec<u8>) -> io::Result<()> {
+        if self.state == State::Undecided {
+            let first = match input.first() {
+                Some(&first) => first,
+                None => return Ok(()),
+            };
+            self.state = if first == b'{' { State::Json } else { State::Binary };
+            debug!("Client chose the {} encoding", if self.is_json() { "JSON" } else { "binary" });
+            let pending = std::mem::take(&mut self.pending);
+            self.send(&pending, reply);
+        }
+        if self.state == State::Binary {
+            data.extend_from_slice(input);
+            return Ok(());
+        }
+        self.input.extend_from_slice(input);
+        while let Some(end) = self.input.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = self.input.drain(..=end).collect();
+            let line = str::from_utf8(&line).map_err(|_| "line is not UTF-8").map(str::trim);
+            match line.and_then(|line| if line.is_empty() { Ok(Vec::new()) } else { decode(line) }) {
+                Ok(message) => data.extend_from_slice(&message),
+                Err(e) => return Err(error(reply, e)),
+            }
+        }
+        if self.input.len() > MAX_LINE {
+            return Err(error(reply, "line too long"));
+        }
+        Ok(())
+    }
+
+    // Encode messages for the client, held back until it picks an encoding
+    pub fn send(&mut self, data: &[u8], output: &mut Vec<u8>) {
+        match self.state {
+            State::Binary => output.extend_from_slice(data),
+            // A binary client need not say anything while it is sent more,
+            // so stop waiting once too much has been held
+            State::Undecided if self.pending.len() + data.len() > MAX_PENDING => {
+                self.state = State::Binary;
+                output.append(&mut self.pending);
+                output.extend_from_slice(data);
+            },
+            State::Undecided => self.pending.extend_from_slice(data),
+            State::Json => {
+                self.pending.extend_from_slice(data);
+                while let Some((control_byte, data)) = connection::take_message(&mut self.pending) {
+                    output.extend_from_slice(encode(control_byte, &data).as_bytes());
+                }
+            },
+        }
+    }
+}
+
+impl Default for JsonLines {
+    fn default() -> JsonLines { JsonLines::new() }
+}
+
+fn error(reply: &mut Vec<u8>, reason: &str) -> io::Error {
+    let mut line = String::from("{\"error\":");
+    push_json_string(&mut line, reason);
+    line.push_str("}\n");
+    reply.extend_from_slice(line.as_bytes());
+    io::Error::new(io::ErrorKind::InvalidInput, reason.to_string())
+}
+
+// One message as a line of JSON, falling back to the raw control byte and
+// data if it does not fit its layout
+pub fn encode(control_byte: u8, data: &[u8]) -> String {
+    let mut line = String::new();
+    let encoded = Message::from_u8(control_byte).is_some_and(|message| {
+        let (name, fields) = layout(&message);
+        line.push_str("{\"type\":");
+        push_json_string(&mut line, name);
+        encode_fields(&mut line, fields, data)
+    });
+    if !encoded {
+        line = format!("{{\"control\":{},\"data\":", control_byte);
+        push_list(&mut line, data);
+    }
+    line.push_str("}\n");
+    line
+}
+
+fn encode_fields(line: &mut String, fields: &[Field], mut data: &[u8]) -> bool {
+    for field in fields {
+        match *field {
+            Byte(name) | Signed(name) if !data.is_empty() => {
+                let value = if let Signed(_) = field { i32::from(data[0] as i8) } else { i32::from(data[0]) };
+                let _ = write!(line, ",\"{}\":{}", name, value);
+                data = &data[1..];
+            },
+            Word(name) if data.len() >= 2 => {
+                let _ = write!(line, ",\"{}\":{}", name, u16::from_be_bytes([data[0], data[1]]));
+                data = &data[2..];
+            },
+            Text(name) => match str::from_utf8(data) {
+                Ok(text) => {
+                    let _ = write!(line, ",\"{}\":", name);
+                    push_json_string(line, text);
+                    data = &[];
+                },
+                Err(_) => return false,
+            },
+            Bytes(name) => {
+                let _ = write!(line, ",\"{}\":", name);
+                push_list(line, data);
+                data = &[];
+            },
+            _ => return false,
+        }
+    }
+    data.is_empty()
+}
+
+fn push_list(line: &mut String, data: &[u8]) {
+    let values: Vec<String> = data.iter().map(|byte| byte.to_string()).collect();
+    let _ = write!(line, "[{}]", values.join(","));
+}
+
+// A line of JSON as a binary message
+pub fn decode(line: &str) -> Result<Vec<u8>, &'static str> {
+    let mut members = parse_object(line)?;
+    let mut take = |key: &str| members.iter().position(|(name, _)| name == key).map(|index| members.remove(index).1);
+    let (control_byte, data) = match take("type") {
+        Some(Value::Text(name)) => {
+            let (control_byte, fields) = (0..=u8::MAX)
+                .filter_map(|control_byte| Message::from_u8(control_byte).map(|message| (control_byte, layout(&message))))
+                .find(|(_, (message_name, _))| *message_name == name)
+                .map(|(control_byte, (_, fields))| (control_byte, fields))
+                .ok_or("unknown message type")?;
+            let mut data = Vec::new();
+            for field in fields {
+                match (*field, take(field_name(field))) {
+                    (Byte(_), Some(Value::Number(value))) => data.push(u8::try_from(value).map_err(|_| "number out of range")?),
+                    (Signed(_), Some(Value::Number(value))) => data.push(i8::try_from(value).map_err(|_| "number out of range")? as u8),
+                    (Word(_), Some(Value::Number(value))) => {
+                        data.extend_from_slice(&u16::try_from(value).map_err(|_| "number out of range")?.to_be_bytes());
+                    },
+                    (Text(_), Some(Value::Text(text))) => data.extend_from_slice(text.as_bytes()),
+                    (Bytes(_), Some(Value::List(values))) => data.extend_from_slice(&values),
+                    (Text(_), None) | (Bytes(_), None) => (),
+                    (_, None) => return Err("missing field"),
+                    (_, Some(_)) => return Err("wrong type of value for field"),
+                }
+            }
+            (control_byte, data)
+        },
+        Some(_) => return Err("type must be a string"),
+        None => match (take("control"), take("data")) {
+            (Some(Value::Number(control_byte)), data) => {
+                let data = match data {
+                    Some(Value::List(data)) => data,
+                    None => Vec::new(),
+                    Some(_) => return Err("data must be a list of bytes"),
+                };
+                (u8::try_from(control_byte).map_err(|_| "number out of range")?, data)
+            },
+            _ => return Err("expected a type or control byte"),
+        },
+    };
+    if !members.is_empty() {
+        return Err("unknown field");
+    }
+    if data.len() > u8::MAX as usize {
+        return Err("message too long");
+    }
+    let mut message = vec![control_byte, data.len() as u8];
+    message.extend_from_slice(&data);
+    Ok(message)
+}
+
+fn field_name(field: &Field) -> &'static str {
+    match *field {
+        Byte(name) | Word(name) | Signed(name) | Text(name) | Bytes(name) => name,
+    }
+}
+
+// Values a message line may hold
+enum Value {
+    Number(i64),
+    Text(String),
+    // Only lists of bytes are needed
+    List(Vec<u8>),
+}
+
+// Members of a flat JSON object
+fn parse_object(line: &str) -> Result<Vec<(String, Value)>, &'static str> {
+    let mut chars = line.chars().peekable();
+    let mut members = Vec::new();
+    expect(&mut chars, '{')?;
+    if skip_whitespace(&mut chars) == Some('}') {
+        chars.next();
+    } else {
+        loop {
+            skip_whitespace(&mut chars);
+            let name = parse_string(&mut chars)?;
+            expect(&mut chars, ':')?;
+            let value = match skip_whitespace(&mut chars) {
+                Some('"') => Value::Text(parse_string(&mut chars)?),
+                Some('[') => Value::List(parse_list(&mut chars)?),
+                _ => Value::Number(parse_number(&mut chars)?),
+            };
+            if members.iter().any(|(member, _)| *member == name) {
+                return Err("duplicate field");
+            }
+            members.push((name, value));
+            match skip_whitespace(&mut chars) {
+                Some(',') => { chars.next(); },
+                Some('}') => {
+                    chars.next();
+                    break;
+                },
+                _ => return Err("expected , or }"),
+            }
+        }
+    }
+    if skip_whitespace(&mut chars).is_some() {
+        return Err("unexpected data after object");
+    }
+    Ok(members)
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) -> Option<char> {
+    while chars.peek().is_some_and(|c| c.is_whitespace()) {
+        chars.next();
+    }
+    chars.peek().copied()
+}
+
+fn expect(chars: &mut Peekable<Chars>, expected: char) -> Result<(), &'static str> {
+    skip_whitespace(chars);
+    match chars.next() {
+        Some(c) if c == expected => Ok(()),
+        _ => Err("malformed JSON"),
+    }
+}
+
+fn parse_string(chars: &mut Peekable<Chars>) -> Result<String, &'static str> {
+    expect(chars, '"')?;
+    let mut string = String::new();
+    loop {
+        match chars.next().ok_or("unterminated string")? {
+            '"' => return Ok(string),
+            '\\' => match chars.next().ok_or("unterminated string")? {
+                '"' => string.push('"'),
+                '\\' => string.push('\\'),
+                '/' => string.push('/'),
+                'b' => string.push('\u{8}'),
+                'f' => string.push('\u{c}'),
+                'n' => string.push('\n'),
+                'r' => string.push('\r'),
+                't' => string.push('\t'),
+                'u' => {
+                    let mut code = parse_hex(chars)?;
+                    // Characters outside the BMP come as surrogate pairs
+                    if (0xd800..0xdc00).contains(&code) {
+                        if chars.next() != Some('\\') || chars.next() != Some('u') {
+                            return Err("bad surrogate pair");
+                        }
+                        let low = parse_hex(chars)?;
+                        if !(0xdc00..0xe000).contains(&low) {
+                            return Err("bad surrogate pair");
+                        }
+                        code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                    }
+                    string.push(char::from_u32(code).ok_or("bad escape")?);
+                },
+                _ => return Err("bad escape"),
+            },
+            c if (c as u32) < 0x20 => return Err("control character in string"),
+            c => string.push(c),
+        }
+    }
+}
+
+fn parse_hex(chars: &mut Peekable<Chars>) -> Result<u32, &'static str> {
+    let mut code = 0;
+    for _ in 0..4 {
+        code = code * 16 + chars.next().and_then(|c| c.to_digit(16)).ok_or("bad escape")?;
+    }
+    Ok(code)
+}
+
+fn parse_number(chars: &mut Peekable<Chars>) -> Result<i64, &'static str> {
+    let mut number = String::new();
+    if chars.peek() == Some(&'-') {
+        number.push('-');
+        chars.next();
+    }
+    while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+        number.push(c);
+        chars.next();
+    }
+    number.parse().map_err(|_| "expected a string, list or whole number")
+}
+
+fn parse_list(chars: &mut Peekable<Chars>) -> Result<Vec<u8>, &'static str> {
+    expect(chars, '[')?;
+    let mut values = Vec::new();
+    if skip_whitespace(chars) == Some(']') {
+        chars.next();
+        return Ok(values);
+    }
+    loop {
+        skip_whitespace(chars);
+        values.push(u8::try_from(parse_number(chars)?).map_err(|_| "number out of range")?);
+        match skip_whitespace(chars) {
+            Some(',') => { chars.next(); },
+            Some(']') => {
+                chars.next();
+                return Ok(values);
+            },
+            _ => return Err("expected , or ]"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Data that fills a layout, with text that needs escaping
+    fn sample(fields: &[Field]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for field in fields {
+            match field {
+                Byte(_) => data.push(200),
+                Word(_) => data.extend_from_slice(&1500u16.to_be_bytes()),
+                Signed(_) => data.push(-3i8 as u8),
+                Text(_) => data.extend_from_slice("a \"b\"\\\n\u{1}é🎲".as_bytes()),
+                Bytes(_) => data.extend_from_slice(&[0, 7, 255]),
+            }
+        }
+        data
+    }
+
+    fn message(control_byte: u8, data: &[u8]) -> Vec<u8> {
+        let mut message = vec![control_byte, data.len() as u8];
+        message.extend_from_slice(data);
+        message
+    }
+
+    // Data and reply from feeding `input` to the decoder, and whether it failed
+    fn receive(json: &mut JsonLines, input: &[u8]) -> (Vec<u8>, Vec<u8>, bool) {
+        let (mut data, mut reply) = (Vec::new(), Vec::new());
+        let result = json.receive(input, &mut data, &mut reply);
+        if let Err(ref e) = result {
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        }
+        (data, reply, result.is_err())
+    }
+
+    #[test]
+    fn round_trips_every_layout() {
+        let mut count = 0;
+        for control_byte in 0..=u8::MAX {
+            let fields = match Message::from_u8(control_byte) {
+                Some(message) => layout(&message).1,
+                None => continue,
+            };
+            let data = sample(fields);
+            let line = encode(control_byte, &data);
+            assert!(line.starts_with("{\"type\":") && line.ends_with("}\n"), "{}", line);
+            assert_eq!(decode(line.trim()), Ok(message(control_byte, &data)), "{}", line);
+            count += 1;
+        }
+        assert_eq!(count, 31);
+
+        // Data that does not fit the layout falls back to the control byte
+        let line = encode(Message::Welcome as u8, &[1]);
+        assert_eq!(line, "{\"control\":8,\"data\":[1]}\n");
+        assert_eq!(decode(line.trim()), Ok(vec![8, 1, 1]));
+        assert_eq!(encode(Message::ServerUserName as u8, &[0xff]), "{\"control\":9,\"data\":[255]}\n");
+        assert_eq!(encode(250, &[]), "{\"control\":250,\"data\":[]}\n");
+    }
+
+    #[test]
+    fn decodes_examples() {
+        assert_eq!(decode("{\"type\":\"ClientUserName\",\"name\":\"alice\"}"), Ok(message(0, b"alice")));
+        assert_eq!(decode(" { \"move\" : [ 3 ] , \"type\" : \"PlayerMove\" } "), Ok(vec![1, 1, 3]));
+        assert_eq!(decode("{\"type\":\"RestartGame\"}"), Ok(vec![2, 0]));
+        assert_eq!(decode("{\"control\":1,\"data\":[3]}"), Ok(vec![1, 1, 3]));
+        assert_eq!(decode("{\"control\":9}"), Ok(vec![9, 0]));
+        // Text and lists may be left out
+        assert_eq!(decode("{\"type\":\"GetStats\"}"), Ok(vec![Message::GetStats as u8, 0]));
+        assert_eq!(decode("{\"type\":\"ClientUserName\",\"name\":\"\\ud83c\\udfb2\\u00e9\"}"), Ok(message(0, "🎲é".as_bytes())));
+    }
+
+    #[test]
+    fn rejects_bad_strings() {
+        let name = |value: &str| decode(&format!("{{\"type\":\"ClientUserName\",\"name\":\"{}\"}}", value));
+        assert_eq!(name("\\ud83c"), Err("bad surrogate pair"));
+        assert_eq!(name("\\ud83cx"), Err("bad surrogate pair"));
+        assert_eq!(name("\\ud83c\\u0041"), Err("bad surrogate pair"));
+        assert_eq!(name("\\udfb2"), Err("bad escape"));
+        assert_eq!(name("\\u12"), Err("bad escape"));
+        assert_eq!(name("\\x"), Err("bad escape"));
+        assert_eq!(name("a\tb"), Err("control character in string"));
+        assert_eq!(decode("{\"type\":\"ClientUserName\",\"name\":\"alice"), Err("unterminated string"));
+    }
+
+    #[test]
+    fn rejects_out_of_range_numbers() {
+        assert_eq!(decode("{\"type\":\"Leaderboard\",\"top_n\":256}"), Err("number out of range"));
+        assert_eq!(decode("{\"type\":\"Leaderboard\",\"top_n\":-1}"), Err("number out of range"));
+        assert_eq!(decode("{\"type\":\"PlayerMove\",\"move\":[1,256]}"), Err("number out of range"));
+        assert_eq!(decode("{\"control\":300}"), Err("number out of range"));
+        let stats = |rating: &str, streak: &str| decode(&format!(
+            "{{\"type\":\"PlayerStats\",\"rating\":{},\"wins\":0,\"losses\":0,\"streak\":{},\"best_streak\":0}}", rating, streak));
+        assert_eq!(stats("65535", "-128"), Ok(vec![Message::PlayerStats as u8, 8, 255, 255, 0, 0, 0, 0, 128, 0]));
+        assert_eq!(stats("65536", "0"), Err("number out of range"));
+        assert_eq!(stats("0", "128"), Err("number out of range"));
+        assert_eq!(stats("0", "-129"), Err("number out of range"));
+        // Beyond i64, and not whole numbers at all
+        assert_eq!(stats("99999999999999999999", "0"), Err("expected a string, list or whole number"));
+        assert_eq!(stats("1.5", "0"), Err("expected , or }"));
+        // Longer than a frame can carry
+        let list = vec!["1"; 256].join(",");
+        assert_eq!(decode(&format!("{{\"control\":1,\"data\":[{}]}}", list)), Err("message too long"));
+    }
+
+    #[test]
+    fn rejects_bad_fields() {
+        assert_eq!(decode("{\"type\":\"Welcome\",\"player_id\":1,\"player_id\":2}"), Err("duplicate field"));
+        assert_eq!(decode("{\"type\":\"Welcome\",\"type\":\"Welcome\",\"player_id\":1}"), Err("duplicate field"));
+        assert_eq!(decode("{\"type\":\"Welcome\",\"player_id\":1,\"colour\":2}"), Err("unknown field"));
+        assert_eq!(decode("{\"control\":1,\"data\":[],\"name\":\"x\"}"), Err("unknown field"));
+        assert_eq!(decode("{\"type\":\"Welcome\"}"), Err("missing field"));
+        assert_eq!(decode("{\"type\":\"Welcome\",\"player_id\":\"1\"}"), Err("wrong type of value for field"));
+        assert_eq!(decode("{\"type\":\"Welcome\",\"player_id\":[1]}"), Err("wrong type of value for field"));
+        assert_eq!(decode("{\"type\":\"Hello\"}"), Err("unknown message type"));
+        assert_eq!(decode("{\"type\":5}"), Err("type must be a string"));
+        assert_eq!(decode("{\"control\":1,\"data\":\"x\"}"), Err("data must be a list of bytes"));
+        assert_eq!(decode("{}"), Err("expected a type or control byte"));
+        assert_eq!(decode("{\"control\":1} x"), Err("unexpected data after object"));
+        assert_eq!(decode("[1]"), Err("malformed JSON"));
+    }
+
+    #[test]
+    fn decodes_lines() {
+        let mut json = JsonLines::new();
+        // Messages sent before the client speaks are held until it does
+        let mut output = Vec::new();
+        json.send(&[Message::Welcome as u8, 2, 1, 4], &mut output);
+        assert!(output.is_empty());
+
+        let (data, reply, failed) = receive(&mut json, b"{\"type\":\"ClientUserName\",");
+        assert!(json.is_json() && !failed && data.is_empty());
+        assert_eq!(reply, b"{\"type\":\"Welcome\",\"player_id\":260}\n");
+        let (data, reply, failed) = receive(&mut json, b"\"name\":\"bob\"}\r\n\n{\"control\":2}\n");
+        assert!(!failed && reply.is_empty());
+        assert_eq!(data, [&message(0, b"bob")[..], &[2, 0]].concat());
+
+        // Messages are encoded once complete
+        json.send(&[Message::SetActivePlayer as u8, 2, 0], &mut output);
+        assert!(output.is_empty());
+        json.send(&[0, Message::AddPlayer as u8, 2, 0, 7], &mut output);
+        assert_eq!(output, b"{\"type\":\"SetActivePlayer\",\"player_id\":0}\n{\"type\":\"AddPlayer\",\"player_id\":7,\"name\":\"\"}\n");
+
+        let (data, reply, failed) = receive(&mut json, b"{\"type\":\"Welcome\"}\n");
+        assert!(failed && data.is_empty());
+        assert_eq!(reply, b"{\"error\":\"missing field\"}\n");
+        let (_, reply, failed) = receive(&mut JsonLines::new(), b"{\xff}\n");
+        assert!(failed);
+        assert_eq!(reply, b"{\"error\":\"line is not UTF-8\"}\n");
+    }
+
+    #[test]
+    fn rejects_overlong_lines() {
+        let mut json = JsonLines::new();
+        let mut line = b"{\"type\":\"Announcement\",\"text\":\"".to_vec();
+        line.resize(MAX_LINE, b'a');
+        let (_, _, failed) = receive(&mut json, &line);
+        assert!(!failed);
+        let (data, reply, failed) = receive(&mut json, b"a");
+        assert!(failed && data.is_empty());
+        assert_eq!(reply, b"{\"error\":\"line too long\"}\n");
+    }
+
+    #[test]
+    fn binary_clients_pass_through() {
+        let mut json = JsonLines::new();
+        let mut output = Vec::new();
+        json.send(&[5, 1, 0], &mut output);
+        let (data, reply, failed) = receive(&mut json, &[0, 3, b'b', b'o', b'b']);
+        assert!(!json.is_json() && !failed);
+        assert_eq!(data, [0, 3, b'b', b'o', b'b']);
+        assert_eq!(reply, [5, 1, 0]);
+        // Even bytes that would start a JSON line
+        let (data, _, _) = receive(&mut json, b"{\n");
+        assert_eq!(data, b"{\n");
+        json.send(&[6, 0], &mut output);
+        assert_eq!(output, [6, 0]);
+    }
+
+    #[test]
+    fn falls_back_to_binary_when_too_much_is_pending() {
+        let mut json = JsonLines::new();
+        let mut output = Vec::new();
+        let frame = message(Message::Announcement as u8, &[b'x'; 254]);
+        let held = MAX_PENDING / frame.len();
+        for _ in 0..held {
+            json.send(&frame, &mut output);
+        }
+        assert!(output.is_empty());
+
+        // The frame that would go over the limit releases everything as binary
+        json.send(&frame, &mut output);
+        assert_eq!(output, frame.repeat(held + 1));
+        assert!(!json.is_json());
+        let (data, reply, failed) = receive(&mut json, b"{\"type\":\"RestartGame\"}\n");
+        assert!(!failed && reply.is_empty());
+        assert_eq!(data, b"{\"type\":\"RestartGame\"}\n");
+    }
+}
@@ -1 +1,19 @@
 pub mod game;
+pub mod names;
+pub mod ratings;
+pub mod matchmaking;
+pub mod history;
+pub mod series;
+pub mod tournament;
+pub mod connection;
+pub mod logging;
+pub mod metrics;
+pub mod admin;
+pub mod tls;
+pub mod shard;
+pub mod lobby;
+pub mod client;
+pub mod limits;
+pub mod listener;
+pub mod websocket;
+pub mod json;
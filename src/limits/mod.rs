@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::lobby::MAX_PLAYERS;
+
+// What a single peer is allowed to do before the server pushes back
+#[derive(Clone, Debug)]
+pub struct Limits {
+    // Open connections allowed in total, at most MAX_PLAYERS
+    pub connections: usize,
+    // Open connections allowed from one address
+    pub connections_per_address: usize,
+    // Messages a connection may send per second, averaged over the burst
+    pub message_rate: f64,
+    // Messages a connection may send at once after being quiet
+    pub message_burst: f64,
+    // Time a connection has to complete its TLS handshake
+    pub handshake_timeout: Duration,
+    // Protocol violations from one address within ban_duration that get it
+    // banned; 0 never bans
+    pub max_violations: usize,
+    pub ban_duration: Duration,
+}
+
+impl Limits {
+    pub fn new() -> Limits {
+        Limits {
+            connections: MAX_PLAYERS,
+            connections_per_address: 8,
+            message_rate: 10.0,
+            message_burst: 20.0,
+            handshake_timeout: Duration::from_secs(10),
+            max_violations: 3,
+            ban_duration: Duration::from_secs(300),
+        }
+    }
+
+    pub fn is_option(flag: &str) -> bool {
+        matches!(flag, "--max-connections" | "--max-connections-per-ip" | "--message-rate" | "--message-burst" | "--handshake-timeout" | "--max-violations" | "--ban-time")
+    }
+
+    // Apply one command line option:
+    // --max-connections n
+    // --max-connections-per-ip n
+    // --message-rate messages per second
+    // --message-burst messages
+    // --handshake-timeout seconds
+    // --max-violations n
+    // --ban-time seconds
+    pub fn set_option(&mut self, flag: &str, value: &str) -> Option<()> {
+        match flag {
+            "--max-connections" => self.connections = value.parse().ok().filter(|&n| n > 0 && n <= MAX_PLAYERS)?,
+            "--max-connections-per-ip" => self.connections_per_address = value.parse().ok().filter(|&n| n > 0)?,
+            "--message-rate" => self.message_rate = value.parse().ok().filter(|&rate: &f64| rate > 0.0)?,
+            "--message-burst" => self.message_burst = value.parse().ok().filter(|&burst: &f64| burst >= 1.0)?,
+            "--handshake-timeout" => self.handshake_timeout = Duration::from_secs(value.parse().ok().filter(|&n| n > 0)?),
+            "--max-violations" => self.max_violations = value.parse().ok()?,
+            "--ban-time" => self.ban_duration = Duration::from_secs(value.parse().ok()?),
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Limits { Limits::new() }
+}
+
+// Per-connection message allowance, refilled at the message rate up to the
+// burst size
+pub struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    // Starts full
+    pub fn new(limits: &Limits, now: Instant) -> TokenBucket {
+        TokenBucket { tokens: limits.message_burst, updated: now }
+    }
+
+    // Spend a token on one message; false if there are none left
+    pub fn take(&mut self, limits: &Limits, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limits.message_rate).min(limits.message_burst);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// Why a connection was turned away
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Refusal {
+    Banned,
+    TooManyConnections,
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Refusal::Banned => write!(f, "address is banned"),
+            Refusal::TooManyConnections => write!(f, "too many connections from address"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Peer {
+    connections: usize,
+    // Times of recent protocol violations, oldest first
+    violations: Vec<Instant>,
+    banned_until: Option<Instant>,
+}
+
+// Connection counts, violations and bans by remote address
+pub struct Peers {
+    limits: Limits,
+    peers: HashMap<IpAddr, Peer>,
+}
+
+impl Peers {
+    pub fn new(limits: Limits) -> Peers {
+        Peers { limits, peers: HashMap::new() }
+    }
+
+    pub fn limits(&self) -> &Limits { &self.limits }
+
+    // Count a new connection from `address`, unless it is banned or already
+    // has as many connections as allowed
+    pub fn admit(&mut self, address: IpAddr, now: Instant) -> Result<(), Refusal> {
+        let peer = self.peers.entry(canonical(address)).or_default();
+        if peer.banned_until.is_some_and(|until| now < until) {
+            return Err(Refusal::Banned);
+        }
+        if peer.connections >= self.limits.connections_per_address {
+            return Err(Refusal::TooManyConnections);
+        }
+        peer.connections += 1;
+        Ok(())
+    }
+
+    // Forget a connection counted by admit
+    pub fn release(&mut self, address: IpAddr) {
+        if let Some(peer) = self.peers.get_mut(&canonical(address)) {
+            peer.connections = peer.connections.saturating_sub(1);
+        }
+    }
+
+    // Record a protocol violation from `address`. Returns true if this gets
+    // the address banned.
+    pub fn violation(&mut self, address: IpAddr, now: Instant) -> bool {
+        let limits = &self.limits;
+        let peer = self.peers.entry(canonical(address)).or_default();
+        peer.violations.retain(|&time| now.saturating_duration_since(time) < limits.ban_duration);
+        peer.violations.push(now);
+        if limits.max_violations == 0 || peer.violations.len() < limits.max_violations {
+            return false;
+        }
+        peer.violations.clear();
+        peer.banned_until = Some(now + limits.ban_duration);
+        true
+    }
+
+    // Drop expired bans and violations, and addresses with nothing left
+    pub fn expire(&mut self, now: Instant) {
+        let ban_duration = self.limits.ban_duration;
+        self.peers.retain(|_, peer| {
+            if peer.banned_until.is_some_and(|until| now >= until) {
+                peer.banned_until = None;
+            }
+            peer.violations.retain(|&time| now.saturating_duration_since(time) < ban_duration);
+            peer.connections > 0 || peer.banned_until.is_some() || !peer.violations.is_empty()
+        });
+    }
+}
+
+// IPv4 clients of a dual-stack listener show up as IPv4-mapped IPv6
+// addresses; count them with those connecting over IPv4
+pub fn canonical(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(address, IpAddr::V4),
+        IpAddr::V4(_) => address,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_a_burst_then_refills() {
+        let limits = Limits::new();
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(&limits, start);
+        for _ in 0..20 {
+            assert!(bucket.take(&limits, start));
+        }
+        assert!(!bucket.take(&limits, start));
+
+        // 10 messages a second: one more every 100ms
+        assert!(!bucket.take(&limits, start + Duration::from_millis(50)));
+        assert!(bucket.take(&limits, start + Duration::from_millis(100)));
+        assert!(!bucket.take(&limits, start + Duration::from_millis(100)));
+
+        // A long quiet spell refills no further than the burst
+        let later = start + Duration::from_secs(60);
+        for _ in 0..20 {
+            assert!(bucket.take(&limits, later));
+        }
+        assert!(!bucket.take(&limits, later));
+
+        // A clock that appears to go backwards adds nothing
+        assert!(!bucket.take(&limits, start));
+    }
+
+    #[test]
+    fn limits_connections_per_address() {
+        let mut limits = Limits::new();
+        limits.connections_per_address = 2;
+        let mut peers = Peers::new(limits);
+        let now = Instant::now();
+        let v4: IpAddr = [192, 0, 2, 1].into();
+        let mapped: IpAddr = "::ffff:192.0.2.1".parse().unwrap();
+        peers.admit(v4, now).unwrap();
+        peers.admit(mapped, now).unwrap();
+        assert_eq!(peers.admit(v4, now), Err(Refusal::TooManyConnections));
+        assert_eq!(peers.admit([192, 0, 2, 2].into(), now), Ok(()));
+        peers.release(mapped);
+        assert_eq!(peers.admit(v4, now), Ok(()));
+    }
+
+    #[test]
+    fn bans_expire() {
+        let limits = Limits::new();
+        let ban = limits.ban_duration;
+        let mut peers = Peers::new(limits);
+        let address: IpAddr = [192, 0, 2, 1].into();
+        let start = Instant::now();
+
+        // Violations older than the ban duration are forgotten
+        assert!(!peers.violation(address, start));
+        assert!(!peers.violation(address, start + Duration::from_secs(1)));
+        assert!(!peers.violation(address, start + ban + Duration::from_secs(1)));
+        assert!(!peers.violation(address, start + ban + Duration::from_secs(2)));
+        let now = start + ban + Duration::from_secs(3);
+        assert!(peers.violation(address, now));
+        assert_eq!(peers.admit(address, now), Err(Refusal::Banned));
+        assert_eq!(peers.admit(address, now + ban - Duration::from_secs(1)), Err(Refusal::Banned));
+
+        let expired = now + ban;
+        peers.expire(expired);
+        assert!(peers.peers.is_empty());
+        assert_eq!(peers.admit(address, expired), Ok(()));
+
+        // max_violations of 0 never bans
+        let mut limits = Limits::new();
+        limits.max_violations = 0;
+        let mut peers = Peers::new(limits);
+        for _ in 0..10 {
+            assert!(!peers.violation(address, start));
+        }
+        assert_eq!(peers.admit(address, start), Ok(()));
+    }
+}
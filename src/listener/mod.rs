@@ -0,0 +1,157 @@
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+
+use net2::TcpBuilder;
+
+// Listener used when none are given on the command line
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:9797";
+
+// Pending connections the kernel queues for each listener
+const BACKLOG: i32 = 1024;
+
+// How clients talk to a listener
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Security {
+    Tls,
+    // Unencrypted messages, for local tools; only allowed on loopback
+    // addresses
+    Plaintext,
+}
+
+impl fmt::Display for Security {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Security::Tls => write!(f, "tls"),
+            Security::Plaintext => write!(f, "plaintext"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ListenerConfig {
+    pub address: SocketAddr,
+    pub security: Security,
+    // Clients connect with a WebSocket upgrade and send messages in binary
+    // WebSocket frames, as browsers must
+    pub websocket: bool,
+    // Clients may send line-delimited JSON instead of binary messages, see
+    // json::JsonLines
+    pub json: bool,
+}
+
+impl ListenerConfig {
+    // Parse a --listen value: address:port, with IPv6 addresses in
+    // brackets, optionally followed by ",tls" (the default) or ",plaintext"
+    // and ",websocket" or ",json"
+    // e.g. 0.0.0.0:9797, [::]:9797, 127.0.0.1:9798,plaintext,websocket
+    pub fn parse(spec: &str) -> Result<ListenerConfig, String> {
+        let mut parts = spec.split(',');
+        let address: SocketAddr = parts.next().unwrap_or("").parse()
+            .map_err(|_| format!("bad listen address {:?}", spec))?;
+        let mut security = Security::Tls;
+        let mut websocket = false;
+        let mut json = false;
+        for option in parts {
+            match option {
+                "tls" => security = Security::Tls,
+                "plaintext" => security = Security::Plaintext,
+                "websocket" => websocket = true,
+                "json" => json = true,
+                _ => return Err(format!("unknown listener option {:?}", option)),
+            }
+        }
+        if security == Security::Plaintext && !address.ip().is_loopback() {
+            return Err(format!("plaintext listener {} must use a loopback address", address));
+        }
+        if websocket && json {
+            return Err(format!("listener {} cannot take both websocket and json", address));
+        }
+        Ok(ListenerConfig { address, security, websocket, json })
+    }
+
+    // How clients frame their messages, for logging: ", websocket" or ", json"
+    pub fn framing(&self) -> &'static str {
+        if self.websocket {
+            ", websocket"
+        } else if self.json {
+            ", json"
+        } else {
+            ""
+        }
+    }
+}
+
+impl Default for ListenerConfig {
+    fn default() -> ListenerConfig {
+        ListenerConfig::parse(DEFAULT_ADDRESS).unwrap()
+    }
+}
+
+// Bind every listener, non-blocking. An IPv6 listener also takes IPv4
+// connections on a wildcard address, unless an IPv4 listener shares its
+// port and would otherwise clash with it.
+pub fn bind(configs: &[ListenerConfig]) -> io::Result<Vec<TcpListener>> {
+    configs.iter().map(|config| {
+        let address = config.address;
+        let only_v6 = configs.iter().any(|other| other.address.is_ipv4() && other.address.port() == address.port());
+        bind_one(address, only_v6).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", address, e)))
+    }).collect()
+}
+
+fn bind_one(address: SocketAddr, only_v6: bool) -> io::Result<TcpListener> {
+    let builder = if address.is_ipv4() {
+        TcpBuilder::new_v4()?
+    } else {
+        let builder = TcpBuilder::new_v6()?;
+        builder.only_v6(only_v6)?;
+        builder
+    };
+    builder.reuse_address(true)?;
+    builder.bind(address)?;
+    let listener = builder.listen(BACKLOG)?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_addresses() {
+        let config = ListenerConfig::parse("0.0.0.0:9797").unwrap();
+        assert_eq!(config.address, "0.0.0.0:9797".parse().unwrap());
+        assert_eq!(config.security, Security::Tls);
+        assert!(!config.websocket && !config.json);
+        assert_eq!(config.framing(), "");
+
+        let config = ListenerConfig::parse("[::]:9797,tls,websocket").unwrap();
+        assert!(config.address.is_ipv6() && config.address.ip().is_unspecified());
+        assert_eq!(config.address.port(), 9797);
+        assert_eq!(config.framing(), ", websocket");
+
+        let config = ListenerConfig::parse("[::1]:9798,plaintext,json").unwrap();
+        assert_eq!(config.address, SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], 9798)));
+        assert_eq!(config.security, Security::Plaintext);
+        assert_eq!(config.framing(), ", json");
+
+        // The last security option wins
+        assert_eq!(ListenerConfig::parse("127.0.0.1:9798,plaintext,tls").unwrap().security, Security::Tls);
+
+        let config = ListenerConfig::default();
+        assert_eq!(config.address, DEFAULT_ADDRESS.parse().unwrap());
+        assert_eq!(config.security, Security::Tls);
+    }
+
+    #[test]
+    fn rejects_bad_specs() {
+        for spec in &["", "9797", "127.0.0.1", "localhost:9797", "::1:9797", "[::1]", "[::1]:", "127.0.0.1:70000", "127.0.0.1:9797,"] {
+            assert!(ListenerConfig::parse(spec).is_err(), "{:?}", spec);
+        }
+        assert_eq!(ListenerConfig::parse("127.0.0.1:9797,quic").unwrap_err(), "unknown listener option \"quic\"");
+        assert!(ListenerConfig::parse("0.0.0.0:9798,plaintext").is_err());
+        assert!(ListenerConfig::parse("[::]:9798,plaintext").is_err());
+        assert!(ListenerConfig::parse("127.0.0.1:9798,websocket,json").is_err());
+    }
+}
@@ -0,0 +1,1167 @@
+use std::fs;
+use std::io;
+use std::net;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, warn};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use slab::Slab;
+
+use crate::admin::Target;
+use crate::game::{self, encode_player_id, Game, GameData, Message, Outcome, COUNTING_GAME, NIM_GAME, PLAYER_ID_LENGTH};
+use crate::game::{nim, subtraction};
+use crate::game::start::{StartPolicy, StartingPlayer};
+use crate::history::GameRecorder;
+use crate::logging::{self, Context};
+use crate::matchmaking::Matchmaker;
+use crate::metrics::{Gauges, Metrics};
+use crate::names::NameRegistry;
+use crate::ratings::{self, Ratings};
+use crate::series::{self, Series};
+use crate::tournament::{self, Format, Pairing, Phase, Tournament};
+
+const MAX_LEADERBOARD: usize = 10;
+// Most players connected at once. Player ids are reused and stay below
+// this, well clear of game::NO_PLAYER.
+pub const MAX_PLAYERS: usize = 16384;
+
+// Enumeration to store client state
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClientState {
+    Connected,
+    WaitingOnOpponent,
+    // Playing against the given player id
+    GameInProgress(usize),
+}
+
+// A connected client as the lobby sees it
+pub struct Player {
+    pub player_name: String,
+    pub address: net::SocketAddr,
+    // Muted players' take back requests are not passed on to their opponent.
+    // Take backs are the only messages a player sends their opponent; the
+    // protocol has no chat to mute.
+    pub muted: bool,
+    pub state: ClientState,
+}
+
+// A game in progress along with its history file
+struct GameRoom<G: Game> {
+    // Identifies the room in log records
+    id: u64,
+    game: G,
+    recorder: Option<GameRecorder>,
+    starting_player: StartingPlayer,
+    series: Series,
+    // Tournament games end with their match and report the result
+    tournament_game: bool,
+    // When the current game's first player was chosen
+    started: Instant,
+}
+
+impl<G: Game> GameRoom<G> {
+    // Append a record to the history file, giving up on the file if it fails
+    fn record<F>(&mut self, write: F) where F: FnOnce(&mut GameRecorder) -> io::Result<()> {
+        if let Some(ref mut recorder) = self.recorder {
+            if let Err(e) = write(recorder) {
+                warn!("Unable to record game history to {:?}: {}", recorder.path(), e);
+                self.recorder = None;
+            }
+        }
+    }
+
+    // Pick who moves first in the next game and tell the players
+    fn choose_starting_player(&mut self, previous: Option<&Outcome>, message_queue: &mut Vec::<(usize, Vec::<u8>)>) {
+        let player_ids = self.game.player_ids().to_vec();
+        let starting_player_id = self.starting_player.choose(&player_ids, previous);
+        self.started = Instant::now();
+        self.game.set_current_player(starting_player_id);
+        let active_player_id = self.game.current_player().unwrap_or(starting_player_id);
+        self.record(|recorder| recorder.record_active_player(active_player_id));
+
+        // Send Coin_Flip message to all players
+        // data[0..2]: player_id who won the toss
+        if self.starting_player.policy().is_announced() {
+            let coin_flip_message = player_message(Message::CoinFlip, active_player_id, &[]);
+            for player_id in player_ids.iter() {
+                message_queue.push((*player_id, coin_flip_message.clone()));
+            }
+        }
+
+        // Send Set_Active_Player message to all players
+        // data[0..2]: player_id
+        let set_active_player_message = player_message(Message::SetActivePlayer, active_player_id, &[]);
+        for player_id in player_ids.iter() {
+            message_queue.push((*player_id, set_active_player_message.clone()));
+        }
+    }
+
+    // Send Match_Score message to all players
+    // data[..]: series score, see series::encode_score
+    fn announce_score(&self, message_queue: &mut Vec::<(usize, Vec::<u8>)>) {
+        let score = series::encode_score(&self.series);
+        let mut match_score_message: Vec<u8> = [Message::MatchScore as u8, score.len() as u8].to_vec();
+        match_score_message.extend_from_slice(&score);
+        for player_id in self.game.player_ids().iter() {
+            message_queue.push((*player_id, match_score_message.clone()));
+        }
+    }
+
+    // Start the next game of an unfinished match without waiting for the
+    // players to ask
+    fn continue_series(&mut self, previous: &Outcome, message_queue: &mut Vec::<(usize, Vec::<u8>)>) {
+        let player_ids = self.game.player_ids().to_vec();
+        for player_id in player_ids.iter() {
+            self.game.request_restart(*player_id);
+
+            // Send Add_Player message to all players, as if each asked to restart
+            // data[0..2]: player_id
+            let add_player_message = player_message(Message::AddPlayer, *player_id, &[]);
+            for recipient_id in player_ids.iter() {
+                message_queue.push((*recipient_id, add_player_message.clone()));
+            }
+        }
+        self.record(|recorder| recorder.record_restart());
+        self.choose_starting_player(Some(previous), message_queue);
+    }
+}
+
+// Server-wide state shared by every connection
+struct ServerState<G: Game> {
+    // Builds the game played in each new room
+    new_game: Box<dyn Fn() -> G>,
+    games: Vec<GameRoom<G>>,
+    next_game_id: u64,
+    history_directory: PathBuf,
+    names: NameRegistry,
+    ratings: Ratings,
+    matchmaker: Matchmaker,
+    start_policy: StartPolicy,
+    // Policy for tournament rooms, if they differ from the rest
+    tournament_start_policy: Option<StartPolicy>,
+    // Games in each match; 1 plays single games
+    best_of: u8,
+    // Most recent tournament, kept after it finishes until the next opens
+    tournament: Option<Tournament>,
+    tournament_format: Format,
+    registration: Duration,
+    // Seeds each room's random number generator
+    rng: StdRng,
+    metrics: Metrics,
+}
+
+impl<G: Game> ServerState<G> {
+    // Room the player is in, if any
+    fn game_id(&self, player_id: usize) -> Option<u64> {
+        self.games.iter().find(|room| room.game.has_player(player_id)).map(|room| room.id)
+    }
+}
+
+// Connected players and the games between them, apart from how their
+// connections are carried. Messages for players are queued until the
+// server collects them with take_messages.
+pub struct Lobby {
+    players: Slab<Player>,
+    server: ServerState<Box<dyn Game>>,
+    message_queue: Vec<(usize, Vec<u8>)>,
+}
+
+impl Lobby {
+    pub fn new(settings: &Settings, history_directory: PathBuf, ratings: Ratings, rng: StdRng) -> Lobby {
+        Lobby {
+            players: Slab::new(),
+            server: ServerState {
+                new_game: settings.new_game(),
+                games: Vec::new(),
+                next_game_id: 1,
+                history_directory,
+                names: NameRegistry::new(),
+                ratings,
+                matchmaker: Matchmaker::new(),
+                start_policy: settings.start_policy,
+                tournament_start_policy: settings.tournament_start_policy,
+                best_of: settings.best_of,
+                tournament: None,
+                tournament_format: settings.tournament_format,
+                registration: settings.registration,
+                rng,
+                metrics: Metrics::new(),
+            },
+            message_queue: Vec::new(),
+        }
+    }
+
+    // Use new settings for rooms opened from now on
+    pub fn apply_settings(&mut self, settings: &Settings) {
+        self.server.new_game = settings.new_game();
+        self.server.start_policy = settings.start_policy;
+        self.server.tournament_start_policy = settings.tournament_start_policy;
+        self.server.best_of = settings.best_of;
+        self.server.tournament_format = settings.tournament_format;
+        self.server.registration = settings.registration;
+    }
+
+    // Add a newly connected client and welcome it, returning its player id.
+    // Ids are reused once a player disconnects, so they stay below
+    // MAX_PLAYERS as long as the server turns away clients beyond it.
+    pub fn connect(&mut self, address: net::SocketAddr) -> usize {
+        debug_assert!(self.players.len() < MAX_PLAYERS);
+        let player_id = self.players.insert(Player {
+            player_name: String::from(""),
+            address,
+            muted: false,
+            state: ClientState::Connected,
+        });
+        // Send a Welcome message
+        // data[0..2]: player_id
+        let welcome_message = player_message(Message::Welcome, player_id, &[]);
+        self.message_queue.push((player_id, welcome_message));
+        player_id
+    }
+
+    // Drop a player and everything held for it, ending its game
+    pub fn disconnect(&mut self, player_id: usize) {
+        disconnect_client(player_id, &mut self.players, &mut self.server, &mut self.message_queue);
+    }
+
+    // Act on one message from a player
+    // Returns false if the message breaks the protocol
+    pub fn receive(&mut self, player_id: usize, control_byte: u8, data: &[u8]) -> bool {
+        process_client_data(control_byte, data, player_id, &mut self.players, &mut self.server, &mut self.message_queue)
+    }
+
+    // Messages queued for players since the last call
+    pub fn take_messages(&mut self) -> Vec<(usize, Vec<u8>)> {
+        std::mem::take(&mut self.message_queue)
+    }
+
+    pub fn len(&self) -> usize { self.players.len() }
+
+    pub fn is_empty(&self) -> bool { self.players.is_empty() }
+
+    pub fn player(&self, player_id: usize) -> Option<&Player> { self.players.get(player_id) }
+
+    pub fn players(&self) -> slab::Iter<'_, Player> { self.players.iter() }
+
+    // Room the player is in, if any
+    pub fn game_id(&self, player_id: usize) -> Option<u64> { self.server.game_id(player_id) }
+
+    pub fn metrics(&mut self) -> &mut Metrics { &mut self.server.metrics }
+
+    pub fn render_metrics(&self) -> String {
+        self.server.metrics.render(&Gauges {
+            connections: self.players.len(),
+            active_games: self.server.games.len(),
+            queued_players: self.server.matchmaker.len(),
+        })
+    }
+
+    // Whether update has work to do as time passes: widening the rating
+    // windows of queued players or closing tournament registration
+    pub fn is_waiting(&self) -> bool {
+        let registration_open = self.server.tournament.as_ref().is_some_and(|tournament| tournament.phase() == Phase::Registration);
+        !self.server.matchmaker.is_empty() || registration_open
+    }
+
+    // Pair up waiting players and move any tournament along. Call after
+    // handling messages and at least once a second while is_waiting.
+    pub fn update(&mut self, now: Instant) {
+        let players = &mut self.players;
+        let server = &mut self.server;
+        let message_queue = &mut self.message_queue;
+
+        // Queue any clients waiting for an opponent with the matchmaker.
+        // Rematches are requested with Restart_Game, so players re-entering
+        // the queue are looking for a new opponent.
+        for (check_id, check_player) in players.iter() {
+            if let ClientState::WaitingOnOpponent = check_player.state {
+                // Tournament entrants wait for their next pairing instead
+                if server.tournament.as_ref().is_some_and(|tournament| tournament.is_playing(check_id)) {
+                    continue;
+                }
+                let rating = server.ratings.rating(&check_player.player_name);
+                server.matchmaker.enqueue(check_id, rating, now);
+            }
+        }
+
+        // Start a game for every pair of clients the matchmaker can match up
+        while let Some((partner1, partner2)) = server.matchmaker.next_pair(now) {
+            start_game(partner1, partner2, false, players, server, message_queue);
+        }
+
+        // Close tournament registration when it is due and start any
+        // tournament games whose players are free
+        let previous_round = server.tournament.as_ref().map_or(0, |tournament| tournament.round());
+        if server.tournament.as_mut().is_some_and(|tournament| tournament.update(now)) {
+            tournament_progress(previous_round, Vec::new(), server, message_queue);
+        }
+        let unstarted_games = server.tournament.as_ref().map_or(Vec::new(), |tournament| tournament.unstarted_games());
+        for (partner1, partner2) in unstarted_games {
+            let is_free = |player_id: usize| {
+                players.get(player_id).is_some_and(|player| matches!(player.state, ClientState::WaitingOnOpponent))
+            };
+            if is_free(partner1) && is_free(partner2) {
+                server.matchmaker.remove(partner1);
+                server.matchmaker.remove(partner2);
+                start_game(partner1, partner2, true, players, server, message_queue);
+                if let Some(ref mut tournament) = server.tournament {
+                    tournament.mark_started(partner1, partner2);
+                }
+            }
+        }
+    }
+
+    // Player named in an admin command
+    pub fn find_player(&self, target: &Target) -> Option<usize> {
+        find_player(&self.players, target)
+    }
+
+    // Returns false if there is no such player
+    pub fn set_muted(&mut self, player_id: usize, muted: bool) -> bool {
+        self.players.get_mut(player_id).map(|player| player.muted = muted).is_some()
+    }
+
+    // Close a room and send its players back to the lobby. Returns false if
+    // there is no such game.
+    pub fn end_game(&mut self, game_id: u64) -> bool {
+        end_game(game_id, &mut self.players, &mut self.server, &mut self.message_queue)
+    }
+
+    // Change how a room picks the starting player, from its next game on
+    pub fn set_start_policy(&mut self, game_id: u64, policy: StartPolicy) -> bool {
+        match self.server.games.iter_mut().find(|room| room.id == game_id) {
+            Some(room) => {
+                room.starting_player.set_policy(policy);
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Send an announcement to every named player, returning how many
+    pub fn announce(&mut self, text: &str) -> usize {
+        // Send Announcement message
+        // data[..]: announcement text, UTF-8
+        let mut length = text.len().min(u8::MAX as usize);
+        while !text.is_char_boundary(length) {
+            length -= 1;
+        }
+        let mut announcement_message: Vec<u8> = [Message::Announcement as u8, length as u8].to_vec();
+        announcement_message.extend_from_slice(&text.as_bytes()[..length]);
+        let mut recipients = 0;
+        for (player_id, player) in self.players.iter() {
+            if let ClientState::Connected = player.state {
+                continue;
+            }
+            self.message_queue.push((player_id, announcement_message.clone()));
+            recipients += 1;
+        }
+        recipients
+    }
+
+    // One line per game room for the admin console
+    pub fn describe_games(&self) -> Vec<String> {
+        self.server.games.iter().map(|room| {
+            let players: Vec<String> = room.game.player_ids().iter()
+                .map(|&player_id| format!("{} ({})", room.game.player_name(player_id).unwrap_or("?"), player_id))
+                .collect();
+            let mut line = format!("{} {}: {}", room.id, room.game.name(), players.join(" vs "));
+            line.push_str(if room.game.is_finished() { ", finished" } else { ", in progress" });
+            if room.series.best_of() > 1 {
+                let wins: Vec<String> = room.series.player_ids().iter().map(|&player_id| room.series.wins(player_id).to_string()).collect();
+                line.push_str(&format!(", match {} of best of {}", wins.join("-"), room.series.best_of()));
+            }
+            if room.tournament_game {
+                line.push_str(", tournament");
+            }
+            line.push_str(&format!(", {} starts", room.starting_player.policy().name()));
+            line
+        }).collect()
+    }
+}
+
+// Drop a client and everything the server holds for it, ending its game
+fn disconnect_client<G: Game>(
+        token: usize,
+        players: &mut Slab<Player>,
+        server: &mut ServerState<G>,
+        message_queue: &mut Vec::<(usize, Vec::<u8>)>) {
+    let player = match players.get(token) {
+        Some(player) => player,
+        None => return,
+    };
+    let _scope = logging::scope(Context::connection(token).with_game(server.game_id(token)));
+
+    // Clean up state of partner if necessary
+    if let ClientState::GameInProgress(partner_token) = player.state {
+        if let Some(partner) = players.get_mut(partner_token) {
+            debug!("Client disconnected, update partner {}", partner_token);
+            let disconnect_message: Vec<u8> = [Message::OpponentDisconnect as u8, 0].to_vec();
+            message_queue.push((partner_token, disconnect_message));
+            partner.state = ClientState::WaitingOnOpponent;
+        }
+    }
+
+    // Socket is closed
+    info!("Connection closed");
+    let gd = players.remove(token);
+    server.names.release(&gd.player_name);
+    server.matchmaker.forget(token);
+    leave_tournament(token, server, message_queue);
+
+    // Remove/Update GameData
+    server.games.retain(|room| {
+        !room.game.has_player(token)
+    });
+}
+
+fn find_player(players: &Slab<Player>, target: &Target) -> Option<usize> {
+    match *target {
+        Target::Id(player_id) => Some(player_id).filter(|&player_id| players.contains(player_id)),
+        Target::Name(ref name) => players.iter()
+            .find(|(_, player)| player.player_name.eq_ignore_ascii_case(name))
+            .map(|(player_id, _)| player_id),
+    }
+}
+
+// Close a room and send its players back to the lobby. Tournament games
+// ended this way count as drawn.
+fn end_game<G: Game>(
+        game_id: u64,
+        players: &mut Slab<Player>,
+        server: &mut ServerState<G>,
+        message_queue: &mut Vec::<(usize, Vec::<u8>)>) -> bool {
+    let player_ids = match close_room(game_id, None, server, message_queue) {
+        Some(player_ids) => player_ids,
+        None => return false,
+    };
+    for player_id in player_ids.iter() {
+        if let Some(player) = players.get_mut(*player_id) {
+            player.state = ClientState::WaitingOnOpponent;
+        }
+    }
+    true
+}
+
+// Remove a room and tell its players the game is over, returning their ids.
+// An unfinished tournament match is forfeited by `leaver` if given and
+// drawn otherwise. Players' states are left for the caller to reset.
+fn close_room<G: Game>(
+        game_id: u64,
+        leaver: Option<usize>,
+        server: &mut ServerState<G>,
+        message_queue: &mut Vec::<(usize, Vec::<u8>)>) -> Option<Vec<usize>> {
+    let index = server.games.iter().position(|room| room.id == game_id)?;
+    let room = server.games.remove(index);
+    let player_ids = room.game.player_ids().to_vec();
+
+    // Send Opponent_Disconnect messages to the players to end the game
+    let disconnect_message: Vec::<u8> = [Message::OpponentDisconnect as u8, 0].to_vec();
+    for player_id in player_ids.iter() {
+        message_queue.push((*player_id, disconnect_message.clone()));
+    }
+
+    if room.tournament_game && player_ids.len() == 2 {
+        let winner = leaver.and_then(|leaver| player_ids.iter().copied().find(|&player_id| player_id != leaver));
+        let previous_round = server.tournament.as_ref().map_or(0, |tournament| tournament.round());
+        let decided = server.tournament.as_mut().map_or(Vec::new(), |tournament| {
+            tournament.record_result(player_ids[0], player_ids[1], winner)
+        });
+        tournament_progress(previous_round, decided, server, message_queue);
+    }
+    Some(player_ids)
+}
+
+// Message whose data is a player id followed by `rest`
+fn player_message(message: Message, player_id: usize, rest: &[u8]) -> Vec<u8> {
+    let mut data: Vec<u8> = [message as u8, (PLAYER_ID_LENGTH + rest.len()) as u8].to_vec();
+    data.extend_from_slice(&encode_player_id(player_id));
+    data.extend_from_slice(rest);
+    data
+}
+
+fn start_game<G: Game>(
+        partner1_token: usize,
+        partner2_token: usize,
+        tournament_game: bool,
+        players: &mut Slab<Player>,
+        server: &mut ServerState<G>,
+        message_queue: &mut Vec::<(usize, Vec::<u8>)>) {
+
+    // Have two clients to match up in a game
+    let partner1_name = players.get(partner1_token).unwrap().player_name.to_string();
+    let partner2_name = players.get(partner2_token).unwrap().player_name.to_string();
+    let game_id = server.next_game_id;
+    server.next_game_id += 1;
+    let _scope = logging::scope(Context::game(game_id));
+    info!("Starting new game: {} vs {}", partner1_name, partner2_name);
+    server.metrics.game_started();
+
+    // Update client status
+    players.get_mut(partner1_token).unwrap().state = ClientState::GameInProgress(partner2_token);
+    players.get_mut(partner2_token).unwrap().state = ClientState::GameInProgress(partner1_token);
+
+    // Build a new Game object
+    let mut game = (server.new_game)();
+    game.add_player(partner1_token, &partner1_name);
+    game.add_player(partner2_token, &partner2_name);
+
+    // Send GameData message to clients:
+    // data[0]: game type
+    // data[1..]: rules (counting game: max_players, max_move, game_board_size)
+    let rules = game.rules();
+    let mut game_data_message: Vec<u8> = [Message::GameData as u8, rules.len() as u8 + 1, game.game_type()].to_vec();
+    game_data_message.extend_from_slice(&rules);
+    message_queue.push((partner1_token, game_data_message.clone()));
+    message_queue.push((partner2_token, game_data_message));
+
+    // Send Add_Player messages to both clients
+    // data[0..2]: player_id
+    // data[2..]: player name
+    let add_player1_message = player_message(Message::AddPlayer, partner1_token, partner1_name.as_bytes());
+    let add_player2_message = player_message(Message::AddPlayer, partner2_token, partner2_name.as_bytes());
+
+    message_queue.push((partner1_token, add_player1_message.clone()));
+    message_queue.push((partner2_token, add_player1_message));
+
+    message_queue.push((partner1_token, add_player2_message.clone()));
+    message_queue.push((partner2_token, add_player2_message));
+
+    // Start the game's history file
+    let recorder = GameRecorder::create(&server.history_directory, &game).and_then(|mut recorder| {
+        recorder.record_player(partner1_token, &partner1_name)?;
+        recorder.record_player(partner2_token, &partner2_name)?;
+        Ok(recorder)
+    });
+    let recorder = match recorder {
+        Ok(recorder) => Some(recorder),
+        Err(e) => {
+            warn!("Unable to record game history: {}", e);
+            None
+        },
+    };
+
+    let start_policy = match server.tournament_start_policy {
+        Some(policy) if tournament_game => policy,
+        _ => server.start_policy,
+    };
+    let starting_player = StartingPlayer::new(start_policy, StdRng::seed_from_u64(server.rng.gen()));
+    let series = Series::new(server.best_of, game.player_ids());
+    let mut room = GameRoom { id: game_id, game, recorder, starting_player, series, tournament_game, started: Instant::now() };
+    if server.best_of > 1 {
+        room.announce_score(message_queue);
+    }
+    room.choose_starting_player(None, message_queue);
+
+    // Add the Game object to the global store
+    server.games.push(room);
+}
+
+fn process_client_data<G: Game>(
+        control_byte: u8,
+        data: &[u8],
+        token: usize,
+        players: &mut Slab<Player>,
+        server: &mut ServerState<G>,
+        message_queue: &mut Vec::<(usize, Vec::<u8>)>) -> bool {
+    let player = match players.get_mut(token) {
+        Some(player) => player,
+        None => return true,
+    };
+
+    debug!("Processing [control_byte: {}; data_len: {}; data: {:?}]", control_byte, data.len(), data);
+    match control_byte {
+
+        // 0: User_Name Message
+        // control_byte: 0
+        // data_len: n
+        // data[..] = user_name
+        // Only process when client is in Connected state
+        0 => if let ClientState::Connected = player.state {
+            // Got a name from the client, validate it and ensure
+            // it is not already in use
+            match server.names.register(data) {
+                Ok(v) => {
+                    info!("Got client name {}, now WaitingOnOpponent", v);
+                    // Update client status to WaitingOnOpponent
+                    player.state = ClientState::WaitingOnOpponent;
+                    player.player_name = v.clone();
+
+                    // Send normalized user name back to client
+                    let mut user_name_message: Vec<u8> = [Message::ServerUserName as u8].to_vec();
+                    user_name_message.push(v.len() as u8);
+                    user_name_message.extend_from_slice(v.as_bytes());
+                    message_queue.push((token, user_name_message));
+                },
+                Err(rejection) => {
+                    info!("Rejected client name {:?}: {}", data, rejection.description());
+                    // Send User_Name_Rejected message
+                    // data[0]: rejection reason
+                    let rejected_message: Vec<u8> = [Message::UserNameRejected as u8, 1, rejection as u8].to_vec();
+                    message_queue.push((token, rejected_message));
+                },
+            }
+        },
+
+        // 1: Player_Move message
+        // control_byte: 1
+        // data_len: n
+        // data[..]: player_move, in the game's move layout
+        // Do nothing for any state other than GameInProgress
+        1 => if let ClientState::GameInProgress(partner_token) = player.state {
+            // Get the game data
+            if let Some(room) = server.games.iter_mut().find(|room| room.game.has_player(token)) {
+                // make the player move
+                if let Err(e) = room.game.apply_move(token, data) {
+                    info!("Rejected move {:?}: {:?}", data, e);
+                    return true;
+                }
+                room.record(|recorder| recorder.record_move(token, data));
+
+                // Record the result if this move finished the game
+                if room.game.is_finished() {
+                    server.metrics.game_finished(room.started.elapsed());
+                }
+                match room.game.outcome() {
+                    Outcome::Decided { winners, losers } => {
+                        for loser_id in losers.iter() {
+                            let loser = room.game.player_name(*loser_id).unwrap().to_string();
+                            for winner_id in winners.iter() {
+                                let winner = room.game.player_name(*winner_id).unwrap();
+                                server.ratings.record_game(winner, &loser);
+                            }
+                            room.record(|recorder| recorder.record_game_over(*loser_id));
+                        }
+                        if let Err(e) = server.ratings.save() {
+                            error!("Unable to save ratings: {}", e);
+                        }
+                    },
+                    Outcome::Draw => room.record(|recorder| recorder.record_draw()),
+                    Outcome::InProgress => (),
+                }
+
+                // Send Move_Player message
+                // data[0..2]: player_id
+                // data[2..]: player_move
+                let move_player_message = player_message(Message::MovePlayer, token, data);
+
+                message_queue.push((partner_token, move_player_message.clone()));
+                message_queue.push((token, move_player_message));
+
+                // Score the match and play on until one side clinches it
+                let outcome = room.game.outcome();
+                if outcome != Outcome::InProgress && (room.series.best_of() > 1 || room.tournament_game) {
+                    room.series.record(&outcome);
+                    if room.series.best_of() > 1 {
+                        room.announce_score(message_queue);
+                    }
+
+                    // A tournament game is over once its match is decided;
+                    // round robin games may also be drawn
+                    let round_robin = server.tournament.as_ref().is_some_and(|tournament| tournament.format() == Format::RoundRobin);
+                    let result = match (room.series.winner(), &outcome) {
+                        (Some(winner), _) => Some(Some(winner)),
+                        (None, Outcome::Draw) if round_robin => Some(None),
+                        _ => None,
+                    };
+                    match result {
+                        Some(winner) if room.tournament_game => {
+                            // Send Opponent_Disconnect messages to both clients to end the game
+                            let disconnect_message: Vec::<u8> = [Message::OpponentDisconnect as u8, 0].to_vec();
+                            message_queue.push((partner_token, disconnect_message.clone()));
+                            message_queue.push((token, disconnect_message));
+                            player.state = ClientState::WaitingOnOpponent;
+                            if let Some(partner) = players.get_mut(partner_token) {
+                                partner.state = ClientState::WaitingOnOpponent;
+                            }
+                            server.games.retain(|room| !room.game.has_player(token));
+
+                            let previous_round = server.tournament.as_ref().map_or(0, |tournament| tournament.round());
+                            let decided = server.tournament.as_mut().map_or(Vec::new(), |tournament| {
+                                tournament.record_result(token, partner_token, winner)
+                            });
+                            tournament_progress(previous_round, decided, server, message_queue);
+                        },
+                        _ if !room.series.is_over() => room.continue_series(&outcome, message_queue),
+                        _ => (),
+                    }
+                }
+            }
+        },
+
+        // 2: Restart_Game message
+        // control_byte: 2
+        // data_len: 0
+        // Do nothing for any state other than GameInProgress
+        2 => if let ClientState::GameInProgress(partner_token) = player.state {
+            // Get the game data; ensure game is over
+            if let Some(room) = server.games.iter_mut().find(|room| room.game.has_player(token)) {
+                if room.game.is_finished() {
+                    // Restart request
+                    let previous = room.game.outcome();
+                    room.game.request_restart(token);
+
+                    // Send Add_Player messages to both clients
+                    // data[0..2]: player_id
+                    let add_player_message = player_message(Message::AddPlayer, token, &[]);
+
+                    message_queue.push((partner_token, add_player_message.clone()));
+                    message_queue.push((token, add_player_message));
+
+                    if !room.game.is_finished() {
+                        room.record(|recorder| recorder.record_restart());
+                        // Playing again after a match starts a new match
+                        if room.series.best_of() > 1 {
+                            room.series.reset();
+                            room.announce_score(message_queue);
+                        }
+                        room.choose_starting_player(Some(&previous), message_queue);
+                    }
+                }
+            }
+        },
+
+        // 3: End_Game message
+        // control_byte: 3
+        // data_len: 0
+        // Do nothing for any state other than GameInProgress. Leaving an
+        // unfinished tournament match forfeits it.
+        3 => if let ClientState::GameInProgress(_) = player.state {
+            if let Some(game_id) = server.game_id(token) {
+                // Update both clients' status to WaitingOnOpponent
+                for player_id in close_room(game_id, Some(token), server, message_queue).unwrap_or_default() {
+                    if let Some(player) = players.get_mut(player_id) {
+                        player.state = ClientState::WaitingOnOpponent;
+                    }
+                }
+            }
+        },
+
+        // 16: Take_Back message
+        // control_byte: 16
+        // data_len: 0
+        // Requests, or agrees to, taking back the last move. The move is
+        // undone once every player in the game has sent Take_Back.
+        16 => if let ClientState::GameInProgress(partner_token) = player.state {
+            if let Some(room) = server.games.iter_mut().find(|room| room.game.has_player(token)) {
+                let first_request = !room.game.is_take_back_pending();
+                if first_request && player.muted {
+                    debug!("Ignoring take back request from muted player");
+                    return true;
+                }
+                if room.game.approve_take_back(token) {
+                    if let Some((player_id, player_move)) = room.game.undo_last_move() {
+                        room.record(|recorder| recorder.record_undo());
+
+                        // Send Move_Taken_Back message to both clients
+                        // data[0..2]: player_id
+                        // data[2..]: player_move
+                        let taken_back_message = player_message(Message::MoveTakenBack, player_id, &player_move);
+                        message_queue.push((partner_token, taken_back_message.clone()));
+                        message_queue.push((token, taken_back_message));
+                    }
+                } else if first_request && room.game.is_take_back_pending() {
+                    // Send Take_Back_Requested message to both clients
+                    // data[0..2]: requesting player_id
+                    let requested_message = player_message(Message::TakeBackRequested, token, &[]);
+                    message_queue.push((partner_token, requested_message.clone()));
+                    message_queue.push((token, requested_message));
+                }
+            }
+        },
+
+        // 17: Decline_Take_Back message
+        // control_byte: 17
+        // data_len: 0
+        17 => if let ClientState::GameInProgress(partner_token) = player.state {
+            if let Some(room) = server.games.iter_mut().find(|room| room.game.has_player(token)) {
+                if room.game.is_take_back_pending() {
+                    room.game.cancel_take_back();
+
+                    // Send Take_Back_Declined message to both clients
+                    // data[0..2]: declining player_id
+                    let declined_message = player_message(Message::TakeBackDeclined, token, &[]);
+                    message_queue.push((partner_token, declined_message.clone()));
+                    message_queue.push((token, declined_message));
+                }
+            }
+        },
+
+        // 11: Get_Stats message
+        // control_byte: 11
+        // data_len: n
+        // data[..] = player name; empty for the requesting player
+        11 => {
+            let name = if data.is_empty() {
+                player.player_name.clone()
+            } else {
+                String::from_utf8_lossy(data).to_string()
+            };
+
+            match server.ratings.stats(&name) {
+                Some(stats) => {
+                    // Send Player_Stats message
+                    let stats_data = ratings::encode_stats(stats);
+                    let mut stats_message: Vec<u8> = [Message::PlayerStats as u8].to_vec();
+                    stats_message.push(stats_data.len() as u8);
+                    stats_message.extend_from_slice(&stats_data);
+                    message_queue.push((token, stats_message));
+                },
+                None => {
+                    // Send Unknown_Player message
+                    let name = &name.as_bytes()[..name.len().min(u8::MAX as usize)];
+                    let mut unknown_message: Vec<u8> = [Message::UnknownPlayer as u8].to_vec();
+                    unknown_message.push(name.len() as u8);
+                    unknown_message.extend_from_slice(name);
+                    message_queue.push((token, unknown_message));
+                },
+            }
+        },
+
+        // 12: Leaderboard message
+        // control_byte: 12
+        // data_len: 1
+        // data[0] = top_n
+        12 => {
+            let top_n = data.first().map_or(MAX_LEADERBOARD, |&n| (n as usize).min(MAX_LEADERBOARD));
+
+            // Send one Leaderboard_Entry message per player
+            // data[0]: rank (1 based)
+            // data[1..]: Player_Stats payload
+            for (rank, stats) in server.ratings.leaderboard(top_n).iter().enumerate() {
+                let stats_data = ratings::encode_stats(stats);
+                let mut entry_message: Vec<u8> = [Message::LeaderboardEntry as u8].to_vec();
+                entry_message.push(stats_data.len() as u8 + 1);
+                entry_message.push(rank as u8 + 1);
+                entry_message.extend_from_slice(&stats_data);
+                message_queue.push((token, entry_message));
+            }
+        },
+
+        // 23: Join_Tournament message
+        // control_byte: 23
+        // data_len: 0
+        // Opens a tournament if none is taking entries. Accepted once the
+        // client has a name; players in a game are paired when it ends.
+        23 => if !matches!(player.state, ClientState::Connected) {
+            let now = Instant::now();
+            if server.tournament.as_ref().is_none_or(|tournament| tournament.is_over()) {
+                info!("Opening tournament registration for {:?}", server.registration);
+                server.tournament = Some(Tournament::new(server.tournament_format, server.registration, now));
+            }
+            let rating = server.ratings.rating(&player.player_name);
+            let tournament = server.tournament.as_mut().unwrap();
+            if tournament.register(token, &player.player_name, rating) {
+                server.matchmaker.remove(token);
+                broadcast_tournament_status(tournament, now, message_queue);
+            } else {
+                send_tournament_status(tournament, now, token, message_queue);
+            }
+        },
+
+        // 24: Leave_Tournament message
+        // control_byte: 24
+        // data_len: 0
+        24 => {
+            leave_tournament(token, server, message_queue);
+        },
+
+        // Unknown control byte; do nothing?
+        unknown => {
+            warn!("Unknown control byte: {}", unknown);
+            server.metrics.protocol_error();
+            return false;
+        }
+    }
+    true
+}
+
+// Send Tournament_Status message to one client
+// data[..]: see tournament::encode_status
+fn send_tournament_status(tournament: &Tournament, now: Instant, player_id: usize, message_queue: &mut Vec::<(usize, Vec::<u8>)>) {
+    let status = tournament::encode_status(tournament, now);
+    let mut status_message: Vec<u8> = [Message::TournamentStatus as u8, status.len() as u8].to_vec();
+    status_message.extend_from_slice(&status);
+    message_queue.push((player_id, status_message));
+}
+
+// Send Tournament_Status message to every entrant still taking part
+fn broadcast_tournament_status(tournament: &Tournament, now: Instant, message_queue: &mut Vec::<(usize, Vec::<u8>)>) {
+    for entrant in tournament.entrants().iter().filter(|entrant| !entrant.withdrawn) {
+        send_tournament_status(tournament, now, entrant.player_id, message_queue);
+    }
+}
+
+// Withdraw a player from the tournament, forfeiting any game in progress
+fn leave_tournament<G: Game>(player_id: usize, server: &mut ServerState<G>, message_queue: &mut Vec::<(usize, Vec::<u8>)>) {
+    let tournament = match server.tournament {
+        Some(ref mut tournament) if !tournament.is_over() && tournament.entrant(player_id).is_some() => tournament,
+        _ => return,
+    };
+    info!("Player {} left the tournament", player_id);
+    let previous_round = tournament.round();
+    let decided = tournament.withdraw(player_id);
+    if tournament.phase() == Phase::Registration {
+        broadcast_tournament_status(tournament, Instant::now(), message_queue);
+    } else {
+        tournament_progress(previous_round, decided, server, message_queue);
+    }
+}
+
+// Tell entrants about decided games, a new round and the final standings
+fn tournament_progress<G: Game>(
+        previous_round: u8,
+        decided: Vec<Pairing>,
+        server: &mut ServerState<G>,
+        message_queue: &mut Vec::<(usize, Vec::<u8>)>) {
+    let tournament = match server.tournament {
+        Some(ref tournament) => tournament,
+        None => return,
+    };
+    let entrant_ids: Vec<usize> = tournament.entrants().iter()
+        .filter(|entrant| !entrant.withdrawn)
+        .map(|entrant| entrant.player_id)
+        .collect();
+
+    // Send Tournament_Result message to every entrant
+    // data[..]: see tournament::encode_result
+    for pairing in decided.iter() {
+        if let Some(result) = tournament::encode_result(tournament, pairing) {
+            let mut result_message: Vec<u8> = [Message::TournamentResult as u8, result.len() as u8].to_vec();
+            result_message.extend_from_slice(&result);
+            for entrant_id in entrant_ids.iter() {
+                message_queue.push((*entrant_id, result_message.clone()));
+            }
+        }
+    }
+
+    if tournament.round() != previous_round || tournament.is_over() {
+        broadcast_tournament_status(tournament, Instant::now(), message_queue);
+    }
+
+    // Send Tournament_Pairing message to each player in a new round
+    // data[0]: round
+    // data[1..]: opponent name; empty for a bye
+    if tournament.round() != previous_round && !tournament.is_over() {
+        // Forfeits against withdrawn players were reported as results
+        for pairing in tournament.current_pairings().into_iter().filter(|pairing| pairing.second.is_none() || pairing.result.is_none()) {
+            for player_id in [Some(pairing.first), pairing.second].iter().flatten() {
+                let opponent_name = pairing.opponent(*player_id)
+                    .and_then(|opponent| tournament.entrant(opponent))
+                    .map_or("", |opponent| opponent.name.as_str());
+                let mut pairing_message: Vec<u8> = [Message::TournamentPairing as u8, opponent_name.len() as u8 + 1, pairing.round].to_vec();
+                pairing_message.extend_from_slice(opponent_name.as_bytes());
+                message_queue.push((*player_id, pairing_message));
+            }
+        }
+    }
+
+    // Send one Tournament_Standing message per entrant to every entrant
+    // data[..]: see tournament::encode_standing
+    if tournament.phase() == Phase::Finished {
+        info!("Tournament finished");
+        for standing in tournament.standings() {
+            let standing_data = tournament::encode_standing(&standing);
+            let mut standing_message: Vec<u8> = [Message::TournamentStanding as u8, standing_data.len() as u8].to_vec();
+            standing_message.extend_from_slice(&standing_data);
+            for entrant_id in entrant_ids.iter() {
+                message_queue.push((*entrant_id, standing_message.clone()));
+            }
+        }
+    }
+}
+
+// Room settings, from the command line and then the config file if there
+// is one. Reloading the config file applies to rooms opened afterwards.
+#[derive(Clone)]
+pub struct Settings {
+    // Game type and rules
+    pub game: (u8, Vec<u8>),
+    pub start_policy: StartPolicy,
+    // Start policy for tournament games; None uses start_policy
+    pub tournament_start_policy: Option<StartPolicy>,
+    pub best_of: u8,
+    pub tournament_format: Format,
+    pub registration: Duration,
+}
+
+impl Settings {
+    pub fn new() -> Settings {
+        Settings {
+            game: parse_game_args(&[]).unwrap(),
+            start_policy: StartPolicy::LoserStarts,
+            tournament_start_policy: None,
+            best_of: 1,
+            tournament_format: Format::SingleElimination,
+            registration: Duration::from_secs(60),
+        }
+    }
+
+    // Whether `flag` is a command line option handled by set
+    pub fn is_setting(flag: &str) -> bool {
+        matches!(flag, "--start" | "--tournament-start" | "--best-of" | "--tournament" | "--registration")
+    }
+
+    pub fn set(&mut self, flag: &str, value: &str) -> Option<()> {
+        match flag {
+            "--start" => self.start_policy = StartPolicy::from_name(value)?,
+            "--tournament-start" => self.tournament_start_policy = Some(StartPolicy::from_name(value)?),
+            "--best-of" => self.best_of = value.parse().ok().filter(|&n| n > 0)?,
+            "--tournament" => self.tournament_format = Format::from_name(value)?,
+            "--registration" => self.registration = Duration::from_secs(value.parse().ok()?),
+            _ => return None,
+        }
+        Some(())
+    }
+
+    // Apply a config file. Each line holds one setting, written as the
+    // command line option without its dashes (e.g. `best-of 3`), or `game`
+    // followed by the game arguments; # starts a comment. Nothing is
+    // changed if any line is bad.
+    pub fn load(&mut self, path: &Path) -> Result<(), String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut settings = self.clone();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let words: Vec<String> = line.split_whitespace().map(String::from).collect();
+            let applied = match words.first().map(String::as_str) {
+                None => Some(()),
+                Some("game") => parse_game_args(&words[1..]).map(|game| settings.game = game),
+                Some(name) if words.len() == 2 => settings.set(&format!("--{}", name), &words[1]),
+                Some(_) => None,
+            };
+            if applied.is_none() {
+                return Err(format!("{}:{}: bad setting {:?}", path.display(), number + 1, line));
+            }
+        }
+        *self = settings;
+        Ok(())
+    }
+
+    // Builds the game these settings choose
+    fn new_game(&self) -> Box<dyn Fn() -> Box<dyn Game>> {
+        let (game_type, rules) = self.game.clone();
+        Box::new(move || game::new_game(game_type, &rules).unwrap())
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Settings { Settings::new() }
+}
+
+// Game type and rules from the command line arguments
+pub fn parse_game_args(args: &[String]) -> Option<(u8, Vec<u8>)> {
+    match args.first().map(String::as_str) {
+        None => Some((COUNTING_GAME, vec![2, 3, 10])),
+        Some("counting") if args.len() == 1 => Some((COUNTING_GAME, vec![2, 3, 10])),
+        Some("counting") => {
+            let move_set: Vec<u8> = args[1..].iter().map(|player_move| player_move.parse().ok()).collect::<Option<_>>()?;
+            if !subtraction::is_valid_move_set(&move_set) {
+                return None;
+            }
+            let game = GameData::with_move_set(2, &move_set, 10);
+            Some((game.game_type(), game.rules()))
+        },
+        Some("nim") => {
+            let misere = args.get(1).map(String::as_str) == Some("misere");
+            let heap_args = if misere { &args[2..] } else { &args[1..] };
+            let mut heaps: Vec<u8> = heap_args.iter().map(|heap| heap.parse().ok()).collect::<Option<_>>()?;
+            if heaps.is_empty() {
+                heaps = vec![3, 4, 5];
+            }
+            if !nim::is_valid_heaps(&heaps) {
+                return None;
+            }
+            let mut rules = vec![misere as u8];
+            rules.extend(heaps);
+            Some((NIM_GAME, rules))
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A lobby with named players 0..count entered in a tournament whose
+    // registration has closed
+    fn tournament_lobby(count: usize, history_directory: &Path) -> Lobby {
+        let mut settings = Settings::new();
+        settings.set("--registration", "0").unwrap();
+        let mut lobby = Lobby::new(&settings, history_directory.to_path_buf(), Ratings::new(), StdRng::seed_from_u64(1));
+        for index in 0..count {
+            let player_id = lobby.connect(([127, 0, 0, 1], 4000 + index as u16).into());
+            assert!(lobby.receive(player_id, Message::ClientUserName as u8, format!("player{}", index).as_bytes()));
+            assert!(lobby.receive(player_id, Message::JoinTournament as u8, &[]));
+        }
+        lobby.update(Instant::now());
+        lobby
+    }
+
+    fn opponent(lobby: &Lobby, player_id: usize) -> usize {
+        match lobby.player(player_id).unwrap().state {
+            ClientState::GameInProgress(partner_id) => partner_id,
+            state => panic!("player {} is not in a game: {:?}", player_id, state),
+        }
+    }
+
+    #[test]
+    fn abandoning_a_tournament_game_forfeits_it() {
+        let directory = std::env::temp_dir().join(format!("clientserver-lobby-{}", std::process::id()));
+        let mut lobby = tournament_lobby(4, &directory);
+        assert_eq!(lobby.server.games.len(), 2);
+
+        // Player 0 walks out of their game; player 1 ends the other one
+        let opponent_0 = opponent(&lobby, 0);
+        let opponent_1 = opponent(&lobby, 1);
+        assert!(lobby.receive(0, Message::EndGame as u8, &[]));
+        assert!(matches!(lobby.player(opponent_0).unwrap().state, ClientState::WaitingOnOpponent));
+        assert!(lobby.receive(1, Message::EndGame as u8, &[]));
+        assert!(lobby.server.games.is_empty());
+        let tournament = lobby.server.tournament.as_ref().unwrap();
+        assert_eq!(tournament.round(), 2);
+        assert_eq!(tournament.entrant(0).unwrap().eliminated_in, Some(1));
+        assert_eq!(tournament.entrant(opponent_0).unwrap().wins, 1);
+
+        // The winners meet in the final once they are back in the lobby
+        lobby.update(Instant::now());
+        assert_eq!(opponent(&lobby, opponent_0), opponent_1);
+        assert!(lobby.take_messages().iter().any(|(player_id, message)| {
+            *player_id == opponent_0 && message[0] == Message::TournamentResult as u8
+        }));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn ids_beyond_a_byte_reach_the_players() {
+        let directory = std::env::temp_dir().join(format!("clientserver-lobby-ids-{}", std::process::id()));
+        let mut lobby = Lobby::new(&Settings::new(), directory.clone(), Ratings::new(), StdRng::seed_from_u64(1));
+        let player_ids: Vec<usize> = (0..300).map(|index| lobby.connect(([127, 0, 0, 1], 4000 + index as u16).into())).collect();
+        assert_eq!(lobby.len(), 300);
+
+        // Only the last two players pick a name, so they are matched together
+        let (first, second) = (player_ids[298], player_ids[299]);
+        assert!(first > 255 && second > 255);
+        assert!(lobby.receive(first, Message::ClientUserName as u8, b"first"));
+        assert!(lobby.receive(second, Message::ClientUserName as u8, b"second"));
+        lobby.update(Instant::now());
+        assert_eq!(opponent(&lobby, first), second);
+
+        let messages = lobby.take_messages();
+        let sent_to = |player_id: usize, message: Message| -> Vec<Option<usize>> {
+            let control_byte = message as u8;
+            messages.iter()
+                .filter(|(to, data)| *to == player_id && data[0] == control_byte)
+                .map(|(_, data)| game::decode_player_id(&data[2..]))
+                .collect()
+        };
+        assert_eq!(sent_to(second, Message::Welcome), vec![Some(second)]);
+        assert_eq!(sent_to(second, Message::AddPlayer), vec![Some(first), Some(second)]);
+        let active = sent_to(second, Message::SetActivePlayer);
+        assert!(active == vec![Some(first)] || active == vec![Some(second)]);
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
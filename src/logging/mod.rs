@@ -0,0 +1,390 @@
+use std::cell::Cell;
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::connection::WIRE_TRACE;
+
+const DEFAULT_MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+const DEFAULT_MAX_FILES: usize = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+// Where log records go and which ones are kept
+#[derive(Clone, Debug)]
+pub struct Config {
+    // Level for records whose target matches no directive
+    level: LevelFilter,
+    // Per target levels; the longest matching target prefix wins
+    directives: Vec<(String, LevelFilter)>,
+    format: Format,
+    // Log file, or standard error if None
+    file: Option<PathBuf>,
+    // Size at which the log file is rotated
+    max_file_size: u64,
+    // Rotated log files kept, as file.1 (newest) to file.N
+    max_files: usize,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            level: LevelFilter::Info,
+            // Wire traces are only written when asked for by name
+            directives: vec![(WIRE_TRACE.to_string(), LevelFilter::Off)],
+            format: Format::Text,
+            file: None,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            max_files: DEFAULT_MAX_FILES,
+        }
+    }
+}
+
+impl Config {
+    // Whether `flag` is a command line option handled by set_option
+    pub fn is_option(flag: &str) -> bool {
+        matches!(flag, "--log-level" | "--log-format" | "--log-file" | "--log-file-size" | "--log-files")
+    }
+
+    // Apply one command line option:
+    // --log-level level[,target=level...]  e.g. debug,clientserver::wire=trace
+    // --log-format text|json
+    // --log-file path
+    // --log-file-size bytes
+    // --log-files count
+    pub fn set_option(&mut self, flag: &str, value: &str) -> Option<()> {
+        match flag {
+            "--log-level" => self.set_levels(value)?,
+            "--log-format" => self.format = match value {
+                "text" => Format::Text,
+                "json" => Format::Json,
+                _ => return None,
+            },
+            "--log-file" => self.file = Some(PathBuf::from(value)),
+            "--log-file-size" => self.max_file_size = value.parse().ok().filter(|&size| size > 0)?,
+            "--log-files" => self.max_files = value.parse().ok()?,
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn set_levels(&mut self, spec: &str) -> Option<()> {
+        for directive in spec.split(',').filter(|directive| !directive.is_empty()) {
+            match directive.find('=') {
+                Some(split) => {
+                    let target = &directive[..split];
+                    let level = directive[split + 1..].parse().ok()?;
+                    self.directives.retain(|(existing, _)| existing != target);
+                    self.directives.push((target.to_string(), level));
+                },
+                None => self.level = directive.parse().ok()?,
+            }
+        }
+        Some(())
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.directives.iter()
+            .filter(|(prefix, _)| target.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.level, |&(_, level)| level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.directives.iter().map(|&(_, level)| level).fold(self.level, |max, level| max.max(level))
+    }
+}
+
+// Install the logger for the whole process. Fails if the log file cannot
+// be opened or a logger is already installed.
+pub fn init(config: Config) -> io::Result<()> {
+    let output = match config.file {
+        Some(ref path) => Output::File(RotatingFile::open(path, config.max_file_size, config.max_files)?),
+        None => Output::Stderr,
+    };
+    let max_level = config.max_level();
+    let logger = Logger { config, output: Mutex::new(output) };
+    log::set_logger(Box::leak(Box::new(logger)))
+        .map_err(|e| io::Error::new(io::ErrorKind::AlreadyExists, e.to_string()))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+// Connection and game a record is about, added to every record logged
+// while a scope is active on the current thread
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Context {
+    pub connection: Option<usize>,
+    pub game: Option<u64>,
+}
+
+impl Context {
+    pub fn connection(connection: usize) -> Context {
+        Context { connection: Some(connection), game: None }
+    }
+
+    pub fn game(game: u64) -> Context {
+        Context { connection: None, game: Some(game) }
+    }
+
+    pub fn with_game(self, game: Option<u64>) -> Context {
+        Context { game, ..self }
+    }
+
+    pub fn current() -> Context {
+        CONTEXT.with(Cell::get)
+    }
+}
+
+thread_local! {
+    static CONTEXT: Cell<Context> = Cell::new(Context::default());
+}
+
+// Restores the previous context when dropped
+pub struct Scope {
+    previous: Context,
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        CONTEXT.with(|context| context.set(self.previous));
+    }
+}
+
+// Use `context` for records logged on this thread until the returned
+// scope is dropped
+pub fn scope(context: Context) -> Scope {
+    Scope { previous: CONTEXT.with(|current| current.replace(context)) }
+}
+
+struct Logger {
+    config: Config,
+    output: Mutex<Output>,
+}
+
+enum Output {
+    Stderr,
+    File(RotatingFile),
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.config.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = match self.config.format {
+            Format::Text => format_text(record, Context::current()),
+            Format::Json => format_json(record, Context::current()),
+        };
+        // A logger has nowhere to report its own failures
+        if let Ok(mut output) = self.output.lock() {
+            let _ = match *output {
+                Output::Stderr => io::stderr().write_all(line.as_bytes()),
+                Output::File(ref mut file) => file.write_line(line.as_bytes()),
+            };
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut output) = self.output.lock() {
+            let _ = match *output {
+                Output::Stderr => io::stderr().flush(),
+                Output::File(ref mut file) => file.file.flush(),
+            };
+        }
+    }
+}
+
+// 2026-01-02T03:04:05.678Z INFO  miosocketlistener conn=3 game=7: message
+fn format_text(record: &Record, context: Context) -> String {
+    let mut line = format!("{} {:<5} {}", timestamp(), record.level(), record.target());
+    if let Some(connection) = context.connection {
+        let _ = write!(line, " conn={}", connection);
+    }
+    if let Some(game) = context.game {
+        let _ = write!(line, " game={}", game);
+    }
+    let _ = writeln!(line, ": {}", record.args());
+    line
+}
+
+// {"time":"...","level":"INFO","target":"...","conn":3,"game":7,"message":"..."}
+fn format_json(record: &Record, context: Context) -> String {
+    let mut line = format!("{{\"time\":\"{}\",\"level\":\"{}\",\"target\":", timestamp(), record.level());
+    push_json_string(&mut line, record.target());
+    if let Some(connection) = context.connection {
+        let _ = write!(line, ",\"conn\":{}", connection);
+    }
+    if let Some(game) = context.game {
+        let _ = write!(line, ",\"game\":{}", game);
+    }
+    line.push_str(",\"message\":");
+    push_json_string(&mut line, &record.args().to_string());
+    line.push_str("}\n");
+    line
+}
+
+pub fn push_json_string(line: &mut String, value: &str) {
+    line.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\r' => line.push_str("\\r"),
+            '\t' => line.push_str("\\t"),
+            c if (c as u32) < 0x20 => { let _ = write!(line, "\\u{:04x}", c as u32); },
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
+
+// Current UTC time in RFC 3339 format with milliseconds
+fn timestamp() -> String {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+    let time_of_day = seconds % 86400;
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year, month, day,
+            time_of_day / 3600, time_of_day / 60 % 60, time_of_day % 60,
+            since_epoch.subsec_millis())
+}
+
+// Year, month and day of a count of days since 1970-01-01 in the
+// proleptic Gregorian calendar
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// Log file that is renamed to file.1 once it reaches its size limit,
+// shifting older files up and deleting the oldest
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64, max_files: usize) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile { path: path.to_path_buf(), file, size, max_size, max_files })
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                let _ = fs::rename(self.rotated_path(index), self.rotated_path(index + 1));
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+            self.file = OpenOptions::new().append(true).create(true).open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.path.as_os_str().to_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_days_to_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        // Leap days, including the 400 year rule, and a century without one
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(11_017), (2000, 3, 1));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(civil_from_days(-25_509), (1900, 2, 28));
+        assert_eq!(civil_from_days(-25_508), (1900, 3, 1));
+        assert_eq!(civil_from_days(20_745), (2026, 10, 19));
+    }
+
+    #[test]
+    fn escapes_json_strings() {
+        let mut line = String::new();
+        push_json_string(&mut line, "say \"hi\"\\\n\r\t\u{1}\u{1f}é");
+        assert_eq!(line, r#""say \"hi\"\\\n\r\t\u0001\u001fé""#);
+    }
+
+    #[test]
+    fn picks_the_longest_matching_directive() {
+        let mut config = Config::default();
+        config.set_option("--log-level", "warn,clientserver=debug,clientserver::lobby=error").unwrap();
+        assert_eq!(config.level_for("miosocketlistener"), LevelFilter::Warn);
+        assert_eq!(config.level_for("clientserver::shard"), LevelFilter::Debug);
+        assert_eq!(config.level_for("clientserver::lobby"), LevelFilter::Error);
+        assert_eq!(config.level_for(WIRE_TRACE), LevelFilter::Off);
+        assert_eq!(config.max_level(), LevelFilter::Debug);
+        assert!(config.set_option("--log-level", "loud").is_none());
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let directory = std::env::temp_dir().join(format!("clientserver-logging-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("server.log");
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        for line in &["one  \n", "two  \n", "three\n", "four \n"] {
+            file.write_line(line.as_bytes()).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "four \n");
+        assert_eq!(fs::read_to_string(file.rotated_path(1)).unwrap(), "three\n");
+        assert_eq!(fs::read_to_string(file.rotated_path(2)).unwrap(), "two  \n");
+        assert!(!file.rotated_path(3).exists());
+
+        // Lines longer than the limit still get written
+        file.write_line(b"a much longer line\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "a much longer line\n");
+
+        // With no rotated files kept the log starts over
+        let mut file = RotatingFile::open(&path, 10, 0).unwrap();
+        file.write_line(b"five \n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "five \n");
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
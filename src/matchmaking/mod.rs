@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Tuning for how far apart in rating two players may be before they are paired
+#[derive(Clone, Debug)]
+pub struct MatchmakerConfig {
+    // Rating difference accepted as soon as a player joins the queue
+    pub initial_window: f64,
+    // Rating points the window widens by for every second spent waiting
+    pub widen_per_second: f64,
+    pub max_window: f64,
+    // How long a player waits before being paired with their previous opponent
+    pub rematch_delay: Duration,
+}
+
+impl Default for MatchmakerConfig {
+    fn default() -> MatchmakerConfig {
+        MatchmakerConfig {
+            initial_window: 100.0,
+            widen_per_second: 25.0,
+            max_window: 800.0,
+            rematch_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+struct QueueEntry {
+    player_id: usize,
+    rating: f64,
+    joined: Instant,
+}
+
+// Pairs waiting players by rating proximity. Time is always passed in by the
+// caller so the matchmaker can be driven without sockets or a real clock.
+pub struct Matchmaker {
+    config: MatchmakerConfig,
+    // Waiting players in the order they joined
+    queue: Vec<QueueEntry>,
+    last_opponents: HashMap<usize, usize>,
+}
+
+impl Matchmaker {
+    pub fn new() -> Matchmaker {
+        Matchmaker::with_config(MatchmakerConfig::default())
+    }
+
+    pub fn with_config(config: MatchmakerConfig) -> Matchmaker {
+        Matchmaker {
+            config,
+            queue: Vec::new(),
+            last_opponents: HashMap::new(),
+        }
+    }
+
+    // Add a player to the queue. Players wanting a rematch send
+    // Restart_Game instead, which keeps them with their opponent.
+    pub fn enqueue(&mut self, player_id: usize, rating: f64, now: Instant) {
+        if !self.is_queued(player_id) {
+            self.queue.push(QueueEntry { player_id, rating, joined: now });
+        }
+    }
+
+    pub fn remove(&mut self, player_id: usize) {
+        self.queue.retain(|entry| entry.player_id != player_id);
+    }
+
+    // Drop everything known about a player, e.g. when they disconnect and
+    // their id may be reused
+    pub fn forget(&mut self, player_id: usize) {
+        self.remove(player_id);
+        self.last_opponents.remove(&player_id);
+        self.last_opponents.retain(|_, opponent| *opponent != player_id);
+    }
+
+    pub fn is_queued(&self, player_id: usize) -> bool {
+        self.queue.iter().any(|entry| entry.player_id == player_id)
+    }
+
+    pub fn is_empty(&self) -> bool { self.queue.is_empty() }
+
+    pub fn len(&self) -> usize { self.queue.len() }
+
+    // Rating difference a player accepts after waiting `waited`
+    pub fn window(&self, waited: Duration) -> f64 {
+        let widened = self.config.initial_window + self.config.widen_per_second * waited.as_secs_f64();
+        widened.min(self.config.max_window)
+    }
+
+    // Find the best pair of waiting players, remove them from the queue and
+    // return their ids, longest waiting player first
+    pub fn next_pair(&mut self, now: Instant) -> Option<(usize, usize)> {
+        let mut best: Option<(usize, usize, f64)> = None;
+        for (i, first) in self.queue.iter().enumerate() {
+            for (j, second) in self.queue.iter().enumerate().skip(i + 1) {
+                if !self.can_pair(first, second, now) {
+                    continue;
+                }
+                let difference = (first.rating - second.rating).abs();
+                if best.is_none_or(|(_, _, best_difference)| difference < best_difference) {
+                    best = Some((i, j, difference));
+                }
+            }
+            // Queue is in arrival order, so prefer pairing the longest waiting player
+            if best.is_some() {
+                break;
+            }
+        }
+
+        let (i, j, _) = best?;
+        let second = self.queue.remove(j);
+        let first = self.queue.remove(i);
+        self.last_opponents.insert(first.player_id, second.player_id);
+        self.last_opponents.insert(second.player_id, first.player_id);
+        Some((first.player_id, second.player_id))
+    }
+
+    fn can_pair(&self, first: &QueueEntry, second: &QueueEntry, now: Instant) -> bool {
+        // The longer of the two waits decides how wide the window is
+        let waited = now.duration_since(first.joined).max(now.duration_since(second.joined));
+
+        // Avoid an immediate rematch unless either player has been waiting
+        // long enough that any opponent will do
+        if self.last_opponents.get(&first.player_id) == Some(&second.player_id) && waited < self.config.rematch_delay {
+            return false;
+        }
+
+        (first.rating - second.rating).abs() <= self.window(waited)
+    }
+}
+
+impl Default for Matchmaker {
+    fn default() -> Matchmaker { Matchmaker::new() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pairs_closest_ratings() {
+        let now = Instant::now();
+        let mut matchmaker = Matchmaker::new();
+        matchmaker.enqueue(1, 1200.0, now);
+        matchmaker.enqueue(2, 1290.0, now);
+        matchmaker.enqueue(3, 1230.0, now);
+        matchmaker.enqueue(4, 1500.0, now);
+        assert_eq!(matchmaker.next_pair(now), Some((1, 3)));
+        // 1290 and 1500 are too far apart until the window widens
+        assert_eq!(matchmaker.next_pair(now), None);
+        assert_eq!(matchmaker.len(), 2);
+    }
+
+    #[test]
+    fn window_widens_while_waiting() {
+        let start = Instant::now();
+        let mut matchmaker = Matchmaker::new();
+        assert_eq!(matchmaker.window(Duration::from_secs(0)), 100.0);
+        assert_eq!(matchmaker.window(Duration::from_secs(4)), 200.0);
+        assert_eq!(matchmaker.window(Duration::from_secs(3600)), 800.0);
+
+        matchmaker.enqueue(1, 1200.0, start);
+        matchmaker.enqueue(2, 1450.0, start);
+        assert_eq!(matchmaker.next_pair(start + Duration::from_secs(5)), None);
+        assert_eq!(matchmaker.next_pair(start + Duration::from_secs(6)), Some((1, 2)));
+    }
+
+    #[test]
+    fn avoids_immediate_rematch() {
+        let start = Instant::now();
+        let mut matchmaker = Matchmaker::new();
+        matchmaker.enqueue(1, 1200.0, start);
+        matchmaker.enqueue(2, 1200.0, start);
+        assert_eq!(matchmaker.next_pair(start), Some((1, 2)));
+
+        // Back in the queue after their game, with a third player further away
+        let later = start + Duration::from_secs(60);
+        matchmaker.enqueue(1, 1200.0, later);
+        matchmaker.enqueue(2, 1200.0, later);
+        matchmaker.enqueue(3, 1290.0, later);
+        assert_eq!(matchmaker.next_pair(later), Some((1, 3)));
+
+        // With nobody else around they meet again once the delay is up
+        matchmaker.enqueue(1, 1200.0, later);
+        assert_eq!(matchmaker.next_pair(later + Duration::from_secs(29)), None);
+        assert_eq!(matchmaker.next_pair(later + Duration::from_secs(30)), Some((2, 1)));
+    }
+
+    #[test]
+    fn forgets_disconnected_players() {
+        let now = Instant::now();
+        let mut matchmaker = Matchmaker::new();
+        matchmaker.enqueue(1, 1200.0, now);
+        matchmaker.enqueue(2, 1200.0, now);
+        assert_eq!(matchmaker.next_pair(now), Some((1, 2)));
+
+        // Player 2's id is reused by a new client
+        matchmaker.forget(2);
+        matchmaker.enqueue(1, 1200.0, now);
+        matchmaker.enqueue(2, 1200.0, now);
+        assert_eq!(matchmaker.next_pair(now), Some((1, 2)));
+    }
+}
@@ -0,0 +1,233 @@
+use std::fmt::Write;
+use std::time::Duration;
+
+// Upper bounds in seconds of the game duration histogram buckets
+const DURATION_BUCKETS: [f64; 8] = [5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0];
+
+// Largest HTTP request accepted by the metrics endpoint
+pub const MAX_REQUEST: usize = 8 * 1024;
+
+// Counters kept by the server since it started
+pub struct Metrics {
+    connections_accepted: u64,
+    connections_rejected: u64,
+    connections_limited: u64,
+    handshakes_completed: u64,
+    handshake_timeouts: u64,
+    tls_failures: u64,
+    protocol_errors: u64,
+    rate_limited: u64,
+    addresses_banned: u64,
+    // Indexed by control byte
+    messages_received: Vec<u64>,
+    messages_sent: Vec<u64>,
+    games_started: u64,
+    // Count of finished games in each duration bucket, plus one for longer games
+    game_durations: Vec<u64>,
+    game_duration_sum: f64,
+}
+
+// Current values read from the server when metrics are rendered
+pub struct Gauges {
+    pub connections: usize,
+    pub active_games: usize,
+    pub queued_players: usize,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            connections_accepted: 0,
+            connections_rejected: 0,
+            connections_limited: 0,
+            handshakes_completed: 0,
+            handshake_timeouts: 0,
+            tls_failures: 0,
+            protocol_errors: 0,
+            rate_limited: 0,
+            addresses_banned: 0,
+            messages_received: vec![0; 256],
+            messages_sent: vec![0; 256],
+            games_started: 0,
+            game_durations: vec![0; DURATION_BUCKETS.len() + 1],
+            game_duration_sum: 0.0,
+        }
+    }
+
+    pub fn connection_accepted(&mut self) { self.connections_accepted += 1; }
+
+    // Connection turned away because the server is full
+    pub fn connection_rejected(&mut self) { self.connections_rejected += 1; }
+
+    // Connection turned away because its address is banned or at its limit
+    pub fn connection_limited(&mut self) { self.connections_limited += 1; }
+
+    pub fn handshake_completed(&mut self) { self.handshakes_completed += 1; }
+
+    pub fn handshake_timeout(&mut self) { self.handshake_timeouts += 1; }
+
+    // Handshake or record that failed to decrypt or verify
+    pub fn tls_failure(&mut self) { self.tls_failures += 1; }
+
+    // Message the server could not make sense of
+    pub fn protocol_error(&mut self) { self.protocol_errors += 1; }
+
+    // Connection dropped for sending messages faster than allowed
+    pub fn rate_limited(&mut self) { self.rate_limited += 1; }
+
+    pub fn address_banned(&mut self) { self.addresses_banned += 1; }
+
+    pub fn message_received(&mut self, control_byte: u8) { self.messages_received[control_byte as usize] += 1; }
+
+    pub fn message_sent(&mut self, control_byte: u8) { self.messages_sent[control_byte as usize] += 1; }
+
+    pub fn game_started(&mut self) { self.games_started += 1; }
+
+    pub fn game_finished(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = DURATION_BUCKETS.iter().position(|&bound| seconds <= bound).unwrap_or(DURATION_BUCKETS.len());
+        self.game_durations[bucket] += 1;
+        self.game_duration_sum += seconds;
+    }
+
+    // Metrics in the Prometheus text exposition format
+    pub fn render(&self, gauges: &Gauges) -> String {
+        let mut text = String::new();
+        metric(&mut text, "connections_accepted_total", "counter", "Connections accepted.", self.connections_accepted);
+        metric(&mut text, "connections_rejected_total", "counter", "Connections refused because the server was full.", self.connections_rejected);
+        metric(&mut text, "connections_limited_total", "counter", "Connections refused because the address was banned or had too many connections.", self.connections_limited);
+        metric(&mut text, "connections", "gauge", "Open client connections.", gauges.connections as u64);
+        metric(&mut text, "tls_handshakes_total", "counter", "TLS handshakes completed.", self.handshakes_completed);
+        metric(&mut text, "tls_handshake_timeouts_total", "counter", "Connections dropped for not completing the TLS handshake in time.", self.handshake_timeouts);
+        metric(&mut text, "tls_failures_total", "counter", "Connections dropped for TLS errors.", self.tls_failures);
+        metric(&mut text, "protocol_errors_total", "counter", "Client messages with an unknown control byte.", self.protocol_errors);
+        metric(&mut text, "rate_limited_total", "counter", "Connections dropped for sending messages too fast.", self.rate_limited);
+        metric(&mut text, "addresses_banned_total", "counter", "Addresses banned for repeated protocol violations.", self.addresses_banned);
+        metric(&mut text, "games_started_total", "counter", "Game rooms opened.", self.games_started);
+        metric(&mut text, "active_games", "gauge", "Game rooms in progress.", gauges.active_games as u64);
+        metric(&mut text, "queued_players", "gauge", "Players waiting for the matchmaker.", gauges.queued_players as u64);
+        messages(&mut text, "messages_received_total", "Messages received from clients by control byte.", &self.messages_received);
+        messages(&mut text, "messages_sent_total", "Messages sent to clients by control byte.", &self.messages_sent);
+
+        let _ = writeln!(text, "# HELP clientserver_game_duration_seconds Time from first move prompt to the end of each game.");
+        let _ = writeln!(text, "# TYPE clientserver_game_duration_seconds histogram");
+        let mut count = 0;
+        for (bound, games) in DURATION_BUCKETS.iter().zip(self.game_durations.iter()) {
+            count += games;
+            let _ = writeln!(text, "clientserver_game_duration_seconds_bucket{{le=\"{}\"}} {}", bound, count);
+        }
+        count += self.game_durations[DURATION_BUCKETS.len()];
+        let _ = writeln!(text, "clientserver_game_duration_seconds_bucket{{le=\"+Inf\"}} {}", count);
+        let _ = writeln!(text, "clientserver_game_duration_seconds_sum {}", self.game_duration_sum);
+        let _ = writeln!(text, "clientserver_game_duration_seconds_count {}", count);
+        text
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics { Metrics::new() }
+}
+
+fn metric(text: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    let _ = writeln!(text, "# HELP clientserver_{} {}", name, help);
+    let _ = writeln!(text, "# TYPE clientserver_{} {}", name, kind);
+    let _ = writeln!(text, "clientserver_{} {}", name, value);
+}
+
+// Counter labelled by control byte, leaving out bytes never seen
+fn messages(text: &mut String, name: &str, help: &str, counts: &[u64]) {
+    let _ = writeln!(text, "# HELP clientserver_{} {}", name, help);
+    let _ = writeln!(text, "# TYPE clientserver_{} counter", name);
+    for (control_byte, count) in counts.iter().enumerate().filter(|(_, &count)| count > 0) {
+        let _ = writeln!(text, "clientserver_{}{{control=\"{}\"}} {}", name, control_byte, count);
+    }
+}
+
+// Whether `request` holds a complete set of HTTP request headers
+pub fn is_complete_request(request: &[u8]) -> bool {
+    request.windows(4).any(|window| window == b"\r\n\r\n")
+}
+
+// HTTP response to a request for the metrics page. `render` is only
+// called for GET /metrics.
+pub fn http_response<F>(request: &[u8], render: F) -> Vec<u8> where F: FnOnce() -> String {
+    let request_line = request.split(|&byte| byte == b'\r').next().unwrap_or(&[]);
+    let request_line = String::from_utf8_lossy(request_line);
+    let mut parts = request_line.split(' ');
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", "text/plain; version=0.0.4", render()),
+        (Some("GET"), _) => ("404 Not Found", "text/plain", String::from("Not found\n")),
+        _ => ("405 Method Not Allowed", "text/plain", String::from("Method not allowed\n")),
+    };
+    format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status, content_type, body.len(), body).into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines_starting(text: &str, prefix: &str) -> Vec<String> {
+        text.lines().filter(|line| line.starts_with(prefix)).map(String::from).collect()
+    }
+
+    #[test]
+    fn renders_prometheus_text() {
+        let mut metrics = Metrics::new();
+        metrics.connection_accepted();
+        metrics.connection_accepted();
+        metrics.message_received(1);
+        metrics.message_received(1);
+        metrics.message_sent(6);
+        metrics.game_finished(Duration::from_secs(10));
+        metrics.game_finished(Duration::from_secs(3600));
+        let text = metrics.render(&Gauges { connections: 3, active_games: 1, queued_players: 0 });
+
+        assert!(text.ends_with('\n'));
+        assert!(text.contains("# HELP clientserver_connections_accepted_total Connections accepted.\n\
+            # TYPE clientserver_connections_accepted_total counter\n\
+            clientserver_connections_accepted_total 2\n"));
+        assert!(text.contains("# TYPE clientserver_connections gauge\nclientserver_connections 3\n"));
+        assert_eq!(lines_starting(&text, "clientserver_messages_received_total"), ["clientserver_messages_received_total{control=\"1\"} 2"]);
+        assert_eq!(lines_starting(&text, "clientserver_messages_sent_total"), ["clientserver_messages_sent_total{control=\"6\"} 1"]);
+
+        // Buckets are cumulative
+        let buckets = lines_starting(&text, "clientserver_game_duration_seconds_bucket");
+        assert_eq!(buckets.len(), DURATION_BUCKETS.len() + 1);
+        assert_eq!(buckets[0], "clientserver_game_duration_seconds_bucket{le=\"5\"} 0");
+        assert_eq!(buckets[1], "clientserver_game_duration_seconds_bucket{le=\"15\"} 1");
+        assert_eq!(buckets[7], "clientserver_game_duration_seconds_bucket{le=\"1800\"} 1");
+        assert_eq!(buckets[8], "clientserver_game_duration_seconds_bucket{le=\"+Inf\"} 2");
+        assert!(text.contains("clientserver_game_duration_seconds_sum 3610\nclientserver_game_duration_seconds_count 2\n"));
+
+        // Every sample follows its HELP and TYPE lines
+        for line in text.lines().filter(|line| !line.starts_with('#')) {
+            let name = line.split(&['{', ' '][..]).next().unwrap();
+            let family = ["_bucket", "_sum", "_count"].iter()
+                .find_map(|suffix| name.strip_suffix(suffix).filter(|family| family.ends_with("_seconds")))
+                .unwrap_or(name);
+            assert!(text.contains(&format!("# TYPE {} ", family)), "{}", line);
+        }
+    }
+
+    #[test]
+    fn answers_http_requests() {
+        let response = http_response(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n", || String::from("body é\n"));
+        let response = String::from_utf8(response).unwrap();
+        let (head, body) = response.split_at(response.find("\r\n\r\n").unwrap() + 4);
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        // Length is in bytes, not characters
+        assert!(head.contains("Content-Length: 8\r\n"));
+        assert_eq!(body, "body é\n");
+
+        let not_found = http_response(b"GET / HTTP/1.1\r\n\r\n", || panic!("rendered for another path"));
+        assert!(not_found.starts_with(b"HTTP/1.1 404 Not Found\r\n"));
+        let not_allowed = http_response(b"POST /metrics HTTP/1.1\r\n\r\n", || panic!("rendered for POST"));
+        assert!(not_allowed.starts_with(b"HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(http_response(b"", String::new).starts_with(b"HTTP/1.1 405"));
+
+        assert!(is_complete_request(b"GET /metrics HTTP/1.1\r\n\r\n"));
+        assert!(!is_complete_request(b"GET /metrics HTTP/1.1\r\n"));
+    }
+}
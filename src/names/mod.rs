@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+use std::str;
+
+use unicode_normalization::UnicodeNormalization;
+
+pub const MIN_NAME_LENGTH: usize = 2;
+pub const MAX_NAME_LENGTH: usize = 16;
+
+// Names (compared case-insensitively) that clients may not claim
+const RESERVED_NAMES: [&str; 8] = [
+    "admin",
+    "administrator",
+    "moderator",
+    "nobody",
+    "root",
+    "server",
+    "system",
+    "you",
+];
+
+// Reasons a user name is rejected, sent to the client in the
+// User_Name_Rejected message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NameRejection {
+    InvalidUtf8 = 1,
+    TooShort = 2,
+    TooLong = 3,
+    InvalidCharacter = 4,
+    Reserved = 5,
+    AlreadyInUse = 6,
+    MixedScripts = 7,
+}
+
+impl NameRejection {
+    pub fn from_u8(value: u8) -> Option<NameRejection> {
+        match value {
+            1 => Some(NameRejection::InvalidUtf8),
+            2 => Some(NameRejection::TooShort),
+            3 => Some(NameRejection::TooLong),
+            4 => Some(NameRejection::InvalidCharacter),
+            5 => Some(NameRejection::Reserved),
+            6 => Some(NameRejection::AlreadyInUse),
+            7 => Some(NameRejection::MixedScripts),
+            _ => None,
+        }
+    }
+
+    pub fn description(self) -> String {
+        match self {
+            NameRejection::InvalidUtf8 => "name is not valid UTF-8".to_string(),
+            NameRejection::TooShort => format!("name must be at least {} characters", MIN_NAME_LENGTH),
+            NameRejection::TooLong => format!("name must be at most {} characters", MAX_NAME_LENGTH),
+            NameRejection::InvalidCharacter => "name may only contain letters, digits, spaces, '-', '_' and '.'".to_string(),
+            NameRejection::Reserved => "name is reserved".to_string(),
+            NameRejection::AlreadyInUse => "name is already in use".to_string(),
+            NameRejection::MixedScripts => "name may not mix Latin, Greek, Cyrillic or Armenian letters".to_string(),
+        }
+    }
+}
+
+// Validate a user name as received off the wire and return its normalized
+// form: NFKC normalized, trimmed, with runs of whitespace collapsed to a
+// single space
+pub fn normalize_name(raw: &[u8]) -> Result<String, NameRejection> {
+    let name = match str::from_utf8(raw) {
+        Ok(name) => name,
+        Err(_) => return Err(NameRejection::InvalidUtf8),
+    };
+
+    // NFKC folds compatibility forms (full width letters, ligatures, ...)
+    // into their canonical characters
+    let name: String = name.nfkc().collect();
+    let name = name.split_whitespace().collect::<Vec<&str>>().join(" ");
+
+    if !name.chars().all(is_allowed_char) {
+        return Err(NameRejection::InvalidCharacter);
+    }
+
+    // Letters from alphabets with look-alike characters may not be mixed,
+    // so "admin" cannot be spelt with a Cyrillic "а"
+    let mut scripts = name.chars().filter_map(script);
+    if let Some(first) = scripts.next() {
+        if scripts.any(|other| other != first) {
+            return Err(NameRejection::MixedScripts);
+        }
+    }
+
+    let length = name.chars().count();
+    if length < MIN_NAME_LENGTH {
+        return Err(NameRejection::TooShort);
+    }
+    if length > MAX_NAME_LENGTH {
+        return Err(NameRejection::TooLong);
+    }
+
+    if RESERVED_NAMES.contains(&name_key(&name).as_str()) {
+        return Err(NameRejection::Reserved);
+    }
+
+    Ok(name)
+}
+
+// Key used to compare names for uniqueness and against the reserved names;
+// names that differ only by case or by look-alike letters from another
+// alphabet map to the same key
+pub fn name_key(name: &str) -> String {
+    name.nfkc().map(skeleton).flat_map(char::to_lowercase).collect()
+}
+
+// Alphabets whose letters are easily confused with each other
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Script {
+    Latin,
+    Greek,
+    Cyrillic,
+    Armenian,
+}
+
+fn script(c: char) -> Option<Script> {
+    match c {
+        'A'..='Z' | 'a'..='z' | '\u{c0}'..='\u{24f}' | '\u{1e00}'..='\u{1eff}' if c.is_alphabetic() => Some(Script::Latin),
+        '\u{370}'..='\u{3ff}' | '\u{1f00}'..='\u{1fff}' => Some(Script::Greek),
+        '\u{400}'..='\u{52f}' => Some(Script::Cyrillic),
+        '\u{530}'..='\u{58f}' => Some(Script::Armenian),
+        _ => None,
+    }
+}
+
+// Latin letter a Greek, Cyrillic or Armenian letter is drawn like, if any
+fn skeleton(c: char) -> char {
+    match c {
+        'А' | 'Α' => 'A',
+        'В' | 'Β' => 'B',
+        'С' => 'C',
+        'Е' | 'Ε' => 'E',
+        'Н' | 'Η' => 'H',
+        'І' | 'Ι' | 'Ӏ' => 'I',
+        'Ј' => 'J',
+        'К' | 'Κ' => 'K',
+        'М' | 'Μ' => 'M',
+        'Ν' => 'N',
+        'О' | 'Ο' | 'Օ' => 'O',
+        'Р' | 'Ρ' => 'P',
+        'Ѕ' => 'S',
+        'Т' | 'Τ' => 'T',
+        'У' | 'Υ' | 'Ү' => 'Y',
+        'Х' | 'Χ' => 'X',
+        'Ζ' => 'Z',
+        'а' | 'α' => 'a',
+        'с' => 'c',
+        'ԁ' => 'd',
+        'е' => 'e',
+        'һ' | 'հ' => 'h',
+        'і' | 'ι' | 'ı' => 'i',
+        'ј' => 'j',
+        'κ' => 'k',
+        'ӏ' => 'l',
+        'ո' => 'n',
+        'о' | 'ο' | 'օ' => 'o',
+        'р' | 'ρ' => 'p',
+        'ԛ' => 'q',
+        'ѕ' => 's',
+        'υ' | 'ս' => 'u',
+        'ν' => 'v',
+        'ԝ' => 'w',
+        'х' | 'χ' => 'x',
+        'у' => 'y',
+        _ => c,
+    }
+}
+
+fn is_allowed_char(c: char) -> bool {
+    c.is_alphanumeric() || c == ' ' || c == '-' || c == '_' || c == '.'
+}
+
+// Set of user names currently claimed by connected clients
+#[derive(Default)]
+pub struct NameRegistry {
+    names: HashMap<String, String>,
+}
+
+impl NameRegistry {
+    pub fn new() -> NameRegistry {
+        NameRegistry { names: HashMap::new() }
+    }
+
+    // Validate and claim a name, returning the normalized name on success
+    pub fn register(&mut self, raw: &[u8]) -> Result<String, NameRejection> {
+        let name = normalize_name(raw)?;
+        let key = name_key(&name);
+        if self.names.contains_key(&key) {
+            return Err(NameRejection::AlreadyInUse);
+        }
+        self.names.insert(key, name.clone());
+        Ok(name)
+    }
+
+    pub fn release(&mut self, name: &str) {
+        self.names.remove(&name_key(name));
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.names.contains_key(&name_key(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_malformed_names() {
+        let cases: [(&[u8], NameRejection); 10] = [
+            (b"\xff\xfeab", NameRejection::InvalidUtf8),
+            (b"ab\xc3", NameRejection::InvalidUtf8),
+            (b"", NameRejection::TooShort),
+            (b"   ", NameRejection::TooShort),
+            (b"\t\n", NameRejection::TooShort),
+            (b"a", NameRejection::TooShort),
+            (b"ab\x00cd", NameRejection::InvalidCharacter),
+            (b"ab\x1b[2Jcd", NameRejection::InvalidCharacter),
+            (b"abcdefghijklmnopq", NameRejection::TooLong),
+            (b"ab!", NameRejection::InvalidCharacter),
+        ];
+        for (raw, rejection) in cases.iter() {
+            assert_eq!(normalize_name(raw), Err(*rejection), "{:?}", raw);
+        }
+        // Surrounding and repeated whitespace is not counted
+        assert_eq!(normalize_name(b"  ab  "), Ok("ab".to_string()));
+        assert_eq!(normalize_name(b"abcdefgh \t ijklmnop"), Err(NameRejection::TooLong));
+        assert_eq!(normalize_name(b"abcdefg \t ijklmnop"), Ok("abcdefg ijklmnop".to_string()));
+    }
+
+    #[test]
+    fn rejections_round_trip_as_bytes() {
+        let rejections = [NameRejection::InvalidUtf8, NameRejection::TooShort, NameRejection::TooLong,
+            NameRejection::InvalidCharacter, NameRejection::Reserved, NameRejection::AlreadyInUse, NameRejection::MixedScripts];
+        for &rejection in rejections.iter() {
+            assert_eq!(NameRejection::from_u8(rejection as u8), Some(rejection));
+        }
+        assert_eq!(NameRejection::from_u8(0), None);
+        assert_eq!(NameRejection::from_u8(8), None);
+    }
+
+    #[test]
+    fn rejects_mixed_scripts() {
+        // Cyrillic "а" followed by Latin letters
+        assert_eq!(normalize_name("\u{430}dmin".as_bytes()), Err(NameRejection::MixedScripts));
+        assert_eq!(normalize_name("Ολα ok".as_bytes()), Err(NameRejection::MixedScripts));
+        assert_eq!(normalize_name("Иван 2".as_bytes()), Ok("Иван 2".to_string()));
+        assert_eq!(normalize_name("José_99".as_bytes()), Ok("José_99".to_string()));
+    }
+
+    #[test]
+    fn reserved_names_match_look_alikes() {
+        // Every letter Cyrillic
+        assert_eq!(normalize_name("ЅУЅТЕМ".as_bytes()), Err(NameRejection::Reserved));
+        assert_eq!(normalize_name("ЅУЅТЕМА".as_bytes()), Ok("ЅУЅТЕМА".to_string()));
+        assert_eq!(normalize_name("ADMIN".as_bytes()), Err(NameRejection::Reserved));
+    }
+
+    #[test]
+    fn look_alikes_share_a_key() {
+        let mut registry = NameRegistry::new();
+        assert_eq!(registry.register(b"coco"), Ok("coco".to_string()));
+        // All Cyrillic
+        assert_eq!(registry.register("СОСО".as_bytes()), Err(NameRejection::AlreadyInUse));
+        assert!(registry.contains("сосо"));
+        registry.release("COCO");
+        assert!(!registry.contains("coco"));
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::names::name_key;
+
+pub const INITIAL_RATING: f64 = 1200.0;
+pub const K_FACTOR: f64 = 32.0;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlayerStats {
+    pub name: String,
+    pub rating: f64,
+    pub wins: u32,
+    pub losses: u32,
+    // Positive for a winning streak, negative for a losing streak
+    pub streak: i32,
+    pub best_streak: u32,
+}
+
+impl PlayerStats {
+    pub fn new(name: &str) -> PlayerStats {
+        PlayerStats {
+            name: name.to_string(),
+            rating: INITIAL_RATING,
+            wins: 0,
+            losses: 0,
+            streak: 0,
+            best_streak: 0,
+        }
+    }
+
+    pub fn games_played(&self) -> u32 { self.wins + self.losses }
+
+    fn record_win(&mut self, rating: f64) {
+        self.rating = rating;
+        self.wins += 1;
+        self.streak = if self.streak > 0 { self.streak + 1 } else { 1 };
+        self.best_streak = self.best_streak.max(self.streak as u32);
+    }
+
+    fn record_loss(&mut self, rating: f64) {
+        self.rating = rating;
+        self.losses += 1;
+        self.streak = if self.streak < 0 { self.streak - 1 } else { -1 };
+    }
+}
+
+// Expected score of a player rated `rating` against an opponent rated
+// `opponent_rating`
+pub fn expected_score(rating: f64, opponent_rating: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf((opponent_rating - rating) / 400.0))
+}
+
+// Elo ratings and win/loss records for every player who has finished a game,
+// optionally persisted to a file after every update
+pub struct Ratings {
+    players: HashMap<String, PlayerStats>,
+    path: Option<PathBuf>,
+}
+
+impl Ratings {
+    pub fn new() -> Ratings {
+        Ratings { players: HashMap::new(), path: None }
+    }
+
+    // Load ratings from `path`; a missing file starts an empty store.
+    // File format is one player per line, tab separated:
+    // name, rating, wins, losses, streak, best_streak
+    pub fn load(path: &Path) -> io::Result<Ratings> {
+        let mut ratings = Ratings { players: HashMap::new(), path: Some(path.to_path_buf()) };
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(ratings),
+            Err(e) => return Err(e),
+        };
+
+        for (line_number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let stats = parse_line(&line).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData,
+                    format!("{}:{}: invalid ratings entry", path.display(), line_number + 1))
+            })?;
+            ratings.players.insert(name_key(&stats.name), stats);
+        }
+        Ok(ratings)
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(ref path) = self.path {
+            // Write to a temporary file and rename so a crash never leaves
+            // a truncated ratings file behind
+            let temp_path = path.with_extension("tmp");
+            let mut file = fs::File::create(&temp_path)?;
+            let mut players: Vec<&PlayerStats> = self.players.values().collect();
+            players.sort_by(|a, b| a.name.cmp(&b.name));
+            for stats in players {
+                writeln!(file, "{}\t{:.2}\t{}\t{}\t{}\t{}",
+                    stats.name, stats.rating, stats.wins, stats.losses, stats.streak, stats.best_streak)?;
+            }
+            file.sync_all()?;
+            fs::rename(&temp_path, path)?;
+        }
+        Ok(())
+    }
+
+    // Update both players' ratings and records for a finished game
+    pub fn record_game(&mut self, winner: &str, loser: &str) {
+        let winner_rating = self.rating(winner);
+        let loser_rating = self.rating(loser);
+
+        let new_winner_rating = winner_rating + K_FACTOR * (1.0 - expected_score(winner_rating, loser_rating));
+        let new_loser_rating = loser_rating + K_FACTOR * (0.0 - expected_score(loser_rating, winner_rating));
+
+        self.players.entry(name_key(winner))
+            .or_insert_with(|| PlayerStats::new(winner))
+            .record_win(new_winner_rating);
+        self.players.entry(name_key(loser))
+            .or_insert_with(|| PlayerStats::new(loser))
+            .record_loss(new_loser_rating);
+    }
+
+    pub fn rating(&self, name: &str) -> f64 {
+        self.stats(name).map_or(INITIAL_RATING, |stats| stats.rating)
+    }
+
+    pub fn stats(&self, name: &str) -> Option<&PlayerStats> {
+        self.players.get(&name_key(name))
+    }
+
+    // Highest rated players first
+    pub fn leaderboard(&self, top_n: usize) -> Vec<&PlayerStats> {
+        let mut players: Vec<&PlayerStats> = self.players.values().collect();
+        players.sort_by(|a, b| {
+            b.rating.total_cmp(&a.rating).then_with(|| a.name.cmp(&b.name))
+        });
+        players.truncate(top_n);
+        players
+    }
+}
+
+impl Default for Ratings {
+    fn default() -> Ratings { Ratings::new() }
+}
+
+fn parse_line(line: &str) -> Option<PlayerStats> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() != 6 {
+        return None;
+    }
+    Some(PlayerStats {
+        name: fields[0].to_string(),
+        // Elo updates would spread a NaN or infinite rating to opponents
+        rating: fields[1].parse().ok().filter(|rating: &f64| rating.is_finite())?,
+        wins: fields[2].parse().ok()?,
+        losses: fields[3].parse().ok()?,
+        streak: fields[4].parse().ok()?,
+        best_streak: fields[5].parse().ok()?,
+    })
+}
+
+// Player_Stats message payload:
+// data[0..2] - rating (big endian, rounded)
+// data[2..4] - wins
+// data[4..6] - losses
+// data[6] - streak (signed)
+// data[7] - best streak
+// data[8..] - player name
+pub fn encode_stats(stats: &PlayerStats) -> Vec<u8> {
+    let rating = stats.rating.round().max(0.0).min(f64::from(u16::MAX)) as u16;
+    let wins = stats.wins.min(u32::from(u16::MAX)) as u16;
+    let losses = stats.losses.min(u32::from(u16::MAX)) as u16;
+    let mut data = Vec::with_capacity(8 + stats.name.len());
+    data.extend_from_slice(&rating.to_be_bytes());
+    data.extend_from_slice(&wins.to_be_bytes());
+    data.extend_from_slice(&losses.to_be_bytes());
+    data.push(stats.streak.max(i32::from(i8::MIN)).min(i32::from(i8::MAX)) as i8 as u8);
+    data.push(stats.best_streak.min(u32::from(u8::MAX)) as u8);
+    data.extend_from_slice(stats.name.as_bytes());
+    data
+}
+
+pub fn decode_stats(data: &[u8]) -> Option<PlayerStats> {
+    if data.len() < 8 {
+        return None;
+    }
+    Some(PlayerStats {
+        name: String::from_utf8(data[8..].to_vec()).ok()?,
+        rating: f64::from(u16::from_be_bytes([data[0], data[1]])),
+        wins: u32::from(u16::from_be_bytes([data[2], data[3]])),
+        losses: u32::from(u16::from_be_bytes([data[4], data[5]])),
+        streak: i32::from(data[6] as i8),
+        best_streak: u32::from(data[7]),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_non_finite_ratings() {
+        assert!(parse_line("bob\t1250.50\t3\t1\t2\t2").is_some());
+        assert!(parse_line("bob\tNaN\t3\t1\t2\t2").is_none());
+        assert!(parse_line("bob\tinf\t3\t1\t2\t2").is_none());
+    }
+
+    #[test]
+    fn leaderboard_orders_by_rating_then_name() {
+        let mut ratings = Ratings::new();
+        ratings.record_game("carol", "bob");
+        ratings.record_game("alice", "dave");
+        let names: Vec<&str> = ratings.leaderboard(3).iter().map(|stats| stats.name.as_str()).collect();
+        assert_eq!(names, ["alice", "carol", "bob"]);
+    }
+}
@@ -0,0 +1,168 @@
+use crate::game::{decode_player_id, encode_player_id, Outcome, PLAYER_ID_LENGTH};
+
+// Running score of a best-of-N match between the players of a room. Drawn
+// games are counted but do not bring anyone closer to winning the match.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Series {
+    best_of: u8,
+    player_ids: Vec<usize>,
+    wins: Vec<u8>,
+    draws: u8,
+}
+
+impl Series {
+    pub fn new(best_of: u8, player_ids: &[usize]) -> Series {
+        Series {
+            best_of: best_of.max(1),
+            player_ids: player_ids.to_vec(),
+            wins: vec![0; player_ids.len()],
+            draws: 0,
+        }
+    }
+
+    pub fn best_of(&self) -> u8 { self.best_of }
+
+    // Games a player must win to take the match
+    pub fn wins_needed(&self) -> u8 { self.best_of / 2 + 1 }
+
+    pub fn player_ids(&self) -> &[usize] { self.player_ids.as_slice() }
+
+    pub fn wins(&self, player_id: usize) -> u8 {
+        self.player_ids.iter().position(|id| *id == player_id).map_or(0, |player| self.wins[player])
+    }
+
+    pub fn draws(&self) -> u8 { self.draws }
+
+    pub fn games_played(&self) -> u32 {
+        self.wins.iter().map(|&wins| u32::from(wins)).sum::<u32>() + u32::from(self.draws)
+    }
+
+    // Add a finished game to the score; ignored once the match is over
+    pub fn record(&mut self, outcome: &Outcome) {
+        if self.is_over() {
+            return;
+        }
+        match *outcome {
+            Outcome::Decided { ref winners, .. } => {
+                for (player, player_id) in self.player_ids.iter().enumerate() {
+                    if winners.contains(player_id) {
+                        self.wins[player] = self.wins[player].saturating_add(1);
+                    }
+                }
+            },
+            Outcome::Draw => self.draws = self.draws.saturating_add(1),
+            Outcome::InProgress => (),
+        }
+    }
+
+    // Player who has clinched the match, if any
+    pub fn winner(&self) -> Option<usize> {
+        let player = self.wins.iter().position(|&wins| wins >= self.wins_needed())?;
+        Some(self.player_ids[player])
+    }
+
+    pub fn is_over(&self) -> bool { self.winner().is_some() }
+
+    // Start a new match between the same players
+    pub fn reset(&mut self) {
+        for wins in self.wins.iter_mut() {
+            *wins = 0;
+        }
+        self.draws = 0;
+    }
+}
+
+// Match_Score message payload:
+// data[0] - best of
+// data[1] - drawn games
+// data[2..] - player_id (two bytes), wins; one entry per player
+pub fn encode_score(series: &Series) -> Vec<u8> {
+    let mut data = vec![series.best_of, series.draws];
+    for (player_id, wins) in series.player_ids.iter().zip(series.wins.iter()) {
+        data.extend_from_slice(&encode_player_id(*player_id));
+        data.push(*wins);
+    }
+    data
+}
+
+pub fn decode_score(data: &[u8]) -> Option<Series> {
+    const ENTRY: usize = PLAYER_ID_LENGTH + 1;
+    if data.len() < 2 || !(data.len() - 2).is_multiple_of(ENTRY) {
+        return None;
+    }
+    let entries = &data[2..];
+    Some(Series {
+        best_of: data[0].max(1),
+        player_ids: entries.chunks(ENTRY).map(decode_player_id).collect::<Option<Vec<usize>>>()?,
+        wins: entries.chunks(ENTRY).map(|entry| entry[PLAYER_ID_LENGTH]).collect(),
+        draws: data[1],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn won_by(winner: usize, loser: usize) -> Outcome {
+        Outcome::Decided { winners: vec![winner], losers: vec![loser] }
+    }
+
+    #[test]
+    fn first_to_a_majority_wins() {
+        let mut series = Series::new(3, &[4, 7]);
+        assert_eq!(series.wins_needed(), 2);
+        series.record(&won_by(4, 7));
+        series.record(&Outcome::Draw);
+        series.record(&won_by(7, 4));
+        assert_eq!(series.winner(), None);
+        series.record(&won_by(7, 4));
+        assert_eq!(series.winner(), Some(7));
+        assert_eq!((series.wins(4), series.wins(7), series.draws()), (1, 2, 1));
+        assert_eq!(series.games_played(), 4);
+    }
+
+    #[test]
+    fn stops_counting_once_decided() {
+        let mut series = Series::new(5, &[4, 7]);
+        for _ in 0..3 {
+            series.record(&won_by(4, 7));
+        }
+        assert!(series.is_over());
+        series.record(&won_by(7, 4));
+        series.record(&Outcome::Draw);
+        assert_eq!((series.wins(4), series.wins(7), series.draws()), (3, 0, 0));
+
+        series.reset();
+        assert!(!series.is_over());
+        assert_eq!(series.games_played(), 0);
+    }
+
+    #[test]
+    fn even_matches_need_a_strict_majority() {
+        let mut series = Series::new(4, &[4, 7]);
+        assert_eq!(series.wins_needed(), 3);
+        for &(winner, loser) in &[(4, 7), (7, 4), (4, 7), (7, 4)] {
+            series.record(&won_by(winner, loser));
+        }
+        // A 2-2 split plays on until someone has three wins
+        assert!(!series.is_over());
+        series.record(&won_by(7, 4));
+        assert_eq!(series.winner(), Some(7));
+
+        assert_eq!(Series::new(0, &[4, 7]).best_of(), 1);
+        assert_eq!(Series::new(1, &[4, 7]).wins_needed(), 1);
+    }
+
+    #[test]
+    fn score_round_trips() {
+        let mut series = Series::new(3, &[4, 700]);
+        series.record(&won_by(700, 4));
+        series.record(&Outcome::Draw);
+        assert_eq!(encode_score(&series), [3, 1, 0, 4, 0, 2, 188, 1]);
+        assert_eq!(decode_score(&encode_score(&series)), Some(series));
+        assert_eq!(decode_score(&[3, 1, 0, 4]), None);
+        assert_eq!(decode_score(&[3]), None);
+        // NO_PLAYER is not a player
+        assert_eq!(decode_score(&[3, 0, 255, 255, 0]), None);
+    }
+}
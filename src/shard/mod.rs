@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::io;
+use std::net;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use log::{trace, warn};
+use mio::{Evented, Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use mio::net::TcpStream;
+use rustls::ServerSession;
+
+use crate::connection::Connection;
+use crate::logging::{self, Context};
+
+// Token for a worker's command mailbox; mio reserves usize::MAX
+const MAILBOX: Token = Token(usize::MAX - 1);
+
+// Sending half of a channel that wakes the Poll its mailbox is registered with
+pub struct MailboxSender<T> {
+    sender: Sender<T>,
+    readiness: SetReadiness,
+}
+
+impl<T> Clone for MailboxSender<T> {
+    fn clone(&self) -> MailboxSender<T> {
+        MailboxSender { sender: self.sender.clone(), readiness: self.readiness.clone() }
+    }
+}
+
+impl<T> MailboxSender<T> {
+    // Returns false if the mailbox has been dropped
+    pub fn send(&self, item: T) -> bool {
+        let sent = self.sender.send(item).is_ok();
+        let _ = self.readiness.set_readiness(Ready::readable());
+        sent
+    }
+}
+
+// Receiving half of a mailbox. Register it edge triggered and call drain
+// whenever it is readable.
+pub struct Mailbox<T> {
+    receiver: Receiver<T>,
+    registration: Registration,
+    readiness: SetReadiness,
+}
+
+impl<T> Mailbox<T> {
+    // Everything sent since the last drain
+    pub fn drain(&self) -> Vec<T> {
+        // Cleared first so an item sent during the drain wakes the Poll again
+        let _ = self.readiness.set_readiness(Ready::empty());
+        self.receiver.try_iter().collect()
+    }
+}
+
+impl<T> Evented for Mailbox<T> {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        poll.deregister(&self.registration)
+    }
+}
+
+pub fn mailbox<T>() -> (MailboxSender<T>, Mailbox<T>) {
+    let (sender, receiver) = mpsc::channel();
+    let (registration, readiness) = Registration::new2();
+    (MailboxSender { sender, readiness: readiness.clone() }, Mailbox { receiver, registration, readiness })
+}
+
+// Sent by the server to the worker that owns a connection
+pub enum Command {
+    // Take over a newly accepted connection. The session is None for
+    // plaintext connections, websocket is set for connections that start
+    // with a WebSocket upgrade and json for those that may talk in JSON
+    // lines. At most buffer_limit bytes of unsent data are held for the
+    // client.
+    Add { player_id: usize, connection_id: u64, socket: TcpStream, session: Option<Box<ServerSession>>, websocket: bool, json: bool, buffer_limit: usize },
+    // Encrypt and send a message
+    Send { player_id: usize, data: Vec<u8> },
+    // Drop the connection; no Closed event is sent back
+    Close { player_id: usize },
+}
+
+// Sent by a worker to the server. Player ids are reused, so events carry
+// the connection id given in Add to tell connections apart.
+pub enum Event {
+    HandshakeCompleted { player_id: usize, connection_id: u64 },
+    // Complete message received: control byte and data
+    Received { player_id: usize, connection_id: u64, control_byte: u8, data: Vec<u8> },
+    // The worker has dropped the connection. The error is None if the peer
+    // closed it, of kind InvalidData for TLS failures and InvalidInput for
+    // WebSocket and JSON protocol errors.
+    Closed { player_id: usize, connection_id: u64, error: Option<io::Error> },
+}
+
+// Start worker thread `index`, which reports to `events` and takes
+// commands through the returned sender
+pub fn spawn(index: usize, events: MailboxSender<Event>) -> io::Result<MailboxSender<Command>> {
+    let (commands, mailbox) = mailbox();
+    let poll = Poll::new()?;
+    poll.register(&mailbox, MAILBOX, Ready::readable(), PollOpt::edge())?;
+    let mut worker = Worker { poll, mailbox, events, clients: HashMap::new(), dirty: Vec::new() };
+    thread::Builder::new()
+        .name(format!("worker-{}", index))
+        .spawn(move || {
+            if let Err(e) = worker.run() {
+                warn!("Worker {} stopped: {}", index, e);
+            }
+        })?;
+    Ok(commands)
+}
+
+struct Client {
+    connection_id: u64,
+    connection: Connection<ServerSession, TcpStream>,
+    // Whether the socket is registered for writable events
+    writable_registered: bool,
+}
+
+// Event loop doing the socket and TLS work for its share of the connections
+struct Worker {
+    poll: Poll,
+    mailbox: Mailbox<Command>,
+    events: MailboxSender<Event>,
+    // Keyed by player id, which is also the connection's token
+    clients: HashMap<usize, Client>,
+    // Connections that may have encrypted data waiting to be written
+    dirty: Vec<usize>,
+}
+
+impl Worker {
+    fn run(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(1024);
+        loop {
+            self.poll.poll(&mut events, None)?;
+            for event in &events {
+                match event.token() {
+                    MAILBOX => {
+                        for command in self.mailbox.drain() {
+                            self.run_command(command);
+                        }
+                    },
+                    Token(player_id) => {
+                        let _scope = logging::scope(Context::connection(player_id));
+                        let readiness = event.readiness();
+                        if readiness.is_readable() {
+                            self.read(player_id);
+                        }
+                        if readiness.is_writable() {
+                            self.dirty.push(player_id);
+                        }
+                    },
+                }
+            }
+
+            // Write out everything queued during this pass
+            let mut dirty = std::mem::take(&mut self.dirty);
+            dirty.sort_unstable();
+            dirty.dedup();
+            for player_id in dirty {
+                let _scope = logging::scope(Context::connection(player_id));
+                if let Err(e) = self.flush(player_id) {
+                    warn!("Write failed: {}", e);
+                    self.close(player_id, Some(e));
+                }
+            }
+        }
+    }
+
+    fn run_command(&mut self, command: Command) {
+        match command {
+            Command::Add { player_id, connection_id, socket, session, websocket, json, buffer_limit } => {
+                if let Err(e) = self.poll.register(&socket, Token(player_id), Ready::readable(), PollOpt::level()) {
+                    self.events.send(Event::Closed { player_id, connection_id, error: Some(e) });
+                    return;
+                }
+                let mut connection = match session {
+                    Some(session) => {
+                        let mut connection = Connection::new(*session, socket);
+                        connection.set_buffer_limit(buffer_limit);
+                        connection
+                    },
+                    None => Connection::plaintext(socket, buffer_limit),
+                };
+                if websocket {
+                    connection.set_websocket();
+                }
+                if json {
+                    connection.set_json();
+                }
+                let client = Client { connection_id, connection, writable_registered: false };
+                self.clients.insert(player_id, client);
+            },
+            Command::Send { player_id, data } => {
+                // The connection may have failed since the message was sent
+                if let Some(client) = self.clients.get_mut(&player_id) {
+                    let _scope = logging::scope(Context::connection(player_id));
+                    if let Err(e) = client.connection.write(&data) {
+                        warn!("Client is not reading its messages: {}", e);
+                        self.close(player_id, Some(e));
+                    } else {
+                        self.dirty.push(player_id);
+                    }
+                }
+            },
+            Command::Close { player_id } => {
+                if let Some(client) = self.clients.remove(&player_id) {
+                    self.shutdown(client);
+                }
+            },
+        }
+    }
+
+    fn read(&mut self, player_id: usize) {
+        let client = match self.clients.get_mut(&player_id) {
+            Some(client) => client,
+            None => return,
+        };
+        if !client.connection.wants_read() {
+            return;
+        }
+        let was_handshaking = client.connection.is_handshaking();
+        trace!("session[is_handshaking={};]", was_handshaking);
+        let result = client.connection.read();
+        let connection_id = client.connection_id;
+        if was_handshaking && !client.connection.is_handshaking() {
+            self.events.send(Event::HandshakeCompleted { player_id, connection_id });
+        }
+        // Pass on what arrived before any error
+        while let Some((control_byte, data)) = client.connection.next_message() {
+            self.events.send(Event::Received { player_id, connection_id, control_byte, data });
+        }
+        match result {
+            // Handshake records may need writing
+            Ok(true) => self.dirty.push(player_id),
+            Ok(false) => self.close(player_id, None),
+            Err(e) => {
+                warn!("Read failed: {}", e);
+                self.close(player_id, Some(e));
+            },
+        }
+    }
+
+    // Write out as much as the socket will take, asking for writable events
+    // only while there is more to write, as the socket is level triggered and
+    // almost always writable
+    fn flush(&mut self, player_id: usize) -> io::Result<()> {
+        let client = match self.clients.get_mut(&player_id) {
+            Some(client) => client,
+            None => return Ok(()),
+        };
+        client.connection.flush()?;
+        let writable = client.connection.wants_write();
+        if writable != client.writable_registered {
+            let interest = if writable { Ready::readable() | Ready::writable() } else { Ready::readable() };
+            self.poll.reregister(client.connection.socket(), Token(player_id), interest, PollOpt::level())?;
+            client.writable_registered = writable;
+        }
+        Ok(())
+    }
+
+    // Drop a connection the worker gave up on and tell the server
+    fn close(&mut self, player_id: usize, error: Option<io::Error>) {
+        if let Some(client) = self.clients.remove(&player_id) {
+            let connection_id = client.connection_id;
+            self.shutdown(client);
+            self.events.send(Event::Closed { player_id, connection_id, error });
+        }
+    }
+
+    fn shutdown(&self, client: Client) {
+        let _ = self.poll.deregister(client.connection.socket());
+        let _ = client.connection.socket().shutdown(net::Shutdown::Both);
+    }
+}
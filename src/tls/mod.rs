@@ -0,0 +1,543 @@
+use std::convert::TryFrom;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::{error, fmt, fs, io};
+
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, KeyInit};
+use ring::pbkdf2;
+use rustls::{Certificate, NoClientAuth, PrivateKey, ServerConfig};
+
+// DER tags
+const SEQUENCE: u8 = 0x30;
+const INTEGER: u8 = 0x02;
+const OCTET_STRING: u8 = 0x04;
+const OID: u8 = 0x06;
+const CONTEXT_0: u8 = 0xa0;
+
+// Object identifiers, as the bytes of their DER encoding
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_PBES2: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x05, 0x0d];
+const OID_PBKDF2: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x05, 0x0c];
+const OID_HMAC_SHA1: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x02, 0x07];
+const OID_HMAC_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x02, 0x09];
+const OID_HMAC_SHA384: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x02, 0x0a];
+const OID_HMAC_SHA512: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x02, 0x0b];
+const OID_AES128_CBC: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x02];
+const OID_AES192_CBC: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x16];
+const OID_AES256_CBC: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x2a];
+
+const AES_BLOCK: usize = 16;
+// Most PBKDF2 iterations accepted, well above what openssl writes, so a
+// corrupt or hostile key cannot keep the server hashing for hours
+const MAX_ITERATIONS: u32 = 10_000_000;
+
+// A certificate or key file that could not be used
+#[derive(Debug)]
+pub struct TlsError {
+    pub path: PathBuf,
+    // PEM block at fault, counting from 1, and the line it starts on
+    pub block: Option<(usize, usize)>,
+    pub kind: ErrorKind,
+}
+
+#[derive(Debug)]
+pub enum ErrorKind {
+    Io(io::Error),
+    // Unterminated block, mismatched END line or bad base64
+    MalformedPem(String),
+    NoCertificates,
+    InvalidCertificate,
+    NoPrivateKey,
+    MultiplePrivateKeys,
+    // Key data that is not a supported RSA or ECDSA key
+    InvalidKey(String),
+    // Encrypted key and no passphrase to decrypt it
+    PassphraseRequired,
+    // Encryption scheme not supported
+    UnsupportedEncryption(String),
+    // Wrong passphrase or corrupt encrypted key
+    DecryptionFailed,
+    // rustls refused the certificate chain and key
+    Rejected(String),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        if let Some((block, line)) = self.block {
+            write!(f, ": PEM block {} (line {})", block, line)?;
+        }
+        match self.kind {
+            ErrorKind::Io(ref e) => write!(f, ": {}", e),
+            ErrorKind::MalformedPem(ref reason) => write!(f, ": malformed PEM, {}", reason),
+            ErrorKind::NoCertificates => write!(f, ": no certificates found"),
+            ErrorKind::InvalidCertificate => write!(f, ": not a valid X.509 certificate"),
+            ErrorKind::NoPrivateKey => write!(f, ": no private key found"),
+            ErrorKind::MultiplePrivateKeys => write!(f, ": more than one private key found"),
+            ErrorKind::InvalidKey(ref reason) => write!(f, ": invalid private key, {}", reason),
+            ErrorKind::PassphraseRequired => write!(f, ": private key is encrypted and no passphrase was given"),
+            ErrorKind::UnsupportedEncryption(ref scheme) => write!(f, ": unsupported key encryption, {}", scheme),
+            ErrorKind::DecryptionFailed => write!(f, ": cannot decrypt private key, wrong passphrase?"),
+            ErrorKind::Rejected(ref reason) => write!(f, ": rejected, {}", reason),
+        }
+    }
+}
+
+impl error::Error for TlsError {}
+
+// One -----BEGIN label----- ... -----END label----- section of a PEM file
+pub struct PemBlock {
+    pub label: String,
+    // RFC 1421 headers such as Proc-Type, used by legacy encrypted keys
+    pub headers: Vec<(String, String)>,
+    pub data: Vec<u8>,
+    // Line the block begins on, counting from 1
+    pub line: usize,
+}
+
+// Split PEM text into its blocks, ignoring text between them
+pub fn parse_pem(text: &str) -> Result<Vec<PemBlock>, (usize, usize, String)> {
+    let mut blocks = Vec::new();
+    let mut lines = text.lines().enumerate();
+    while let Some((number, line)) = lines.next() {
+        let label = match pem_boundary(line, "BEGIN") {
+            Some(label) => label,
+            None => continue,
+        };
+        let block = blocks.len() + 1;
+        let line_number = number + 1;
+        let mut headers = Vec::new();
+        let mut base64_text = String::new();
+        let mut finished = false;
+        for (_, line) in lines.by_ref() {
+            let line = line.trim();
+            if let Some(end_label) = pem_boundary(line, "END") {
+                if end_label != label {
+                    return Err((block, line_number, format!("BEGIN {} ended by END {}", label, end_label)));
+                }
+                finished = true;
+                break;
+            }
+            match line.find(':') {
+                Some(split) if base64_text.is_empty() => {
+                    headers.push((line[..split].trim().to_string(), line[split + 1..].trim().to_string()));
+                },
+                _ => base64_text.push_str(line),
+            }
+        }
+        if !finished {
+            return Err((block, line_number, format!("no END {} line", label)));
+        }
+        let data = base64::decode(&base64_text)
+            .map_err(|e| (block, line_number, format!("bad base64, {}", e)))?;
+        blocks.push(PemBlock { label: label.to_string(), headers, data, line: line_number });
+    }
+    Ok(blocks)
+}
+
+// Label of a "-----BEGIN label-----" or "-----END label-----" line
+fn pem_boundary<'a>(line: &'a str, kind: &str) -> Option<&'a str> {
+    let line = line.trim();
+    let rest = line.strip_prefix("-----")?.strip_suffix("-----")?;
+    rest.strip_prefix(kind)?.strip_prefix(' ')
+}
+
+fn read_pem(path: &Path) -> Result<Vec<PemBlock>, TlsError> {
+    let text = fs::read_to_string(path).map_err(|e| TlsError { path: path.to_path_buf(), block: None, kind: ErrorKind::Io(e) })?;
+    parse_pem(&text).map_err(|(block, line, reason)| TlsError {
+        path: path.to_path_buf(),
+        block: Some((block, line)),
+        kind: ErrorKind::MalformedPem(reason),
+    })
+}
+
+// Certificate chain from a PEM file: the server's certificate followed by
+// any intermediate certificates, in the order they appear
+pub fn load_certificates(path: &Path) -> Result<Vec<Certificate>, TlsError> {
+    let mut certificates = Vec::new();
+    for (index, block) in read_pem(path)?.into_iter().enumerate() {
+        if block.label != "CERTIFICATE" {
+            continue;
+        }
+        if webpki::EndEntityCert::from(&block.data).is_err() {
+            return Err(TlsError { path: path.to_path_buf(), block: Some((index + 1, block.line)), kind: ErrorKind::InvalidCertificate });
+        }
+        certificates.push(Certificate(block.data));
+    }
+    if certificates.is_empty() {
+        return Err(TlsError { path: path.to_path_buf(), block: None, kind: ErrorKind::NoCertificates });
+    }
+    Ok(certificates)
+}
+
+// The one private key in a PEM file. RSA keys may be PKCS#1 or PKCS#8,
+// EC keys SEC1 or PKCS#8, and PKCS#8 keys may be encrypted with PBES2.
+pub fn load_private_key(path: &Path, passphrase: Option<&[u8]>) -> Result<PrivateKey, TlsError> {
+    let mut key = None;
+    for (index, block) in read_pem(path)?.into_iter().enumerate() {
+        let fail = |kind| TlsError { path: path.to_path_buf(), block: Some((index + 1, block.line)), kind };
+        let der = match block.label.as_str() {
+            "PRIVATE KEY" => block.data.clone(),
+            "RSA PRIVATE KEY" | "EC PRIVATE KEY" if block.headers.iter().any(|(name, _)| name == "Proc-Type") => {
+                let scheme = "legacy OpenSSL encryption, convert the key with `openssl pkcs8 -topk8`";
+                return Err(fail(ErrorKind::UnsupportedEncryption(String::from(scheme))));
+            },
+            "RSA PRIVATE KEY" => block.data.clone(),
+            "EC PRIVATE KEY" => sec1_to_pkcs8(&block.data).map_err(|reason| fail(ErrorKind::InvalidKey(reason)))?,
+            "ENCRYPTED PRIVATE KEY" => {
+                let passphrase = passphrase.ok_or_else(|| fail(ErrorKind::PassphraseRequired))?;
+                decrypt_pkcs8(&block.data, passphrase).map_err(fail)?
+            },
+            _ => continue,
+        };
+        if key.is_some() {
+            return Err(fail(ErrorKind::MultiplePrivateKeys));
+        }
+        let private_key = PrivateKey(der);
+        if rustls::sign::any_supported_type(&private_key).is_err() {
+            return Err(fail(ErrorKind::InvalidKey(String::from("not a supported RSA or ECDSA key"))));
+        }
+        key = Some(private_key);
+    }
+    key.ok_or_else(|| TlsError { path: path.to_path_buf(), block: None, kind: ErrorKind::NoPrivateKey })
+}
+
+// Server configuration presenting the certificate chain in `cert_path`
+// with the key in `key_path`
+pub fn server_config(cert_path: &Path, key_path: &Path, passphrase: Option<&[u8]>) -> Result<ServerConfig, TlsError> {
+    let certificates = load_certificates(cert_path)?;
+    let key = load_private_key(key_path, passphrase)?;
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(certificates, key).map_err(|e| TlsError {
+        path: cert_path.to_path_buf(),
+        block: None,
+        kind: ErrorKind::Rejected(e.to_string()),
+    })?;
+    Ok(config)
+}
+
+// Passphrase for an encrypted key, kept in a file without its line ending
+pub fn read_passphrase(path: &Path) -> Result<String, TlsError> {
+    let passphrase = fs::read_to_string(path).map_err(|e| TlsError { path: path.to_path_buf(), block: None, kind: ErrorKind::Io(e) })?;
+    Ok(passphrase.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+// Wrap a SEC1 ECPrivateKey in the PKCS#8 structure ring expects, taking
+// the curve from the key's parameters
+fn sec1_to_pkcs8(sec1: &[u8]) -> Result<Vec<u8>, String> {
+    let mut outer = Der::new(sec1);
+    let mut key = Der::new(outer.read(SEQUENCE).ok_or("not a DER sequence")?);
+    key.read(INTEGER).ok_or("missing version")?;
+    key.read(OCTET_STRING).ok_or("missing private key")?;
+    let parameters = key.read(CONTEXT_0).ok_or("missing curve parameters")?;
+    let curve = Der::new(parameters).read(OID).ok_or("curve is not a named curve")?;
+
+    let mut algorithm = der(OID, OID_EC_PUBLIC_KEY);
+    algorithm.extend(der(OID, curve));
+    let mut info = der(INTEGER, &[0]);
+    info.extend(der(SEQUENCE, &algorithm));
+    info.extend(der(OCTET_STRING, sec1));
+    Ok(der(SEQUENCE, &info))
+}
+
+// Decrypt a PKCS#8 EncryptedPrivateKeyInfo protected with PBES2, using
+// PBKDF2 and AES-CBC as written by `openssl pkcs8 -topk8 -v2 aes256`
+fn decrypt_pkcs8(encrypted: &[u8], passphrase: &[u8]) -> Result<Vec<u8>, ErrorKind> {
+    let unsupported = |what: &str| ErrorKind::UnsupportedEncryption(String::from(what));
+    let malformed = || ErrorKind::InvalidKey(String::from("malformed encrypted key"));
+
+    let mut outer = Der::new(encrypted);
+    let mut info = Der::new(outer.read(SEQUENCE).ok_or_else(malformed)?);
+    let mut algorithm = Der::new(info.read(SEQUENCE).ok_or_else(malformed)?);
+    let data = info.read(OCTET_STRING).ok_or_else(malformed)?;
+    if algorithm.read(OID).ok_or_else(malformed)? != OID_PBES2 {
+        return Err(unsupported("only PBES2 is supported"));
+    }
+    let mut parameters = Der::new(algorithm.read(SEQUENCE).ok_or_else(malformed)?);
+
+    // Key derivation
+    let mut kdf = Der::new(parameters.read(SEQUENCE).ok_or_else(malformed)?);
+    if kdf.read(OID).ok_or_else(malformed)? != OID_PBKDF2 {
+        return Err(unsupported("only PBKDF2 key derivation is supported"));
+    }
+    let mut kdf_parameters = Der::new(kdf.read(SEQUENCE).ok_or_else(malformed)?);
+    let salt = kdf_parameters.read(OCTET_STRING).ok_or_else(malformed)?;
+    let iterations = der_integer(kdf_parameters.read(INTEGER).ok_or_else(malformed)?)
+        .and_then(|iterations| u32::try_from(iterations).ok())
+        .filter(|&iterations| iterations <= MAX_ITERATIONS)
+        .and_then(NonZeroU32::new)
+        .ok_or_else(malformed)?;
+    if kdf_parameters.peek() == Some(INTEGER) {
+        kdf_parameters.read(INTEGER);
+    }
+    let prf = match kdf_parameters.read(SEQUENCE) {
+        Some(prf) => Der::new(prf).read(OID).ok_or_else(malformed)?,
+        None => OID_HMAC_SHA1,
+    };
+    let prf = match prf {
+        OID_HMAC_SHA1 => pbkdf2::PBKDF2_HMAC_SHA1,
+        OID_HMAC_SHA256 => pbkdf2::PBKDF2_HMAC_SHA256,
+        OID_HMAC_SHA384 => pbkdf2::PBKDF2_HMAC_SHA384,
+        OID_HMAC_SHA512 => pbkdf2::PBKDF2_HMAC_SHA512,
+        _ => return Err(unsupported("unknown PBKDF2 hash")),
+    };
+
+    // Cipher
+    let mut cipher = Der::new(parameters.read(SEQUENCE).ok_or_else(malformed)?);
+    let key_length = match cipher.read(OID).ok_or_else(malformed)? {
+        OID_AES128_CBC => 16,
+        OID_AES192_CBC => 24,
+        OID_AES256_CBC => 32,
+        _ => return Err(unsupported("only AES-CBC is supported")),
+    };
+    let iv = cipher.read(OCTET_STRING).filter(|iv| iv.len() == AES_BLOCK).ok_or_else(malformed)?;
+    if data.is_empty() || data.len() % AES_BLOCK != 0 {
+        return Err(malformed());
+    }
+
+    let mut key = vec![0; key_length];
+    pbkdf2::derive(prf, iterations, salt, passphrase, &mut key);
+    let mut plain = match key_length {
+        16 => aes_cbc_decrypt(&aes::Aes128::new_from_slice(&key).unwrap(), iv, data),
+        24 => aes_cbc_decrypt(&aes::Aes192::new_from_slice(&key).unwrap(), iv, data),
+        _ => aes_cbc_decrypt(&aes::Aes256::new_from_slice(&key).unwrap(), iv, data),
+    };
+
+    // A wrong passphrase almost always leaves invalid PKCS#7 padding
+    let padding = *plain.last().unwrap() as usize;
+    if padding == 0 || padding > AES_BLOCK || !plain[plain.len() - padding..].iter().all(|&byte| byte as usize == padding) {
+        return Err(ErrorKind::DecryptionFailed);
+    }
+    plain.truncate(plain.len() - padding);
+    if Der::new(&plain).read(SEQUENCE).is_none() {
+        return Err(ErrorKind::DecryptionFailed);
+    }
+    Ok(plain)
+}
+
+fn aes_cbc_decrypt<C: BlockDecrypt>(cipher: &C, iv: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut plain = Vec::with_capacity(data.len());
+    let mut previous = iv;
+    for chunk in data.chunks(AES_BLOCK) {
+        let mut block = GenericArray::clone_from_slice(chunk);
+        cipher.decrypt_block(&mut block);
+        plain.extend(block.iter().zip(previous.iter()).map(|(byte, mask)| byte ^ mask));
+        previous = chunk;
+    }
+    plain
+}
+
+// Reads DER elements one after another
+struct Der<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Der<'a> {
+    fn new(data: &'a [u8]) -> Der<'a> { Der { data } }
+
+    fn peek(&self) -> Option<u8> { self.data.first().copied() }
+
+    // Contents of the next element if it has the given tag
+    fn read(&mut self, tag: u8) -> Option<&'a [u8]> {
+        if self.peek()? != tag {
+            return None;
+        }
+        let first = *self.data.get(1)? as usize;
+        let (length, header) = if first < 0x80 {
+            (first, 2)
+        } else {
+            let count = first & 0x7f;
+            if count == 0 || count > 4 {
+                return None;
+            }
+            let bytes = self.data.get(2..2 + count)?;
+            (bytes.iter().fold(0, |length, &byte| (length << 8) | byte as usize), 2 + count)
+        };
+        let contents = self.data.get(header..header.checked_add(length)?)?;
+        self.data = &self.data[header + length..];
+        Some(contents)
+    }
+}
+
+// Small non-negative DER integer
+fn der_integer(contents: &[u8]) -> Option<u64> {
+    if contents.is_empty() || contents.len() > 8 || contents[0] & 0x80 != 0 {
+        return None;
+    }
+    Some(contents.iter().fold(0, |value, &byte| (value << 8) | u64::from(byte)))
+}
+
+// Encode one DER element
+fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    let length = contents.len();
+    if length < 0x80 {
+        encoded.push(length as u8);
+    } else {
+        let bytes: Vec<u8> = length.to_be_bytes().iter().copied().skip_while(|&byte| byte == 0).collect();
+        encoded.push(0x80 | bytes.len() as u8);
+        encoded.extend(bytes);
+    }
+    encoded.extend_from_slice(contents);
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Keys generated with openssl for these tests; the encrypted ones use
+    // the passphrase "cheese"
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("src/tls/testdata").join(name)
+    }
+
+    fn key_der(name: &str) -> Vec<u8> {
+        let text = fs::read_to_string(testdata(name)).unwrap();
+        parse_pem(&text).ok().unwrap().remove(0).data
+    }
+
+    #[test]
+    fn loads_unencrypted_keys() {
+        for name in &["ec_sec1.pem", "ec_pkcs8.pem", "rsa_pkcs1.pem", "rsa_pkcs8.pem"] {
+            assert!(load_private_key(&testdata(name), None).is_ok(), "{}", name);
+        }
+        // SEC1 keys are wrapped with the same algorithm as openssl uses for
+        // them; openssl also drops the curve from the inner key, which we keep
+        let key = load_private_key(&testdata("ec_sec1.pem"), None).unwrap();
+        let mut wrapped = Der::new(&key.0);
+        let mut info = Der::new(wrapped.read(SEQUENCE).unwrap());
+        let openssl = key_der("ec_pkcs8.pem");
+        let mut expected = Der::new(Der::new(&openssl).read(SEQUENCE).unwrap());
+        assert_eq!(info.read(INTEGER), expected.read(INTEGER));
+        assert_eq!(info.read(SEQUENCE), expected.read(SEQUENCE));
+        assert_eq!(info.read(OCTET_STRING), Some(&key_der("ec_sec1.pem")[..]));
+    }
+
+    #[test]
+    fn decrypts_pkcs8_keys() {
+        for name in &["ec_aes256.pem", "ec_aes128_sha256.pem"] {
+            let key = load_private_key(&testdata(name), Some(b"cheese")).unwrap();
+            assert_eq!(key.0, key_der("ec_pkcs8.pem"), "{}", name);
+        }
+        assert!(server_config(&testdata("ec_cert.pem"), &testdata("ec_aes256.pem"), Some(b"cheese")).is_ok());
+    }
+
+    #[test]
+    fn rejects_wrong_or_missing_passphrase() {
+        for name in &["ec_aes256.pem", "ec_aes128_sha256.pem"] {
+            let error = load_private_key(&testdata(name), Some(b"cheddar")).unwrap_err();
+            assert!(matches!(error.kind, ErrorKind::DecryptionFailed), "{}: {}", name, error);
+            assert_eq!(error.block, Some((1, 1)));
+            let error = load_private_key(&testdata(name), None).unwrap_err();
+            assert!(matches!(error.kind, ErrorKind::PassphraseRequired), "{}: {}", name, error);
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_der() {
+        let encrypted = key_der("ec_aes256.pem");
+        for length in 0..encrypted.len() {
+            let result = decrypt_pkcs8(&encrypted[..length], b"cheese");
+            assert!(matches!(result, Err(ErrorKind::InvalidKey(_))), "length {}", length);
+        }
+        let sec1 = key_der("ec_sec1.pem");
+        for length in 0..sec1.len() {
+            assert!(sec1_to_pkcs8(&sec1[..length]).is_err(), "length {}", length);
+        }
+        // A length running past the end of the data
+        assert_eq!(Der::new(&[SEQUENCE, 0x82, 0x01]).read(SEQUENCE), None);
+        assert_eq!(Der::new(&[SEQUENCE, 0x82, 0x01, 0x00, 0x00]).read(SEQUENCE), None);
+    }
+
+    // PBES2 AES-256-CBC key with the given DER iteration count
+    fn pbes2_key(iterations: &[u8]) -> Vec<u8> {
+        let mut kdf_parameters = der(OCTET_STRING, &[0; 8]);
+        kdf_parameters.extend(der(INTEGER, iterations));
+        let mut kdf = der(OID, OID_PBKDF2);
+        kdf.extend(der(SEQUENCE, &kdf_parameters));
+        let mut cipher = der(OID, OID_AES256_CBC);
+        cipher.extend(der(OCTET_STRING, &[0; AES_BLOCK]));
+        let mut parameters = der(SEQUENCE, &kdf);
+        parameters.extend(der(SEQUENCE, &cipher));
+        let mut algorithm = der(OID, OID_PBES2);
+        algorithm.extend(der(SEQUENCE, &parameters));
+        let mut info = der(SEQUENCE, &algorithm);
+        info.extend(der(OCTET_STRING, &[0; 2 * AES_BLOCK]));
+        der(SEQUENCE, &info)
+    }
+
+    #[test]
+    fn rejects_excessive_iterations() {
+        // 10,000,001 and 4,000,000,000
+        for iterations in &[&[0x00, 0x98, 0x96, 0x81][..], &[0x00, 0xee, 0x6b, 0x28, 0x00][..]] {
+            let result = decrypt_pkcs8(&pbes2_key(iterations), b"cheese");
+            assert!(matches!(result, Err(ErrorKind::InvalidKey(_))), "{:?}", iterations);
+        }
+        assert!(matches!(decrypt_pkcs8(&pbes2_key(&[0]), b"cheese"), Err(ErrorKind::InvalidKey(_))));
+        // A sane count gets as far as decrypting
+        assert!(matches!(decrypt_pkcs8(&pbes2_key(&[0x08, 0x00]), b"cheese"), Err(ErrorKind::DecryptionFailed)));
+    }
+
+    #[test]
+    fn reads_der_lengths() {
+        let long = vec![7; 300];
+        let encoded = der(OCTET_STRING, &long);
+        assert_eq!(&encoded[..4], &[OCTET_STRING, 0x82, 0x01, 0x2c]);
+        let mut reader = Der::new(&encoded);
+        assert_eq!(reader.read(SEQUENCE), None);
+        assert_eq!(reader.read(OCTET_STRING), Some(&long[..]));
+        assert_eq!(reader.peek(), None);
+        assert_eq!(der_integer(&[0x08, 0x00]), Some(2048));
+        assert_eq!(der_integer(&[0x80]), None);
+        assert_eq!(der_integer(&[]), None);
+    }
+
+    #[test]
+    fn decrypts_aes_cbc() {
+        // NIST SP 800-38A, F.2.2 CBC-AES128.Decrypt, first two blocks
+        let key = [0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf, 0x4f, 0x3c];
+        let iv: Vec<u8> = (0..16).collect();
+        let ciphertext = [
+            0x76, 0x49, 0xab, 0xac, 0x81, 0x19, 0xb2, 0x46, 0xce, 0xe9, 0x8e, 0x9b, 0x12, 0xe9, 0x19, 0x7d,
+            0x50, 0x86, 0xcb, 0x9b, 0x50, 0x72, 0x19, 0xee, 0x95, 0xdb, 0x11, 0x3a, 0x91, 0x76, 0x78, 0xb2,
+        ];
+        let plaintext = [
+            0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93, 0x17, 0x2a,
+            0xae, 0x2d, 0x8a, 0x57, 0x1e, 0x03, 0xac, 0x9c, 0x9e, 0xb7, 0x6f, 0xac, 0x45, 0xaf, 0x8e, 0x51,
+        ];
+        let cipher = aes::Aes128::new_from_slice(&key).unwrap();
+        assert_eq!(aes_cbc_decrypt(&cipher, &iv, &ciphertext), plaintext);
+    }
+
+    #[test]
+    fn reports_bad_pem() {
+        let key = fs::read_to_string(testdata("ec_sec1.pem")).unwrap();
+        let body: Vec<&str> = key.lines().collect();
+
+        let unterminated = format!("{}\n", body[..body.len() - 1].join("\n"));
+        let error = parse_pem(&unterminated).err().unwrap();
+        assert_eq!((error.0, error.1), (1, 1));
+        assert!(error.2.contains("no END"), "{}", error.2);
+
+        let mismatched = format!("{}\n-----END PRIVATE KEY-----\n", body[..body.len() - 1].join("\n"));
+        assert!(parse_pem(&mismatched).err().unwrap().2.contains("ended by END PRIVATE KEY"));
+
+        // Second block, starting on line 3, with a line that is not base64
+        let bad_line = format!("comment\n\n{}\n{}\n!!!!\n{}\n",
+            body[0], body[1], body[2..].join("\n"));
+        let two_blocks = format!("{}{}", key, bad_line);
+        let error = parse_pem(&two_blocks).err().unwrap();
+        assert_eq!((error.0, error.1), (2, body.len() + 3));
+        assert!(error.2.contains("bad base64"), "{}", error.2);
+
+        // Errors carry the block and line into the message
+        let path = std::env::temp_dir().join(format!("clientserver-bad-{}.pem", std::process::id()));
+        fs::write(&path, &unterminated).unwrap();
+        let error = load_private_key(&path, None).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(error.kind, ErrorKind::MalformedPem(_)));
+        assert!(error.to_string().ends_with(": PEM block 1 (line 1): malformed PEM, no END EC PRIVATE KEY line"), "{}", error);
+    }
+}
@@ -0,0 +1,640 @@
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    SingleElimination,
+    RoundRobin,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "elimination" => Some(Format::SingleElimination),
+            "roundrobin" => Some(Format::RoundRobin),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    Registration,
+    InProgress,
+    Finished,
+    // Registration closed without enough entrants
+    Cancelled,
+}
+
+#[derive(Clone, Debug)]
+pub struct Entrant {
+    pub player_id: usize,
+    pub name: String,
+    // Used for seeding
+    pub rating: f64,
+    pub wins: u8,
+    pub losses: u8,
+    pub draws: u8,
+    pub byes: u8,
+    // Round the player was knocked out in, single elimination only
+    pub eliminated_in: Option<u8>,
+    pub withdrawn: bool,
+}
+
+impl Entrant {
+    // Round robin points: two for a win, one for a draw
+    pub fn points(&self) -> u32 {
+        2 * u32::from(self.wins) + u32::from(self.draws)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PairingResult {
+    Won(usize),
+    Drawn,
+}
+
+// One game of a round; `second` is None for a bye
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pairing {
+    pub round: u8,
+    pub first: usize,
+    pub second: Option<usize>,
+    pub started: bool,
+    pub result: Option<PairingResult>,
+}
+
+impl Pairing {
+    pub fn has_player(&self, player_id: usize) -> bool {
+        self.first == player_id || self.second == Some(player_id)
+    }
+
+    pub fn opponent(&self, player_id: usize) -> Option<usize> {
+        if self.first == player_id { self.second } else { Some(self.first) }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Standing {
+    pub place: u8,
+    pub player_id: usize,
+    pub name: String,
+    pub wins: u8,
+    pub losses: u8,
+    pub draws: u8,
+}
+
+// A tournament run one round at a time. Like the Matchmaker it never looks
+// at sockets or the clock: the caller passes the time in, starts the games
+// returned by unstarted_games and reports their results.
+pub struct Tournament {
+    format: Format,
+    phase: Phase,
+    registration_closes: Instant,
+    entrants: Vec<Entrant>,
+    // Pairings of every round so far
+    pairings: Vec<Pairing>,
+    round: u8,
+    // Round robin schedule, one list of pairings per round, worked out
+    // once registration closes
+    schedule: Vec<Vec<(usize, Option<usize>)>>,
+}
+
+impl Tournament {
+    pub fn new(format: Format, registration: Duration, now: Instant) -> Tournament {
+        Tournament {
+            format,
+            phase: Phase::Registration,
+            registration_closes: now + registration,
+            entrants: Vec::new(),
+            pairings: Vec::new(),
+            round: 0,
+            schedule: Vec::new(),
+        }
+    }
+
+    pub fn format(&self) -> Format { self.format }
+
+    pub fn phase(&self) -> Phase { self.phase }
+
+    pub fn round(&self) -> u8 { self.round }
+
+    pub fn entrants(&self) -> &[Entrant] { self.entrants.as_slice() }
+
+    pub fn is_over(&self) -> bool {
+        self.phase == Phase::Finished || self.phase == Phase::Cancelled
+    }
+
+    pub fn registration_remaining(&self, now: Instant) -> Duration {
+        match self.phase {
+            Phase::Registration => self.registration_closes.saturating_duration_since(now),
+            _ => Duration::from_secs(0),
+        }
+    }
+
+    // Sign a player up; only possible while registration is open
+    pub fn register(&mut self, player_id: usize, name: &str, rating: f64) -> bool {
+        if self.phase != Phase::Registration || self.entrant(player_id).is_some() {
+            return false;
+        }
+        self.entrants.push(Entrant {
+            player_id,
+            name: name.to_string(),
+            rating,
+            wins: 0,
+            losses: 0,
+            draws: 0,
+            byes: 0,
+            eliminated_in: None,
+            withdrawn: false,
+        });
+        true
+    }
+
+    pub fn entrant(&self, player_id: usize) -> Option<&Entrant> {
+        self.entrants.iter().find(|entrant| entrant.player_id == player_id)
+    }
+
+    // True for entrants who may still have games to play, so should be kept
+    // out of the matchmaker
+    pub fn is_playing(&self, player_id: usize) -> bool {
+        !self.is_over() && self.entrant(player_id)
+            .is_some_and(|entrant| !entrant.withdrawn && entrant.eliminated_in.is_none())
+    }
+
+    // Remove a player. Before the first round they simply leave; after that
+    // any game of theirs still to be decided is forfeited, and they are out
+    // of a single elimination bracket even if they had no game left this
+    // round. Returns the pairings decided by the forfeit.
+    pub fn withdraw(&mut self, player_id: usize) -> Vec<Pairing> {
+        match self.phase {
+            Phase::Registration => {
+                self.entrants.retain(|entrant| entrant.player_id != player_id);
+                Vec::new()
+            },
+            Phase::InProgress => {
+                let round = self.round;
+                let format = self.format;
+                if let Some(entrant) = self.entrant_mut(player_id) {
+                    entrant.withdrawn = true;
+                    if format == Format::SingleElimination && entrant.eliminated_in.is_none() {
+                        entrant.eliminated_in = Some(round);
+                    }
+                }
+                let forfeited: Vec<Pairing> = self.pairings.iter()
+                    .filter(|pairing| pairing.round == round && pairing.result.is_none() && pairing.has_player(player_id))
+                    .cloned()
+                    .collect();
+                let mut decided = Vec::new();
+                for pairing in forfeited {
+                    let winner = pairing.opponent(player_id).unwrap();
+                    decided.extend(self.record_result(pairing.first, pairing.second.unwrap(), Some(winner)));
+                }
+                decided
+            },
+            Phase::Finished | Phase::Cancelled => Vec::new(),
+        }
+    }
+
+    // Close registration once its time is up and start the first round.
+    // Returns true if the tournament changed phase.
+    pub fn update(&mut self, now: Instant) -> bool {
+        if self.phase != Phase::Registration || now < self.registration_closes {
+            return false;
+        }
+        if self.entrants.len() < 2 {
+            self.phase = Phase::Cancelled;
+            return true;
+        }
+
+        // Highest rated entrants are the top seeds
+        self.entrants.sort_by(|a, b| b.rating.total_cmp(&a.rating).then_with(|| a.name.cmp(&b.name)));
+        if self.format == Format::RoundRobin {
+            self.schedule = round_robin_schedule(&self.entrants.iter().map(|entrant| entrant.player_id).collect::<Vec<usize>>());
+        }
+        self.phase = Phase::InProgress;
+        self.start_next_round();
+        true
+    }
+
+    // Pairings of the current round
+    pub fn current_pairings(&self) -> Vec<&Pairing> {
+        self.pairings.iter().filter(|pairing| pairing.round == self.round).collect()
+    }
+
+    // Games of the current round waiting to be started
+    pub fn unstarted_games(&self) -> Vec<(usize, usize)> {
+        if self.phase != Phase::InProgress {
+            return Vec::new();
+        }
+        self.current_pairings().into_iter()
+            .filter(|pairing| !pairing.started && pairing.result.is_none())
+            .filter_map(|pairing| pairing.second.map(|second| (pairing.first, second)))
+            .collect()
+    }
+
+    pub fn mark_started(&mut self, first: usize, second: usize) {
+        if let Some(pairing) = self.find_pairing_mut(first, second) {
+            pairing.started = true;
+        }
+    }
+
+    // Report the result of a game between two entrants; `winner` is None
+    // for a draw. A drawn single elimination game is played again. Returns
+    // the pairings decided, including byes handed out if the result
+    // completed the round.
+    pub fn record_result(&mut self, first: usize, second: usize, winner: Option<usize>) -> Vec<Pairing> {
+        let format = self.format;
+        let round = self.round;
+        let pairing = match self.find_pairing_mut(first, second) {
+            Some(pairing) if pairing.result.is_none() => pairing,
+            _ => return Vec::new(),
+        };
+        pairing.started = false;
+        let result = match winner {
+            Some(winner) => PairingResult::Won(winner),
+            // Someone has to go through, so play the game again
+            None if format == Format::SingleElimination => return Vec::new(),
+            None => PairingResult::Drawn,
+        };
+        pairing.result = Some(result);
+        let decided = pairing.clone();
+
+        match result {
+            PairingResult::Won(winner) => {
+                let loser = if winner == first { second } else { first };
+                if let Some(entrant) = self.entrant_mut(winner) {
+                    entrant.wins = entrant.wins.saturating_add(1);
+                }
+                if let Some(entrant) = self.entrant_mut(loser) {
+                    entrant.losses = entrant.losses.saturating_add(1);
+                    if format == Format::SingleElimination {
+                        entrant.eliminated_in = Some(round);
+                    }
+                }
+            },
+            PairingResult::Drawn => {
+                for player_id in [first, second].iter() {
+                    if let Some(entrant) = self.entrant_mut(*player_id) {
+                        entrant.draws = entrant.draws.saturating_add(1);
+                    }
+                }
+            },
+        }
+
+        let mut decided = vec![decided];
+        if self.current_pairings().iter().all(|pairing| pairing.result.is_some()) {
+            decided.extend(self.start_next_round());
+        }
+        decided
+    }
+
+    // Final placings, best first; players finishing level share a place
+    pub fn standings(&self) -> Vec<Standing> {
+        let key = |entrant: &Entrant| match self.format {
+            // Champion first, then by the round each player went out in
+            Format::SingleElimination => (u32::from(entrant.eliminated_in.unwrap_or(u8::MAX)), u32::from(entrant.wins)),
+            Format::RoundRobin => (entrant.points(), u32::from(entrant.wins)),
+        };
+        let mut entrants: Vec<&Entrant> = self.entrants.iter().collect();
+        entrants.sort_by(|a, b| key(b).cmp(&key(a)).then_with(|| a.name.cmp(&b.name)));
+        entrants.iter().map(|entrant| Standing {
+            place: 1 + entrants.iter().filter(|other| key(other) > key(entrant)).count() as u8,
+            player_id: entrant.player_id,
+            name: entrant.name.clone(),
+            wins: entrant.wins,
+            losses: entrant.losses,
+            draws: entrant.draws,
+        }).collect()
+    }
+
+    fn entrant_mut(&mut self, player_id: usize) -> Option<&mut Entrant> {
+        self.entrants.iter_mut().find(|entrant| entrant.player_id == player_id)
+    }
+
+    fn find_pairing_mut(&mut self, first: usize, second: usize) -> Option<&mut Pairing> {
+        let round = self.round;
+        self.pairings.iter_mut().find(|pairing| {
+            pairing.round == round && pairing.has_player(first) && pairing.has_player(second)
+        })
+    }
+
+    // Pair up the next round, or finish the tournament if there is none.
+    // Byes and forfeits against withdrawn players are decided straight away
+    // and returned.
+    fn start_next_round(&mut self) -> Vec<Pairing> {
+        let next_round: Vec<(usize, Option<usize>)> = match self.format {
+            Format::SingleElimination => {
+                let remaining: Vec<&Entrant> = self.entrants.iter()
+                    .filter(|entrant| entrant.eliminated_in.is_none())
+                    .collect();
+                if remaining.len() < 2 {
+                    Vec::new()
+                } else {
+                    elimination_round(&remaining)
+                }
+            },
+            Format::RoundRobin => {
+                // Withdrawn players sit out their byes
+                let mut round = self.schedule.get(self.round as usize).cloned().unwrap_or_default();
+                round.retain(|&(first, second)| second.is_some() || !self.entrant(first).is_some_and(|entrant| entrant.withdrawn));
+                round
+            },
+        };
+        if next_round.is_empty() {
+            self.phase = Phase::Finished;
+            return Vec::new();
+        }
+
+        self.round += 1;
+        let round = self.round;
+        for (first, second) in next_round {
+            self.pairings.push(Pairing { round, first, second, started: false, result: None });
+        }
+
+        let mut decided = Vec::new();
+        for (first, second) in self.current_pairings().iter().map(|pairing| (pairing.first, pairing.second)).collect::<Vec<_>>() {
+            match second {
+                None => {
+                    if let Some(pairing) = self.find_pairing_mut(first, first) {
+                        pairing.result = Some(PairingResult::Won(first));
+                        decided.push(pairing.clone());
+                    }
+                    if let Some(entrant) = self.entrant_mut(first) {
+                        entrant.byes = entrant.byes.saturating_add(1);
+                    }
+                },
+                Some(second) => {
+                    let first_withdrawn = self.entrant(first).is_some_and(|entrant| entrant.withdrawn);
+                    let second_withdrawn = self.entrant(second).is_some_and(|entrant| entrant.withdrawn);
+                    if first_withdrawn || second_withdrawn {
+                        let winner = if first_withdrawn { second } else { first };
+                        // Recursion is bounded: each call decides a pairing
+                        decided.extend(self.record_result(first, second, Some(winner)));
+                        if self.round != round {
+                            break;
+                        }
+                    }
+                },
+            }
+        }
+        if self.phase == Phase::InProgress && self.round == round
+            && self.current_pairings().iter().all(|pairing| pairing.result.is_some()) {
+            decided.extend(self.start_next_round());
+        }
+        decided
+    }
+}
+
+// Pair the players left in a single elimination tournament, best seed
+// against worst. With an odd number the best seed who has not yet had a bye
+// sits the round out.
+fn elimination_round(remaining: &[&Entrant]) -> Vec<(usize, Option<usize>)> {
+    let mut players: Vec<&Entrant> = remaining.to_vec();
+    let mut round = Vec::new();
+    if players.len() % 2 == 1 {
+        let bye = players.iter().position(|entrant| entrant.byes == 0).unwrap_or(0);
+        round.push((players.remove(bye).player_id, None));
+    }
+    let half = players.len() / 2;
+    for index in 0..half {
+        round.push((players[index].player_id, Some(players[players.len() - 1 - index].player_id)));
+    }
+    round
+}
+
+// Circle method: one player stays put while the rest rotate, so everyone
+// meets everyone once. An odd field gets a bye slot.
+fn round_robin_schedule(player_ids: &[usize]) -> Vec<Vec<(usize, Option<usize>)>> {
+    let mut slots: Vec<Option<usize>> = player_ids.iter().map(|&id| Some(id)).collect();
+    if slots.len() % 2 == 1 {
+        slots.push(None);
+    }
+    let mut rounds = Vec::new();
+    for _ in 1..slots.len() {
+        let mut round = Vec::new();
+        for index in 0..slots.len() / 2 {
+            match (slots[index], slots[slots.len() - 1 - index]) {
+                (Some(first), second) => round.push((first, second)),
+                (None, Some(second)) => round.push((second, None)),
+                (None, None) => (),
+            }
+        }
+        rounds.push(round);
+        let last = slots.pop().unwrap();
+        slots.insert(1, last);
+    }
+    rounds
+}
+
+// Tournament_Status message payload:
+// data[0] - phase: 0 registration, 1 in progress, 2 finished, 3 cancelled
+// data[1] - format: 0 single elimination, 1 round robin
+// data[2] - round, 0 during registration
+// data[3] - number of entrants
+// data[4..6] - seconds until registration closes (big endian)
+pub fn encode_status(tournament: &Tournament, now: Instant) -> Vec<u8> {
+    let phase = match tournament.phase {
+        Phase::Registration => 0,
+        Phase::InProgress => 1,
+        Phase::Finished => 2,
+        Phase::Cancelled => 3,
+    };
+    let format = match tournament.format {
+        Format::SingleElimination => 0,
+        Format::RoundRobin => 1,
+    };
+    let remaining = tournament.registration_remaining(now).as_secs().min(u64::from(u16::MAX)) as u16;
+    let mut data = vec![phase, format, tournament.round, tournament.entrants.len().min(usize::from(u8::MAX)) as u8];
+    data.extend_from_slice(&remaining.to_be_bytes());
+    data
+}
+
+// Tournament_Result message payload:
+// data[0] - round
+// data[1] - 0 if the first player won, 1 for a draw
+// data[2] - length n of the first player's name
+// data[3..3+n] - first player's name, the winner unless drawn
+// data[3+n..] - second player's name
+pub fn encode_result(tournament: &Tournament, pairing: &Pairing) -> Option<Vec<u8>> {
+    let second = pairing.second?;
+    let (first, second, drawn) = match pairing.result? {
+        PairingResult::Won(winner) if winner == second => (second, pairing.first, 0),
+        PairingResult::Won(_) => (pairing.first, second, 0),
+        PairingResult::Drawn => (pairing.first, second, 1),
+    };
+    let first_name = &tournament.entrant(first)?.name;
+    let second_name = &tournament.entrant(second)?.name;
+    let mut data = vec![pairing.round, drawn, first_name.len() as u8];
+    data.extend_from_slice(first_name.as_bytes());
+    data.extend_from_slice(second_name.as_bytes());
+    Some(data)
+}
+
+// Tournament_Standing message payload:
+// data[0] - place
+// data[1] - wins
+// data[2] - losses
+// data[3] - draws
+// data[4..] - player name
+pub fn encode_standing(standing: &Standing) -> Vec<u8> {
+    let mut data = vec![standing.place, standing.wins, standing.losses, standing.draws];
+    data.extend_from_slice(standing.name.as_bytes());
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Entrants 1..=count, rated so that player 1 is the top seed
+    fn started(format: Format, count: usize) -> Tournament {
+        let now = Instant::now();
+        let mut tournament = Tournament::new(format, Duration::from_secs(60), now);
+        for player_id in 1..=count {
+            assert!(tournament.register(player_id, &format!("player{}", player_id), 2000.0 - player_id as f64));
+        }
+        assert!(tournament.update(now + Duration::from_secs(60)));
+        tournament
+    }
+
+    #[test]
+    fn cancels_without_enough_entrants() {
+        let now = Instant::now();
+        let mut tournament = Tournament::new(Format::SingleElimination, Duration::from_secs(60), now);
+        tournament.register(1, "player1", 1200.0);
+        assert!(!tournament.update(now));
+        assert!(tournament.update(now + Duration::from_secs(60)));
+        assert_eq!(tournament.phase(), Phase::Cancelled);
+    }
+
+    #[test]
+    fn odd_fields_give_the_top_seed_a_bye() {
+        let mut tournament = started(Format::SingleElimination, 3);
+        assert_eq!(tournament.round(), 1);
+        assert_eq!(tournament.unstarted_games(), [(2, 3)]);
+        assert_eq!(tournament.entrant(1).unwrap().byes, 1);
+
+        // The winner meets the player who had the bye
+        let decided = tournament.record_result(2, 3, Some(3));
+        assert_eq!(decided.len(), 1);
+        assert_eq!(tournament.round(), 2);
+        assert_eq!(tournament.unstarted_games(), [(1, 3)]);
+        tournament.record_result(1, 3, Some(1));
+        assert_eq!(tournament.phase(), Phase::Finished);
+    }
+
+    #[test]
+    fn elimination_draws_are_replayed() {
+        let mut tournament = started(Format::SingleElimination, 2);
+        tournament.mark_started(1, 2);
+        assert!(tournament.unstarted_games().is_empty());
+        assert!(tournament.record_result(1, 2, None).is_empty());
+        assert_eq!(tournament.unstarted_games(), [(1, 2)]);
+    }
+
+    #[test]
+    fn round_robin_pairs_everyone_once() {
+        for count in 2..=7 {
+            let player_ids: Vec<usize> = (1..=count).collect();
+            let schedule = round_robin_schedule(&player_ids);
+            let mut pairs: Vec<(usize, usize)> = schedule.iter().flatten()
+                .filter_map(|&(first, second)| second.map(|second| (first.min(second), first.max(second))))
+                .collect();
+            pairs.sort_unstable();
+            let mut expected = Vec::new();
+            for first in 1..=count {
+                for second in first + 1..=count {
+                    expected.push((first, second));
+                }
+            }
+            assert_eq!(pairs, expected);
+
+            // Nobody plays twice in a round, and odd fields give out one bye a round
+            for round in schedule.iter() {
+                let mut seen: Vec<usize> = round.iter().flat_map(|&(first, second)| std::iter::once(first).chain(second)).collect();
+                seen.sort_unstable();
+                seen.dedup();
+                assert_eq!(seen.len(), count);
+                assert_eq!(round.iter().filter(|&&(_, second)| second.is_none()).count(), count % 2);
+            }
+        }
+    }
+
+    #[test]
+    fn withdrawing_forfeits_and_the_bracket_moves_on() {
+        let mut tournament = started(Format::SingleElimination, 4);
+        assert_eq!(tournament.unstarted_games(), [(1, 4), (2, 3)]);
+        tournament.mark_started(1, 4);
+        tournament.mark_started(2, 3);
+        tournament.record_result(1, 4, Some(1));
+
+        let decided = tournament.withdraw(3);
+        assert_eq!(decided[0].result, Some(PairingResult::Won(2)));
+        assert!(!tournament.is_playing(3));
+        assert_eq!(tournament.round(), 2);
+        assert_eq!(tournament.unstarted_games(), [(1, 2)]);
+
+        // A withdrawn player's later games are forfeited as the round starts
+        let mut tournament = started(Format::RoundRobin, 3);
+        tournament.withdraw(2);
+        for (first, second) in tournament.unstarted_games() {
+            tournament.record_result(first, second, Some(first));
+        }
+        for (first, second) in tournament.unstarted_games() {
+            tournament.record_result(first, second, Some(first));
+        }
+        assert_eq!(tournament.phase(), Phase::Finished);
+        assert_eq!(tournament.entrant(2).unwrap().wins, 0);
+    }
+
+    #[test]
+    fn withdrawn_players_get_no_byes() {
+        // Player 1 has the first round bye, then leaves
+        let mut tournament = started(Format::SingleElimination, 5);
+        assert_eq!(tournament.entrant(1).unwrap().byes, 1);
+        assert!(tournament.withdraw(1).is_empty());
+        tournament.record_result(2, 5, Some(2));
+        tournament.record_result(3, 4, Some(3));
+        assert_eq!(tournament.round(), 2);
+        assert_eq!(tournament.unstarted_games(), [(2, 3)]);
+        assert_eq!(tournament.entrant(1).unwrap().byes, 1);
+        tournament.record_result(2, 3, Some(2));
+        assert_eq!(tournament.phase(), Phase::Finished);
+        assert_eq!(tournament.standings()[0].player_id, 2);
+
+        // Player 3's bye comes in the last round, after they have left
+        let mut tournament = started(Format::RoundRobin, 3);
+        tournament.withdraw(3);
+        let mut decided = Vec::new();
+        while tournament.phase() == Phase::InProgress {
+            for (first, second) in tournament.unstarted_games() {
+                decided.extend(tournament.record_result(first, second, Some(first)));
+            }
+        }
+        assert!(!decided.iter().any(|pairing| pairing.first == 3 && pairing.second.is_none()));
+        assert_eq!(tournament.entrant(3).unwrap().byes, 0);
+        assert_eq!(tournament.entrant(2).unwrap().byes, 1);
+    }
+
+    #[test]
+    fn standings_order_by_points_and_share_places() {
+        let mut tournament = started(Format::RoundRobin, 4);
+        while tournament.phase() == Phase::InProgress {
+            for (first, second) in tournament.unstarted_games() {
+                // Player 1 beats everyone; the rest draw
+                let winner = if first == 1 || second == 1 { Some(1) } else { None };
+                tournament.record_result(first, second, winner);
+            }
+        }
+        let standings: Vec<(u8, usize)> = tournament.standings().iter().map(|standing| (standing.place, standing.player_id)).collect();
+        assert_eq!(standings, [(1, 1), (2, 2), (2, 3), (2, 4)]);
+        assert_eq!(tournament.entrant(1).unwrap().points(), 6);
+
+        let mut tournament = started(Format::SingleElimination, 4);
+        tournament.record_result(1, 4, Some(4));
+        tournament.record_result(2, 3, Some(2));
+        tournament.record_result(4, 2, Some(2));
+        let standings: Vec<(u8, usize)> = tournament.standings().iter().map(|standing| (standing.place, standing.player_id)).collect();
+        assert_eq!(standings, [(1, 2), (2, 4), (3, 1), (3, 3)]);
+    }
+}
@@ -0,0 +1,434 @@
+use std::io;
+use std::str;
+
+use log::debug;
+use ring::digest;
+
+// Appended to the client's key to make the Sec-WebSocket-Accept value
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// Largest upgrade request accepted
+const MAX_REQUEST: usize = 8 * 1024;
+// Largest frame payload accepted from a client
+const MAX_PAYLOAD: u64 = 64 * 1024;
+
+// Frame opcodes
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+// Close status codes
+const PROTOCOL_ERROR: u16 = 1002;
+const UNSUPPORTED_DATA: u16 = 1003;
+const MESSAGE_TOO_BIG: u16 = 1009;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    // Waiting for the HTTP upgrade request
+    Upgrading,
+    Open,
+    Closed,
+}
+
+// A frame received from the client, unmasked
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+// Server side of a WebSocket connection (RFC 6455) whose binary frames carry
+// the same framed messages as a raw connection. It only transforms bytes, so
+// it works over any transport: feed it what arrives from the client and
+// send the client what it hands back.
+pub struct WebSocket {
+    state: State,
+    // Bytes received but not yet parsed
+    input: Vec<u8>,
+    // Whether a fragmented binary message is being received
+    fragmented: bool,
+    // Frames sent before the upgrade completed
+    pending: Vec<u8>,
+}
+
+impl WebSocket {
+    pub fn new() -> WebSocket {
+        WebSocket { state: State::Upgrading, input: Vec::new(), fragmented: false, pending: Vec::new() }
+    }
+
+    pub fn is_upgrading(&self) -> bool { self.state == State::Upgrading }
+
+    // Once closed, by either side, nothing more is sent or received
+    pub fn is_closed(&self) -> bool { self.state == State::Closed }
+
+    // Handle bytes from the client, appending the message data they carry to
+    // `data` and whatever the client is owed (the upgrade response, pongs,
+    // the closing frame) to `reply`. A bad request or frame closes the
+    // connection and fails with InvalidInput.
+    pub fn receive(&mut self, input: &[u8], data: &mut Vec<u8>, reply: &mut Vec<u8>) -> io::Result<()> {
+        if self.state == State::Closed {
+            return Ok(());
+        }
+        self.input.extend_from_slice(input);
+        if self.state == State::Upgrading {
+            let end = match self.input.windows(4).position(|window| window == b"\r\n\r\n") {
+                Some(end) => end + 4,
+                None if self.input.len() > MAX_REQUEST => return Err(self.reject(reply, "upgrade request too large")),
+                None => return Ok(()),
+            };
+            let request: Vec<u8> = self.input.drain(..end).collect();
+            match upgrade_response(&request) {
+                Ok(response) => {
+                    debug!("WebSocket upgrade complete");
+                    reply.extend_from_slice(&response);
+                    reply.append(&mut self.pending);
+                    self.state = State::Open;
+                },
+                Err(e) => return Err(self.reject(reply, e)),
+            }
+        }
+        while self.state == State::Open {
+            let Frame { fin, opcode, payload } = match parse_frame(&mut self.input) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err((code, e)) => return Err(self.fail(reply, code, e)),
+            };
+            match opcode {
+                BINARY | CONTINUATION => {
+                    if (opcode == BINARY) == self.fragmented {
+                        return Err(self.fail(reply, PROTOCOL_ERROR, "unexpected continuation frame"));
+                    }
+                    data.extend_from_slice(&payload);
+                    self.fragmented = !fin;
+                },
+                TEXT => return Err(self.fail(reply, UNSUPPORTED_DATA, "text frames are not supported")),
+                CLOSE | PING | PONG if !fin || payload.len() > 125 => {
+                    return Err(self.fail(reply, PROTOCOL_ERROR, "bad control frame"));
+                },
+                // Echo the status code back and stop
+                CLOSE => {
+                    debug!("WebSocket closed by client");
+                    reply.extend_from_slice(&encode_frame(CLOSE, &payload[..payload.len().min(2)]));
+                    self.state = State::Closed;
+                },
+                PING => reply.extend_from_slice(&encode_frame(PONG, &payload)),
+                PONG => (),
+                _ => return Err(self.fail(reply, PROTOCOL_ERROR, "unknown opcode")),
+            }
+        }
+        Ok(())
+    }
+
+    // Wrap one or more messages in a binary frame, held back until the
+    // upgrade completes
+    pub fn send(&mut self, data: &[u8], output: &mut Vec<u8>) {
+        let frame = encode_frame(BINARY, data);
+        match self.state {
+            State::Upgrading => self.pending.extend_from_slice(&frame),
+            State::Open => output.extend_from_slice(&frame),
+            State::Closed => (),
+        }
+    }
+
+    fn reject(&mut self, reply: &mut Vec<u8>, reason: &str) -> io::Error {
+        reply.extend_from_slice(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        self.state = State::Closed;
+        io::Error::new(io::ErrorKind::InvalidInput, reason)
+    }
+
+    fn fail(&mut self, reply: &mut Vec<u8>, code: u16, reason: &str) -> io::Error {
+        reply.extend_from_slice(&encode_frame(CLOSE, &code.to_be_bytes()));
+        self.state = State::Closed;
+        io::Error::new(io::ErrorKind::InvalidInput, reason)
+    }
+}
+
+impl Default for WebSocket {
+    fn default() -> WebSocket { WebSocket::new() }
+}
+
+// 101 response to a WebSocket upgrade request, or why it is unacceptable
+fn upgrade_response(request: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let request = str::from_utf8(request).map_err(|_| "upgrade request is not UTF-8")?;
+    let mut lines = request.split("\r\n");
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    if request_line.next() != Some("GET") || request_line.nth(1) != Some("HTTP/1.1") {
+        return Err("not an HTTP/1.1 GET request");
+    }
+    let headers: Vec<(String, &str)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim()))
+        .collect();
+    let header = |name: &str| headers.iter().find(|(header, _)| header == name).map(|&(_, value)| value);
+
+    if !header("upgrade").is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket")) {
+        return Err("missing Upgrade: websocket");
+    }
+    if !header("connection").is_some_and(|connection| connection.split(',').any(|token| token.trim().eq_ignore_ascii_case("upgrade"))) {
+        return Err("missing Connection: Upgrade");
+    }
+    if header("sec-websocket-version") != Some("13") {
+        return Err("unsupported WebSocket version");
+    }
+    let key = header("sec-websocket-key").ok_or("missing Sec-WebSocket-Key")?;
+    let hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, format!("{}{}", key, ACCEPT_GUID).as_bytes());
+    Ok(format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+               base64::encode(hash.as_ref())).into_bytes())
+}
+
+// Remove the first complete frame from `input`. Errors carry the close
+// status code to send.
+fn parse_frame(input: &mut Vec<u8>) -> Result<Option<Frame>, (u16, &'static str)> {
+    if input.len() < 2 {
+        return Ok(None);
+    }
+    let fin = input[0] & 0x80 != 0;
+    if input[0] & 0x70 != 0 {
+        return Err((PROTOCOL_ERROR, "reserved bits set"));
+    }
+    let opcode = input[0] & 0x0f;
+    // Clients must mask every frame
+    if input[1] & 0x80 == 0 {
+        return Err((PROTOCOL_ERROR, "unmasked client frame"));
+    }
+    let (length, header_len) = match input[1] & 0x7f {
+        126 if input.len() >= 4 => (u16::from_be_bytes([input[2], input[3]]) as u64, 4),
+        127 if input.len() >= 10 => {
+            let mut length = [0; 8];
+            length.copy_from_slice(&input[2..10]);
+            (u64::from_be_bytes(length), 10)
+        },
+        126 | 127 => return Ok(None),
+        length => (length as u64, 2),
+    };
+    if length > MAX_PAYLOAD {
+        return Err((MESSAGE_TOO_BIG, "frame too large"));
+    }
+    let length = length as usize;
+    if input.len() < header_len + 4 + length {
+        return Ok(None);
+    }
+    let frame: Vec<u8> = input.drain(..header_len + 4 + length).collect();
+    let mask = &frame[header_len..header_len + 4];
+    let payload = frame[header_len + 4..].iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]).collect();
+    Ok(Some(Frame { fin, opcode, payload }))
+}
+
+// Unmasked, unfragmented frame as sent by the server
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        length if length < 126 => frame.push(length as u8),
+        length if length <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        },
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        },
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sample handshake from RFC 6455 section 1.3
+    const REQUEST: &[u8] = b"GET /chat HTTP/1.1\r\nHost: server.example.com\r\nUpgrade: websocket\r\n\
+        Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Origin: http://example.com\r\nSec-WebSocket-Version: 13\r\n\r\n";
+    const RESPONSE: &[u8] = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+        Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n";
+
+    // Frame as a client sends it, masked
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = encode_frame(opcode, payload);
+        if !fin {
+            frame[0] &= 0x7f;
+        }
+        let header_len = frame.len() - payload.len();
+        frame[1] |= 0x80;
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        for (i, byte) in frame[header_len..].iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        frame.splice(header_len..header_len, mask.iter().copied());
+        frame
+    }
+
+    fn open() -> WebSocket {
+        let mut websocket = WebSocket::new();
+        let (mut data, mut reply) = (Vec::new(), Vec::new());
+        websocket.receive(REQUEST, &mut data, &mut reply).unwrap();
+        assert_eq!(reply, RESPONSE);
+        websocket
+    }
+
+    // Data and reply from feeding `input` to the WebSocket, and whether it failed
+    fn receive(websocket: &mut WebSocket, input: &[u8]) -> (Vec<u8>, Vec<u8>, bool) {
+        let (mut data, mut reply) = (Vec::new(), Vec::new());
+        let result = websocket.receive(input, &mut data, &mut reply);
+        if let Err(ref e) = result {
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        }
+        (data, reply, result.is_err())
+    }
+
+    fn close_frame(code: u16) -> Vec<u8> {
+        encode_frame(CLOSE, &code.to_be_bytes())
+    }
+
+    #[test]
+    fn answers_rfc_sample_handshake() {
+        assert_eq!(upgrade_response(REQUEST).unwrap(), RESPONSE);
+
+        // Split across reads, with a message sent before the upgrade finished
+        let mut websocket = WebSocket::new();
+        let mut output = Vec::new();
+        websocket.send(&[7, 0], &mut output);
+        assert!(output.is_empty());
+        let (_, reply, _) = receive(&mut websocket, &REQUEST[..40]);
+        assert!(reply.is_empty() && websocket.is_upgrading());
+        let (_, reply, _) = receive(&mut websocket, &REQUEST[40..]);
+        assert_eq!(reply, [RESPONSE, &[0x82, 2, 7, 0][..]].concat());
+        assert!(!websocket.is_upgrading());
+    }
+
+    #[test]
+    fn rejects_bad_upgrades() {
+        let text = str::from_utf8(REQUEST).unwrap();
+        for bad in &[text.replace("GET", "POST"), text.replace("Version: 13", "Version: 8"),
+                     text.replace("Upgrade: websocket", "Upgrade: h2c"), text.replace("Sec-WebSocket-Key", "X-Key")] {
+            let mut websocket = WebSocket::new();
+            let (_, reply, failed) = receive(&mut websocket, bad.as_bytes());
+            assert!(failed && websocket.is_closed(), "{}", bad);
+            assert!(reply.starts_with(b"HTTP/1.1 400 Bad Request\r\n"));
+        }
+        let mut websocket = WebSocket::new();
+        assert!(receive(&mut websocket, &vec![b'a'; MAX_REQUEST + 1]).2);
+    }
+
+    #[test]
+    fn unmasks_frames() {
+        let mut websocket = open();
+        let (data, reply, failed) = receive(&mut websocket, &client_frame(true, BINARY, &[3, 2, 1, 9, 8]));
+        assert_eq!(data, [3, 2, 1, 9, 8]);
+        assert!(reply.is_empty() && !failed);
+
+        // One byte at a time
+        let frame = client_frame(true, BINARY, b"abcdef");
+        let mut data = Vec::new();
+        for byte in frame.iter() {
+            data.extend(receive(&mut websocket, &[*byte]).0);
+        }
+        assert_eq!(data, b"abcdef");
+    }
+
+    #[test]
+    fn joins_fragmented_messages() {
+        let mut websocket = open();
+        let mut input = client_frame(false, BINARY, &[1, 4]);
+        input.extend(client_frame(true, PING, b"hi"));
+        input.extend(client_frame(false, CONTINUATION, &[5, 6]));
+        input.extend(client_frame(true, CONTINUATION, &[7, 8]));
+        let (data, reply, failed) = receive(&mut websocket, &input);
+        assert_eq!(data, [1, 4, 5, 6, 7, 8]);
+        assert_eq!(reply, encode_frame(PONG, b"hi"));
+        assert!(!failed);
+
+        let (_, reply, failed) = receive(&mut websocket, &client_frame(true, CONTINUATION, &[1]));
+        assert!(failed);
+        assert_eq!(reply, close_frame(PROTOCOL_ERROR));
+
+        let mut websocket = open();
+        let mut input = client_frame(false, BINARY, &[1]);
+        input.extend(client_frame(true, BINARY, &[2]));
+        assert_eq!(receive(&mut websocket, &input).1, close_frame(PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn reads_extended_lengths() {
+        let medium: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let large: Vec<u8> = (0..70_000).map(|i| (i * 7) as u8).collect();
+        assert_eq!(&encode_frame(BINARY, &medium)[..4], &[0x82, 126, 0x01, 0x2c]);
+        assert_eq!(&encode_frame(BINARY, &large)[..10], &[0x82, 127, 0, 0, 0, 0, 0, 0x01, 0x11, 0x70]);
+
+        let mut websocket = open();
+        assert_eq!(receive(&mut websocket, &client_frame(true, BINARY, &medium)).0, medium);
+
+        // A 64 bit length need not be minimal
+        let mut frame = vec![0x82, 0x80 | 127, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0];
+        frame.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(receive(&mut websocket, &frame).0, [1, 2, 3]);
+    }
+
+    #[test]
+    fn refuses_oversized_frames() {
+        let mut websocket = open();
+        // Refused from the header alone, before the payload arrives
+        let mut header = vec![0x82, 0x80 | 127];
+        header.extend_from_slice(&(MAX_PAYLOAD + 1).to_be_bytes());
+        let (data, reply, failed) = receive(&mut websocket, &header);
+        assert!(failed && data.is_empty() && websocket.is_closed());
+        assert_eq!(reply, close_frame(MESSAGE_TOO_BIG));
+
+        let mut websocket = open();
+        let payload = vec![0; MAX_PAYLOAD as usize];
+        assert_eq!(receive(&mut websocket, &client_frame(true, BINARY, &payload)).0.len(), payload.len());
+    }
+
+    #[test]
+    fn checks_control_frames() {
+        let mut websocket = open();
+        let (_, reply, failed) = receive(&mut websocket, &client_frame(true, PING, &[0; 126]));
+        assert!(failed);
+        assert_eq!(reply, close_frame(PROTOCOL_ERROR));
+
+        let mut websocket = open();
+        assert_eq!(receive(&mut websocket, &client_frame(false, PING, b"x")).1, close_frame(PROTOCOL_ERROR));
+
+        let mut websocket = open();
+        let (_, reply, failed) = receive(&mut websocket, &client_frame(true, PING, &[9; 125]));
+        assert_eq!(reply, encode_frame(PONG, &[9; 125]));
+        assert!(!failed);
+        let (_, reply, failed) = receive(&mut websocket, &client_frame(true, PONG, b"late"));
+        assert!(reply.is_empty() && !failed);
+    }
+
+    #[test]
+    fn rejects_unmasked_and_text_frames() {
+        let mut websocket = open();
+        let (data, reply, failed) = receive(&mut websocket, &encode_frame(BINARY, &[1, 2]));
+        assert!(failed && data.is_empty());
+        assert_eq!(reply, close_frame(PROTOCOL_ERROR));
+
+        let mut websocket = open();
+        assert_eq!(receive(&mut websocket, &client_frame(true, TEXT, b"{}")).1, close_frame(UNSUPPORTED_DATA));
+
+        let mut websocket = open();
+        let mut frame = client_frame(true, BINARY, &[1]);
+        frame[0] |= 0x40;
+        assert_eq!(receive(&mut websocket, &frame).1, close_frame(PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn echoes_close() {
+        let mut websocket = open();
+        let mut input = client_frame(true, CLOSE, &[0x03, 0xe8, b'b', b'y', b'e']);
+        input.extend(client_frame(true, BINARY, &[1]));
+        let (data, reply, failed) = receive(&mut websocket, &input);
+        assert!(data.is_empty() && !failed && websocket.is_closed());
+        assert_eq!(reply, close_frame(1000));
+
+        // Nothing more goes either way
+        let mut output = Vec::new();
+        websocket.send(&[1, 0], &mut output);
+        assert!(output.is_empty());
+        assert_eq!(receive(&mut websocket, &client_frame(true, PING, b"")), (Vec::new(), Vec::new(), false));
+    }
+}